@@ -196,8 +196,109 @@ CREATE TABLE IF NOT EXISTS ab_rule (
                                     created_at datetime not null default(current_timestamp)
 ) without rowid;
 INSERT OR IGNORE INTO ab_rule VALUES(X'018f255623117efa9d25470a9160c6d9',X'018f255623117efa9d25470a9160c6d7',NULL,X'018f255622fb73ee9afdbbcdc0cc387b',3,current_timestamp);
+CREATE TABLE IF NOT EXISTS peer_presence (
+                                    guid blob primary key not null,
+                                    peer blob not null,
+                                    online tinyint not null,
+                                    created_at datetime not null default(current_timestamp)
+) without rowid;
+INSERT OR IGNORE INTO settings VALUES('peer_online_threshold_secs','60');
+INSERT OR IGNORE INTO settings VALUES('peer_cleanup_stale_days','0');
+INSERT OR IGNORE INTO settings VALUES('peer_cleanup_action','flag');
+INSERT OR IGNORE INTO settings VALUES('peer_cleanup_merge_duplicates','0');
+CREATE TABLE IF NOT EXISTS peer_sysinfo (
+                                    guid blob primary key not null,
+                                    peer blob not null,
+                                    version integer not null,
+                                    info JSON not null,
+                                    diff JSON not null,
+                                    created_at datetime not null default(current_timestamp)
+) without rowid;
+CREATE TABLE IF NOT EXISTS ab_legacy_migration (
+                                    user_guid blob primary key not null,
+                                    migrated_at datetime not null default(current_timestamp)
+) without rowid;
+INSERT OR IGNORE INTO settings VALUES('ab_legacy_compat','0');
+INSERT OR IGNORE INTO settings VALUES('ab_peer_recycle_retention_days','30');
+CREATE TABLE IF NOT EXISTS ab_peer_revision (
+                                    guid blob primary key not null,
+                                    ab_peer blob not null,
+                                    revision integer not null,
+                                    action varchar(20) not null,
+                                    info text not null,
+                                    updated_by blob,
+                                    created_at datetime not null default(current_timestamp)
+) without rowid;
+CREATE TABLE IF NOT EXISTS ab_revision (
+                                    ab blob primary key not null,
+                                    revision integer not null,
+                                    updated_at datetime not null default(current_timestamp)
+) without rowid;
+CREATE TABLE IF NOT EXISTS role (
+                                    guid blob primary key not null,
+                                    name varchar(100) not null,
+                                    note varchar(300),
+                                    permissions JSON not null default '[]',
+                                    created_at datetime not null default(current_timestamp),
+                                    updated_at datetime not null default(current_timestamp)
+) without rowid;
+CREATE TABLE IF NOT EXISTS user_role (
+                                    "user" blob not null,
+                                    role blob not null,
+                                    created_at datetime not null default(current_timestamp),
+                                    primary key ("user", role)
+) without rowid;
+CREATE TABLE IF NOT EXISTS user_token (
+                                    guid blob primary key not null,
+                                    "user" blob not null,
+                                    kind varchar(20) not null,
+                                    token_hash char(64) not null,
+                                    created_at datetime not null default(current_timestamp),
+                                    expires_at datetime not null
+) without rowid;
+INSERT OR IGNORE INTO settings VALUES('public_url','http://127.0.0.1:21114');
+INSERT OR IGNORE INTO settings VALUES('smtp_host','');
+INSERT OR IGNORE INTO settings VALUES('smtp_port','587');
+INSERT OR IGNORE INTO settings VALUES('smtp_security','starttls');
+INSERT OR IGNORE INTO settings VALUES('smtp_username','');
+INSERT OR IGNORE INTO settings VALUES('smtp_password','');
+INSERT OR IGNORE INTO settings VALUES('smtp_from','SCTGDesk <noreply@example.org>');
+INSERT OR IGNORE INTO settings VALUES('invite_expiry_hours','72');
+INSERT OR IGNORE INTO settings VALUES('password_reset_expiry_hours','1');
+INSERT OR IGNORE INTO settings VALUES('mail_invite_subject','You are invited to {server}');
+INSERT OR IGNORE INTO settings VALUES('mail_invite_body','Hello {name},
+
+You have been invited to {server}, choose your password with the following link before {expires_at} UTC:
+
+{link}
+');
+INSERT OR IGNORE INTO settings VALUES('mail_reset_subject','Reset your {server} password');
+INSERT OR IGNORE INTO settings VALUES('mail_reset_body','Hello {name},
+
+A password reset was requested for your {server} account, choose a new password with the following link before {expires_at} UTC:
+
+{link}
+
+If you did not request it, you can ignore this message.
+');
+CREATE TABLE IF NOT EXISTS user_password_history (
+                                    id integer primary key autoincrement,
+                                    "user" blob not null,
+                                    password varchar(100) not null,
+                                    created_at datetime not null default(current_timestamp)
+);
+INSERT OR IGNORE INTO settings VALUES('password_min_length','8');
+INSERT OR IGNORE INTO settings VALUES('password_min_classes','1');
+INSERT OR IGNORE INTO settings VALUES('password_banned_file','');
+INSERT OR IGNORE INTO settings VALUES('password_history_count','3');
+INSERT OR IGNORE INTO settings VALUES('password_hash_cost','12');
+INSERT OR IGNORE INTO settings VALUES('user_data_max_size','65536');
+INSERT OR IGNORE INTO settings VALUES('user_data_max_entries','32');
 CREATE UNIQUE INDEX IF NOT EXISTS index_team_name on team (name);
 CREATE INDEX IF NOT EXISTS index_session_user on session ("user");
+CREATE UNIQUE INDEX IF NOT EXISTS index_user_token_hash on user_token (token_hash);
+CREATE INDEX IF NOT EXISTS index_user_token_user on user_token ("user");
+CREATE INDEX IF NOT EXISTS index_user_password_history_user on user_password_history ("user");
 CREATE INDEX IF NOT EXISTS index_session_expiry_at on session (expiry_at);
 CREATE UNIQUE INDEX IF NOT EXISTS index_peer_id on peer (id);
 CREATE INDEX IF NOT EXISTS index_peer_user on peer ("user");
@@ -247,6 +348,58 @@ CREATE INDEX IF NOT EXISTS index_ab_peer_ab_deleted_at on ab_peer (ab, deleted_a
 CREATE INDEX IF NOT EXISTS index_ab_rule_user on ab_rule ("user");
 CREATE INDEX IF NOT EXISTS index_ab_rule_grp on ab_rule (grp);
 CREATE INDEX IF NOT EXISTS index_ab_rule_ab_created_at on ab_rule (ab, created_at);
+CREATE INDEX IF NOT EXISTS index_peer_presence_peer_created_at on peer_presence (peer, created_at);
+CREATE UNIQUE INDEX IF NOT EXISTS index_peer_sysinfo_peer_version on peer_sysinfo (peer, version);
+CREATE UNIQUE INDEX IF NOT EXISTS index_ab_peer_revision_ab_peer_revision on ab_peer_revision (ab_peer, revision);
+CREATE INDEX IF NOT EXISTS index_ab_revision_revision on ab_revision (revision);
+CREATE UNIQUE INDEX IF NOT EXISTS index_role_name on role (name);
+CREATE INDEX IF NOT EXISTS index_user_role_role on user_role (role);
+-- Each change of the peers or tags of an address book gives it a new revision, the revisions are
+-- increasing across all the address books so they can be used as a cursor
+CREATE TRIGGER IF NOT EXISTS trigger_ab_peer_insert_ab_revision AFTER INSERT ON ab_peer
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (NEW.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_ab_peer_update_ab_revision AFTER UPDATE ON ab_peer
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (NEW.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_ab_peer_delete_ab_revision AFTER DELETE ON ab_peer
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (OLD.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_ab_tag_insert_ab_revision AFTER INSERT ON ab_tag
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (NEW.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_ab_tag_update_ab_revision AFTER UPDATE ON ab_tag
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (NEW.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_ab_tag_delete_ab_revision AFTER DELETE ON ab_tag
+BEGIN
+    INSERT INTO ab_revision (ab, revision, updated_at)
+        VALUES (OLD.ab, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+        ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+END;
+CREATE TRIGGER IF NOT EXISTS trigger_user_password_history AFTER UPDATE OF password ON user
+WHEN OLD.password <> NEW.password
+BEGIN
+    DELETE FROM user_password_history WHERE "user" = OLD.guid AND id NOT IN (
+        SELECT id FROM user_password_history WHERE "user" = OLD.guid ORDER BY id DESC
+            LIMIT COALESCE((SELECT CAST(value AS INTEGER) FROM settings WHERE key = 'password_history_count'), 0));
+    INSERT INTO user_password_history ("user", password) VALUES (OLD.guid, OLD.password);
+END;
 
 -- Needed for compilation time sqlx check
 -- INSERT OR IGNORE INTO peer (guid, id, uuid, pk, created_at, "user", status, note, region, strategy, info, last_online) VALUES
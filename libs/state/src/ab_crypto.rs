@@ -0,0 +1,339 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::env;
+use std::path::PathBuf;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use utils::AbPeer;
+
+/// Prefix of the encrypted values, followed by the key id and the base64 encoded nonce and ciphertext
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const HKDF_SALT: &[u8] = b"sctgdesk";
+const HKDF_INFO: &[u8] = b"address book peer credentials";
+const NONCE_LEN: usize = 12;
+const DEFAULT_MASTER_KEY_FILE: &str = "ab_master.key";
+
+/// Where the master keys come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AbKeySource {
+    /// `AB_MASTER_KEY` environment variable
+    Env,
+    /// Key file, `AB_MASTER_KEY_FILE` or ab_master.key by default
+    File(PathBuf),
+    /// No key could be loaded, the credentials are stored in clear
+    None,
+}
+
+struct AbKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+/// Keys used to encrypt the address book peers credentials (password and hash)
+///
+/// The master keys are base64 encoded and read from the `AB_MASTER_KEY` environment variable
+/// (comma separated) or from a key file (one per line) whose path is `AB_MASTER_KEY_FILE`.
+/// The default key file is created with a random key when it does not exist and no credential is encrypted yet.
+/// The first master key encrypts, the others are previous keys only used to decrypt until the
+/// rows are re-encrypted.
+/// The encryption keys are derived from the master keys with HKDF-SHA256 and used with AES-256-GCM.
+pub struct AbKeyring {
+    source: AbKeySource,
+    keys: Vec<AbKey>,
+}
+
+impl AbKeyring {
+    /// Load the keyring from the environment
+    ///
+    /// # Arguments
+    ///
+    /// * `create_key_file` - create the key file with a new key when it does not exist, it must be false
+    ///   when credentials are already encrypted because a new key cannot decrypt them
+    pub fn load(create_key_file: bool) -> Self {
+        if let Ok(master_keys) = env::var("AB_MASTER_KEY") {
+            let keyring = Self::from_master_keys(
+                AbKeySource::Env,
+                master_keys.split(',').map(|key| key.to_string()).collect(),
+            );
+            if !keyring.keys.is_empty() {
+                return keyring;
+            }
+        }
+        let path = env::var("AB_MASTER_KEY_FILE").unwrap_or(DEFAULT_MASTER_KEY_FILE.to_string());
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            if !create_key_file {
+                log::error!(
+                    "the address book master key file {:?} does not exist but credentials are encrypted, restore it or set AB_MASTER_KEY",
+                    path
+                );
+                return Self::from_master_keys(AbKeySource::None, Vec::new());
+            }
+            if let Err(e) = write_key_file(&path, &[generate_master_key()]) {
+                log::error!("cannot create the address book master key file {:?}: {}", path, e);
+                return Self::from_master_keys(AbKeySource::None, Vec::new());
+            }
+            log::info!("address book master key created in {:?}, keep it with your backups", path);
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::from_master_keys(
+                AbKeySource::File(path),
+                content.lines().map(|line| line.to_string()).collect(),
+            ),
+            Err(e) => {
+                log::error!("cannot read the address book master key file {:?}: {}", path, e);
+                Self::from_master_keys(AbKeySource::None, Vec::new())
+            }
+        }
+    }
+
+    /// Build a keyring from base64 encoded master keys, the invalid ones are ignored
+    pub fn from_master_keys(source: AbKeySource, master_keys: Vec<String>) -> Self {
+        let keys: Vec<AbKey> = master_keys
+            .iter()
+            .map(|key| key.trim())
+            .filter(|key| !key.is_empty())
+            .filter_map(|key| {
+                let master_key = BASE64_STANDARD.decode(key);
+                if master_key.is_err() || master_key.as_ref().unwrap().len() < 32 {
+                    log::error!("invalid address book master key, it must be at least 32 bytes encoded in base64");
+                    return None;
+                }
+                Some(derive_key(&master_key.unwrap()))
+            })
+            .collect();
+        let source = if keys.is_empty() {
+            log::warn!("no address book master key, peer credentials are stored in clear");
+            AbKeySource::None
+        } else {
+            source
+        };
+        AbKeyring { source, keys }
+    }
+
+    pub fn source(&self) -> &AbKeySource {
+        &self.source
+    }
+
+    /// Check if a key of the keyring has the given id
+    pub fn has_key(&self, id: &str) -> bool {
+        self.keys.iter().any(|key| key.id == id)
+    }
+
+    /// Encrypt a value with the current key, the value is returned as is without key
+    /// The values are always clear text, even when they look encrypted, so a client cannot store a
+    /// value which would then be read as encrypted with an unknown key
+    ///
+    /// # Returns
+    ///
+    /// Result<String, String> - the encrypted value, or an error which must not be stored in place of the value
+    pub fn encrypt(&self, value: &str) -> Result<String, String> {
+        let key = match self.keys.first() {
+            Some(key) => key,
+            None if value.starts_with(ENCRYPTED_PREFIX) => {
+                return Err(format!(
+                    "a credential stored in clear cannot start with {}",
+                    ENCRYPTED_PREFIX
+                ))
+            }
+            None => return Ok(value.to_string()),
+        };
+        if value.is_empty() {
+            return Ok(value.to_string());
+        }
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|e| format!("cannot encrypt with the address book key {}: {}", key.id, e))?;
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        Ok(format!("{}{}:{}", ENCRYPTED_PREFIX, key.id, BASE64_STANDARD.encode(data)))
+    }
+
+    /// Get the id of the key which encrypted a value, None if the value is not encrypted
+    pub fn key_id_of(value: &str) -> Option<&str> {
+        let (id, _) = value.strip_prefix(ENCRYPTED_PREFIX)?.split_once(':')?;
+        Some(id)
+    }
+
+    /// Decrypt a value, values which are not encrypted are returned as is
+    ///
+    /// # Returns
+    ///
+    /// Result<String, String> - the value, or an error if the key is unknown or the value is corrupted
+    pub fn decrypt(&self, value: &str) -> Result<String, String> {
+        let encrypted = match value.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encrypted) => encrypted,
+            None => return Ok(value.to_string()),
+        };
+        let (id, data) = encrypted
+            .split_once(':')
+            .ok_or("malformed encrypted value")?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or(format!("unknown address book key {}", id))?;
+        let data = BASE64_STANDARD.decode(data).map_err(|e| e.to_string())?;
+        if data.len() < NONCE_LEN {
+            return Err("truncated encrypted value".to_string());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| format!("cannot decrypt with the address book key {}: {}", id, e))?;
+        String::from_utf8(plaintext).map_err(|e| e.to_string())
+    }
+
+    /// Encrypt the credentials of a peer
+    pub fn encrypt_peer(&self, mut peer: AbPeer) -> Result<AbPeer, String> {
+        peer.password = peer.password.map(|password| self.encrypt(&password)).transpose()?;
+        peer.hash = peer.hash.map(|hash| self.encrypt(&hash)).transpose()?;
+        Ok(peer)
+    }
+
+    /// Decrypt the credentials of a peer
+    /// A credential which cannot be decrypted is an error, it must not be dropped because the peer
+    /// would then be written back without it
+    pub fn decrypt_peer(&self, mut peer: AbPeer) -> Result<AbPeer, String> {
+        peer.password = peer.password.map(|password| self.decrypt(&password)).transpose()?;
+        peer.hash = peer.hash.map(|hash| self.decrypt(&hash)).transpose()?;
+        Ok(peer)
+    }
+
+    /// Generate a new master key and put it first in the key file, the previous keys are kept
+    /// so that the existing rows can still be decrypted
+    ///
+    /// # Returns
+    ///
+    /// Option<AbKeyring> - the new keyring, None if the keys do not come from a key file
+    pub fn rotate(&self) -> Option<AbKeyring> {
+        let path = match &self.source {
+            AbKeySource::File(path) => path.clone(),
+            _ => return None,
+        };
+        let content = std::fs::read_to_string(&path).ok()?;
+        let mut master_keys = vec![generate_master_key()];
+        master_keys.extend(content.lines().map(|line| line.to_string()));
+        if let Err(e) = write_key_file(&path, &master_keys) {
+            log::error!("cannot write the address book master key file {:?}: {}", path, e);
+            return None;
+        }
+        Some(Self::from_master_keys(AbKeySource::File(path), master_keys))
+    }
+
+    /// Remove the previous keys from the key file once all the rows use the current key
+    pub fn retire_previous_keys(&self) -> Option<()> {
+        let path = match &self.source {
+            AbKeySource::File(path) => path,
+            _ => return None,
+        };
+        let content = std::fs::read_to_string(path).ok()?;
+        let current = content.lines().find(|line| !line.trim().is_empty())?;
+        write_key_file(path, &[current.to_string()]).ok()
+    }
+}
+
+fn derive_key(master_key: &[u8]) -> AbKey {
+    let hkdf = Hkdf::<Sha256>::new(Some(HKDF_SALT), master_key);
+    let mut key = [0u8; 32];
+    hkdf.expand(HKDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let id = Sha256::digest(key)[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    AbKey {
+        id,
+        cipher: Aes256Gcm::new(&key.into()),
+    }
+}
+
+/// Generate a random base64 encoded master key
+pub(crate) fn generate_master_key() -> String {
+    BASE64_STANDARD.encode(Aes256Gcm::generate_key(&mut OsRng))
+}
+
+fn write_key_file(path: &PathBuf, master_keys: &[String]) -> std::io::Result<()> {
+    let mut content = master_keys.join("\n");
+    content.push('\n');
+    std::fs::write(path, content)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_peer() {
+        let keyring = AbKeyring::from_master_keys(AbKeySource::Env, vec![generate_master_key()]);
+        let peer = keyring.encrypt_peer(AbPeer::default_test()).unwrap();
+        assert!(peer.password.as_ref().unwrap().starts_with(ENCRYPTED_PREFIX));
+        assert!(peer.hash.as_ref().unwrap().starts_with(ENCRYPTED_PREFIX));
+        let encrypted = peer.clone();
+        let peer = keyring.decrypt_peer(peer).unwrap();
+        assert_eq!(peer.password.as_deref(), Some("none"));
+        assert_eq!(peer.hash.as_deref(), Some("0"));
+        // a value which looks encrypted is encrypted like any other value
+        let value = "enc:v1:bogus:AAAA";
+        let encrypted_value = keyring.encrypt(value).unwrap();
+        assert_ne!(AbKeyring::key_id_of(&encrypted_value), Some("bogus"));
+        assert_eq!(keyring.decrypt(&encrypted_value).as_deref(), Ok(value));
+        // the credentials are not dropped when they cannot be decrypted
+        let other = AbKeyring::from_master_keys(AbKeySource::Env, vec![generate_master_key()]);
+        assert!(other.decrypt_peer(encrypted).is_err());
+    }
+
+    #[test]
+    fn test_previous_key() {
+        let old_key = generate_master_key();
+        let old = AbKeyring::from_master_keys(AbKeySource::Env, vec![old_key.clone()]);
+        let encrypted = old.encrypt("secret").unwrap();
+        let id = AbKeyring::key_id_of(&encrypted).unwrap();
+        assert!(old.has_key(id));
+        let new = AbKeyring::from_master_keys(AbKeySource::Env, vec![generate_master_key(), old_key]);
+        assert_eq!(new.decrypt(&encrypted).as_deref(), Ok("secret"));
+        assert_ne!(new.encrypt("secret").unwrap().split(':').nth(2), encrypted.split(':').nth(2));
+        let other = AbKeyring::from_master_keys(AbKeySource::Env, vec![generate_master_key()]);
+        assert!(!other.has_key(id));
+        assert!(other.decrypt(&encrypted).is_err());
+        let corrupted = format!("{}A", &encrypted[..encrypted.len() - 4]);
+        assert!(new.decrypt(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_clear_values() {
+        let keyring = AbKeyring::from_master_keys(AbKeySource::None, Vec::new());
+        assert_eq!(keyring.encrypt("secret").as_deref(), Ok("secret"));
+        assert!(keyring.encrypt("enc:v1:bogus:AAAA").is_err());
+        assert_eq!(AbKeyring::key_id_of("secret"), None);
+        assert_eq!(keyring.decrypt("secret").as_deref(), Ok("secret"));
+    }
+}
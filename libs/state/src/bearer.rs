@@ -17,7 +17,7 @@ use std::marker::PhantomData;
 use rocket::{
     http::Status, outcome::try_outcome, request::{FromRequest, Outcome, Request}, State
 };
-use utils::{unwrap_or_return, Token, IntoToken, BearerAuthToken};
+use utils::{unwrap_or_return, Token, IntoToken, BearerAuthToken, Permission};
 use crate::{
     SessionId, UserId, 
     state::ApiState,
@@ -42,11 +42,58 @@ pub struct AuthenticatedUser<T> {
     pub _ph: PhantomData<T>,
 }
 
+/// The permission required by an `Authorized` request guard
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+/// Marker types of the permissions, used as the parameter of `Authorized`
+pub mod permission {
+    use super::RequiredPermission;
+    use utils::Permission;
+
+    macro_rules! permission_markers {
+        ($($name:ident),*) => {
+            $(
+                #[derive(Debug)]
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Permission = Permission::$name;
+                }
+            )*
+        };
+    }
+
+    permission_markers!(
+        UsersManage,
+        GroupsManage,
+        TeamsManage,
+        PeersRead,
+        PeersManage,
+        AddressBooksManage,
+        AuditRead,
+        SettingsManage,
+        RolesManage
+    );
+}
+
+/// An authenticated user with the permission `P`
+/// The admins have every permission, the other users have the permissions of their roles
 #[derive(Debug)]
-pub struct AuthenticatedAdmin<T> {
+pub struct Authorized<T, P> {
     pub info: AuthenticatedUserInfo,
     pub username: String,
-    pub _ph: PhantomData<T>,
+    /// guid of the team the user is restricted to, None for the members of the `Default` team who manage every team
+    pub team: Option<String>,
+    pub _ph: PhantomData<(T, P)>,
+}
+
+impl<T, P> Authorized<T, P> {
+    /// Check if the user manages every team
+    pub fn is_global(&self) -> bool {
+        self.team.is_none()
+    }
 }
 
 
@@ -80,25 +127,35 @@ impl<'r, T> FromRequest<'r> for AuthenticatedUser<T> where T: FromRequest<'r, Er
 }
 
 #[rocket::async_trait]
-impl<'r, T> FromRequest<'r> for AuthenticatedAdmin<T> where T: FromRequest<'r, Error = ()> + IntoToken + Send {
+impl<'r, T, P> FromRequest<'r> for Authorized<T, P>
+where
+    T: FromRequest<'r, Error = ()> + IntoToken + Send,
+    P: RequiredPermission + Send,
+{
     type Error = T::Error;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let state = try_outcome!(request.guard::<&State<ApiState>>().await);
         let user = try_outcome!(request.guard::<AuthenticatedUser<T>>().await);
+        let team = unwrap_or_return!(
+            state
+            .get_user_team_scope(&user.info.user_id)
+            .await
+            .ok_or(Outcome::Forward(Status::Unauthorized))
+        );
+        if !state.has_permission(&user.info.user_id, P::PERMISSION).await {
+            return Outcome::Forward(Status::Unauthorized);
+        }
 
         state.with_user_info(&user.info.user_id, |user_info| -> Outcome<Self, Self::Error> {
-            if !user_info.admin {
-                return Outcome::Forward(Status::Unauthorized);
-            }
-
-            let authenticated_admin = AuthenticatedAdmin {
+            let authorized = Authorized {
                 info: user.info.clone(),
                 username: user_info.username.clone(),
+                team: team.clone(),
                 _ph: PhantomData,
             };
     
-            Outcome::Success(authenticated_admin)
+            Outcome::Success(authorized)
         })
         .await
         .unwrap_or_else(|| Outcome::Forward(Status::Unauthorized))
@@ -134,7 +191,10 @@ impl<'r> rocket_okapi::request::OpenApiFromRequest<'r> for AuthenticatedUser<Bea
     }
 }
 
-impl<'r> rocket_okapi::request::OpenApiFromRequest<'r> for AuthenticatedAdmin<BearerAuthToken> {
+impl<'r, P> rocket_okapi::request::OpenApiFromRequest<'r> for Authorized<BearerAuthToken, P>
+where
+    P: RequiredPermission + Send,
+{
     fn from_request_input(
         _gen: &mut rocket_okapi::gen::OpenApiGenerator,
         _name: String,
@@ -142,7 +202,7 @@ impl<'r> rocket_okapi::request::OpenApiFromRequest<'r> for AuthenticatedAdmin<Be
     ) -> rocket_okapi::Result<rocket_okapi::request::RequestHeaderInput> {
                 // Setup global requirement for Security scheme
                 let security_scheme = SecurityScheme {
-                    description: Some("Requires an Admin API key to access, format the key as a 256 bit base64 encoded string like `o5Zci3V8o2QIWBDrRgDNxXwrfmX3Gk3sRjY5I302dzU=`.".to_owned()),
+                    description: Some("Requires an API key of a user with the permission of the endpoint, format the key as a 256 bit base64 encoded string like `o5Zci3V8o2QIWBDrRgDNxXwrfmX3Gk3sRjY5I302dzU=`.".to_owned()),
                     data: SecuritySchemeData::Http {
                         scheme: "bearer".to_owned(),
                         bearer_format: Some("256 bit base64 encoded string".to_owned()),
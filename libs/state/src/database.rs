@@ -13,28 +13,66 @@
 //
 // You should have received a copy of the Affero General Public License
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
-use crate::password::UserPasswordInfo;
+use crate::ab_crypto::AbKeyring;
+use crate::password::{PasswordPolicy, UserPasswordInfo};
 use crate::types;
 use crate::UserId;
 use serde::Serialize;
 use sqlx::{
     pool::PoolConnection,
-    sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool},
+    sqlite::{Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool},
     QueryBuilder,
 };
+use std::collections::HashMap;
 use std::env;
 use std::path::Path;
+use tokio::sync::watch;
 use utils::guid_into_uuid;
 use utils::types::AddressBook;
+use utils::AbOrphanedTag;
+use utils::AbChange;
 use utils::AbPeer;
+use utils::AbPeerRevision;
+use utils::AbPeersBatchItem;
+use utils::AbRecycledPeer;
 use utils::AbRule;
 use utils::AbTag;
+use utils::AbTagRepairMode;
+use utils::AuditConn;
 use utils::CpuCount;
+use utils::DEFAULT_TEAM_GUID;
 use utils::Group;
+use utils::InviteUserRequest;
 use utils::Peer;
+use utils::PeerCleanupItem;
+use utils::PeerCleanupReport;
+use utils::PeerDuplicateGroup;
+use utils::PeerPresenceBucket;
+use utils::PeerStat;
+use utils::PeerStatField;
+use utils::PeerSysinfoChange;
+use utils::PeerSysinfoSnapshot;
+use utils::PeerUptime;
+use utils::Permission;
 use utils::Platform;
+use utils::Role;
+use utils::Setting;
+use utils::Team;
 use utils::UpdateUserRequest;
 use utils::UserListResponse;
+use utils::UserImportReport;
+use utils::UserAddressBook;
+use utils::UserDataEntry;
+use utils::UserDataError;
+use utils::UserDataItem;
+use utils::UserDataTransfer;
+use utils::UserDeletionSummary;
+use utils::UserInvite;
+use utils::UserNotifications;
+use utils::UserProfile;
+use utils::UserTokenKind;
+use utils::UserImportRow;
+use utils::UserTransfer;
 
 use base64::prelude::{Engine as _, BASE64_STANDARD};
 
@@ -42,6 +80,8 @@ use uuid::Uuid;
 
 pub struct Database {
     pool: SqlitePool,
+    keyring: AbKeyring,
+    ab_changes: watch::Sender<u64>,
 }
 
 pub struct DatabaseConnection {
@@ -53,6 +93,19 @@ pub struct DatabaseUserInfo {
     pub admin: bool,
 }
 
+/// An item belonging to a team
+/// Users and groups have a team, peers and address books belong to the team of their owner
+pub enum TeamMember<'a> {
+    /// user guid in string format
+    User(&'a str),
+    /// group guid in string format
+    Group(&'a str),
+    /// peer id
+    Peer(&'a str),
+    /// address book guid in string format
+    AddressBook(&'a str),
+}
+
 #[derive(Serialize, Debug)]
 pub struct DatabaseUserPasswordInfo {
     pub password: String,
@@ -60,6 +113,568 @@ pub struct DatabaseUserPasswordInfo {
     pub user_id: UserId,
 }
 
+/// Status of the peers flagged as stale by the cleanup job
+const PEER_STATUS_STALE: i64 = 2;
+
+/// Status of the deleted users kept for the audit logs, they cannot log in nor be enabled again
+const USER_STATUS_ARCHIVED: i64 = 2;
+
+/// Default number of days the deleted address book peers are kept in the recycle bin
+const DEFAULT_AB_PEER_RECYCLE_RETENTION_DAYS: i64 = 30;
+
+/// Default number of seconds without heartbeat after which a peer is considered offline
+const DEFAULT_PEER_ONLINE_THRESHOLD_SECS: i64 = 60;
+
+/// Default validity of the invitations in hours
+const DEFAULT_INVITE_EXPIRY_HOURS: i64 = 72;
+
+/// Default validity of the password reset tokens in hours
+const DEFAULT_PASSWORD_RESET_EXPIRY_HOURS: i64 = 1;
+
+/// Default maximum size in bytes of an entry of the user data storage
+const DEFAULT_USER_DATA_MAX_SIZE: usize = 65536;
+
+/// Default maximum number of entries of the user data storage of a user
+const DEFAULT_USER_DATA_MAX_ENTRIES: u32 = 32;
+
+/// Range of the bcrypt cost accepted by the `password_hash_cost` setting
+const PASSWORD_HASH_COST_RANGE: std::ops::RangeInclusive<u32> = 4..=31;
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// Actions recorded in the address book peers history
+const AB_PEER_REVISION_CREATE: &str = "create";
+const AB_PEER_REVISION_UPDATE: &str = "update";
+const AB_PEER_REVISION_DELETE: &str = "delete";
+const AB_PEER_REVISION_RESTORE: &str = "restore";
+
+/// Results of the address book batch operations for each peer
+const AB_BATCH_OK: &str = "ok";
+const AB_BATCH_UNCHANGED: &str = "unchanged";
+const AB_BATCH_NOT_FOUND: &str = "not_found";
+
+/// Move a peer to or from the recycle bin and record the revision
+///
+/// # Returns
+///
+/// Option<bool> - true if the peer was found in the expected state
+async fn set_ab_peer_deleted(
+    conn: &mut SqliteConnection,
+    ab_guid: &Vec<u8>,
+    id: &str,
+    deleted: bool,
+    user_id: &UserId,
+) -> Option<bool> {
+    let res = sqlx::query!(
+        r#"
+        SELECT
+            guid,
+            info
+        FROM
+            ab_peer
+        WHERE
+            ab = ? AND id = ? AND (deleted_at IS NULL) = ?
+    "#,
+        ab_guid,
+        id,
+        deleted
+    )
+    .fetch_optional(&mut *conn)
+    .await;
+    if res.is_err() {
+        log::error!("set_ab_peer_deleted error: {:?}", res);
+        return None;
+    }
+    let row = match res.unwrap() {
+        Some(row) => row,
+        None => return Some(false),
+    };
+    let res = sqlx::query!(
+        r#"
+        UPDATE ab_peer SET deleted_at = CASE WHEN ? THEN current_timestamp ELSE NULL END
+        WHERE guid = ?
+    "#,
+        deleted,
+        row.guid
+    )
+    .execute(&mut *conn)
+    .await;
+    if res.is_err() {
+        log::error!("set_ab_peer_deleted error: {:?}", res);
+        return None;
+    }
+    let action = if deleted {
+        AB_PEER_REVISION_DELETE
+    } else {
+        AB_PEER_REVISION_RESTORE
+    };
+    add_ab_peer_revision(conn, &row.guid, action, &row.info, user_id).await?;
+    Some(true)
+}
+
+/// Get the tags used by the live peers of an address book which do not exist in the address book
+async fn get_ab_orphaned_tags(
+    conn: &mut SqliteConnection,
+    ab_guid: &Vec<u8>,
+) -> Option<Vec<AbOrphanedTag>> {
+    let res = sqlx::query!(
+        r#"
+        SELECT
+            CAST(json_each.value AS TEXT) as "name!: String",
+            ab_peer.id as "id!: String"
+        FROM
+            ab_peer,
+            json_each(ab_peer.info, '$.tags')
+        WHERE
+            ab_peer.ab = ?
+            AND ab_peer.deleted_at IS NULL
+            AND json_each.value NOT IN (SELECT name FROM ab_tag WHERE ab = ?)
+        ORDER BY
+            json_each.value,
+            ab_peer.id
+    "#,
+        ab_guid,
+        ab_guid
+    )
+    .fetch_all(conn)
+    .await;
+    if res.is_err() {
+        log::error!("get_ab_orphaned_tags error: {:?}", res);
+        return None;
+    }
+    let mut orphaned_tags: Vec<AbOrphanedTag> = Vec::new();
+    for row in res.unwrap() {
+        match orphaned_tags.last_mut() {
+            Some(tag) if tag.name == row.name => tag.peers.push(row.id),
+            _ => orphaned_tags.push(AbOrphanedTag {
+                name: row.name,
+                peers: vec![row.id],
+            }),
+        }
+    }
+    Some(orphaned_tags)
+}
+
+/// Parse the permissions of a role stored as a JSON array, the unknown permissions are ignored
+fn parse_permissions(permissions: &str) -> Vec<Permission> {
+    serde_json::from_str::<Vec<serde_json::Value>>(permissions)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|permission| serde_json::from_value(permission).ok())
+        .collect()
+}
+
+/// Convert a team scope into the team guid used for filtering the queries
+/// No team means every team
+///
+/// # Returns
+///
+/// Option<Option<Vec<u8>>> - None if the team guid is invalid
+fn team_scope_guid(team: Option<&str>) -> Option<Option<Vec<u8>>> {
+    match team {
+        None => Some(None),
+        Some(team) => {
+            let team_guid = Uuid::parse_str(team);
+            if team_guid.is_err() {
+                log::error!("team_scope_guid error: {:?}", team_guid);
+                return None;
+            }
+            Some(Some(team_guid.unwrap().as_bytes().to_vec()))
+        }
+    }
+}
+
+/// Replace the cross group access of a group
+/// A row of cross_grp allows the devices of the `outgoing` group to connect to the devices of the `incoming` group
+///
+/// # Returns
+///
+/// Option<()> - None if a guid is invalid, is not an existing group or is the group itself
+async fn set_cross_groups(
+    conn: &mut SqliteConnection,
+    group_guid: &Vec<u8>,
+    allowed_incomings: &[String],
+    allowed_outgoings: &[String],
+) -> Option<()> {
+    let res = sqlx::query!(
+        r#"DELETE FROM cross_grp WHERE incoming = ? OR outgoing = ?"#,
+        group_guid,
+        group_guid
+    )
+    .execute(&mut *conn)
+    .await;
+    if res.is_err() {
+        log::error!("set_cross_groups error: {:?}", res);
+        return None;
+    }
+    let rows = allowed_incomings
+        .iter()
+        .map(|other| (other, true))
+        .chain(allowed_outgoings.iter().map(|other| (other, false)));
+    for (other, incoming) in rows {
+        let other_guid = Uuid::parse_str(other);
+        if other_guid.is_err() {
+            log::error!("set_cross_groups error: {:?}", other_guid);
+            return None;
+        }
+        let other_guid = other_guid.unwrap().as_bytes().to_vec();
+        if &other_guid == group_guid {
+            log::error!("set_cross_groups error: a group cannot reference itself");
+            return None;
+        }
+        let (incoming_guid, outgoing_guid) = if incoming {
+            (group_guid, &other_guid)
+        } else {
+            (&other_guid, group_guid)
+        };
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM grp WHERE guid = ?) as "exists!: bool""#,
+            other_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if !exists.unwrap_or(false) {
+            log::error!("set_cross_groups error: group {} not found", other);
+            return None;
+        }
+        let res = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO cross_grp (incoming, outgoing, created_at)
+                VALUES (?, ?, current_timestamp)
+        "#,
+            incoming_guid,
+            outgoing_guid
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("set_cross_groups error: {:?}", res);
+            return None;
+        }
+    }
+    Some(())
+}
+
+/// Get all the cross group access as (incoming, outgoing) group guids in string format
+async fn get_cross_groups(conn: &mut SqliteConnection) -> Option<Vec<(String, String)>> {
+    let res = sqlx::query!(r#"SELECT incoming, outgoing FROM cross_grp"#)
+        .fetch_all(conn)
+        .await;
+    if res.is_err() {
+        log::error!("get_cross_groups error: {:?}", res);
+        return None;
+    }
+    let cross_groups = res
+        .unwrap()
+        .into_iter()
+        .filter_map(|row| Some((guid_into_uuid(row.incoming)?, guid_into_uuid(row.outgoing)?)))
+        .collect();
+    Some(cross_groups)
+}
+
+/// Groups whose devices can be accessed by the group
+fn cross_groups_access_to(cross_groups: &[(String, String)], group: &str) -> Vec<String> {
+    cross_groups
+        .iter()
+        .filter(|(_, outgoing)| outgoing == group)
+        .map(|(incoming, _)| incoming.clone())
+        .collect()
+}
+
+/// Groups which can access the devices of the group
+fn cross_groups_accessed_from(cross_groups: &[(String, String)], group: &str) -> Vec<String> {
+    cross_groups
+        .iter()
+        .filter(|(incoming, _)| incoming == group)
+        .map(|(_, outgoing)| outgoing.clone())
+        .collect()
+}
+
+/// Import one user with its personal address book
+/// The groups are looked up by name in the team scope and cached in `groups`, the created groups are added to `groups_created`
+///
+/// # Arguments
+///
+/// * `password_hashed` - the hashed password of the user
+/// * `new_groups_team` - the team of the missing groups, None for not creating them
+///
+/// # Returns
+///
+/// Result<UserId, String> - the guid of the user, or the error of the row
+async fn import_user(
+    conn: &mut SqliteConnection,
+    user: &UserTransfer,
+    password_hashed: &str,
+    team_guid: &Option<Vec<u8>>,
+    new_groups_team: Option<&Vec<u8>>,
+    groups: &mut HashMap<String, (Vec<u8>, Vec<u8>)>,
+    groups_created: &mut Vec<String>,
+) -> Result<UserId, String> {
+    if !groups.contains_key(&user.group) {
+        let res = sqlx::query!(
+            r#"SELECT guid, team FROM grp WHERE name = ? AND (? IS NULL OR team = ?)"#,
+            user.group,
+            team_guid,
+            team_guid
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+        let group = match (res, new_groups_team) {
+            (Some(group), _) => (group.guid, group.team),
+            (None, Some(new_groups_team)) => {
+                let group_guid = Uuid::new_v4().as_bytes().to_vec();
+                let res = sqlx::query!(
+                    r#"
+                    INSERT INTO grp(guid, team, name, note, created_at, info)
+                        VALUES (?, ?, ?, '', current_timestamp, '{}')
+                "#,
+                    group_guid,
+                    new_groups_team,
+                    user.group
+                )
+                .execute(&mut *conn)
+                .await;
+                if res.is_err() {
+                    log::error!("import_user error: {:?}", res);
+                    return Err(format!("group {} cannot be created", user.group));
+                }
+                groups_created.push(user.group.clone());
+                (group_guid, new_groups_team.clone())
+            }
+            (None, None) => return Err(format!("group {} not found", user.group)),
+        };
+        groups.insert(user.group.clone(), group);
+    }
+    let (group_guid, group_team) = groups.get(&user.group).unwrap();
+
+    let res = sqlx::query!(
+        r#"
+        SELECT
+            EXISTS (SELECT 1 FROM user WHERE name = ?) as "name_used!: bool",
+            EXISTS (SELECT 1 FROM user WHERE email = ?) as "email_used!: bool"
+    "#,
+        user.name,
+        user.email
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    if res.name_used {
+        return Err(format!("user {} already exists", user.name));
+    }
+    if res.email_used {
+        return Err(format!("email {} already used", user.email.as_deref().unwrap_or("")));
+    }
+
+    let user_guid = Uuid::new_v4().as_bytes().to_vec();
+    let ab_guid = Uuid::new_v4().as_bytes().to_vec();
+    let ab_name = format!("{}'s Personal Address Book", user.name);
+    let res = sqlx::query!(
+        r#"
+        INSERT INTO user(guid, grp, team, status, role, name, password, email, note)
+            VALUES (?, ?, ?, 1, ?, ?, ?, ?, ?);
+        INSERT INTO ab(guid, name, owner, personal, info)
+            VALUES (?, ?, ?, 1, '{}');
+        "#,
+        user_guid,
+        group_guid,
+        group_team,
+        user.is_admin,
+        user.name,
+        password_hashed,
+        user.email,
+        user.note,
+        ab_guid,
+        ab_name,
+        user_guid
+    )
+    .execute(&mut *conn)
+    .await;
+    if res.is_err() {
+        log::error!("import_user error: {:?}", res);
+        return Err(format!("user {} cannot be created", user.name));
+    }
+    let updated_at = user_data_version(None);
+    for entry in user.data.iter() {
+        let res = sqlx::query!(
+            r#"INSERT INTO user_data ("user", type, data, updated_at) VALUES (?, ?, ?, ?)"#,
+            user_guid,
+            entry.data_type,
+            entry.data,
+            updated_at
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("import_user error: {:?}", res);
+            return Err(format!("user data {} cannot be created", entry.data_type));
+        }
+    }
+    Ok(user_guid)
+}
+
+/// Create a token of a user, the previous token of the same kind is replaced
+///
+/// # Returns
+///
+/// Option<String> - the expiry date of the token
+async fn insert_user_token(
+    conn: &mut SqliteConnection,
+    user_id: &UserId,
+    kind: UserTokenKind,
+    token_hash: &str,
+    hours: i64,
+) -> Option<String> {
+    let guid = Uuid::new_v4().as_bytes().to_vec();
+    let kind = kind.as_str();
+    let validity = format!("+{} hours", hours);
+    let res = sqlx::query!(
+        r#"
+        DELETE FROM user_token WHERE "user" = ? AND kind = ?;
+        INSERT INTO user_token (guid, "user", kind, token_hash, expires_at)
+            VALUES (?, ?, ?, ?, datetime('now', ?));
+    "#,
+        user_id,
+        kind,
+        guid,
+        user_id,
+        kind,
+        token_hash,
+        validity
+    )
+    .execute(&mut *conn)
+    .await;
+    if res.is_err() {
+        log::error!("insert_user_token error: {:?}", res);
+        return None;
+    }
+    let expires_at = sqlx::query_scalar!(
+        r#"SELECT expires_at as "expires_at!: String" FROM user_token WHERE guid = ?"#,
+        guid
+    )
+    .fetch_one(&mut *conn)
+    .await;
+    if expires_at.is_err() {
+        log::error!("insert_user_token error: {:?}", expires_at);
+        return None;
+    }
+    expires_at.ok()
+}
+
+/// Build the `updated_at` of a new version of a user data entry, it is always after the previous version
+fn user_data_version(previous: Option<&str>) -> String {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+    let now = chrono::Utc::now().naive_utc();
+    let previous = previous.and_then(|previous| chrono::NaiveDateTime::parse_from_str(previous, FORMAT).ok());
+    let version = match previous {
+        Some(previous) if previous >= now => previous + chrono::Duration::milliseconds(1),
+        _ => now,
+    };
+    version.format(FORMAT).to_string()
+}
+
+/// Build a LIKE pattern matching a substring, the wildcards of the substring are escaped with a backslash
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Record a new revision of an address book peer
+async fn add_ab_peer_revision(
+    conn: &mut SqliteConnection,
+    ab_peer_guid: &Vec<u8>,
+    action: &str,
+    info: &str,
+    user_id: &UserId,
+) -> Option<()> {
+    let guid = Uuid::new_v4().as_bytes().to_vec();
+    let res = sqlx::query!(
+        r#"
+        INSERT INTO ab_peer_revision (guid, ab_peer, revision, action, info, updated_by, created_at)
+            VALUES (?, ?,
+                COALESCE((SELECT MAX(revision) FROM ab_peer_revision WHERE ab_peer = ?), 0) + 1,
+                ?, ?, ?, current_timestamp)
+    "#,
+        guid,
+        ab_peer_guid,
+        ab_peer_guid,
+        action,
+        info,
+        user_id
+    )
+    .execute(conn)
+    .await;
+    if res.is_err() {
+        log::error!("add_ab_peer_revision error: {:?}", res);
+        return None;
+    }
+    Some(())
+}
+
+/// Clip the online intervals to the period and split them into daily buckets
+/// Returns the total online seconds and one bucket per day of the period
+fn presence_histogram(
+    intervals: &[(i64, i64)],
+    from: i64,
+    to: i64,
+) -> (u64, Vec<PeerPresenceBucket>) {
+    let mut histogram: Vec<PeerPresenceBucket> = Vec::new();
+    let mut total: u64 = 0;
+    let mut day_start = from - from.rem_euclid(SECS_PER_DAY);
+    while day_start < to {
+        let day_end = day_start + SECS_PER_DAY;
+        let mut online_secs: u64 = 0;
+        for (start, end) in intervals {
+            let start = (*start).max(day_start).max(from);
+            let end = (*end).min(day_end).min(to);
+            if end > start {
+                online_secs += (end - start) as u64;
+            }
+        }
+        total += online_secs;
+        let day = chrono::DateTime::from_timestamp(day_start, 0)
+            .map(|d| d.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        histogram.push(PeerPresenceBucket { day, online_secs });
+        day_start = day_end;
+    }
+    (total, histogram)
+}
+
+/// List the system info fields which differ between two system infos
+fn sysinfo_changes(
+    old: &utils::SystemInfo,
+    new: &utils::SystemInfo,
+) -> Vec<PeerSysinfoChange> {
+    let as_string = |value: Option<&serde_json::Value>| match value {
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(serde_json::Value::Null) | None => None,
+        Some(v) => Some(v.to_string()),
+    };
+    let old = serde_json::to_value(old).unwrap_or_default();
+    let new = serde_json::to_value(new).unwrap_or_default();
+    let mut changes = Vec::new();
+    if let Some(fields) = new.as_object() {
+        // the ip address is not part of the inventory
+        for (field, value) in fields.iter().filter(|(field, _)| *field != "last_ip") {
+            let old_value = as_string(old.get(field));
+            let new_value = as_string(Some(value));
+            if old_value != new_value {
+                changes.push(PeerSysinfoChange {
+                    field: field.clone(),
+                    old: old_value,
+                    new: new_value,
+                });
+            }
+        }
+    }
+    changes
+}
+
 macro_rules! unwrap_or_return_tuple {
     ($first:expr, $opt:expr) => {
         match $opt {
@@ -70,7 +685,15 @@ macro_rules! unwrap_or_return_tuple {
 }
 
 impl Database {
+    /// Open the database, the address book keyring is loaded from the environment
     pub async fn open<P: AsRef<Path>>(db_filename: P) -> Self {
+        Self::open_with_keyring(db_filename, None).await
+    }
+
+    /// Open the database with an address book keyring, or the keyring of the environment if it is None
+    /// The address book credentials encrypted with a key which is not in the keyring are logged,
+    /// the peers which hold them cannot be read or written until the key is restored
+    pub async fn open_with_keyring<P: AsRef<Path>>(db_filename: P, keyring: Option<AbKeyring>) -> Self {
         let db_opts = SqliteConnectOptions::new()
             .filename(db_filename.as_ref())
             .journal_mode(SqliteJournalMode::Wal)
@@ -80,7 +703,56 @@ impl Database {
 
         Self::init_db(&pool).await;
 
-        Self { pool }
+        let key_ids = Self::get_ab_key_ids(&pool).await;
+        let keyring = keyring.unwrap_or_else(|| AbKeyring::load(key_ids.is_empty()));
+        let unknown_key_ids: Vec<&String> = key_ids.iter().filter(|id| !keyring.has_key(id)).collect();
+        if !unknown_key_ids.is_empty() {
+            log::error!(
+                "the address book credentials are encrypted with the keys {:?} which are not in AB_MASTER_KEY or AB_MASTER_KEY_FILE",
+                unknown_key_ids
+            );
+        }
+        let (ab_changes, _) = watch::channel(0);
+
+        Self {
+            pool,
+            keyring,
+            ab_changes,
+        }
+    }
+
+    /// Get the ids of the keys which encrypted the address book peers credentials and their revisions
+    async fn get_ab_key_ids(pool: &SqlitePool) -> Vec<String> {
+        let mut conn = pool.acquire().await.unwrap();
+        let res = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT
+                value as "value!: String"
+            FROM (
+                SELECT json_extract(info, '$.password') AS value FROM ab_peer
+                UNION ALL
+                SELECT json_extract(info, '$.hash') FROM ab_peer
+                UNION ALL
+                SELECT json_extract(info, '$.password') FROM ab_peer_revision
+                UNION ALL
+                SELECT json_extract(info, '$.hash') FROM ab_peer_revision
+            )
+            WHERE
+                value LIKE 'enc:%'
+        "#
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .unwrap();
+        let mut key_ids: Vec<String> = Vec::new();
+        for value in res.iter() {
+            if let Some(id) = AbKeyring::key_id_of(value) {
+                if !key_ids.iter().any(|key_id| key_id == id) {
+                    key_ids.push(id.to_string());
+                }
+            }
+        }
+        key_ids
     }
 
     async fn init_db(pool: &SqlitePool) {
@@ -263,6 +935,75 @@ impl Database {
         Some(ab)
     }
 
+    /// Get the users whose legacy address book has not been migrated to their personal address book
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<UserId>>
+    pub async fn get_pending_legacy_address_books(&self) -> Option<Vec<UserId>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user_guid
+            FROM
+                ab_legacy
+            WHERE
+                user_guid NOT IN (SELECT user_guid FROM ab_legacy_migration)
+        "#
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_pending_legacy_address_books error: {:?}", res);
+            return None;
+        }
+        Some(res.unwrap().into_iter().map(|row| row.user_guid).collect())
+    }
+
+    /// Check if the legacy address book of a user must be migrated
+    pub async fn is_legacy_address_book_pending(&self, user_id: &UserId) -> bool {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                COUNT(*) as "count!: i64"
+            FROM
+                ab_legacy
+            WHERE
+                user_guid = ?
+                AND user_guid NOT IN (SELECT user_guid FROM ab_legacy_migration)
+        "#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("is_legacy_address_book_pending error: {:?}", res);
+            return false;
+        }
+        res.unwrap().count > 0
+    }
+
+    /// Record that the legacy address book of a user has been migrated
+    pub async fn set_legacy_address_book_migrated(&self, user_id: &UserId) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            INSERT OR REPLACE INTO ab_legacy_migration (user_guid, migrated_at)
+                VALUES (?, current_timestamp)
+        "#,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("set_legacy_address_book_migrated error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
     // Update user password
     // first check if password is correct
     // if correct, update password
@@ -290,7 +1031,7 @@ impl Database {
         if !old_password_info.check(dbpi) {
             return None;
         }
-        let new_password_hashed = UserPasswordInfo::hash_password(new_password.as_str());
+        let new_password_hashed = self.hash_password(new_password.as_str()).await;
         let mut tx = self.pool.begin().await.unwrap();
         let res = sqlx::query!(
             r#"
@@ -317,7 +1058,7 @@ impl Database {
 
     // reset user password
     pub async fn reset_user_password(&self, username: String, new_password: String) -> Option<()> {
-        let new_password_hashed = UserPasswordInfo::hash_password(new_password.as_str());
+        let new_password_hashed = self.hash_password(new_password.as_str()).await;
         let mut tx = self.pool.begin().await.unwrap();
         let res = sqlx::query!(
             r#"
@@ -434,7 +1175,7 @@ impl Database {
         password: String,
         admin: bool,
     ) -> Option<UserId> {
-        let password_hashed = UserPasswordInfo::hash_password(password.as_str());
+        let password_hashed = self.hash_password(password.as_str()).await;
         let mut tx = self.pool.begin().await.unwrap();
         let _res = sqlx::query!(
             r#"
@@ -460,97 +1201,255 @@ impl Database {
         Some(user_id.0 as UserId)
     }
 
-    /// Delete a user and its address book
-    pub async fn delete_user(&self, uuid: &str) -> Option<()> {
-        let mut tx = self.pool.begin().await.unwrap();
-        let user_id = Uuid::parse_str(uuid);
-        if user_id.is_err() {
-            log::error!("delete user error: {:?}", uuid);
+    /// Delete a user in a single transaction
+    /// The shared address books and the devices of the user are given to `transfer_to` or deleted when it is None,
+    /// the personal address book, the address book rules, the sessions, the third party identities and the
+    /// user data are always deleted
+    /// When `archive` is set the user is kept with the archived status, without email nor usable password,
+    /// so that the audit logs still show its name
+    ///
+    /// # Returns
+    ///
+    /// Option<UserDeletionSummary> - None if the user or the transfer target does not exist or is archived
+    pub async fn delete_user(
+        &self,
+        user_id: &UserId,
+        transfer_to: Option<&UserId>,
+        archive: bool,
+    ) -> Option<UserDeletionSummary> {
+        if transfer_to == Some(user_id) {
+            log::error!("delete_user error: cannot transfer to the deleted user");
             return None;
         }
-        let user_id = user_id.unwrap().as_bytes().to_vec();
+        // an unusable password, computed before the transaction because hashing is slow
+        let archived_password = self.hash_password(&Uuid::new_v4().to_string()).await;
+        let mut tx = self.pool.begin().await.ok()?;
         let res = sqlx::query!(
-            r#"
-            DELETE FROM
-                user
-            WHERE
-                guid = ?;
-            DELETE FROM
-                ab_legacy
-            WHERE
-                user_guid = ?;
-            DELETE FROM
-                ab
-            WHERE
-                owner = ?;
-        "#,
-            user_id,
+            r#"SELECT name FROM user WHERE guid = ? AND status != ?"#,
             user_id,
-            user_id
+            USER_STATUS_ARCHIVED
         )
-        .execute(&mut *tx)
-        .await
-        .ok()?
-        .rows_affected();
-        tx.commit().await.ok()?;
-        if res == 0 {
+        .fetch_optional(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
             return None;
         }
-        Some(())
-    }
-
-    pub async fn update_systeminfo(&self, systeminfo: utils::SystemInfo) -> Option<()> {
-        let mut tx = self.pool.begin().await.unwrap();
-        let mut systeminfo = systeminfo;
-        let uuid = systeminfo.uuid.clone().unwrap();
-
-        let uuid_decoded = BASE64_STANDARD.decode(uuid);
-        if uuid_decoded.is_ok() {
-            let uuid_decoded = uuid_decoded.unwrap();
-            log::debug!(
-                "uuid_decoded: {:?} {:?}",
-                uuid_decoded,
-                String::from_utf8(uuid_decoded.clone())
-            );
-            // get old info (if any for keeping ip setted by hbbs)
+        let mut summary = UserDeletionSummary {
+            user: guid_into_uuid(user_id.clone())?,
+            name: res.unwrap()?.name,
+            archived: archive,
+            ..Default::default()
+        };
+        if let Some(transfer_to) = transfer_to {
             let res = sqlx::query!(
-                r#"SELECT info as "info!: String" FROM peer WHERE uuid = ?"#,
-                uuid_decoded
+                r#"SELECT guid FROM user WHERE guid = ? AND status != ?"#,
+                transfer_to,
+                USER_STATUS_ARCHIVED
             )
-            .fetch_one(&mut *tx)
+            .fetch_optional(&mut *tx)
             .await;
             if res.is_err() {
-                log::debug!("peer select error: {:?}", res);
+                log::error!("delete_user error: {:?}", res);
                 return None;
-            } else {
-                let res = res.unwrap();
-                let old_systeminfo: utils::SystemInfo =
-                    rocket::serde::json::from_str(&res.info).unwrap();
-                systeminfo.ip = old_systeminfo.ip.clone();
             }
-            let systeminfo_string = rocket::serde::json::to_string(&systeminfo).unwrap();
-            log::debug!("systeminfo_string: {:?}", systeminfo_string);
-            let res = sqlx::query!(
-                r#"UPDATE peer SET info = ? WHERE uuid = ?"#,
-                systeminfo_string,
-                uuid_decoded
+            res.unwrap()?;
+            summary.transferred_to = guid_into_uuid(transfer_to.clone());
+        }
+
+        // the archived user keeps its row, it must be updated before the cleanup of the password history
+        // because of the history trigger
+        let res = if archive {
+            sqlx::query!(
+                r#"UPDATE user SET status = ?, email = NULL, password = ? WHERE guid = ?"#,
+                USER_STATUS_ARCHIVED,
+                archived_password,
+                user_id
             )
             .execute(&mut *tx)
             .await
-            .ok()?
-            .rows_affected();
-            tx.commit().await.ok()?;
-            if res == 0 {
-                return None;
-            } else {
-                return Some(());
-            }
+        } else {
+            sqlx::query!(r#"DELETE FROM user WHERE guid = ?"#, user_id)
+                .execute(&mut *tx)
+                .await
+        };
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
         }
-        Some(())
-    }
 
-    pub async fn update_heartbeat(&self, heartbeat: utils::HeartbeatRequest) -> Option<()> {
-        let uuid = heartbeat.uuid.clone();
+        let res = sqlx::query!(r#"DELETE FROM ab_rule WHERE "user" = ?"#, user_id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        summary.rules_deleted = res.unwrap().rows_affected();
+
+        if let Some(transfer_to) = transfer_to {
+            let res = sqlx::query!(
+                r#"UPDATE ab SET owner = ? WHERE owner = ? AND personal = 0"#,
+                transfer_to,
+                user_id
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("delete_user error: {:?}", res);
+                return None;
+            }
+            summary.address_books_transferred = res.unwrap().rows_affected();
+        }
+        let transfer = transfer_to.is_some();
+        let res = sqlx::query!(
+            r#"SELECT guid as "guid!: Vec<u8>" FROM ab WHERE owner = ? AND (personal = 1 OR NOT ?)"#,
+            user_id,
+            transfer
+        )
+        .fetch_all(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        for ab in res.unwrap() {
+            // the revision is deleted last because deleting the peers and the tags bumps it
+            let res = sqlx::query!(
+                r#"
+                DELETE FROM ab_peer_revision WHERE ab_peer IN (SELECT guid FROM ab_peer WHERE ab = ?);
+                DELETE FROM ab_peer WHERE ab = ?;
+                DELETE FROM ab_tag WHERE ab = ?;
+                DELETE FROM ab_rule WHERE ab = ?;
+                DELETE FROM ab_revision WHERE ab = ?;
+                DELETE FROM ab WHERE guid = ?;
+            "#,
+                ab.guid,
+                ab.guid,
+                ab.guid,
+                ab.guid,
+                ab.guid,
+                ab.guid
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("delete_user error: {:?}", res);
+                return None;
+            }
+            summary.address_books_deleted += 1;
+        }
+
+        let res = match transfer_to {
+            Some(transfer_to) => {
+                sqlx::query!(r#"UPDATE peer SET "user" = ? WHERE "user" = ?"#, transfer_to, user_id)
+                    .execute(&mut *tx)
+                    .await
+            }
+            None => {
+                // the address book entries of the other users keep their id but lose the link to the device
+                let res = sqlx::query!(
+                    r#"
+                    UPDATE ab_peer SET peer = NULL WHERE peer IN (SELECT guid FROM peer WHERE "user" = ?);
+                    DELETE FROM peer_presence WHERE peer IN (SELECT guid FROM peer WHERE "user" = ?);
+                    DELETE FROM peer_sysinfo WHERE peer IN (SELECT guid FROM peer WHERE "user" = ?);
+                "#,
+                    user_id,
+                    user_id,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("delete_user error: {:?}", res);
+                    return None;
+                }
+                sqlx::query!(r#"DELETE FROM peer WHERE "user" = ?"#, user_id)
+                    .execute(&mut *tx)
+                    .await
+            }
+        };
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        if transfer {
+            summary.peers_transferred = res.unwrap().rows_affected();
+        } else {
+            summary.peers_deleted = res.unwrap().rows_affected();
+        }
+
+        let res = sqlx::query!(r#"DELETE FROM session WHERE "user" = ?"#, user_id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        summary.sessions_closed = res.unwrap().rows_affected();
+
+        let res = sqlx::query!(r#"DELETE FROM user_third_auth WHERE "user" = ?"#, user_id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        summary.identities_deleted = res.unwrap().rows_affected();
+
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM
+                ab_legacy
+            WHERE
+                user_guid = ?;
+            DELETE FROM
+                ab_legacy_migration
+            WHERE
+                user_guid = ?;
+            DELETE FROM
+                user_role
+            WHERE
+                "user" = ?;
+            DELETE FROM
+                user_token
+            WHERE
+                "user" = ?;
+            DELETE FROM
+                user_password_history
+            WHERE
+                "user" = ?;
+            DELETE FROM
+                user_data
+            WHERE
+                "user" = ?;
+        "#,
+            user_id,
+            user_id,
+            user_id,
+            user_id,
+            user_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_user error: {:?}", res);
+            return None;
+        }
+        tx.commit().await.ok()?;
+        Some(summary)
+    }
+
+    pub async fn update_systeminfo(
+        &self,
+        systeminfo: utils::SystemInfo,
+        client_ip: Option<String>,
+    ) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let mut systeminfo = systeminfo;
+        let uuid = systeminfo.uuid.clone().unwrap();
+
         let uuid_decoded = BASE64_STANDARD.decode(uuid);
         if uuid_decoded.is_ok() {
             let uuid_decoded = uuid_decoded.unwrap();
@@ -559,737 +1458,4251 @@ impl Database {
                 uuid_decoded,
                 String::from_utf8(uuid_decoded.clone())
             );
+            // get old info (if any for keeping ip setted by hbbs)
             let res = sqlx::query!(
-                r#"UPDATE peer SET last_online = current_timestamp WHERE uuid = ?"#,
+                r#"SELECT guid, info as "info!: String" FROM peer WHERE uuid = ?"#,
                 uuid_decoded
             )
-            .execute(&self.pool)
+            .fetch_one(&mut *tx)
             .await;
             if res.is_err() {
-                log::debug!("update_heartbeat error: {:?}", res);
+                log::debug!("peer select error: {:?}", res);
                 return None;
             }
-            let res = res.unwrap().rows_affected();
+            let res = res.unwrap();
+            let peer_guid = res.guid;
+            let old_systeminfo: utils::SystemInfo =
+                rocket::serde::json::from_str(&res.info).unwrap();
+            systeminfo.ip = old_systeminfo.ip.clone();
+            systeminfo.last_ip = client_ip.or(old_systeminfo.last_ip.clone());
+            let systeminfo_string = rocket::serde::json::to_string(&systeminfo).unwrap();
+            log::debug!("systeminfo_string: {:?}", systeminfo_string);
+            let res = sqlx::query!(
+                r#"UPDATE peer SET info = ? WHERE uuid = ?"#,
+                systeminfo_string,
+                uuid_decoded
+            )
+            .execute(&mut *tx)
+            .await
+            .ok()?
+            .rows_affected();
 
+            // keep a snapshot each time the system info changes
+            let changes = sysinfo_changes(&old_systeminfo, &systeminfo);
+            let last_version = sqlx::query!(
+                r#"SELECT MAX(version) as "version: i64" FROM peer_sysinfo WHERE peer = ?"#,
+                peer_guid
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .ok()?
+            .version;
+            if res > 0 && (last_version.is_none() || !changes.is_empty()) {
+                let snapshot_guid = Uuid::new_v4().as_bytes().to_vec();
+                let version = last_version.unwrap_or(0) + 1;
+                let diff = serde_json::to_string(&changes).unwrap_or("[]".to_string());
+                let res = sqlx::query!(
+                    r#"INSERT INTO peer_sysinfo (guid, peer, version, info, diff) VALUES (?, ?, ?, ?, ?)"#,
+                    snapshot_guid,
+                    peer_guid,
+                    version,
+                    systeminfo_string,
+                    diff
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("update_systeminfo snapshot error: {:?}", res);
+                    return None;
+                }
+            }
+            tx.commit().await.ok()?;
             if res == 0 {
                 return None;
             } else {
-                log::debug!("update_heartbeat row affected: {:?}", res);
                 return Some(());
             }
         }
+        Some(())
+    }
+
+    pub async fn update_heartbeat(
+        &self,
+        heartbeat: utils::HeartbeatRequest,
+        client_ip: Option<String>,
+    ) -> Option<()> {
+        let uuid = heartbeat.uuid.clone();
+        let uuid_decoded = BASE64_STANDARD.decode(uuid);
+        if uuid_decoded.is_ok() {
+            let uuid_decoded = uuid_decoded.unwrap();
+            log::debug!(
+                "uuid_decoded: {:?} {:?}",
+                uuid_decoded,
+                String::from_utf8(uuid_decoded.clone())
+            );
+            let threshold = self.get_peer_online_threshold().await;
+            let mut tx = self.pool.begin().await.unwrap();
+            // the previous heartbeat tells whether the peer is coming back online
+            let res = sqlx::query!(
+                r#"
+                SELECT
+                    guid,
+                    last_online as "last_online!: String",
+                    (CAST(strftime('%s','now') AS INTEGER) - CAST(strftime('%s', last_online) AS INTEGER)) as "age!: i64"
+                FROM
+                    peer
+                WHERE
+                    uuid = ?
+            "#,
+                uuid_decoded
+            )
+            .fetch_optional(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::debug!("update_heartbeat error: {:?}", res);
+                return None;
+            }
+            let peer = res.unwrap()?;
+            let res = sqlx::query!(
+                r#"
+                UPDATE
+                    peer
+                SET
+                    last_online = current_timestamp,
+                    status = CASE WHEN status = 2 THEN 1 ELSE status END,
+                    info = CASE WHEN ?1 IS NULL THEN info ELSE json_set(info, '$.last_ip', ?1) END
+                WHERE
+                    guid = ?2
+            "#,
+                client_ip,
+                peer.guid
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::debug!("update_heartbeat error: {:?}", res);
+                return None;
+            }
+            let res = res.unwrap().rows_affected();
+            if res == 0 {
+                return None;
+            }
+
+            let last_event = sqlx::query!(
+                r#"
+                SELECT
+                    online
+                FROM
+                    peer_presence
+                WHERE
+                    peer = ?
+                ORDER BY
+                    created_at DESC
+                LIMIT 1
+            "#,
+                peer.guid
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .ok()?;
+            let last_event_online = last_event.map(|e| e.online == 1).unwrap_or(false);
+            let was_offline = peer.age > threshold;
+            if was_offline && last_event_online {
+                // the peer went offline after its last heartbeat
+                let guid = Uuid::new_v4().as_bytes().to_vec();
+                let res = sqlx::query!(
+                    r#"INSERT INTO peer_presence (guid, peer, online, created_at) VALUES (?, ?, 0, ?)"#,
+                    guid,
+                    peer.guid,
+                    peer.last_online
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("update_heartbeat presence error: {:?}", res);
+                    return None;
+                }
+            }
+            if was_offline || !last_event_online {
+                let guid = Uuid::new_v4().as_bytes().to_vec();
+                let res = sqlx::query!(
+                    r#"INSERT INTO peer_presence (guid, peer, online) VALUES (?, ?, 1)"#,
+                    guid,
+                    peer.guid
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("update_heartbeat presence error: {:?}", res);
+                    return None;
+                }
+            }
+            tx.commit().await.ok()?;
+            log::debug!("update_heartbeat row affected: {:?}", res);
+            return Some(());
+        }
         None
     }
 
-    /// Get user for oauth2 flow
-    /// if the user does not exist, create it with status=0 and role=0
-    /// if environment variable OAUTH2_CREATE_USER is set to 1, create the user with status=1 and role=0
-    /// if the user exists return its guid
+    /// Get the system info history of a peer
     ///
     /// # Arguments
+    ///
     /// * `id` - peer id
-    /// * `uuid` - peer uuid
     ///
     /// # Returns
-    /// Option<(UserId, name: String,DatabaseUserInfo)>
     ///
-    pub async fn get_user_for_oauth2(
+    /// Option<Vec<PeerSysinfoSnapshot>> - snapshots from the most recent to the oldest
+    pub async fn get_peer_sysinfo_history(
         &self,
-        id: String,
-        email: String,
-        uuid: String,
-    ) -> Option<(UserId, String, DatabaseUserInfo)> {
-        let mut conn = DatabaseConnection {
-            conn: self.pool.acquire().await.unwrap(),
-        };
-        let status = { env::var("OAUTH2_CREATE_USER").unwrap_or("0".to_string()) == "1" };
-        let ab_guid = Uuid::new_v4().as_bytes().to_vec();
-        let user_guid = Uuid::new_v4().as_bytes().to_vec();
-        let random_password = Uuid::new_v4().to_string();
-        let hashed_random_password = UserPasswordInfo::hash_password(random_password.as_str());
-        log::debug!(
-            "user: {:?}/{:?} has random_password: {:?}",
-            uuid,
-            id,
-            random_password
-        );
-        let name = format!("{}'s Personal Address Book", id);
+        id: &str,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerSysinfoSnapshot>> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
         let res = sqlx::query!(
             r#"
-            INSERT OR IGNORE INTO user(guid, grp, team, status, role, name, email, password)
-                VALUES (?,
-                    (SELECT guid FROM grp  WHERE name = 'Default'),
-                    (SELECT guid FROM team  WHERE name = 'Default'), ?, 0, ?, ?, ?);
-            INSERT OR IGNORE INTO ab(guid, name, owner, personal, info)
-                VALUES (?,?,?,1,'{}');
-            "#,
-            user_guid,
-            status,
+            SELECT
+                peer_sysinfo.version,
+                peer_sysinfo.info as "info!: String",
+                peer_sysinfo.diff as "diff!: String",
+                peer_sysinfo.created_at as "created_at!: String"
+            FROM
+                peer_sysinfo
+                JOIN peer ON peer.guid = peer_sysinfo.peer
+            WHERE
+                peer.id = ?
+                AND (? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?))
+            ORDER BY
+                peer_sysinfo.version DESC
+        "#,
             id,
-            email,
-            hashed_random_password,
-            ab_guid,
-            name,
-            user_guid
+            team_guid,
+            team_guid
         )
-        .execute(&mut *conn.conn)
+        .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!(
-                "get_user_for_oauth2 error while creating/getting user: {:?}",
-                res
-            );
+            log::error!("get_peer_sysinfo_history error: {:?}", res);
+            return None;
         }
+        let mut snapshots: Vec<PeerSysinfoSnapshot> = Vec::new();
+        for row in res.unwrap() {
+            let info = serde_json::from_str::<utils::PeerInfo>(&row.info);
+            if info.is_err() {
+                log::error!("get_peer_sysinfo_history error: {:?}", info);
+                return None;
+            }
+            snapshots.push(PeerSysinfoSnapshot {
+                version: row.version as u32,
+                created_at: row.created_at,
+                info: info.unwrap(),
+                changes: serde_json::from_str(&row.diff).unwrap_or_default(),
+            });
+        }
+        Some(snapshots)
+    }
+
+    /// Record a session opened by a user
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - session id
+    /// * `user_id` - user guid
+    /// * `ttl_secs` - session lifetime in seconds
+    /// * `info` - session info in JSON format (client ip...)
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn add_session(
+        &self,
+        id: &str,
+        user_id: UserId,
+        ttl_secs: i64,
+        info: &str,
+    ) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
         let res = sqlx::query!(
-            r#"  
-            SELECT guid, status, role, name FROM user WHERE name = ?;
-            "#,
-            id
+            r#"
+            INSERT INTO session (id, ttl_secs, "user", info, expiry_at)
+            VALUES (?, ?, ?, ?, datetime('now', '+' || ? || ' seconds'))
+        "#,
+            id,
+            ttl_secs,
+            user_id,
+            info,
+            ttl_secs
         )
-        .fetch_one(&mut *conn.conn)
+        .execute(&mut *conn)
         .await;
-
         if res.is_err() {
-            log::error!(
-                "get_user_for_oauth2 error while creating/getting user: {:?}",
-                res
-            );
+            log::error!("add_session error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        let user_id: UserId = res.guid;
-        let dbi = DatabaseUserInfo {
-            active: res.status == 1,
-            admin: res.role == 1,
-        };
-        Some((user_id, res.name, dbi))
-    }
-
-    pub async fn get_personal_address_book(&self, user_id: UserId) {
-        let mut conn = self.pool.acquire().await.unwrap();
-
-        let _res = sqlx::query!(
-            r#"
-            SELECT
-                guid
-            FROM
-                ab
-            WHERE
-                owner = ?
-                AND personal = 1
-        "#,
-            user_id
-        )
-        .fetch_one(&mut *conn)
-        .await
-        .ok();
+        Some(())
     }
 
-    /// Get the personal address book guid for a user
-    /// if the user is inactive (status != 1) return None
-    pub async fn get_ab_personal_guid(&self, user_id: UserId) -> Option<String> {
+    /// Delete a recorded session
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - session id
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn delete_session(&self, id: &str) -> Option<()> {
         let mut conn = self.pool.acquire().await.unwrap();
-
-        let res = sqlx::query!(
-            r#"
-            SELECT
-                a.guid
-            FROM
-                ab as a, user as u
-            WHERE
-                u.guid = ?
-                AND a.personal = 1
-                AND a.owner = u.guid
-                AND u.status = 1
-        "#,
-            user_id
-        )
-        .fetch_one(&mut *conn)
-        .await;
+        let res = sqlx::query!(r#"DELETE FROM session WHERE id = ?"#, id)
+            .execute(&mut *conn)
+            .await;
         if res.is_err() {
-            log::error!("get_ab_personal_guid error: {:?}", res);
+            log::error!("delete_session error: {:?}", res);
             return None;
         }
+        Some(())
+    }
 
-        let res = res.unwrap();
-        let guid_u8: Result<[u8; 16], _> = res.guid.try_into();
-        if guid_u8.is_err() {
-            log::error!("get_ab_personal_guid error: {:?}", guid_u8);
+    /// Delete the expired sessions
+    ///
+    /// # Returns
+    ///
+    /// Option<u64> - the number of deleted sessions
+    pub async fn delete_expired_sessions(&self) -> Option<u64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(r#"DELETE FROM session WHERE expiry_at < current_timestamp"#)
+            .execute(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("delete_expired_sessions error: {:?}", res);
             return None;
         }
-        let guid_u8: [u8; 16] = guid_u8.unwrap();
-        let guid = Uuid::from_bytes(guid_u8).to_string();
-        Some(guid)
+        Some(res.unwrap().rows_affected())
     }
 
-    /// Add a peer to the address book
-    pub async fn add_peer_to_ab(&self, ab: &str, ab_peer: AbPeer) -> Option<()> {
+    /// Record a connection audit event sent by a client
+    /// The remote is the peer guid if the peer is known, its id otherwise
+    ///
+    /// # Arguments
+    ///
+    /// * `audit` - the audit event
+    /// * `client_ip` - the ip address of the client which sent the event
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn add_audit_conn(
+        &self,
+        audit: &utils::AuditRequest,
+        client_ip: Option<String>,
+    ) -> Option<()> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("add_peer_to_ab error: {:?}", ab_guid);
-            return None;
-        }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
-        let ab_peer_guid = Uuid::new_v4().as_bytes().to_vec();
-        let ab_peer_json = rocket::serde::json::to_string(&ab_peer).unwrap();
+        let peer = sqlx::query!(r#"SELECT guid FROM peer WHERE id = ?"#, audit.id)
+            .fetch_optional(&mut *conn)
+            .await
+            .ok()?;
+        let remote = match peer {
+            Some(peer) => peer.guid,
+            None => audit.id.as_bytes().to_vec(),
+        };
+        let guid = Uuid::new_v4().as_bytes().to_vec();
+        let info = serde_json::json!({
+            "action": audit.action,
+            "id": audit.id,
+            "ip": audit.ip,
+            "uuid": audit.uuid,
+            "conn_id": audit.conn_id,
+            "session_id": audit.session_id,
+            "client_ip": client_ip,
+        })
+        .to_string();
         let res = sqlx::query!(
-            r#"
-            -- must be improved !
-            DELETE FROM ab_peer WHERE ab = ? AND id = ?;
-            INSERT OR IGNORE INTO ab_peer (
-                guid,
-                ab,
-                peer,
-                id,
-                note,
-                created_at,
-                info
-                )
-                VALUES (?, ?, (select guid from peer where id = ?), ?, ?, current_timestamp, ?)
-        "#,
-            ab_guid,
-            ab_peer.id,
-            ab_peer_guid,
-            ab_guid,
-            ab_peer.id,
-            ab_peer.id,
-            "",
-            ab_peer_json
+            r#"INSERT INTO audit_conn (guid, type, remote, info) VALUES (?, ?, ?, ?)"#,
+            guid,
+            audit.conn_type,
+            remote,
+            info
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("add_peer_to_ab error: {:?}", res);
+            log::error!("add_audit_conn error: {:?}", res);
             return None;
         }
         Some(())
     }
 
-    pub async fn get_peers_from_ab(&self, ab: &str) -> Option<Vec<AbPeer>> {
+    /// Get a page of the connection audit events, the most recent first
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - the page number, starting at 1
+    /// * `page_size` - the number of events per page
+    /// * `team` - only return the events of the peers of the users of this team
+    ///
+    /// # Returns
+    ///
+    /// Option<(u32, Vec<AuditConn>)> - the number of events and the page
+    pub async fn get_audit_conns(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<AuditConn>)> {
+        let team_guid = team_scope_guid(team)?;
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("get_peers_from_ab error: {:?}", ab_guid);
+        let current = if current < 1 { 1 } else { current };
+        let offset = (current - 1) as i64 * page_size as i64;
+        let page_size = page_size as i64;
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) as "count!: u32" FROM audit_conn
+            WHERE ? IS NULL OR remote IN (SELECT peer.guid FROM peer JOIN user ON user.guid = peer.user WHERE user.team = ?)
+        "#,
+            team_guid,
+            team_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if total.is_err() {
+            log::error!("get_audit_conns error: {:?}", total);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
             SELECT
+                guid,
+                type as "conn_type: i64",
+                created_at as "created_at!: String",
                 info
             FROM
-                ab_peer
+                audit_conn
             WHERE
-                ab_peer.ab = ?
+                ? IS NULL OR remote IN (SELECT peer.guid FROM peer JOIN user ON user.guid = peer.user WHERE user.team = ?)
+            ORDER BY
+                created_at DESC
+            LIMIT ?
+            OFFSET ?
         "#,
-            ab_guid
+            team_guid,
+            team_guid,
+            page_size,
+            offset
         )
         .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_peers_from_ab error: {:?}", res);
+            log::error!("get_audit_conns error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        let mut ab_peers = Vec::new();
-        for row in res {
-            let ab_peer: AbPeer = rocket::serde::json::from_str(&row.info).unwrap();
-            log::debug!("ab_peer: {:?}", ab_peer);
-            ab_peers.push(ab_peer);
+        let audits = res
+            .unwrap()
+            .into_iter()
+            .map(|row| AuditConn {
+                guid: guid_into_uuid(row.guid).unwrap_or("".to_string()),
+                conn_type: row.conn_type,
+                created_at: row.created_at,
+                info: row.info,
+            })
+            .collect();
+        Some((total.unwrap(), audits))
+    }
+
+    /// Get a setting value from the settings table
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - setting key
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - None if the setting is not defined
+    pub async fn get_setting(&self, key: &str) -> Option<String> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(r#"SELECT value FROM settings WHERE key = ?"#, key)
+            .fetch_optional(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("get_setting error: {:?}", res);
+            return None;
         }
-        Some(ab_peers)
+        res.unwrap().map(|row| row.value)
     }
 
-    pub async fn delete_peer_from_ab(&self, ab: &str, id: &str) -> Option<()> {
+    /// Get all the settings
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<Setting>>
+    pub async fn get_settings(&self) -> Option<Vec<Setting>> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("delete_peer_from_ab error: {:?}", ab_guid);
+        let res = sqlx::query_as!(Setting, r#"SELECT key, value FROM settings ORDER BY key"#)
+            .fetch_all(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("get_settings error: {:?}", res);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        Some(res.unwrap())
+    }
+
+    /// Create or update a setting
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - setting key
+    /// * `value` - setting value
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn set_setting(&self, key: &str, value: &str) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
         let res = sqlx::query!(
-            r#"
-            DELETE FROM ab_peer WHERE ab = ? AND id = ?
-        "#,
-            ab_guid,
-            id
+            r#"INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value"#,
+            key,
+            value
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("delete_peer_from_ab error: {:?}", res);
+            log::error!("set_setting error: {:?}", res);
             return None;
         }
         Some(())
     }
 
-    pub async fn get_ab_peer(&self, ab: &str, id: &str) -> Option<AbPeer> {
+    /// Get the number of days the deleted address book peers are kept in the recycle bin
+    /// It is read from the `ab_peer_recycle_retention_days` setting
+    ///
+    /// # Returns
+    ///
+    /// i64 - the retention period in days
+    pub async fn get_ab_peer_recycle_retention_days(&self) -> i64 {
+        self.get_setting("ab_peer_recycle_retention_days")
+            .await
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(|value| *value >= 0)
+            .unwrap_or(DEFAULT_AB_PEER_RECYCLE_RETENTION_DAYS)
+    }
+
+    /// Get the number of seconds without heartbeat after which a peer is considered offline
+    /// It is read from the `peer_online_threshold_secs` setting
+    ///
+    /// # Returns
+    ///
+    /// i64 - the threshold in seconds
+    pub async fn get_peer_online_threshold(&self) -> i64 {
+        self.get_setting("peer_online_threshold_secs")
+            .await
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(DEFAULT_PEER_ONLINE_THRESHOLD_SECS)
+    }
+
+    /// Get the validity in hours of the tokens of a kind
+    /// It is read from the `invite_expiry_hours` or `password_reset_expiry_hours` setting
+    pub async fn get_user_token_validity(&self, kind: UserTokenKind) -> i64 {
+        let (key, default) = match kind {
+            UserTokenKind::Invite => ("invite_expiry_hours", DEFAULT_INVITE_EXPIRY_HOURS),
+            UserTokenKind::PasswordReset => ("password_reset_expiry_hours", DEFAULT_PASSWORD_RESET_EXPIRY_HOURS),
+        };
+        self.get_setting(key)
+            .await
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(default)
+    }
+
+    /// Get the bcrypt cost of the new password hashes
+    /// It is read from the `password_hash_cost` setting
+    pub async fn get_password_hash_cost(&self) -> u32 {
+        self.get_setting("password_hash_cost")
+            .await
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .filter(|value| PASSWORD_HASH_COST_RANGE.contains(value))
+            .unwrap_or(bcrypt::DEFAULT_COST)
+    }
+
+    /// Hash a password with the configured cost
+    pub async fn hash_password(&self, password: &str) -> String {
+        UserPasswordInfo::hash_password(password, self.get_password_hash_cost().await)
+    }
+
+    /// Get the password policy
+    /// It is read from the `password_min_length`, `password_min_classes`, `password_banned_file` and `password_history_count` settings
+    pub async fn get_password_policy(&self) -> PasswordPolicy {
+        let default = PasswordPolicy::default();
+        let setting = |value: Option<String>| value.and_then(|value| value.trim().parse::<u32>().ok());
+        PasswordPolicy {
+            min_length: setting(self.get_setting("password_min_length").await)
+                .map(|value| value as usize)
+                .unwrap_or(default.min_length),
+            min_classes: setting(self.get_setting("password_min_classes").await)
+                .map(|value| value.min(4) as usize)
+                .unwrap_or(default.min_classes),
+            banned_file: self
+                .get_setting("password_banned_file")
+                .await
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
+            history_count: setting(self.get_setting("password_history_count").await)
+                .unwrap_or(default.history_count),
+        }
+    }
+
+    /// Check if a password is the current password of a user or one of its previous passwords
+    /// The previous passwords are recorded by a trigger when the password is changed
+    ///
+    /// # Arguments
+    ///
+    /// * `history_count` - number of previous passwords to check, 0 for not checking anything
+    ///
+    /// # Returns
+    ///
+    /// Option<bool> - None if the user does not exist
+    pub async fn is_password_reused(&self, user_id: &UserId, password: &str, history_count: u32) -> Option<bool> {
+        if history_count == 0 {
+            return Some(false);
+        }
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("get_ab_peer error: {:?}", ab_guid);
+        let res = sqlx::query!(r#"SELECT password FROM user WHERE guid = ?"#, user_id)
+            .fetch_optional(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("is_password_reused error: {:?}", res);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let current = res.unwrap()?.password;
         let res = sqlx::query!(
-            r#"
-            SELECT
-                info
-            FROM
-                ab_peer
-            WHERE
-                ab_peer.ab = ? AND ab_peer.id = ?
-        "#,
-            ab_guid,
-            id
+            r#"SELECT password FROM user_password_history WHERE "user" = ? ORDER BY id DESC LIMIT ?"#,
+            user_id,
+            history_count
         )
-        .fetch_one(&mut *conn)
+        .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_ab_peer error: {:?}", res);
+            log::error!("is_password_reused error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        let ab_peer: AbPeer = rocket::serde::json::from_str(&res.info).unwrap();
-        Some(ab_peer)
+        let reused = std::iter::once(current)
+            .chain(res.unwrap().into_iter().map(|row| row.password))
+            .any(|hashed_password| bcrypt::verify(password, hashed_password.as_str()).unwrap_or(false));
+        Some(reused)
     }
 
-    pub async fn add_tag_to_ab(&self, ab: &str, tag: AbTag) -> Option<()> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("add_tag_to_ab error: {:?}", ab_guid);
-            return None;
-        }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+    /// Replace the hash of the current password of a user, when the password is hashed again with another cost
+    /// The history row added by the trigger is removed since the password is unchanged
+    pub async fn rehash_user_password(&self, user_id: &UserId, password_hashed: &str) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
         let res = sqlx::query!(
             r#"
-            DELETE FROM ab_tag WHERE ab = ? AND name = ?;
-            INSERT OR IGNORE INTO ab_tag (ab, name, color) VALUES (?, ?, ?)
+            UPDATE user SET password = ? WHERE guid = ?;
+            DELETE FROM user_password_history WHERE id = (SELECT MAX(id) FROM user_password_history WHERE "user" = ?);
         "#,
-            ab_guid,
-            tag.name,
-            ab_guid,
-            tag.name,
-            tag.color
+            password_hashed,
+            user_id,
+            user_id
         )
-        .execute(&mut *conn)
+        .execute(&mut *tx)
         .await;
         if res.is_err() {
-            log::error!("add_tag_to_ab error: {:?}", res);
+            log::error!("rehash_user_password error: {:?}", res);
             return None;
         }
+        tx.commit().await.ok()?;
         Some(())
     }
 
-    pub async fn get_ab_tags(&self, ab: &str) -> Option<Vec<AbTag>> {
+    /// Get the uptime of all peers between two unix timestamps
+    /// Online intervals are rebuilt from the peer_presence log, an interval still open
+    /// ends at the last heartbeat of the peer or now if the peer is still online
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - start of the period (unix timestamp)
+    /// * `to` - end of the period (unix timestamp)
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<PeerUptime>>
+    pub async fn get_peers_uptime(
+        &self,
+        from: i64,
+        to: i64,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerUptime>> {
+        let team_guid = team_scope_guid(team)?;
+        let threshold = self.get_peer_online_threshold().await;
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("get_ab_tags error: {:?}", ab_guid);
+        let peers = sqlx::query!(
+            r#"
+            SELECT
+                guid,
+                id,
+                last_online as "last_online!: String",
+                CAST(strftime('%s', last_online) AS INTEGER) as "last_online_secs!: i64",
+                CAST(strftime('%s','now') AS INTEGER) as "now!: i64"
+            FROM
+                peer
+            WHERE
+                ? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?)
+        "#,
+            team_guid,
+            team_guid
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if peers.is_err() {
+            log::error!("get_peers_uptime error: {:?}", peers);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
-        let res = sqlx::query!(
+        let peers = peers.unwrap();
+        // the presence log of all the peers in one query, ordered by peer then time
+        let events = sqlx::query!(
             r#"
             SELECT
-                name,
-                color
+                peer_presence.peer,
+                peer_presence.online,
+                CAST(strftime('%s', peer_presence.created_at) AS INTEGER) as "created_at!: i64"
             FROM
-                ab_tag
+                peer_presence
+                JOIN peer ON peer.guid = peer_presence.peer
             WHERE
-                ab = ?
+                peer_presence.created_at <= datetime(?, 'unixepoch')
+                AND (? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?))
+            ORDER BY
+                peer_presence.peer, peer_presence.created_at
         "#,
-            ab_guid
+            to,
+            team_guid,
+            team_guid
         )
         .fetch_all(&mut *conn)
         .await;
-        if res.is_err() {
-            log::error!("get_ab_tags error: {:?}", res);
+        if events.is_err() {
+            log::error!("get_peers_uptime error: {:?}", events);
             return None;
         }
-        let res = res.unwrap();
-        let mut ab_tags = Vec::new();
-        for row in res {
-            let ab_tag = AbTag {
-                name: row.name,
-                color: row.color as u32,
-            };
-            ab_tags.push(ab_tag);
+        let mut events_by_peer: HashMap<Vec<u8>, Vec<(i64, i64)>> = HashMap::new();
+        for event in events.unwrap() {
+            events_by_peer
+                .entry(event.peer)
+                .or_default()
+                .push((event.online, event.created_at));
         }
-        Some(ab_tags)
+        let mut uptimes: Vec<PeerUptime> = Vec::new();
+        for peer in peers {
+            let online = peer.now - peer.last_online_secs <= threshold;
+            let mut intervals: Vec<(i64, i64)> = Vec::new();
+            let mut online_since: Option<i64> = None;
+            let events = events_by_peer.remove(&peer.guid).unwrap_or_default();
+            for (event_online, created_at) in events {
+                if event_online == 1 {
+                    if online_since.is_none() {
+                        online_since = Some(created_at);
+                    }
+                } else if let Some(since) = online_since.take() {
+                    intervals.push((since, created_at));
+                }
+            }
+            if let Some(since) = online_since {
+                let end = if online { peer.now } else { peer.last_online_secs };
+                intervals.push((since, end.max(since)));
+            }
+            let (uptime_secs, histogram) = presence_histogram(&intervals, from, to);
+            let period = (to - from).max(1) as f64;
+            uptimes.push(PeerUptime {
+                guid: guid_into_uuid(peer.guid).unwrap_or("".to_string()),
+                id: peer.id,
+                online,
+                last_online: peer.last_online,
+                uptime_percent: (uptime_secs as f64 * 100.0 / period).min(100.0),
+                histogram,
+            });
+        }
+        Some(uptimes)
     }
 
-    pub async fn get_ab_tag(&self, ab: &str, tag: &str) -> Option<AbTag> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("get_ab_tag error: {:?}", ab_guid);
+    /// Get user for oauth2 flow
+    /// if the user does not exist, create it with status=0 and role=0
+    /// if environment variable OAUTH2_CREATE_USER is set to 1, create the user with status=1 and role=0
+    /// if the user exists return its guid
+    ///
+    /// # Arguments
+    /// * `id` - peer id
+    /// * `uuid` - peer uuid
+    ///
+    /// # Returns
+    /// Option<(UserId, name: String,DatabaseUserInfo)>
+    ///
+    pub async fn get_user_for_oauth2(
+        &self,
+        id: String,
+        email: String,
+        uuid: String,
+    ) -> Option<(UserId, String, DatabaseUserInfo)> {
+        let mut conn = DatabaseConnection {
+            conn: self.pool.acquire().await.unwrap(),
+        };
+        let status = { env::var("OAUTH2_CREATE_USER").unwrap_or("0".to_string()) == "1" };
+        let ab_guid = Uuid::new_v4().as_bytes().to_vec();
+        let user_guid = Uuid::new_v4().as_bytes().to_vec();
+        let random_password = Uuid::new_v4().to_string();
+        let hashed_random_password = self.hash_password(random_password.as_str()).await;
+        log::debug!(
+            "user: {:?}/{:?} has random_password: {:?}",
+            uuid,
+            id,
+            random_password
+        );
+        let name = format!("{}'s Personal Address Book", id);
+        let res = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO user(guid, grp, team, status, role, name, email, password)
+                VALUES (?,
+                    (SELECT guid FROM grp  WHERE name = 'Default'),
+                    (SELECT guid FROM team  WHERE name = 'Default'), ?, 0, ?, ?, ?);
+            INSERT OR IGNORE INTO ab(guid, name, owner, personal, info)
+                VALUES (?,?,?,1,'{}');
+            "#,
+            user_guid,
+            status,
+            id,
+            email,
+            hashed_random_password,
+            ab_guid,
+            name,
+            user_guid
+        )
+        .execute(&mut *conn.conn)
+        .await;
+        if res.is_err() {
+            log::error!(
+                "get_user_for_oauth2 error while creating/getting user: {:?}",
+                res
+            );
+        }
+        let res = sqlx::query!(
+            r#"  
+            SELECT guid, status, role, name FROM user WHERE name = ?;
+            "#,
+            id
+        )
+        .fetch_one(&mut *conn.conn)
+        .await;
+
+        if res.is_err() {
+            log::error!(
+                "get_user_for_oauth2 error while creating/getting user: {:?}",
+                res
+            );
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = res.unwrap();
+        let user_id: UserId = res.guid;
+        let dbi = DatabaseUserInfo {
+            active: res.status == 1,
+            admin: res.role == 1,
+        };
+        Some((user_id, res.name, dbi))
+    }
+
+    pub async fn get_personal_address_book(&self, user_id: UserId) {
+        let mut conn = self.pool.acquire().await.unwrap();
+
+        let _res = sqlx::query!(
+            r#"
+            SELECT
+                guid
+            FROM
+                ab
+            WHERE
+                owner = ?
+                AND personal = 1
+        "#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .ok();
+    }
+
+    /// Get the personal address book guid for a user
+    /// if the user is inactive (status != 1) return None
+    pub async fn get_ab_personal_guid(&self, user_id: UserId) -> Option<String> {
+        let mut conn = self.pool.acquire().await.unwrap();
+
         let res = sqlx::query!(
             r#"
             SELECT
-                name,
-                color
+                a.guid
             FROM
-                ab_tag
+                ab as a, user as u
             WHERE
-                ab = ?
-                AND name = ?
+                u.guid = ?
+                AND a.personal = 1
+                AND a.owner = u.guid
+                AND u.status = 1
         "#,
-            ab_guid,
-            tag
+            user_id
         )
-        .fetch_all(&mut *conn)
+        .fetch_one(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_ab_tags error: {:?}", res);
+            log::error!("get_ab_personal_guid error: {:?}", res);
             return None;
         }
+
         let res = res.unwrap();
-        if res.len() == 0 {
+        let guid_u8: Result<[u8; 16], _> = res.guid.try_into();
+        if guid_u8.is_err() {
+            log::error!("get_ab_personal_guid error: {:?}", guid_u8);
             return None;
         }
-        let ab_tag = AbTag {
-            name: res[0].name.clone(),
-            color: res[0].color as u32,
-        };
-        Some(ab_tag)
+        let guid_u8: [u8; 16] = guid_u8.unwrap();
+        let guid = Uuid::from_bytes(guid_u8).to_string();
+        Some(guid)
     }
 
-    pub async fn rename_ab_tag(&self, ab: &str, old_name: &str, tag: AbTag) -> Option<()> {
-        let mut conn = self.pool.acquire().await.unwrap();
+    /// Add or update a peer of an address book
+    /// The row is upserted on (ab, id) so its guid and creation time are kept,
+    /// a peer in the recycle bin is restored. A revision is recorded when the peer changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - address book guid in string format
+    /// * `ab_peer` - the peer
+    /// * `user_id` - the user who edits the peer
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn add_peer_to_ab(&self, ab: &str, ab_peer: AbPeer, user_id: &UserId) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
         let ab_guid = Uuid::parse_str(ab);
         if ab_guid.is_err() {
-            log::error!("rename_ab_tag error: {:?}", ab_guid);
+            log::error!("add_peer_to_ab error: {:?}", ab_guid);
             return None;
         }
         let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
-        let res = sqlx::query!(
+        self.write_ab_peer(&mut tx, &ab_guid, &ab_peer, user_id)
+            .await?;
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(())
+    }
+
+    /// Insert or update a peer of an address book and record the revision
+    ///
+    /// # Returns
+    ///
+    /// Option<bool> - false if the peer was already stored unchanged
+    async fn write_ab_peer(
+        &self,
+        conn: &mut SqliteConnection,
+        ab_guid: &Vec<u8>,
+        ab_peer: &AbPeer,
+        user_id: &UserId,
+    ) -> Option<bool> {
+        // the last change is known from the revisions
+        let ab_peer = &AbPeer {
+            updated_at: None,
+            updated_by: None,
+            ..ab_peer.clone()
+        };
+        let ab_peer_json = rocket::serde::json::to_string(ab_peer).unwrap();
+        let encrypted_ab_peer = self.keyring.encrypt_peer(ab_peer.clone());
+        if encrypted_ab_peer.is_err() {
+            log::error!("write_ab_peer error: {:?}", encrypted_ab_peer);
+            return None;
+        }
+        let encrypted_ab_peer_json = rocket::serde::json::to_string(&encrypted_ab_peer.unwrap()).unwrap();
+        let old = sqlx::query!(
             r#"
-            UPDATE ab_tag SET name = ?, color = ? WHERE ab = ? AND name = ?
+            SELECT
+                guid,
+                info,
+                deleted_at IS NOT NULL as "deleted!: bool"
+            FROM
+                ab_peer
+            WHERE
+                ab = ? AND id = ?
         "#,
-            tag.name,
-            tag.color,
             ab_guid,
-            old_name
+            ab_peer.id
         )
-        .execute(&mut *conn)
+        .fetch_optional(&mut *conn)
         .await;
-        if res.is_err() {
-            log::error!("rename_ab_tag error: {:?}", res);
-            return None;
-        }
-        Some(())
-    }
-
-    pub async fn delete_tag_from_ab(&self, ab: &str, tag: &str) -> Option<()> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("delete_tag_from_ab error: {:?}", ab_guid);
+        if old.is_err() {
+            log::error!("write_ab_peer error: {:?}", old);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let (ab_peer_guid, action) = match old.unwrap() {
+            None => (Uuid::new_v4().as_bytes().to_vec(), AB_PEER_REVISION_CREATE),
+            Some(old) if old.deleted => (old.guid, AB_PEER_REVISION_RESTORE),
+            Some(old) => {
+                let old_ab_peer = self.read_ab_peer(&old.info)?;
+                if rocket::serde::json::to_string(&old_ab_peer).unwrap() == ab_peer_json {
+                    // nothing changed
+                    return Some(false);
+                }
+                (old.guid, AB_PEER_REVISION_UPDATE)
+            }
+        };
         let res = sqlx::query!(
             r#"
-            DELETE FROM ab_tag WHERE ab = ? AND name = ?
+            INSERT INTO ab_peer (
+                guid,
+                ab,
+                peer,
+                id,
+                note,
+                created_at,
+                info
+                )
+                VALUES (?, ?, (SELECT guid FROM peer WHERE id = ?), ?, '', current_timestamp, ?)
+            ON CONFLICT (ab, id) DO UPDATE SET
+                peer = excluded.peer,
+                info = excluded.info,
+                deleted_at = NULL
         "#,
+            ab_peer_guid,
             ab_guid,
-            tag
+            ab_peer.id,
+            ab_peer.id,
+            encrypted_ab_peer_json
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("delete_tag_from_ab error: {:?}", res);
+            log::error!("write_ab_peer error: {:?}", res);
             return None;
         }
-        Some(())
+        add_ab_peer_revision(conn, &ab_peer_guid, action, &encrypted_ab_peer_json, user_id)
+            .await?;
+        Some(true)
     }
 
-    pub async fn add_user(
+    /// Read a peer of an address book which is not in the recycle bin
+    async fn read_ab_peer_by_id(
         &self,
-        name: String,
-        password: String,
-        email: String,
-        is_admin: bool,
-        group_name: String,
-    ) -> Option<()> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let user_guid = Uuid::new_v4().as_bytes().to_vec();
-        // Create a personal address book name based on the user name
-        let ab_name = format!("{}'s Personal Address Book", name);
-        // Check if the group exists
+        conn: &mut SqliteConnection,
+        ab_guid: &Vec<u8>,
+        id: &str,
+    ) -> Option<Option<AbPeer>> {
         let res = sqlx::query!(
             r#"
             SELECT
-                guid
+                info
             FROM
-                grp
+                ab_peer
             WHERE
-                name = ?
+                ab_peer.ab = ? AND ab_peer.id = ? AND ab_peer.deleted_at IS NULL
         "#,
-            group_name
+            ab_guid,
+            id
         )
-        .fetch_all(&mut *conn)
+        .fetch_optional(conn)
         .await;
         if res.is_err() {
-            log::error!("add_user error: {:?}", res);
+            log::error!("read_ab_peer_by_id error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        if res.len() == 0 {
+        match res.unwrap() {
+            Some(row) => Some(Some(self.read_ab_peer(&row.info)?)),
+            None => Some(None),
+        }
+    }
+
+    /// Copy or move peers from an address book to another one in a single transaction
+    /// The copied peers are merged into the peers of the target address book, the tags they use are
+    /// created with their color, or the default one. The moved peers are put in the recycle bin of the source address book.
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - source address book guid in string format
+    /// * `target` - target address book guid in string format
+    /// * `ids` - the ids of the peers
+    /// * `move_peers` - remove the peers from the source address book
+    /// * `user_id` - the user who edits the peers
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbPeersBatchItem>> - the result for each peer, None on error and nothing is changed
+    pub async fn copy_peers_to_ab(
+        &self,
+        ab: &str,
+        target: &str,
+        ids: &[String],
+        move_peers: bool,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        let ab_guid = Uuid::parse_str(ab);
+        let target_guid = Uuid::parse_str(target);
+        if ab_guid.is_err() || target_guid.is_err() {
+            log::error!("copy_peers_to_ab error: {:?} {:?}", ab_guid, target_guid);
             return None;
         }
-        let group_guid: Vec<u8> = res[0].guid.clone();
-        let ab_guid = Uuid::new_v4().as_bytes().to_vec();
-        let password_hashed = UserPasswordInfo::hash_password(password.as_str());
-        let res = sqlx::query!(
-            r#"
-            INSERT OR IGNORE INTO user(guid, grp, team, status, role, name, password, email)
-                VALUES (?,
-                    ?,
-                    (SELECT guid FROM team  WHERE name = 'Default'), 1, ?, ?, ?, ?);
-            INSERT OR IGNORE INTO ab(guid, name, owner, personal, info)
-                VALUES (?,?,?,1,'{}');
-            "#,
-            user_guid,
-            group_guid,
-            is_admin,
-            name,
-            password_hashed,
-            email,
-            ab_guid,
-            ab_name,
-            user_guid
-        )
-        .execute(&mut *conn)
-        .await;
-        if res.is_err() {
-            log::error!("add_user error: {:?}", res);
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let target_guid = target_guid.unwrap().as_bytes().to_vec();
+        let default_color = AbTag::default().color;
+        let mut tx = self.pool.begin().await.unwrap();
+        let mut items = Vec::new();
+        for id in ids {
+            let ab_peer = match self.read_ab_peer_by_id(&mut tx, &ab_guid, id).await? {
+                Some(ab_peer) => ab_peer,
+                None => {
+                    items.push(AbPeersBatchItem::new(id, AB_BATCH_NOT_FOUND));
+                    continue;
+                }
+            };
+            for tag in ab_peer.tags.clone().unwrap_or_default() {
+                let res = sqlx::query!(
+                    r#"
+                    INSERT OR IGNORE INTO ab_tag (ab, name, color)
+                        VALUES (?, ?, COALESCE((SELECT color FROM ab_tag WHERE ab = ? AND name = ?), ?))
+                "#,
+                    target_guid,
+                    tag,
+                    ab_guid,
+                    tag,
+                    default_color
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("copy_peers_to_ab error: {:?}", res);
+                    return None;
+                }
+            }
+            let old = self.read_ab_peer_by_id(&mut tx, &target_guid, id).await?;
+            let ab_peer = match old {
+                Some(old) => ab_peer.merge(old),
+                None => ab_peer,
+            };
+            let changed = self
+                .write_ab_peer(&mut tx, &target_guid, &ab_peer, user_id)
+                .await?;
+            if move_peers {
+                set_ab_peer_deleted(&mut tx, &ab_guid, id, true, user_id).await?;
+            }
+            let result = if changed || move_peers {
+                AB_BATCH_OK
+            } else {
+                AB_BATCH_UNCHANGED
+            };
+            items.push(AbPeersBatchItem::new(id, result));
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(items)
+    }
+
+    /// Apply the changes of an address book import in a single transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - address book guid in string format
+    /// * `tags` - the tags to add or update
+    /// * `peers` - the peers to add or update
+    /// * `removed_tags` - the tags to delete, they are removed from the peers
+    /// * `removed_peers` - the ids of the peers to move to the recycle bin
+    /// * `user_id` - the user who imports the address book
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None on error and nothing is changed
+    pub async fn import_ab_changes(
+        &self,
+        ab: &str,
+        tags: &[AbTag],
+        peers: &[AbPeer],
+        removed_tags: &[String],
+        removed_peers: &[String],
+        user_id: &UserId,
+    ) -> Option<()> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("import_ab_changes error: {:?}", ab_guid);
             return None;
         }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        for tag in tags {
+            let res = sqlx::query!(
+                r#"
+                DELETE FROM ab_tag WHERE ab = ? AND name = ?;
+                INSERT OR IGNORE INTO ab_tag (ab, name, color) VALUES (?, ?, ?)
+            "#,
+                ab_guid,
+                tag.name,
+                ab_guid,
+                tag.name,
+                tag.color
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("import_ab_changes error: {:?}", res);
+                return None;
+            }
+        }
+        for ab_peer in peers {
+            self.write_ab_peer(&mut tx, &ab_guid, ab_peer, user_id)
+                .await?;
+        }
+        for tag in removed_tags {
+            let res = sqlx::query!(
+                r#"
+                DELETE FROM ab_tag WHERE ab = ? AND name = ?
+            "#,
+                ab_guid,
+                tag
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("import_ab_changes error: {:?}", res);
+                return None;
+            }
+            self.retag_ab_peers(&mut tx, &ab_guid, tag, None, user_id)
+                .await?;
+        }
+        for id in removed_peers {
+            set_ab_peer_deleted(&mut tx, &ab_guid, id, true, user_id).await?;
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
         Some(())
     }
 
-    /// Change user status
-    /// if status is 1, the user is active
-    /// if status is 0, the user is inactive
-    pub async fn user_change_status(&self, uuid: &str, status: u32) -> Option<()> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let guid = Uuid::parse_str(uuid);
-        if guid.is_err() {
-            log::error!("change_user_status error: {:?}", guid);
+    /// Add or remove a tag on peers of an address book in a single transaction
+    /// The tag is created with the default color when it is added and does not exist
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - address book guid in string format
+    /// * `tag` - the tag name
+    /// * `ids` - the ids of the peers
+    /// * `add` - add the tag when true, remove it otherwise
+    /// * `user_id` - the user who edits the peers
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbPeersBatchItem>> - the result for each peer, None on error and nothing is changed
+    pub async fn tag_ab_peers(
+        &self,
+        ab: &str,
+        tag: &str,
+        ids: &[String],
+        add: bool,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("tag_ab_peers error: {:?}", ab_guid);
             return None;
         }
-        let guid = guid.unwrap().as_bytes().to_vec();
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        if add {
+            let color = AbTag::default().color;
+            let res = sqlx::query!(
+                r#"INSERT OR IGNORE INTO ab_tag (ab, name, color) VALUES (?, ?, ?)"#,
+                ab_guid,
+                tag,
+                color
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("tag_ab_peers error: {:?}", res);
+                return None;
+            }
+        }
+        let mut items = Vec::new();
+        for id in ids {
+            let mut ab_peer = match self.read_ab_peer_by_id(&mut tx, &ab_guid, id).await? {
+                Some(ab_peer) => ab_peer,
+                None => {
+                    items.push(AbPeersBatchItem::new(id, AB_BATCH_NOT_FOUND));
+                    continue;
+                }
+            };
+            let mut tags = ab_peer.tags.clone().unwrap_or_default();
+            let has_tag = tags.iter().any(|t| t == tag);
+            if has_tag == add {
+                items.push(AbPeersBatchItem::new(id, AB_BATCH_UNCHANGED));
+                continue;
+            }
+            if add {
+                tags.push(tag.to_string());
+            } else {
+                tags.retain(|t| t != tag);
+            }
+            ab_peer.tags = Some(tags);
+            self.write_ab_peer(&mut tx, &ab_guid, &ab_peer, user_id)
+                .await?;
+            items.push(AbPeersBatchItem::new(id, AB_BATCH_OK));
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(items)
+    }
+
+    /// Move all the peers of an address book having a tag to the recycle bin in a single transaction
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbPeersBatchItem>> - the deleted peers, None on error and nothing is changed
+    pub async fn delete_ab_peers_by_tag(
+        &self,
+        ab: &str,
+        tag: &str,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("delete_ab_peers_by_tag error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
         let res = sqlx::query!(
             r#"
-            UPDATE
-                user
-            SET
-                status = ?
+            SELECT
+                id as "id!: String"
+            FROM
+                ab_peer
             WHERE
-                guid = ?
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NULL
+                AND EXISTS (SELECT 1 FROM json_each(ab_peer.info, '$.tags') WHERE json_each.value = ?)
         "#,
-            status,
-            guid
+            ab_guid,
+            tag
         )
-        .execute(&mut *conn)
+        .fetch_all(&mut *tx)
         .await;
         if res.is_err() {
-            log::error!("change_user_status error: {:?}", res);
+            log::error!("delete_ab_peers_by_tag error: {:?}", res);
             return None;
         }
-        Some(())
+        let mut items = Vec::new();
+        for row in res.unwrap() {
+            set_ab_peer_deleted(&mut tx, &ab_guid, &row.id, true, user_id).await?;
+            items.push(AbPeersBatchItem::new(&row.id, AB_BATCH_OK));
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(items)
     }
 
-    pub async fn get_all_users(
-        &self,
-        name: Option<&str>,
-        email: Option<&str>,
-        current: u32,
-        page_size: u32,
-    ) -> Option<Vec<UserListResponse>> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let mut email_filter = "%";
-        let mut name_filter = "%";
-        if email.is_some() {
-            email_filter = email.unwrap();
+    /// Parse the info of an address book peer and decrypt its credentials
+    ///
+    /// # Returns
+    ///
+    /// Option<AbPeer> - None if a credential cannot be decrypted, the writers must then abort
+    /// instead of writing the peer back without it and the readers skip the peer
+    fn read_ab_peer(&self, info: &str) -> Option<AbPeer> {
+        let ab_peer: AbPeer = rocket::serde::json::from_str(info).unwrap();
+        let id = ab_peer.id.clone();
+        let res = self.keyring.decrypt_peer(ab_peer);
+        if res.is_err() {
+            log::error!("read_ab_peer error for the peer {}: {:?}", id, res);
+            return None;
         }
-        if name.is_some() {
-            name_filter = name.unwrap();
+        res.ok()
+    }
+
+    /// Re-encrypt the credentials of all the address book peers and of their revisions
+    /// They are decrypted with the current keyring and encrypted with the new one, the rows which
+    /// cannot be decrypted are logged and left unchanged
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - the new keyring
+    ///
+    /// # Returns
+    ///
+    /// Option<u64> - the number of re-encrypted rows
+    pub async fn reencrypt_ab_peers(&self, keyring: &AbKeyring) -> Option<u64> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let peers = sqlx::query!(r#"SELECT guid, info FROM ab_peer"#)
+            .fetch_all(&mut *tx)
+            .await;
+        if peers.is_err() {
+            log::error!("reencrypt_ab_peers error: {:?}", peers);
+            return None;
         }
-        let current = {
-            if current < 1 {
-                1
+        let revisions = sqlx::query!(r#"SELECT guid, info FROM ab_peer_revision"#)
+            .fetch_all(&mut *tx)
+            .await;
+        if revisions.is_err() {
+            log::error!("reencrypt_ab_peers error: {:?}", revisions);
+            return None;
+        }
+        let rows = peers
+            .unwrap()
+            .into_iter()
+            .map(|row| (false, row.guid, row.info))
+            .chain(
+                revisions
+                    .unwrap()
+                    .into_iter()
+                    .map(|row| (true, row.guid, row.info)),
+            );
+        let mut count = 0;
+        for (revision, guid, info) in rows {
+            // a credential which cannot be decrypted is kept as is, it would be lost otherwise
+            let ab_peer = match self.read_ab_peer(&info) {
+                Some(ab_peer) => keyring.encrypt_peer(ab_peer),
+                None => continue,
+            };
+            if ab_peer.is_err() {
+                log::error!("reencrypt_ab_peers error: {:?}", ab_peer);
+                return None;
+            }
+            let info = rocket::serde::json::to_string(&ab_peer.unwrap()).unwrap();
+            let res = if revision {
+                sqlx::query!(
+                    r#"UPDATE ab_peer_revision SET info = ? WHERE guid = ?"#,
+                    info,
+                    guid
+                )
+                .execute(&mut *tx)
+                .await
             } else {
-                current
+                sqlx::query!(r#"UPDATE ab_peer SET info = ? WHERE guid = ?"#, info, guid)
+                    .execute(&mut *tx)
+                    .await
+            };
+            if res.is_err() {
+                log::error!("reencrypt_ab_peers error: {:?}", res);
+                return None;
             }
-        };
-        let offset = ((current - 1) * page_size) as i32;
-        let page_size = page_size as i32;
+            count += 1;
+        }
+        tx.commit().await.ok()?;
+        Some(count)
+    }
+
+    /// Get the keyring used to encrypt the address book peers credentials
+    pub fn get_ab_keyring(&self) -> &AbKeyring {
+        &self.keyring
+    }
 
+    pub async fn get_peers_from_ab(&self, ab: &str) -> Option<Vec<AbPeer>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_peers_from_ab error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
             SELECT
-                user.guid as id,
-                user.status as "active!: i32",
-                user.role as "admin!: bool",
-                user.name as username,
-                user.email as email,
-                user.note as note,
-                grp.name as group_name
+                info
             FROM
-                user
-                LEFT JOIN grp
-                    ON user.grp = grp.guid
+                ab_peer
             WHERE
-                user.name LIKE ?
-                AND user.email LIKE ?
-            LIMIT ?
-            OFFSET ?
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NULL
         "#,
-            name_filter,
-            email_filter,
-            page_size,
-            offset
+            ab_guid
         )
         .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_all_users error: {:?}", res);
+            log::error!("get_peers_from_ab error: {:?}", res);
             return None;
         }
         let res = res.unwrap();
-        let mut users: Vec<UserListResponse> = Vec::new();
+        let mut ab_peers = Vec::new();
         for row in res {
-            let guid_u8: Result<[u8; 16], _> = row.id.try_into();
-            if guid_u8.is_err() {
-                log::error!("get_ab_personal_guid error: {:?}", guid_u8);
-                return None;
-            }
-            let guid_u8: [u8; 16] = guid_u8.unwrap();
-            let guid = Uuid::from_bytes(guid_u8).to_string();
-            let user = UserListResponse {
-                guid: guid,
-                name: row.username,
-                email: row.email.unwrap_or("".to_string()),
-                note: row.note,
-                status: row.active,
-                is_admin: row.admin,
-                group_name: row.group_name.unwrap_or("Defaut".to_string()),
+            let ab_peer = match self.read_ab_peer(&row.info) {
+                Some(ab_peer) => ab_peer,
+                None => continue,
             };
-            users.push(user);
+            log::debug!("ab_peer: {:?}", ab_peer);
+            ab_peers.push(ab_peer);
         }
-        Some(users)
+        Some(ab_peers)
     }
 
-    pub async fn user_update(
+    /// Get a page of the peers of an address book and the number of peers matching the filters
+    /// The time and the author of the last revision of each peer are set in `updated_at` and `updated_by`
+    ///
+    /// # Arguments
+    ///
+    /// - `tag` - only the peers with this tag
+    /// - `search` - substring of the peer id or alias, case insensitive
+    /// - `platform` - only the peers of this platform, case insensitive
+    /// - `current` - the page number, starting at 1
+    /// - `page_size` - the number of peers per page
+    pub async fn get_peers_page_from_ab(
         &self,
-        user_id: UserId,
-        user_parameters: UpdateUserRequest,
-    ) -> Option<()> {
+        ab: &str,
+        tag: Option<&str>,
+        search: Option<&str>,
+        platform: Option<&str>,
+        current: u32,
+        page_size: u32,
+    ) -> Option<(u32, Vec<AbPeer>)> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let mut query = "UPDATE user SET ".to_string();
-        let mut query_params = Vec::new();
-        if user_parameters.name.is_some() && user_parameters.name.clone().unwrap().len() > 0 {
-            query.push_str("name = ?, ");
-            query_params.push(user_parameters.name.unwrap());
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_peers_page_from_ab error: {:?}", ab_guid);
+            return None;
         }
-        if user_parameters.email.is_some() && user_parameters.email.clone().unwrap().len() > 0 {
-            query.push_str("email = ?, ");
-            query_params.push(user_parameters.email.unwrap());
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let search = search.map(like_pattern);
+        let current = if current < 1 { 1 } else { current };
+        let offset = (current - 1) as i64 * page_size as i64;
+        let page_size = page_size as i64;
+
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT
+                COUNT(*) as "count!: u32"
+            FROM
+                ab_peer
+            WHERE
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NULL
+                AND (? IS NULL OR EXISTS (SELECT 1 FROM json_each(ab_peer.info, '$.tags') WHERE json_each.value = ?))
+                AND (? IS NULL OR ab_peer.id LIKE ? ESCAPE '\' OR json_extract(ab_peer.info, '$.alias') LIKE ? ESCAPE '\')
+                AND (? IS NULL OR json_extract(ab_peer.info, '$.platform') = ? COLLATE NOCASE)
+        "#,
+            ab_guid,
+            tag,
+            tag,
+            search,
+            search,
+            search,
+            platform,
+            platform
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if total.is_err() {
+            log::error!("get_peers_page_from_ab error: {:?}", total);
+            return None;
         }
-        if user_parameters.note.is_some() && user_parameters.note.clone().unwrap().len() > 0 {
-            query.push_str("note = ?, ");
-            query_params.push(user_parameters.note.unwrap());
+        let total = total.unwrap();
+
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                ab_peer.info,
+                ab_peer_revision.created_at as "updated_at?: String",
+                user.name as "updated_by?: String"
+            FROM
+                ab_peer
+                LEFT JOIN ab_peer_revision ON ab_peer_revision.ab_peer = ab_peer.guid
+                    AND ab_peer_revision.revision = (SELECT MAX(revision) FROM ab_peer_revision WHERE ab_peer = ab_peer.guid)
+                LEFT JOIN user ON user.guid = ab_peer_revision.updated_by
+            WHERE
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NULL
+                AND (? IS NULL OR EXISTS (SELECT 1 FROM json_each(ab_peer.info, '$.tags') WHERE json_each.value = ?))
+                AND (? IS NULL OR ab_peer.id LIKE ? ESCAPE '\' OR json_extract(ab_peer.info, '$.alias') LIKE ? ESCAPE '\')
+                AND (? IS NULL OR json_extract(ab_peer.info, '$.platform') = ? COLLATE NOCASE)
+            ORDER BY
+                ab_peer.created_at,
+                ab_peer.id
+            LIMIT ?
+            OFFSET ?
+        "#,
+            ab_guid,
+            tag,
+            tag,
+            search,
+            search,
+            search,
+            platform,
+            platform,
+            page_size,
+            offset
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_peers_page_from_ab error: {:?}", res);
+            return None;
         }
-        if user_parameters.password.is_some()
-            && user_parameters.confirm_password.is_some()
-            && user_parameters.password.clone().unwrap().len() > 0
-            && user_parameters.confirm_password.clone().unwrap().len() > 0
-        {
-            let password = user_parameters.password.unwrap();
-            let confirm_password = user_parameters.confirm_password.unwrap();
-            if password == confirm_password {
-                let password_hashed = UserPasswordInfo::hash_password(password.as_str());
-                query.push_str("password = ?, ");
-                query_params.push(password_hashed);
-            }
+        let ab_peers = res
+            .unwrap()
+            .into_iter()
+            .filter_map(|row| {
+                Some(AbPeer {
+                    updated_at: row.updated_at,
+                    updated_by: row.updated_by,
+                    ..self.read_ab_peer(&row.info)?
+                })
+            })
+            .collect::<Vec<AbPeer>>();
+        Some((total, ab_peers))
+    }
+
+    /// Move a peer of an address book to the recycle bin
+    /// The peer is only flagged as deleted, it can be restored until it is purged
+    pub async fn delete_peer_from_ab(&self, ab: &str, id: &str, user_id: &UserId) -> Option<()> {
+        self.set_ab_peer_deleted(ab, id, true, user_id).await.map(|_| ())
+    }
+
+    /// Restore a peer of an address book from the recycle bin
+    ///
+    /// # Returns
+    ///
+    /// Option<bool> - true if the peer was in the recycle bin
+    pub async fn restore_peer_from_ab(&self, ab: &str, id: &str, user_id: &UserId) -> Option<bool> {
+        self.set_ab_peer_deleted(ab, id, false, user_id).await
+    }
+
+    /// Move a peer to or from the recycle bin and record the revision
+    ///
+    /// # Returns
+    ///
+    /// Option<bool> - true if the peer was found in the expected state
+    async fn set_ab_peer_deleted(
+        &self,
+        ab: &str,
+        id: &str,
+        deleted: bool,
+        user_id: &UserId,
+    ) -> Option<bool> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("set_ab_peer_deleted error: {:?}", ab_guid);
+            return None;
         }
-        if let Some(status) = user_parameters.status {
-            query.push_str("status = ?, ");
-            query_params.push(status.to_string());
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let found = set_ab_peer_deleted(&mut tx, &ab_guid, id, deleted, user_id).await?;
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(found)
+    }
+
+    /// Get the peers of an address book which are in the recycle bin
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - address book guid in string format
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbRecycledPeer>> - the deleted peers, the most recently deleted first
+    pub async fn get_recycled_peers_from_ab(&self, ab: &str) -> Option<Vec<AbRecycledPeer>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_recycled_peers_from_ab error: {:?}", ab_guid);
+            return None;
         }
-        if let Some(is_admin) = user_parameters.is_admin {
-            query.push_str("role = ?, ");
-            query_params.push(if is_admin { "1" } else { "0" }.to_string());
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                info,
+                deleted_at as "deleted_at!: String"
+            FROM
+                ab_peer
+            WHERE
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NOT NULL
+            ORDER BY
+                deleted_at DESC
+        "#,
+            ab_guid
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_recycled_peers_from_ab error: {:?}", res);
+            return None;
         }
-        query.pop();
-        query.pop();
+        let mut peers = Vec::new();
+        for row in res.unwrap() {
+            let peer = match self.read_ab_peer(&row.info) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            peers.push(AbRecycledPeer {
+                deleted_at: row.deleted_at,
+                peer,
+            });
+        }
+        Some(peers)
+    }
+
+    /// Purge the address book peers which are in the recycle bin for more than the retention period
+    ///
+    /// # Arguments
+    ///
+    /// * `retention_days` - number of days the deleted peers are kept
+    ///
+    /// # Returns
+    ///
+    /// Option<u64> - the number of purged peers
+    pub async fn purge_recycled_ab_peers(&self, retention_days: i64) -> Option<u64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let modifier = format!("-{} days", retention_days);
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_peer
+            WHERE deleted_at IS NOT NULL AND deleted_at < datetime('now', ?)
+        "#,
+            modifier
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("purge_recycled_ab_peers error: {:?}", res);
+            return None;
+        }
+        let purged = res.unwrap().rows_affected();
+        // the history of the peers removed from the address books is not kept
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_peer_revision
+            WHERE ab_peer NOT IN (SELECT guid FROM ab_peer)
+        "#
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("purge_recycled_ab_peers error: {:?}", res);
+            return None;
+        }
+        Some(purged)
+    }
 
-        query.push_str(" WHERE guid = ?");
+    /// Wake up the subscribers waiting for address book changes
+    fn notify_ab_changed(&self) {
+        self.ab_changes.send_modify(|changes| *changes = changes.wrapping_add(1));
+    }
 
-        log::debug!("query: {:?}", query);
-        let mut res = sqlx::query(&query);
-        for param in query_params {
-            res = res.bind(param);
+    /// Subscribe to the address book changes made by this server
+    /// The receiver is marked as changed after each change of the peers or tags of an address book
+    pub fn subscribe_ab_changes(&self) -> watch::Receiver<u64> {
+        self.ab_changes.subscribe()
+    }
+
+    /// Get the revision of an address book, 0 if it never changed
+    pub async fn get_ab_revision(&self, ab: &str) -> Option<i64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_revision error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query_scalar!(
+            r#"SELECT revision as "revision!: i64" FROM ab_revision WHERE ab = ?"#,
+            ab_guid
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_revision error: {:?}", res);
+            return None;
+        }
+        Some(res.unwrap().unwrap_or(0))
+    }
+
+    /// Get the address books whose revision is greater than `since`, in revision order
+    pub async fn get_ab_changes(&self, since: i64) -> Option<Vec<AbChange>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                ab,
+                revision as "revision!: i64",
+                updated_at as "updated_at!: String"
+            FROM
+                ab_revision
+            WHERE
+                revision > ?
+            ORDER BY
+                revision
+        "#,
+            since
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_changes error: {:?}", res);
+            return None;
+        }
+        let changes = res
+            .unwrap()
+            .into_iter()
+            .filter_map(|row| {
+                Some(AbChange {
+                    guid: guid_into_uuid(row.ab)?,
+                    revision: row.revision,
+                    updated_at: row.updated_at,
+                })
+            })
+            .collect();
+        Some(changes)
+    }
+
+    /// Get the revisions of a peer of an address book
+    ///
+    /// # Arguments
+    ///
+    /// * `ab` - address book guid in string format
+    /// * `id` - peer id
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbPeerRevision>> - the revisions, the latest first
+    pub async fn get_ab_peer_history(&self, ab: &str, id: &str) -> Option<Vec<AbPeerRevision>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_peer_history error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                ab_peer_revision.revision,
+                ab_peer_revision.action,
+                ab_peer_revision.info,
+                ab_peer_revision.created_at as "created_at!: String",
+                user.name as "user?: String"
+            FROM
+                ab_peer_revision
+                JOIN ab_peer ON ab_peer.guid = ab_peer_revision.ab_peer
+                LEFT JOIN user ON user.guid = ab_peer_revision.updated_by
+            WHERE
+                ab_peer.ab = ? AND ab_peer.id = ?
+            ORDER BY
+                ab_peer_revision.revision DESC
+        "#,
+            ab_guid,
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_peer_history error: {:?}", res);
+            return None;
+        }
+        let mut revisions = Vec::new();
+        for row in res.unwrap() {
+            let peer = match self.read_ab_peer(&row.info) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            revisions.push(AbPeerRevision {
+                revision: row.revision,
+                action: row.action,
+                updated_at: row.created_at,
+                updated_by: row.user,
+                peer,
+            });
+        }
+        Some(revisions)
+    }
+
+    pub async fn get_ab_peer(&self, ab: &str, id: &str) -> Option<AbPeer> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_peer error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                info
+            FROM
+                ab_peer
+            WHERE
+                ab_peer.ab = ? AND ab_peer.id = ? AND ab_peer.deleted_at IS NULL
+        "#,
+            ab_guid,
+            id
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_peer error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        self.read_ab_peer(&res.info)
+    }
+
+    pub async fn add_tag_to_ab(&self, ab: &str, tag: AbTag) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("add_tag_to_ab error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_tag WHERE ab = ? AND name = ?;
+            INSERT OR IGNORE INTO ab_tag (ab, name, color) VALUES (?, ?, ?)
+        "#,
+            ab_guid,
+            tag.name,
+            ab_guid,
+            tag.name,
+            tag.color
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("add_tag_to_ab error: {:?}", res);
+            return None;
+        }
+        self.notify_ab_changed();
+        Some(())
+    }
+
+    pub async fn get_ab_tags(&self, ab: &str) -> Option<Vec<AbTag>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_tags error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                name,
+                color
+            FROM
+                ab_tag
+            WHERE
+                ab = ?
+        "#,
+            ab_guid
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_tags error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        let mut ab_tags = Vec::new();
+        for row in res {
+            let ab_tag = AbTag {
+                name: row.name,
+                color: row.color as u32,
+            };
+            ab_tags.push(ab_tag);
+        }
+        Some(ab_tags)
+    }
+
+    pub async fn get_ab_tag(&self, ab: &str, tag: &str) -> Option<AbTag> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_tag error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                name,
+                color
+            FROM
+                ab_tag
+            WHERE
+                ab = ?
+                AND name = ?
+        "#,
+            ab_guid,
+            tag
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_tags error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        if res.len() == 0 {
+            return None;
+        }
+        let ab_tag = AbTag {
+            name: res[0].name.clone(),
+            color: res[0].color as u32,
+        };
+        Some(ab_tag)
+    }
+
+    /// Rename a tag of an address book and the tag in the peers using it in a single transaction
+    /// If a tag with the new name already exists the two tags are merged and its color is kept
+    pub async fn rename_ab_tag(
+        &self,
+        ab: &str,
+        old_name: &str,
+        tag: AbTag,
+        user_id: &UserId,
+    ) -> Option<()> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("rename_ab_tag error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_tag
+            WHERE ab = ? AND name = ? AND name != ?
+                AND EXISTS (SELECT 1 FROM ab_tag WHERE ab = ? AND name = ?);
+            UPDATE ab_tag SET name = ?, color = ? WHERE ab = ? AND name = ?
+        "#,
+            ab_guid,
+            old_name,
+            tag.name,
+            ab_guid,
+            tag.name,
+            tag.name,
+            tag.color,
+            ab_guid,
+            old_name
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("rename_ab_tag error: {:?}", res);
+            return None;
+        }
+        if old_name != tag.name {
+            self.retag_ab_peers(&mut tx, &ab_guid, old_name, Some(&tag.name), user_id)
+                .await?;
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(())
+    }
+
+    /// Delete a tag of an address book and remove it from the peers using it in a single transaction
+    pub async fn delete_tag_from_ab(&self, ab: &str, tag: &str, user_id: &UserId) -> Option<()> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("delete_tag_from_ab error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_tag WHERE ab = ? AND name = ?
+        "#,
+            ab_guid,
+            tag
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_tag_from_ab error: {:?}", res);
+            return None;
+        }
+        self.retag_ab_peers(&mut tx, &ab_guid, tag, None, user_id)
+            .await?;
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(())
+    }
+
+    /// Replace or remove a tag in the tags of the peers of an address book
+    /// The peers in the recycle bin are not changed
+    ///
+    /// # Arguments
+    ///
+    /// * `old_name` - the tag to replace
+    /// * `new_name` - the new tag, None to remove the tag
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<String>> - the ids of the changed peers
+    async fn retag_ab_peers(
+        &self,
+        conn: &mut SqliteConnection,
+        ab_guid: &Vec<u8>,
+        old_name: &str,
+        new_name: Option<&str>,
+        user_id: &UserId,
+    ) -> Option<Vec<String>> {
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                info
+            FROM
+                ab_peer
+            WHERE
+                ab_peer.ab = ?
+                AND ab_peer.deleted_at IS NULL
+                AND EXISTS (SELECT 1 FROM json_each(ab_peer.info, '$.tags') WHERE json_each.value = ?)
+        "#,
+            ab_guid,
+            old_name
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("retag_ab_peers error: {:?}", res);
+            return None;
+        }
+        let mut changed = Vec::new();
+        for row in res.unwrap() {
+            let mut ab_peer = self.read_ab_peer(&row.info)?;
+            let mut tags: Vec<String> = Vec::new();
+            for tag in ab_peer.tags.clone().unwrap_or_default() {
+                let tag = match (tag == old_name, new_name) {
+                    (true, Some(new_name)) => new_name.to_string(),
+                    (true, None) => continue,
+                    (false, _) => tag,
+                };
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            ab_peer.tags = Some(tags);
+            self.write_ab_peer(&mut *conn, ab_guid, &ab_peer, user_id)
+                .await?;
+            changed.push(ab_peer.id);
+        }
+        Some(changed)
+    }
+
+    /// Get the tags used by the peers of an address book which do not exist in the address book
+    /// The peers in the recycle bin are ignored
+    pub async fn get_ab_orphaned_tags(&self, ab: &str) -> Option<Vec<AbOrphanedTag>> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_orphaned_tags error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut conn = self.pool.acquire().await.unwrap();
+        get_ab_orphaned_tags(&mut conn, &ab_guid).await
+    }
+
+    /// Repair the orphaned tags of an address book in a single transaction
+    /// They are either created with the default color or removed from the peers
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<AbOrphanedTag>> - the repaired tags
+    pub async fn repair_ab_orphaned_tags(
+        &self,
+        ab: &str,
+        mode: AbTagRepairMode,
+        user_id: &UserId,
+    ) -> Option<Vec<AbOrphanedTag>> {
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("repair_ab_orphaned_tags error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let orphaned_tags = get_ab_orphaned_tags(&mut tx, &ab_guid).await?;
+        let color = AbTag::default().color;
+        for tag in orphaned_tags.iter() {
+            match mode {
+                AbTagRepairMode::Create => {
+                    let res = sqlx::query!(
+                        r#"INSERT OR IGNORE INTO ab_tag (ab, name, color) VALUES (?, ?, ?)"#,
+                        ab_guid,
+                        tag.name,
+                        color
+                    )
+                    .execute(&mut *tx)
+                    .await;
+                    if res.is_err() {
+                        log::error!("repair_ab_orphaned_tags error: {:?}", res);
+                        return None;
+                    }
+                }
+                AbTagRepairMode::Remove => {
+                    self.retag_ab_peers(&mut tx, &ab_guid, &tag.name, None, user_id)
+                        .await?;
+                }
+            }
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(orphaned_tags)
+    }
+
+    pub async fn add_user(
+        &self,
+        name: String,
+        password: String,
+        email: String,
+        is_admin: bool,
+        group_name: String,
+        team: Option<&str>,
+    ) -> Option<()> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let user_guid = Uuid::new_v4().as_bytes().to_vec();
+        // Create a personal address book name based on the user name
+        let ab_name = format!("{}'s Personal Address Book", name);
+        // Check if the group exists, the user belongs to the team of its group
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                guid,
+                team
+            FROM
+                grp
+            WHERE
+                name = ?
+                AND (? IS NULL OR team = ?)
+        "#,
+            group_name,
+            team_guid,
+            team_guid
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("add_user error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        if res.len() == 0 {
+            return None;
+        }
+        let group_guid: Vec<u8> = res[0].guid.clone();
+        let group_team: Vec<u8> = res[0].team.clone();
+        let ab_guid = Uuid::new_v4().as_bytes().to_vec();
+        let password_hashed = self.hash_password(password.as_str()).await;
+        let res = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO user(guid, grp, team, status, role, name, password, email)
+                VALUES (?, ?, ?, 1, ?, ?, ?, ?);
+            INSERT OR IGNORE INTO ab(guid, name, owner, personal, info)
+                VALUES (?,?,?,1,'{}');
+            "#,
+            user_guid,
+            group_guid,
+            group_team,
+            is_admin,
+            name,
+            password_hashed,
+            email,
+            ab_guid,
+            ab_name,
+            user_guid
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("add_user error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
+    /// Import users in a single transaction
+    /// Nothing is written if a row fails or for a dry run, the report lists the error of each failed row
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - the users or the parse error of each row
+    /// * `create_groups` - create the missing groups in the team scope, in the Default team without scope
+    /// * `allow_admins` - allow importing admins
+    /// * `team` - the team scope, the groups of the users must belong to this team
+    /// * `dry_run` - validate the users without writing them
+    ///
+    /// # Returns
+    ///
+    /// Option<UserImportReport> - None if the team guid is invalid or the transaction fails
+    pub async fn import_users(
+        &self,
+        users: &[Result<UserTransfer, String>],
+        create_groups: bool,
+        allow_admins: bool,
+        team: Option<&str>,
+        dry_run: bool,
+    ) -> Option<UserImportReport> {
+        let team_guid = team_scope_guid(team)?;
+        let new_groups_team = match &team_guid {
+            Some(team_guid) => team_guid.clone(),
+            None => Uuid::parse_str(DEFAULT_TEAM_GUID).unwrap().as_bytes().to_vec(),
+        };
+        let new_groups_team = if create_groups { Some(&new_groups_team) } else { None };
+        let mut report = UserImportReport::new(dry_run);
+        let mut groups = HashMap::new();
+        let password_policy = self.get_password_policy().await;
+        let password_hash_cost = self.get_password_hash_cost().await;
+        let mut tx = self.pool.begin().await.unwrap();
+        for (index, user) in users.iter().enumerate() {
+            let res = match user {
+                Ok(user) if user.is_admin && !allow_admins => Err("only the admins can import admins".to_string()),
+                Ok(user) => match user.password.as_ref().map(|password| password_policy.check(password)) {
+                    Some(Err(error)) => Err(error),
+                    _ => {
+                        // a generated password is random enough to skip the policy
+                        let generated_password = match user.password {
+                            Some(_) => None,
+                            None => Some(utils::Token::new_random().to_base64()),
+                        };
+                        let password = user.password.as_ref().or(generated_password.as_ref()).unwrap();
+                        let password_hashed = UserPasswordInfo::hash_password(password, password_hash_cost);
+                        import_user(
+                            &mut tx,
+                            user,
+                            &password_hashed,
+                            &team_guid,
+                            new_groups_team,
+                            &mut groups,
+                            &mut report.groups_created,
+                        )
+                        .await
+                        .map(|_| generated_password)
+                    }
+                },
+                Err(error) => Err(error.clone()),
+            };
+            let mut row = UserImportRow {
+                row: index as u32 + 1,
+                name: user.as_ref().map(|user| user.name.clone()).unwrap_or_default(),
+                error: None,
+                generated_password: None,
+            };
+            match res {
+                Ok(generated_password) => {
+                    report.users_created += 1;
+                    row.generated_password = generated_password;
+                }
+                Err(error) => {
+                    report.users_failed += 1;
+                    row.error = Some(error);
+                }
+            }
+            report.rows.push(row);
+        }
+        if report.users_failed > 0 || dry_run {
+            tx.rollback().await.ok()?;
+            // the generated passwords of users which are not created are useless
+            for row in report.rows.iter_mut() {
+                row.generated_password = None;
+            }
+            return Some(report);
+        }
+        let res = tx.commit().await;
+        if res.is_err() {
+            log::error!("import_users error: {:?}", res);
+            return None;
+        }
+        report.imported = true;
+        Some(report)
+    }
+
+    /// Export the users with the name of their group, the passwords are not exported
+    ///
+    /// # Arguments
+    ///
+    /// * `team` - only export the users of this team
+    pub async fn export_users(&self, team: Option<&str>) -> Option<Vec<UserTransfer>> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.name,
+                user.email,
+                grp.name as "group_name!: String",
+                user.role,
+                user.note
+            FROM
+                user
+                JOIN grp ON grp.guid = user.grp
+            WHERE
+                (? IS NULL OR user.team = ?)
+                AND user.status != ?
+            ORDER BY
+                user.name
+        "#,
+            team_guid,
+            team_guid,
+            USER_STATUS_ARCHIVED
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("export_users error: {:?}", res);
+            return None;
+        }
+        let users = res.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.name,
+                user_data.type as "data_type!: String",
+                user_data.data
+            FROM
+                user_data
+                JOIN user ON user.guid = user_data.user
+            WHERE
+                ? IS NULL OR user.team = ?
+            ORDER BY
+                user_data.type
+        "#,
+            team_guid,
+            team_guid
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("export_users error: {:?}", res);
+            return None;
+        }
+        let mut data: HashMap<String, Vec<UserDataTransfer>> = HashMap::new();
+        for row in res.unwrap() {
+            data.entry(row.name).or_default().push(UserDataTransfer {
+                data_type: row.data_type,
+                data: row.data,
+            });
+        }
+        let users = users
+            .into_iter()
+            .map(|row| UserTransfer {
+                data: data.remove(&row.name).unwrap_or_default(),
+                name: row.name,
+                email: row.email.filter(|email| !email.is_empty()),
+                group: row.group_name,
+                is_admin: row.role == 1,
+                note: row.note.filter(|note| !note.is_empty()),
+                password: None,
+            })
+            .collect();
+        Some(users)
+    }
+
+    /// Find a user given its email
+    ///
+    /// # Returns
+    ///
+    /// Option<Option<(UserId, String, bool)>> - the guid, the name and the active state of the user, None on error
+    pub async fn find_user_by_email(&self, email: &str) -> Option<Option<(UserId, String, bool)>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"SELECT guid, name, status FROM user WHERE email = ?"#,
+            email
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("find_user_by_email error: {:?}", res);
+            return None;
+        }
+        Some(res.unwrap().map(|row| (row.guid, row.name, row.status == 1)))
+    }
+
+    /// Get the profile of a user
+    pub async fn get_user_profile(&self, user_id: &UserId) -> Option<UserProfile> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.guid,
+                user.name,
+                user.email,
+                user.note,
+                user.role,
+                user.info as "info!: String",
+                user.created_at as "created_at!: String",
+                grp.name as "group_name!: String",
+                team.name as "team_name!: String"
+            FROM
+                user
+                JOIN grp ON grp.guid = user.grp
+                JOIN team ON team.guid = user.team
+            WHERE
+                user.guid = ?
+        "#,
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_profile error: {:?}", res);
+            return None;
+        }
+        let row = res.unwrap()?;
+        let notifications = serde_json::from_str::<UserNotifications>(&row.info).unwrap_or_default();
+        Some(UserProfile {
+            guid: guid_into_uuid(row.guid).unwrap_or_default(),
+            name: row.name,
+            email: row.email,
+            note: row.note,
+            is_admin: row.role == 1,
+            group_name: row.group_name,
+            team_name: row.team_name,
+            created_at: row.created_at,
+            notifications,
+        })
+    }
+
+    /// Update the profile of a user, the notification preferences are merged into the info of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the new email, an empty email removes it, None for keeping it
+    /// * `note` - the new note, None for keeping it
+    /// * `notifications` - the new notification preferences, None for keeping them
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the user does not exist or if the email is used by another user
+    pub async fn update_user_profile(
+        &self,
+        user_id: &UserId,
+        email: Option<&str>,
+        note: Option<&str>,
+        notifications: Option<&UserNotifications>,
+    ) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        // the preferences are merged in the update itself, a transaction reading the info first may fail to get the write lock
+        let notifications = notifications
+            .and_then(|notifications| serde_json::to_string(notifications).ok())
+            .unwrap_or("{}".to_string());
+        let update_email = email.is_some();
+        let email = email.map(|email| email.trim());
+        let res = sqlx::query!(
+            r#"
+            UPDATE
+                user
+            SET
+                email = CASE WHEN ? THEN NULLIF(?, '') ELSE email END,
+                note = COALESCE(?, note),
+                info = json_patch(CASE WHEN json_valid(info) AND json_type(info) = 'object' THEN info ELSE '{}' END, ?)
+            WHERE
+                guid = ?
+        "#,
+            update_email,
+            email,
+            note,
+            notifications,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("update_user_profile error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            return None;
+        }
+        Some(())
+    }
+
+    /// Change the password of a user if its current password is right
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the user does not exist or if the current password is wrong
+    pub async fn change_user_password(&self, user_id: &UserId, current_password: &str, new_password: &str) -> Option<()> {
+        let conn = DatabaseConnection {
+            conn: self.pool.acquire().await.unwrap(),
+        };
+        let (mut conn, dbpi) = self.get_user_hashed_password(conn, user_id.clone()).await;
+        if !UserPasswordInfo::from_password(current_password).check(dbpi?) {
+            return None;
+        }
+        let new_password_hashed = self.hash_password(new_password).await;
+        let res = sqlx::query!(
+            r#"UPDATE user SET password = ? WHERE guid = ?"#,
+            new_password_hashed,
+            user_id
+        )
+        .execute(&mut *conn.conn)
+        .await;
+        if res.is_err() {
+            log::error!("change_user_password error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            return None;
+        }
+        Some(())
+    }
+
+    /// Get the entries of the user data storage of a user, without their data
+    pub async fn get_user_data_items(&self, user_id: &UserId) -> Option<Vec<UserDataItem>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                type as "data_type!: String",
+                length(CAST(data AS BLOB)) as "size!: i64",
+                updated_at as "updated_at!: String"
+            FROM
+                user_data
+            WHERE
+                "user" = ?
+            ORDER BY
+                type
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_data_items error: {:?}", res);
+            return None;
+        }
+        let items = res
+            .unwrap()
+            .into_iter()
+            .map(|row| UserDataItem {
+                data_type: row.data_type,
+                size: row.size as u32,
+                updated_at: row.updated_at,
+            })
+            .collect();
+        Some(items)
+    }
+
+    /// Get an entry of the user data storage of a user
+    ///
+    /// # Returns
+    ///
+    /// Option<UserDataEntry> - None if the entry does not exist
+    pub async fn get_user_data(&self, user_id: &UserId, data_type: &str) -> Option<UserDataEntry> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                type as "data_type!: String",
+                data,
+                updated_at as "updated_at!: String"
+            FROM
+                user_data
+            WHERE
+                "user" = ? AND type = ?
+        "#,
+            user_id,
+            data_type
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_data error: {:?}", res);
+            return None;
+        }
+        res.unwrap().map(|row| UserDataEntry {
+            data_type: row.data_type,
+            data: row.data,
+            updated_at: row.updated_at,
+        })
+    }
+
+    /// Create or replace an entry of the user data storage of a user
+    /// The entry is only replaced if it is still at the version `updated_at`, it is only created if `updated_at` is None
+    ///
+    /// # Arguments
+    ///
+    /// * `updated_at` - the version of the entry read before, None for creating the entry
+    /// * `max_entries` - the maximum number of entries of the user
+    ///
+    /// # Returns
+    ///
+    /// Option<Result<String, UserDataError>> - the new version of the entry or the reason of the rejection, None on error
+    pub async fn put_user_data(
+        &self,
+        user_id: &UserId,
+        data_type: &str,
+        data: &str,
+        updated_at: Option<&str>,
+        max_entries: u32,
+    ) -> Option<Result<String, UserDataError>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let version = user_data_version(updated_at);
+        let res = match updated_at {
+            Some(updated_at) => {
+                sqlx::query!(
+                    r#"UPDATE user_data SET data = ?, updated_at = ? WHERE "user" = ? AND type = ? AND updated_at = ?"#,
+                    data,
+                    version,
+                    user_id,
+                    data_type,
+                    updated_at
+                )
+                .execute(&mut *conn)
+                .await
+            }
+            None => {
+                // the number of entries is checked by the insert itself so that concurrent writes cannot exceed it
+                let max_entries = max_entries as i64;
+                sqlx::query!(
+                    r#"
+                    INSERT INTO user_data ("user", type, data, updated_at)
+                        SELECT ?, ?, ?, ?
+                        WHERE (SELECT COUNT(*) FROM user_data WHERE "user" = ?) < ?
+                    ON CONFLICT DO NOTHING
+                "#,
+                    user_id,
+                    data_type,
+                    data,
+                    version,
+                    user_id,
+                    max_entries
+                )
+                .execute(&mut *conn)
+                .await
+            }
+        };
+        if res.is_err() {
+            log::error!("put_user_data error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            let current = self.get_user_data(user_id, data_type).await;
+            if updated_at.is_none() && current.is_none() {
+                return Some(Err(UserDataError::Invalid(format!(
+                    "a user cannot have more than {} entries",
+                    max_entries
+                ))));
+            }
+            return Some(Err(UserDataError::Conflict(current.map(|entry| entry.updated_at))));
+        }
+        Some(Ok(version))
+    }
+
+    /// Delete an entry of the user data storage of a user
+    ///
+    /// # Arguments
+    ///
+    /// * `updated_at` - the version of the entry read before, None for deleting any version
+    ///
+    /// # Returns
+    ///
+    /// Option<Result<(), UserDataError>> - a conflict with the current version, or without version if the entry does not exist, None on error
+    pub async fn delete_user_data(
+        &self,
+        user_id: &UserId,
+        data_type: &str,
+        updated_at: Option<&str>,
+    ) -> Option<Result<(), UserDataError>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"DELETE FROM user_data WHERE "user" = ? AND type = ? AND (? IS NULL OR updated_at = ?)"#,
+            user_id,
+            data_type,
+            updated_at,
+            updated_at
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("delete_user_data error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            let current = self.get_user_data(user_id, data_type).await;
+            return Some(Err(UserDataError::Conflict(current.map(|entry| entry.updated_at))));
+        }
+        Some(Ok(()))
+    }
+
+    /// Get the maximum size in bytes of an entry and the maximum number of entries of the user data storage of a user
+    /// They are read from the `user_data_max_size` and `user_data_max_entries` settings
+    pub async fn get_user_data_limits(&self) -> (usize, u32) {
+        let max_size = self
+            .get_setting("user_data_max_size")
+            .await
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .unwrap_or(DEFAULT_USER_DATA_MAX_SIZE);
+        let max_entries = self
+            .get_setting("user_data_max_entries")
+            .await
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .unwrap_or(DEFAULT_USER_DATA_MAX_ENTRIES);
+        (max_size, max_entries)
+    }
+
+    /// Create a single use token of a user, the previous tokens of the same kind are revoked
+    ///
+    /// # Arguments
+    ///
+    /// * `token_hash` - the hash of the token, the token itself is never stored
+    /// * `hours` - the validity of the token
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - the expiry date of the token
+    pub async fn create_user_token(
+        &self,
+        user_id: &UserId,
+        kind: UserTokenKind,
+        token_hash: &str,
+        hours: i64,
+    ) -> Option<String> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let expires_at = insert_user_token(&mut tx, user_id, kind, token_hash, hours).await?;
+        tx.commit().await.ok()?;
+        Some(expires_at)
+    }
+
+    /// Create a disabled user and its invitation in a single transaction
+    /// The user gets a random password, it cannot log in before accepting the invitation
+    ///
+    /// # Arguments
+    ///
+    /// * `team` - the team scope, the group must belong to this team
+    /// * `token_hash` - the hash of the invitation token
+    ///
+    /// # Returns
+    ///
+    /// Option<(UserId, String)> - the guid of the user and the expiry date of the invitation,
+    /// None if the name or the email is used or the group does not exist
+    pub async fn create_invited_user(
+        &self,
+        request: &InviteUserRequest,
+        team: Option<&str>,
+        token_hash: &str,
+    ) -> Option<(UserId, String)> {
+        let team_guid = team_scope_guid(team)?;
+        let password_hashed = self.hash_password(Uuid::new_v4().to_string().as_str()).await;
+        let hours = self.get_user_token_validity(UserTokenKind::Invite).await;
+        let user = UserTransfer {
+            name: request.name.clone(),
+            email: Some(request.email.clone()),
+            group: request.group_name.clone(),
+            is_admin: request.is_admin,
+            note: None,
+            password: None,
+            data: Vec::new(),
+        };
+        let mut tx = self.pool.begin().await.ok()?;
+        let user_id = import_user(
+            &mut tx,
+            &user,
+            &password_hashed,
+            &team_guid,
+            None,
+            &mut HashMap::new(),
+            &mut Vec::new(),
+        )
+        .await;
+        if user_id.is_err() {
+            log::error!("create_invited_user error: {:?}", user_id);
+            return None;
+        }
+        let user_id = user_id.unwrap();
+        let res = sqlx::query!(r#"UPDATE user SET status = 0 WHERE guid = ?"#, user_id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("create_invited_user error: {:?}", res);
+            return None;
+        }
+        let expires_at = insert_user_token(&mut tx, &user_id, UserTokenKind::Invite, token_hash, hours).await?;
+        tx.commit().await.ok()?;
+        Some((user_id, expires_at))
+    }
+
+    /// Get the user of a valid token, an invitation or a password reset of an active user
+    ///
+    /// # Returns
+    ///
+    /// Option<UserId> - None if the token is unknown or expired
+    pub async fn get_user_token_user(&self, kind: UserTokenKind, token_hash: &str) -> Option<UserId> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let invite = kind == UserTokenKind::Invite;
+        let kind = kind.as_str();
+        let res = sqlx::query_scalar!(
+            r#"
+            SELECT
+                user.guid
+            FROM
+                user_token
+                JOIN user ON user.guid = user_token.user
+            WHERE
+                user_token.kind = ?
+                AND user_token.token_hash = ?
+                AND user_token.expires_at > datetime('now')
+                AND (? OR user.status = 1)
+        "#,
+            kind,
+            token_hash,
+            invite
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_token_user error: {:?}", res);
+            return None;
+        }
+        res.unwrap()
+    }
+
+    /// Set the password of a user with a valid token and revoke all the tokens of the user
+    /// An invitation also enables the user, a password reset only works for the active users
+    ///
+    /// # Returns
+    ///
+    /// Option<UserId> - the user of the token, None if the token is unknown or expired
+    pub async fn use_user_token(
+        &self,
+        kind: UserTokenKind,
+        token_hash: &str,
+        password_hashed: &str,
+    ) -> Option<UserId> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let invite = kind == UserTokenKind::Invite;
+        let kind = kind.as_str();
+        let res = sqlx::query_scalar!(
+            r#"
+            SELECT
+                user.guid
+            FROM
+                user_token
+                JOIN user ON user.guid = user_token.user
+            WHERE
+                user_token.kind = ?
+                AND user_token.token_hash = ?
+                AND user_token.expires_at > datetime('now')
+                AND (? OR user.status = 1)
+        "#,
+            kind,
+            token_hash,
+            invite
+        )
+        .fetch_optional(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("use_user_token error: {:?}", res);
+            return None;
+        }
+        let user_id = res.unwrap()?;
+        let res = sqlx::query!(
+            r#"
+            UPDATE user SET password = ?, status = CASE WHEN ? THEN 1 ELSE status END WHERE guid = ?;
+            DELETE FROM user_token WHERE "user" = ?;
+        "#,
+            password_hashed,
+            invite,
+            user_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("use_user_token error: {:?}", res);
+            return None;
+        }
+        tx.commit().await.ok()?;
+        Some(user_id)
+    }
+
+    /// Get the name and the email of a user with an invitation, expired or not
+    ///
+    /// # Returns
+    ///
+    /// Option<(String, Option<String>)> - None if the user has no invitation
+    pub async fn get_invited_user(&self, user_id: &UserId) -> Option<(String, Option<String>)> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let kind = UserTokenKind::Invite.as_str();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.name,
+                user.email
+            FROM
+                user_token
+                JOIN user ON user.guid = user_token.user
+            WHERE
+                user_token.user = ?
+                AND user_token.kind = ?
+        "#,
+            user_id,
+            kind
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_invited_user error: {:?}", res);
+            return None;
+        }
+        res.unwrap().map(|row| (row.name, row.email))
+    }
+
+    /// Revoke the tokens of a user
+    ///
+    /// # Returns
+    ///
+    /// Option<u64> - the number of revoked tokens
+    pub async fn delete_user_tokens(&self, user_id: &UserId, kind: UserTokenKind) -> Option<u64> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let kind = kind.as_str();
+        let res = sqlx::query!(
+            r#"DELETE FROM user_token WHERE "user" = ? AND kind = ?"#,
+            user_id,
+            kind
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("delete_user_tokens error: {:?}", res);
+            return None;
+        }
+        Some(res.unwrap().rows_affected())
+    }
+
+    /// Get a page of the invitations, expired or not, the most recent first
+    ///
+    /// # Arguments
+    ///
+    /// * `team` - only get the invitations of the users of this team
+    ///
+    /// # Returns
+    ///
+    /// Option<(u32, Vec<UserInvite>)> - the number of invitations and the page
+    pub async fn get_user_invites(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<UserInvite>)> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let kind = UserTokenKind::Invite.as_str();
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) FROM user_token JOIN user ON user.guid = user_token.user
+            WHERE user_token.kind = ? AND (? IS NULL OR user.team = ?)
+        "#,
+            kind,
+            team_guid,
+            team_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if total.is_err() {
+            log::error!("get_user_invites error: {:?}", total);
+            return None;
+        }
+        let offset = current.saturating_sub(1) * page_size;
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.guid,
+                user.name,
+                user.email,
+                user_token.created_at as "created_at!: String",
+                user_token.expires_at as "expires_at!: String",
+                user_token.expires_at <= datetime('now') as "expired!: bool"
+            FROM
+                user_token
+                JOIN user ON user.guid = user_token.user
+            WHERE
+                user_token.kind = ?
+                AND (? IS NULL OR user.team = ?)
+            ORDER BY
+                user_token.created_at DESC
+            LIMIT ?
+            OFFSET ?
+        "#,
+            kind,
+            team_guid,
+            team_guid,
+            page_size,
+            offset
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_invites error: {:?}", res);
+            return None;
+        }
+        let invites = res
+            .unwrap()
+            .into_iter()
+            .map(|row| UserInvite {
+                user: guid_into_uuid(row.guid).unwrap_or_default(),
+                name: row.name,
+                email: row.email,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                expired: row.expired,
+            })
+            .collect();
+        Some((total.unwrap() as u32, invites))
+    }
+
+    /// Change user status
+    /// if status is 1, the user is active
+    /// if status is 0, the user is inactive
+    pub async fn user_change_status(&self, uuid: &str, status: u32) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let guid = Uuid::parse_str(uuid);
+        if guid.is_err() {
+            log::error!("change_user_status error: {:?}", guid);
+            return None;
+        }
+        let guid = guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            UPDATE
+                user
+            SET
+                status = ?
+            WHERE
+                guid = ?
+                AND status != ?
+        "#,
+            status,
+            guid,
+            USER_STATUS_ARCHIVED
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("change_user_status error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
+    pub async fn get_all_users(
+        &self,
+        name: Option<&str>,
+        email: Option<&str>,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<Vec<UserListResponse>> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let mut email_filter = "%";
+        let mut name_filter = "%";
+        if email.is_some() {
+            email_filter = email.unwrap();
+        }
+        if name.is_some() {
+            name_filter = name.unwrap();
+        }
+        let current = {
+            if current < 1 {
+                1
+            } else {
+                current
+            }
+        };
+        let offset = ((current - 1) * page_size) as i32;
+        let page_size = page_size as i32;
+
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                user.guid as id,
+                user.status as "active!: i32",
+                user.role as "admin!: bool",
+                user.name as username,
+                user.email as email,
+                user.note as note,
+                grp.name as group_name
+            FROM
+                user
+                LEFT JOIN grp
+                    ON user.grp = grp.guid
+            WHERE
+                user.name LIKE ?
+                AND user.email LIKE ?
+                AND (? IS NULL OR user.team = ?)
+                AND user.status != ?
+            LIMIT ?
+            OFFSET ?
+        "#,
+            name_filter,
+            email_filter,
+            team_guid,
+            team_guid,
+            USER_STATUS_ARCHIVED,
+            page_size,
+            offset
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_all_users error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        let mut users: Vec<UserListResponse> = Vec::new();
+        for row in res {
+            let guid_u8: Result<[u8; 16], _> = row.id.try_into();
+            if guid_u8.is_err() {
+                log::error!("get_ab_personal_guid error: {:?}", guid_u8);
+                return None;
+            }
+            let guid_u8: [u8; 16] = guid_u8.unwrap();
+            let guid = Uuid::from_bytes(guid_u8).to_string();
+            let user = UserListResponse {
+                guid: guid,
+                name: row.username,
+                email: row.email.unwrap_or("".to_string()),
+                note: row.note,
+                status: row.active,
+                is_admin: row.admin,
+                group_name: row.group_name.unwrap_or("Defaut".to_string()),
+            };
+            users.push(user);
+        }
+        Some(users)
+    }
+
+    pub async fn user_update(
+        &self,
+        user_id: UserId,
+        user_parameters: UpdateUserRequest,
+    ) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let mut query = "UPDATE user SET ".to_string();
+        let mut query_params = Vec::new();
+        if user_parameters.name.is_some() && user_parameters.name.clone().unwrap().len() > 0 {
+            query.push_str("name = ?, ");
+            query_params.push(user_parameters.name.unwrap());
+        }
+        if user_parameters.email.is_some() && user_parameters.email.clone().unwrap().len() > 0 {
+            query.push_str("email = ?, ");
+            query_params.push(user_parameters.email.unwrap());
+        }
+        if user_parameters.note.is_some() && user_parameters.note.clone().unwrap().len() > 0 {
+            query.push_str("note = ?, ");
+            query_params.push(user_parameters.note.unwrap());
+        }
+        if user_parameters.password.is_some()
+            && user_parameters.confirm_password.is_some()
+            && user_parameters.password.clone().unwrap().len() > 0
+            && user_parameters.confirm_password.clone().unwrap().len() > 0
+        {
+            let password = user_parameters.password.unwrap();
+            let confirm_password = user_parameters.confirm_password.unwrap();
+            if password == confirm_password {
+                let password_hashed = self.hash_password(password.as_str()).await;
+                query.push_str("password = ?, ");
+                query_params.push(password_hashed);
+            }
+        }
+        if let Some(status) = user_parameters.status {
+            query.push_str("status = ?, ");
+            query_params.push(status.to_string());
+        }
+        if let Some(is_admin) = user_parameters.is_admin {
+            query.push_str("role = ?, ");
+            query_params.push(if is_admin { "1" } else { "0" }.to_string());
+        }
+        query.pop();
+        query.pop();
+
+        // an archived user cannot be updated
+        query.push_str(" WHERE guid = ? AND status != ?");
+
+        log::debug!("query: {:?}", query);
+        let mut res = sqlx::query(&query);
+        for param in query_params {
+            res = res.bind(param);
+        }
+        let res = res
+            .bind(user_id)
+            .bind(USER_STATUS_ARCHIVED)
+            .execute(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("user_update error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
+    /// Get the peers of a team
+    /// With a viewer, only the peers owned by the viewer and the peers reachable with the cross group rules of its group are returned
+    ///
+    /// # Arguments
+    ///
+    /// * `team` - only get the peers of the users of this team
+    /// * `viewer` - the user restricting the visible peers, None for every peer
+    pub async fn get_all_peers(
+        &self,
+        team: Option<&str>,
+        viewer: Option<&UserId>,
+        owner: Option<&UserId>,
+    ) -> Option<Vec<Peer>> {
+        let team_guid = team_scope_guid(team)?;
+        let threshold = self.get_peer_online_threshold().await;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                guid,
+                id,
+                uuid,
+                status,
+                info as "info!: String",
+                last_online  as "last_online!: String",
+                (CAST(strftime('%s','now') AS INTEGER) - CAST(strftime('%s', last_online) AS INTEGER)) <= ? as "online!: bool"
+            FROM
+                peer
+            WHERE
+                (? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?))
+                AND (? IS NULL OR peer.user = ? OR peer.user IN (
+                    SELECT user.guid FROM user JOIN cross_grp ON cross_grp.incoming = user.grp
+                    WHERE cross_grp.outgoing = (SELECT grp FROM user WHERE guid = ?)))
+                AND (? IS NULL OR peer.user = ?)
+        "#,
+            threshold,
+            team_guid,
+            team_guid,
+            viewer,
+            viewer,
+            viewer,
+            owner,
+            owner
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .ok()?;
+
+        let mut peers: Vec<Peer> = Vec::new();
+        for row in res {
+            let uuid = guid_into_uuid(row.guid).unwrap_or("".to_string());
+            let peer_info = serde_json::from_str::<utils::PeerInfo>(&row.info);
+            if peer_info.is_err() {
+                log::error!("get_all_peers error: {:?}", peer_info);
+                return None;
+            }
+            let peer_info = peer_info.unwrap();
+            peers.push(Peer {
+                id: row.id,
+                guid: uuid,
+                info: peer_info,
+                last_online: row.last_online.into(),
+                online: row.online,
+                status: row.status as i32,
+                strategy_name: "-".to_string(),
+            });
+        }
+        Some(peers)
+    }
+
+    pub async fn get_groups(
+        &self,
+        offset: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<Vec<Group>> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                guid,
+                team,
+                name,
+                note,
+                created_at as "created_at!: String",
+                info as "info!: String"
+            FROM
+                grp
+            WHERE
+                ? IS NULL OR team = ?
+            LIMIT ?
+            OFFSET ?
+        "#,
+            team_guid,
+            team_guid,
+            page_size,
+            offset
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .ok()?;
+        let cross_groups = get_cross_groups(&mut conn).await?;
+        let mut groups: Vec<Group> = Vec::new();
+        for row in res {
+            let guid = guid_into_uuid(row.guid).unwrap_or("".to_string());
+            let team = guid_into_uuid(row.team).unwrap_or("".to_string());
+            groups.push(Group {
+                access_to: cross_groups_access_to(&cross_groups, &guid),
+                accessed_from: cross_groups_accessed_from(&cross_groups, &guid),
+                guid: guid,
+                name: row.name,
+                team: team,
+                note: row.note,
+                created_at: row.created_at.into(),
+                info: row.info,
+            });
+        }
+        Some(groups)
+    }
+
+    /// Delete a shared address book, all rules associated with it and all peers associated with it
+    /// The revision of the address book is kept and bumped so that the users waiting for its changes
+    /// see the removal
+    ///
+    /// # Arguments
+    ///
+    /// * `guid` - address book uuid in string format
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the address book is not a shared address book
+    pub async fn delete_shared_address_book(&self, guid: &str) -> Option<()> {
+        let ab_guid = Uuid::parse_str(guid);
+        if ab_guid.is_err() {
+            log::error!("delete_ab error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let res = sqlx::query!(r#"DELETE FROM ab WHERE guid = ? AND personal = 0"#, ab_guid)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("delete_ab error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            return None;
+        }
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_peer_revision WHERE ab_peer IN (SELECT guid FROM ab_peer WHERE ab = ?);
+            DELETE FROM ab_peer WHERE ab = ?;
+            DELETE FROM ab_tag WHERE ab = ?;
+            DELETE FROM ab_rule WHERE ab = ?;
+            INSERT INTO ab_revision (ab, revision, updated_at)
+                VALUES (?, (SELECT COALESCE(MAX(revision), 0) + 1 FROM ab_revision), current_timestamp)
+                ON CONFLICT (ab) DO UPDATE SET revision = excluded.revision, updated_at = excluded.updated_at;
+            "#,
+            ab_guid,
+            ab_guid,
+            ab_guid,
+            ab_guid,
+            ab_guid
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_ab error: {:?}", res);
+            return None;
+        }
+        tx.commit().await.ok()?;
+        self.notify_ab_changed();
+        Some(())
+    }
+
+    /// Check if an address book exists and is a shared address book
+    pub async fn is_shared_address_book(&self, ab: &str) -> bool {
+        let ab_guid = match Uuid::parse_str(ab) {
+            Ok(ab_guid) => ab_guid.as_bytes().to_vec(),
+            Err(_) => return false,
+        };
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!: i64" FROM ab WHERE guid = ? AND personal = 0"#,
+            ab_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("is_shared_address_book error: {:?}", res);
+            return false;
+        }
+        res.unwrap() > 0
+    }
+
+    pub async fn get_shared_address_books(&self, user_id: UserId) -> Option<Vec<AddressBook>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                guid as "guid!: Vec<u8>",
+                name as "name!: String",
+                owner as "owner!: Vec<u8>",
+                rule as "rule!: i64"
+            FROM
+                (SELECT
+                    ab.guid,
+                    ab.name,
+                    ab.owner,
+                    CASE WHEN ab.owner = ?1 THEN 3 ELSE COALESCE(
+                        (SELECT MAX(r.rule) FROM ab_rule r
+                        WHERE r.ab = ab.guid
+                            AND (r.user = ?1
+                                OR r.grp IN (SELECT grp FROM user WHERE guid = ?1)
+                                OR (r.user IS NULL AND r.grp IS NULL))),
+                        0) END as rule
+                FROM
+                    ab
+                WHERE
+                    personal = 0)
+            WHERE
+                rule > 0
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .ok()?;
+        let mut address_books: Vec<AddressBook> = Vec::new();
+        for row in res {
+            let access_level = row.rule as u32;
+            address_books.push(AddressBook {
+                ab: guid_into_uuid(row.guid).unwrap_or("".to_string()),
+                name: Some(row.name),
+                owner: Some(row.owner),
+                rule: Some(access_level),
+                ..Default::default()
+            });
+        }
+        Some(address_books)
+    }
+
+    /// Get the personal address book and the shared address books a user can access
+    pub async fn get_user_address_books(&self, user_id: &UserId) -> Option<Vec<UserAddressBook>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                guid as "guid!: Vec<u8>",
+                name as "name!: String",
+                personal as "personal!: bool",
+                owner as "owner!: Vec<u8>",
+                rule as "rule!: i64"
+            FROM
+                (SELECT
+                    ab.guid,
+                    ab.name,
+                    ab.personal,
+                    ab.owner,
+                    CASE WHEN ab.owner = ?1 THEN 3 WHEN ab.personal = 1 THEN 0 ELSE COALESCE(
+                        (SELECT MAX(r.rule) FROM ab_rule r
+                        WHERE r.ab = ab.guid
+                            AND (r.user = ?1
+                                OR r.grp IN (SELECT grp FROM user WHERE guid = ?1)
+                                OR (r.user IS NULL AND r.grp IS NULL))),
+                        0) END as rule
+                FROM
+                    ab)
+            WHERE
+                rule > 0
+            ORDER BY
+                personal DESC, name
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_address_books error: {:?}", res);
+            return None;
+        }
+        let address_books = res
+            .unwrap()
+            .into_iter()
+            .map(|row| UserAddressBook {
+                guid: guid_into_uuid(row.guid).unwrap_or_default(),
+                name: row.name,
+                personal: row.personal,
+                owner: guid_into_uuid(row.owner).unwrap_or_default(),
+                rule: row.rule as u32,
+            })
+            .collect();
+        Some(address_books)
+    }
+
+    /// Get the effective rule of a user on an address book
+    /// The owner has full control, otherwise it is the highest rule given to the user,
+    /// to its group or to everyone (rule without user and group)
+    /// The personal address books are only accessible by their owner, the rules do not apply to them
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - user guid
+    /// * `ab` - address book guid in string format
+    ///
+    /// # Returns
+    ///
+    /// Option<u32> - the rule level, 0 if the user has no access, None if the address book does not exist
+    pub async fn get_ab_effective_rule(&self, user_id: &UserId, ab: &str) -> Option<u32> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_effective_rule error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                CASE WHEN ab.owner = ?1 THEN 3 WHEN ab.personal = 1 THEN 0 ELSE COALESCE(
+                    (SELECT MAX(r.rule) FROM ab_rule r
+                    WHERE r.ab = ab.guid
+                        AND (r.user = ?1
+                            OR r.grp IN (SELECT grp FROM user WHERE guid = ?1)
+                            OR (r.user IS NULL AND r.grp IS NULL))),
+                    0) END as "rule!: i64"
+            FROM
+                ab
+            WHERE
+                ab.guid = ?2
+        "#,
+            user_id,
+            ab_guid
+        )
+        .fetch_optional(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_effective_rule error: {:?}", res);
+            return None;
+        }
+        res.unwrap().map(|row| row.rule as u32)
+    }
+
+    /// Get the address book of a rule
+    ///
+    /// # Arguments
+    ///
+    /// * `rule` - rule guid in string format
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - address book guid
+    pub async fn get_ab_rule_ab(&self, rule: &str) -> Option<String> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let rule_guid = Uuid::parse_str(rule);
+        if rule_guid.is_err() {
+            log::error!("get_ab_rule_ab error: {:?}", rule_guid);
+            return None;
+        }
+        let rule_guid = rule_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(r#"SELECT ab FROM ab_rule WHERE guid = ?"#, rule_guid)
+            .fetch_optional(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("get_ab_rule_ab error: {:?}", res);
+            return None;
+        }
+        guid_into_uuid(res.unwrap()?.ab)
+    }
+
+    /// Get a page of the rules of a shared address book and the number of rules
+    pub async fn get_ab_rules(
+        &self,
+        current: u32,
+        page_size: u32,
+        ab: &str,
+    ) -> Option<(u32, Vec<AbRule>)> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let ab_guid = Uuid::parse_str(ab);
+        if ab_guid.is_err() {
+            log::error!("get_ab_rules error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let current = if current < 1 { 1 } else { current };
+        let offset = (current - 1) as i64 * page_size as i64;
+        let page_size = page_size as i64;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!: u32" FROM ab_rule WHERE ab = ?"#,
+            ab_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if total.is_err() {
+            log::error!("get_ab_rules error: {:?}", total);
+            return None;
+        }
+        let total = total.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                r.guid,
+                u.name as username,
+                g.name as groupname,
+                r.rule
+            FROM
+                ab_rule r
+            LEFT JOIN
+                user u ON u.guid = r.user
+            LEFT JOIN
+                grp g ON g.guid = r.grp
+            WHERE
+                ab = ?
+            ORDER BY
+                r.created_at,
+                r.guid
+            LIMIT ?
+            OFFSET ?
+        "#,
+            ab_guid,
+            page_size,
+            offset
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_ab_rules error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        let mut ab_rules = Vec::new();
+        for row in res {
+            let uuid = guid_into_uuid(row.guid).unwrap_or("".to_string());
+            let user = row.username;
+            let group = row.groupname;
+            let ab_rule = AbRule {
+                user: Some(user),
+                group: Some(group),
+                rule: row.rule as u32,
+                guid: uuid,
+            };
+            ab_rules.push(ab_rule);
+        }
+        Some((total, ab_rules))
+    }
+
+    pub async fn delete_ab_rule(&self, rule: &str) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let rule_guid = Uuid::parse_str(rule);
+        if rule_guid.is_err() {
+            log::error!("delete_ab_rule error: {:?}", rule_guid);
+            return None;
+        }
+        let rule_guid = rule_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM ab_rule WHERE guid = ?
+        "#,
+            rule_guid
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("delete_ab_rule error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
+    /// Add a rule to an address book
+    /// A rule without user and group gives access to everyone, so a user or a group which is
+    /// supplied but is not a valid uuid is an error instead of being ignored
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if a guid is invalid or on error
+    pub async fn add_ab_rule(&self, rule: AbRule) -> Option<()> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let rule_guid = Uuid::new_v4().as_bytes().to_vec();
+        let ab_guid = Uuid::parse_str(&rule.guid);
+        if ab_guid.is_err() {
+            log::error!("add_ab_rule error: {:?}", ab_guid);
+            return None;
+        }
+        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let user_guid = match rule.user.as_deref().map(Uuid::parse_str).transpose() {
+            Ok(uuid) => uuid.map(|uuid| uuid.as_bytes().to_vec()),
+            Err(e) => {
+                log::error!("add_ab_rule error: invalid user {:?}: {:?}", rule.user, e);
+                return None;
+            }
+        };
+        let group_guid = match rule.group.as_deref().map(Uuid::parse_str).transpose() {
+            Ok(uuid) => uuid.map(|uuid| uuid.as_bytes().to_vec()),
+            Err(e) => {
+                log::error!("add_ab_rule error: invalid group {:?}: {:?}", rule.group, e);
+                return None;
+            }
+        };
+
+        let res = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO ab_rule (guid, ab, user, grp, rule)
+                VALUES (?, ?, ?, ?, ?)
+        "#,
+            rule_guid,
+            ab_guid,
+            user_guid,
+            group_guid,
+            rule.rule
+        )
+        .execute(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("add_ab_rule error: {:?}", res);
+            return None;
+        }
+        Some(())
+    }
+
+    pub async fn get_peers_count(
+        &self,
+        platform: Platform,
+        team: Option<&str>,
+        viewer: Option<&UserId>,
+    ) -> u32 {
+        let team_guid = match team_scope_guid(team) {
+            Some(team_guid) => team_guid,
+            None => return 0,
+        };
+        let mut conn = self.pool.acquire().await.unwrap();
+        let filter = match platform {
+            Platform::Windows => "windows%",
+            Platform::Linux => "linux%",
+            Platform::MacOS => "macos%",
+            Platform::Android => "android%",
+            Platform::All => "%",
+            _ => "unknown%",
+        };
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                COUNT(*) as count
+            FROM
+                peer
+            WHERE
+                json_extract(info,'$.os') like ?
+                AND (? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?))
+                AND (? IS NULL OR peer.user = ? OR peer.user IN (
+                    SELECT user.guid FROM user JOIN cross_grp ON cross_grp.incoming = user.grp
+                    WHERE cross_grp.outgoing = (SELECT grp FROM user WHERE guid = ?)))
+        "#,
+            filter,
+            team_guid,
+            team_guid,
+            viewer,
+            viewer,
+            viewer
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .ok();
+        if res.is_none() {
+            return 0;
         }
-        let res = res.bind(user_id).execute(&mut *conn).await;
+        let res = res.unwrap();
+        res.count as u32
+    }
+
+    /// Count the peers grouped by a field
+    /// The memory is grouped in buckets and the online state uses the `peer_online_threshold_secs` setting
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - the field used for grouping the peers
+    /// * `team` - only count the peers of the users of this team
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<PeerStat>> - the number of peers for each value of the field, the most frequent first
+    pub async fn get_peers_stats(
+        &self,
+        field: PeerStatField,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerStat>> {
+        let team_guid = team_scope_guid(team)?;
+        let threshold = self.get_peer_online_threshold().await;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let key = match field {
+            PeerStatField::Os => "trim(json_extract(peer.info,'$.os'))",
+            PeerStatField::Version => "trim(json_extract(peer.info,'$.version'))",
+            PeerStatField::Cpu => "trim(json_extract(peer.info,'$.cpu'))",
+            PeerStatField::Hostname => "trim(json_extract(peer.info,'$.hostname'))",
+            PeerStatField::Username => "trim(json_extract(peer.info,'$.username'))",
+            // memory is reported as "15.54GB" by the client
+            PeerStatField::Memory => {
+                r#"CASE
+                    WHEN json_extract(peer.info,'$.memory') IS NULL THEN NULL
+                    WHEN CAST(json_extract(peer.info,'$.memory') AS REAL) < 4 THEN '< 4GB'
+                    WHEN CAST(json_extract(peer.info,'$.memory') AS REAL) < 8 THEN '4-8GB'
+                    WHEN CAST(json_extract(peer.info,'$.memory') AS REAL) < 16 THEN '8-16GB'
+                    WHEN CAST(json_extract(peer.info,'$.memory') AS REAL) < 32 THEN '16-32GB'
+                    ELSE '>= 32GB'
+                END"#
+            }
+            PeerStatField::Owner => "user.name",
+            PeerStatField::Group => "grp.name",
+            PeerStatField::Strategy => "strategy.name",
+            PeerStatField::Online => {
+                r#"CASE
+                    WHEN (CAST(strftime('%s','now') AS INTEGER) - CAST(strftime('%s', peer.last_online) AS INTEGER)) <= ?1 THEN 'online'
+                    ELSE 'offline'
+                END"#
+            }
+        };
+        let sql = format!(
+            r#"
+            SELECT
+                COALESCE({}, 'unknown') as key,
+                COUNT(*) as total
+            FROM
+                peer
+                LEFT JOIN user ON user.guid = peer.user
+                LEFT JOIN grp ON grp.guid = user.grp
+                LEFT JOIN strategy ON strategy.guid = peer.strategy
+            WHERE
+                ?2 IS NULL OR user.team = ?2
+            GROUP BY
+                key
+            ORDER BY
+                total DESC, key
+        "#,
+            key
+        );
+        // ?1 is only used by the online state but it is always bound for keeping the team as ?2
+        let res = sqlx::query_as::<_, (String, i64)>(&sql)
+            .bind(threshold)
+            .bind(team_guid)
+            .fetch_all(&mut *conn)
+            .await;
         if res.is_err() {
-            log::error!("user_update error: {:?}", res);
+            log::error!("get_peers_stats error: {:?}", res);
             return None;
         }
-        Some(())
+        Some(
+            res.unwrap()
+                .into_iter()
+                .map(|(key, total)| PeerStat {
+                    key,
+                    total: total as u32,
+                })
+                .collect(),
+        )
     }
 
-    pub async fn get_all_peers(&self) -> Option<Vec<Peer>> {
+    /// Find the stale and the duplicate peers according to the peer_cleanup_* settings
+    /// Nothing is modified, the report can be applied with `apply_peer_cleanup`
+    ///
+    /// Peers are duplicates if they share the same id (case insensitive), or the same hostname when
+    /// they are stale or flagged as stale, the most recently seen one is kept.
+    /// Peers unseen for more than `peer_cleanup_stale_days` days are stale.
+    ///
+    /// # Returns
+    ///
+    /// Option<PeerCleanupReport>
+    pub async fn get_peer_cleanup_report(&self) -> Option<PeerCleanupReport> {
+        let stale_days = self
+            .get_setting("peer_cleanup_stale_days")
+            .await
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+        let action = match self.get_setting("peer_cleanup_action").await.as_deref() {
+            Some("delete") => "delete".to_string(),
+            _ => "flag".to_string(),
+        };
+        let merge_duplicates = self
+            .get_setting("peer_cleanup_merge_duplicates")
+            .await
+            .map(|v| v.trim() == "1" || v.trim() == "true")
+            .unwrap_or(false);
+
         let mut conn = self.pool.acquire().await.unwrap();
         let res = sqlx::query!(
             r#"
             SELECT
                 guid,
                 id,
-                uuid,
                 status,
-                info as "info!: String",
-                last_online  as "last_online!: String"
+                trim(json_extract(info, '$.hostname')) as "hostname: String",
+                last_online as "last_online!: String",
+                (julianday('now') - julianday(last_online)) as "age_days!: f64"
             FROM
                 peer
+            ORDER BY
+                last_online DESC
         "#
         )
         .fetch_all(&mut *conn)
-        .await
-        .ok()?;
+        .await;
+        if res.is_err() {
+            log::error!("get_peer_cleanup_report error: {:?}", res);
+            return None;
+        }
+        let peers = res.unwrap();
+        let items: Vec<PeerCleanupItem> = peers
+            .iter()
+            .map(|p| PeerCleanupItem {
+                guid: guid_into_uuid(p.guid.clone()).unwrap_or("".to_string()),
+                id: p.id.clone(),
+                hostname: p.hostname.clone(),
+                last_online: p.last_online.clone(),
+            })
+            .collect();
 
-        let mut peers: Vec<Peer> = Vec::new();
-        for row in res {
-            let uuid = guid_into_uuid(row.guid).unwrap_or("".to_string());
-            let peer_info = serde_json::from_str::<utils::PeerInfo>(&row.info);
-            if peer_info.is_err() {
-                log::error!("get_all_peers error: {:?}", peer_info);
+        // group the peers sharing an id or a hostname, peers are sorted by last_online
+        // so the first peer of each group is the one to keep
+        // hostnames such as localhost are shared by distinct devices, so a peer is only a duplicate by
+        // hostname when it is stale, and the most recently seen peer of the hostname is kept
+        let is_stale = |status: i64, age_days: f64| {
+            status == PEER_STATUS_STALE || (stale_days > 0 && age_days > stale_days as f64)
+        };
+        let mut group_of: Vec<usize> = (0..peers.len()).collect();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for (i, peer) in peers.iter().enumerate() {
+            let mut keys = vec![format!("id:{}", peer.id.to_lowercase())];
+            if let Some(hostname) = peer.hostname.as_ref().filter(|h| !h.is_empty()) {
+                let key = format!("hostname:{}", hostname.to_lowercase());
+                if !seen.contains_key(&key) || is_stale(peer.status, peer.age_days) {
+                    keys.push(key);
+                }
+            }
+            for key in keys {
+                match seen.get(&key) {
+                    Some(first) => {
+                        let (a, b) = (group_of[*first], group_of[i]);
+                        let (keep, merge) = (a.min(b), a.max(b));
+                        group_of.iter_mut().filter(|g| **g == merge).for_each(|g| *g = keep);
+                    }
+                    None => {
+                        seen.insert(key, i);
+                    }
+                }
+            }
+        }
+        let mut duplicates: Vec<PeerDuplicateGroup> = Vec::new();
+        let mut is_duplicate = vec![false; peers.len()];
+        if merge_duplicates {
+            for keep in 0..peers.len() {
+                let members: Vec<usize> = (0..peers.len())
+                    .filter(|i| *i != keep && group_of[*i] == keep)
+                    .collect();
+                if members.is_empty() {
+                    continue;
+                }
+                let mut ab_references = 0;
+                for i in members.iter() {
+                    is_duplicate[*i] = true;
+                    let res = sqlx::query!(
+                        r#"SELECT COUNT(*) as "count!: i64" FROM ab_peer WHERE peer = ? OR id = ?"#,
+                        peers[*i].guid,
+                        peers[*i].id
+                    )
+                    .fetch_one(&mut *conn)
+                    .await
+                    .ok()?;
+                    ab_references += res.count as u32;
+                }
+                duplicates.push(PeerDuplicateGroup {
+                    keep: items[keep].clone(),
+                    duplicates: members.iter().map(|i| items[*i].clone()).collect(),
+                    ab_references,
+                });
+            }
+        }
+
+        let stale: Vec<PeerCleanupItem> = if stale_days > 0 {
+            peers
+                .iter()
+                .enumerate()
+                .filter(|(i, p)| {
+                    !is_duplicate[*i]
+                        && p.age_days > stale_days as f64
+                        && (action == "delete" || p.status != PEER_STATUS_STALE)
+                })
+                .map(|(i, _)| items[i].clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Some(PeerCleanupReport {
+            dry_run: true,
+            stale_days,
+            action,
+            merge_duplicates,
+            stale,
+            duplicates,
+        })
+    }
+
+    /// Apply a cleanup report in a single transaction
+    /// Address book entries of the duplicates are moved to the kept peer (or dropped if the
+    /// address book already contains it), then duplicates and stale peers are flagged
+    /// (status = 2) or deleted according to the report action.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - report built by `get_peer_cleanup_report`
+    ///
+    /// # Returns
+    ///
+    /// Option<()>
+    pub async fn apply_peer_cleanup(&self, report: &PeerCleanupReport) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let parse = |guid: &str| -> Option<Vec<u8>> {
+            let guid = Uuid::parse_str(guid);
+            if guid.is_err() {
+                log::error!("apply_peer_cleanup error: {:?}", guid);
                 return None;
             }
-            let peer_info = peer_info.unwrap();
-            peers.push(Peer {
-                id: row.id,
-                guid: uuid,
-                info: peer_info,
-                last_online: row.last_online.into(),
-                status: row.status as i32,
-                strategy_name: "-".to_string(),
-            });
+            Some(guid.unwrap().as_bytes().to_vec())
+        };
+        let mut to_remove: Vec<Vec<u8>> = Vec::new();
+        for group in report.duplicates.iter() {
+            let keep_guid = parse(&group.keep.guid)?;
+            for duplicate in group.duplicates.iter() {
+                let duplicate_guid = parse(&duplicate.guid)?;
+                let res = sqlx::query!(
+                    r#"
+                    UPDATE ab_peer SET peer = ?1, id = ?2
+                    WHERE
+                        (peer = ?3 OR id = ?4)
+                        AND ab NOT IN (SELECT ab FROM ab_peer WHERE peer = ?1 OR id = ?2)
+                "#,
+                    keep_guid,
+                    group.keep.id,
+                    duplicate_guid,
+                    duplicate.id
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("apply_peer_cleanup error: {:?}", res);
+                    return None;
+                }
+                // the address book already contains the kept peer
+                let res = sqlx::query!(
+                    r#"DELETE FROM ab_peer WHERE peer = ? OR (id = ? AND id <> ?)"#,
+                    duplicate_guid,
+                    duplicate.id,
+                    group.keep.id
+                )
+                .execute(&mut *tx)
+                .await;
+                if res.is_err() {
+                    log::error!("apply_peer_cleanup error: {:?}", res);
+                    return None;
+                }
+                to_remove.push(duplicate_guid);
+            }
         }
-        Some(peers)
+        for stale in report.stale.iter() {
+            to_remove.push(parse(&stale.guid)?);
+        }
+        for guid in to_remove {
+            let res = if report.action == "delete" {
+                sqlx::query!(
+                    r#"
+                    UPDATE ab_peer SET peer = NULL WHERE peer = ?;
+                    DELETE FROM peer_presence WHERE peer = ?;
+                    DELETE FROM peer_sysinfo WHERE peer = ?;
+                    DELETE FROM peer WHERE guid = ?;
+                "#,
+                    guid,
+                    guid,
+                    guid,
+                    guid
+                )
+                .execute(&mut *tx)
+                .await
+            } else {
+                sqlx::query!(
+                    r#"UPDATE peer SET status = ? WHERE guid = ?"#,
+                    PEER_STATUS_STALE,
+                    guid
+                )
+                .execute(&mut *tx)
+                .await
+            };
+            if res.is_err() {
+                log::error!("apply_peer_cleanup error: {:?}", res);
+                return None;
+            }
+        }
+        tx.commit().await.ok()?;
+        Some(())
+    }
+
+    pub async fn get_cpus_count(&self, team: Option<&str>, viewer: Option<&UserId>) -> Vec<CpuCount> {
+        let team_guid = match team_scope_guid(team) {
+            Some(team_guid) => team_guid,
+            None => return Vec::new(),
+        };
+        let mut conn = self.pool.acquire().await.unwrap();
+        // for avoiding compiltion error you can use the following query it is included in the build.rs
+        // INSERT OR IGNORE INTO peer (guid, id, uuid, pk, created_at, "user", status, note, region, strategy, info, last_online) VALUES
+        //     (x'95CC7775BA37481DAD7214A4F6CE5A94', 'TESTUSER', randomblob(16), randomblob(16), '1901-01-01 12:00:00', randomblob(16), 0, '', NULL, randomblob(16), '{}', '1901-01-01 12:00:00');
+        // Note: that the build.rs will not remove the peer from the database
+        // for removing the peer from the database you can use the following query
+        // DELETE FROM peer WHERE guid = x'95CC7775BA37481DAD7214A4F6CE5A94';
+        let res = sqlx::query!(
+            r#"
+            SELECT 
+                COALESCE(trim(json_extract(info,'$.cpu')),"unknown") as cpu, 
+                COUNT(*) AS machine_count
+            FROM 
+                peer 
+            WHERE
+                (? IS NULL OR peer.user IN (SELECT guid FROM user WHERE team = ?))
+                AND (? IS NULL OR peer.user = ? OR peer.user IN (
+                    SELECT user.guid FROM user JOIN cross_grp ON cross_grp.incoming = user.grp
+                    WHERE cross_grp.outgoing = (SELECT grp FROM user WHERE guid = ?)))
+            GROUP BY 
+                cpu;
+        "#,
+            team_guid,
+            team_guid,
+            viewer,
+            viewer,
+            viewer
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .ok();
+        if res.is_none() {
+            return Vec::new();
+        }
+        let res = res.unwrap();
+        let mut cpu_counts = Vec::new();
+        for row in res {
+            let cpu = row.cpu.unwrap_or_else(|| "unknown".to_string());
+            let total = row.machine_count.unwrap_or(0) as u32;
+            cpu_counts.push(CpuCount { cpu, total });
+        }
+        cpu_counts
     }
 
-    pub async fn get_groups(&self, offset: u32, page_size: u32) -> Option<Vec<Group>> {
+    /// Get a group given its guid
+    ///
+    /// # Arguments
+    ///
+    /// * `guid` - group guid in string format
+    ///
+    /// # Returns
+    ///
+    /// Option<Group>
+    pub async fn get_group(&self, guid: &str) -> Option<Group> {
         let mut conn = self.pool.acquire().await.unwrap();
+        let group_guid = Uuid::parse_str(guid);
+        if group_guid.is_err() {
+            log::error!("get_group error: {:?}", group_guid);
+            return None;
+        }
+        let group_guid = group_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
             SELECT
@@ -1301,457 +5714,860 @@ impl Database {
                 info as "info!: String"
             FROM
                 grp
-            LIMIT ?
-            OFFSET ?
+            WHERE
+                guid = ?
         "#,
-            page_size,
-            offset
+            group_guid
         )
-        .fetch_all(&mut *conn)
-        .await
-        .ok()?;
-        let mut groups: Vec<Group> = Vec::new();
-        for row in res {
-            let guid = guid_into_uuid(row.guid).unwrap_or("".to_string());
-            let team = guid_into_uuid(row.team).unwrap_or("".to_string());
-            groups.push(Group {
-                guid: guid,
-                name: row.name,
-                team: team,
-                note: row.note,
-                created_at: row.created_at.into(),
-                access_to: Vec::<String>::new(),
-                accessed_from: Vec::<String>::new(),
-                info: row.info,
-            });
+        .fetch_one(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_group error: {:?}", res);
+            return None;
+        }
+        let res = res.unwrap();
+        let cross_groups = get_cross_groups(&mut conn).await?;
+        let guid = guid_into_uuid(res.guid).unwrap_or("".to_string());
+        let team = guid_into_uuid(res.team).unwrap_or("".to_string());
+        Some(Group {
+            access_to: cross_groups_access_to(&cross_groups, &guid),
+            accessed_from: cross_groups_accessed_from(&cross_groups, &guid),
+            guid: guid,
+            name: res.name,
+            team: team,
+            note: res.note,
+            created_at: res.created_at.into(),
+            info: res.info,
+        })
+    }
+
+    /// Create a new group and its cross group access in a single transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - group name
+    /// * `team` - team guid in string format ( must exist in the database )
+    /// * `note` - group note
+    /// * `allowed_incomings` - guids of the groups allowed to connect to the devices of this group
+    /// * `allowed_outgoings` - guids of the groups whose devices this group is allowed to connect to
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - the guid of the group, None if the name exists, the team does not exist or a group guid is invalid
+    pub async fn create_group(
+        &self,
+        name: &str,
+        team: &str,
+        note: &str,
+        allowed_incomings: &[String],
+        allowed_outgoings: &[String],
+    ) -> Option<String> {
+        let team_guid = Uuid::parse_str(team);
+        if team_guid.is_err() {
+            log::error!("create_group error: {:?}", team_guid);
+            return None;
+        }
+        let team_guid = team_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let group_guid = Uuid::new_v4().as_bytes().to_vec();
+
+        let res = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO grp(guid, team, name, note, created_at, info)
+                SELECT ?, guid, ?, ?, current_timestamp, '{}' FROM team WHERE guid = ?
+        "#,
+            group_guid,
+            name,
+            note,
+            team_guid
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("create_group error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("create_group error: group {} already exists or team {} not found", name, team);
+            return None;
+        }
+        set_cross_groups(&mut tx, &group_guid, allowed_incomings, allowed_outgoings).await?;
+        tx.commit().await.ok()?;
+        guid_into_uuid(group_guid)
+    }
+
+    /// Update a group and replace its cross group access in a single transaction
+    /// The team of the group is not modified
+    ///
+    /// # Arguments
+    ///
+    /// * `guid` - uuid of the group in string format
+    /// * `name` - group name
+    /// * `note` - group note
+    /// * `allowed_incomings` - guids of the groups allowed to connect to the devices of this group
+    /// * `allowed_outgoings` - guids of the groups whose devices this group is allowed to connect to
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the group does not exist or a group guid is invalid
+    pub async fn update_group(
+        &self,
+        guid: &str,
+        name: &str,
+        note: &str,
+        allowed_incomings: &[String],
+        allowed_outgoings: &[String],
+    ) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let group_guid = Uuid::parse_str(guid);
+        if group_guid.is_err() {
+            log::error!("update_group error: {:?}", group_guid);
+            return None;
+        }
+        let group_guid = group_guid.unwrap().as_bytes().to_vec();
+
+        let res = sqlx::query!(
+            r#"
+            UPDATE grp SET name = ?, note = ?, created_at = current_timestamp
+                WHERE guid = ?
+        "#,
+            name,
+            note,
+            group_guid
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("update_group error: {:?}", res);
+            return None;
         }
-        Some(groups)
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("update_group error: group {} not found", guid);
+            return None;
+        }
+        set_cross_groups(&mut tx, &group_guid, allowed_incomings, allowed_outgoings).await?;
+        tx.commit().await.ok()?;
+        Some(())
     }
 
-    /// Delete a shared address book, all rules associated with it and all peers associated with it
+    /// Delete a group
     ///
     /// # Arguments
     ///
-    /// * `guid` - address book uuid in string format
+    /// * `guid` - uuid of the group in string format
     ///
     /// # Returns
     ///
     /// Option<()>
-    pub async fn delete_shared_address_book(&self, guid: &str) -> Option<()> {
+    pub async fn delete_group(&self, guid: &str) -> Option<()> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(guid);
-        if ab_guid.is_err() {
-            log::error!("delete_ab error: {:?}", ab_guid);
+        let group_guid = Uuid::parse_str(guid);
+        if group_guid.is_err() {
+            log::error!("delete_group error: {:?}", group_guid);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
+        let group_guid = group_guid.unwrap().as_bytes().to_vec();
+
         let res = sqlx::query!(
             r#"
-            BEGIN;
-            DELETE FROM ab_peer WHERE ab = ?;
-            DELETE FROM ab_rule WHERE ab = ?;
-            DELETE FROM ab WHERE guid = ?;
-            COMMIT;
-            "#,
-            ab_guid,ab_guid,ab_guid
+            DELETE FROM cross_grp WHERE incoming = ? OR outgoing = ?;
+            DELETE FROM grp WHERE guid = ?
+        "#,
+            group_guid,
+            group_guid,
+            group_guid
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("delete_ab error: {:?}", res);
+            log::error!("delete_group error: {:?}", res);
             return None;
         }
         Some(())
     }
 
-    pub async fn get_shared_address_books(&self, user_id: UserId) -> Option<Vec<AddressBook>> {
+    /// Get the team scope of a user
+    /// The members of the `Default` team are not restricted to a team
+    ///
+    /// # Returns
+    ///
+    /// Option<Option<String>> - None if the user does not exist, None for the `Default` team or the team guid otherwise
+    pub async fn get_user_team_scope(&self, user_id: &UserId) -> Option<Option<String>> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let res = sqlx::query!(
-            r#"
-            SELECT
-                ab.guid,
-                ab.name,
-                ab.owner,
-                COALESCE(MaxRule.rule, 0) as rule
-            FROM
-                ab
-            JOIN
-                (SELECT ab, MAX(rule) as rule
-                FROM ab_rule
-                WHERE user = ? OR grp IN (SELECT grp FROM user WHERE guid = ?)
-                GROUP BY ab) as MaxRule
-            ON ab.guid = MaxRule.ab
-            WHERE
-                personal = 0
-        "#,
-            user_id,
-            user_id
-        )
-        .fetch_all(&mut *conn)
-        .await
-        .ok()?;
-        let mut address_books: Vec<AddressBook> = Vec::new();
-        for row in res {
-            let access_level = row.rule as u32;
-            address_books.push(AddressBook {
-                ab: guid_into_uuid(row.guid).unwrap_or("".to_string()),
-                name: Some(row.name),
-                owner: Some(row.owner),
-                rule: Some(access_level),
-                ..Default::default()
-            });
+        let res = sqlx::query!(r#"SELECT team FROM user WHERE guid = ?"#, user_id)
+            .fetch_optional(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("get_user_team_scope error: {:?}", res);
+            return None;
         }
-        Some(address_books)
+        let team = guid_into_uuid(res.unwrap()?.team)?;
+        if team == DEFAULT_TEAM_GUID {
+            return Some(None);
+        }
+        Some(Some(team))
     }
 
-    pub async fn get_ab_rules(&self, offset: u32, page_size: u32, ab: &str) -> Option<Vec<AbRule>> {
+    /// Get the team of a user, a group, a peer or an address book
+    ///
+    /// # Returns
+    ///
+    /// Option<Option<String>> - None if the item does not exist, None for a peer without owner or the team guid otherwise
+    pub async fn get_team_of(&self, member: TeamMember<'_>) -> Option<Option<String>> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let ab_guid = Uuid::parse_str(ab);
-        if ab_guid.is_err() {
-            log::error!("get_ab_rules error: {:?}", ab_guid);
+        let guid = match member {
+            TeamMember::User(guid) | TeamMember::Group(guid) | TeamMember::AddressBook(guid) => {
+                let guid = Uuid::parse_str(guid);
+                if guid.is_err() {
+                    log::error!("get_team_of error: {:?}", guid);
+                    return None;
+                }
+                guid.unwrap().as_bytes().to_vec()
+            }
+            TeamMember::Peer(_) => Vec::new(),
+        };
+        let res = match member {
+            TeamMember::User(_) => {
+                sqlx::query_scalar!(r#"SELECT team as "team?: Vec<u8>" FROM user WHERE guid = ?"#, guid)
+                    .fetch_optional(&mut *conn)
+                    .await
+            }
+            TeamMember::Group(_) => {
+                sqlx::query_scalar!(r#"SELECT team as "team?: Vec<u8>" FROM grp WHERE guid = ?"#, guid)
+                    .fetch_optional(&mut *conn)
+                    .await
+            }
+            TeamMember::Peer(id) => {
+                sqlx::query_scalar!(
+                    r#"
+                    SELECT user.team as "team?: Vec<u8>"
+                    FROM peer LEFT JOIN user ON user.guid = peer.user
+                    WHERE peer.id = ?
+                "#,
+                    id
+                )
+                .fetch_optional(&mut *conn)
+                .await
+            }
+            TeamMember::AddressBook(_) => {
+                sqlx::query_scalar!(
+                    r#"
+                    SELECT user.team as "team?: Vec<u8>"
+                    FROM ab LEFT JOIN user ON user.guid = ab.owner
+                    WHERE ab.guid = ?
+                "#,
+                    guid
+                )
+                .fetch_optional(&mut *conn)
+                .await
+            }
+        };
+        if res.is_err() {
+            log::error!("get_team_of error: {:?}", res);
+            return None;
+        }
+        Some(res.unwrap()?.and_then(guid_into_uuid))
+    }
+
+    /// Get a page of the teams and the number of teams
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - the page number, starting at 1
+    /// * `page_size` - the number of teams per page
+    /// * `team` - only return this team
+    ///
+    /// # Returns
+    ///
+    /// Option<(u32, Vec<Team>)>
+    pub async fn get_teams(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<Team>)> {
+        let team_guid = team_scope_guid(team)?;
+        let mut conn = self.pool.acquire().await.unwrap();
+        let current = if current < 1 { 1 } else { current };
+        let offset = (current - 1) as i64 * page_size as i64;
+        let page_size = page_size as i64;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!: u32" FROM team WHERE ? IS NULL OR guid = ?"#,
+            team_guid,
+            team_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if total.is_err() {
+            log::error!("get_teams error: {:?}", total);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
             SELECT
-                r.guid,
-                u.name as username,
-                g.name as groupname,
-                r.rule
+                guid,
+                name,
+                email,
+                note,
+                created_at as "created_at!: String",
+                updated_at as "updated_at!: String",
+                (SELECT COUNT(*) FROM user WHERE user.team = team.guid) as "users!: u32",
+                (SELECT COUNT(*) FROM grp WHERE grp.team = team.guid) as "groups!: u32"
             FROM
-                ab_rule r
-            LEFT JOIN
-                user u ON u.guid = r.user
-            LEFT JOIN
-                grp g ON g.guid = r.grp
+                team
             WHERE
-                ab = ?
+                ? IS NULL OR guid = ?
+            ORDER BY
+                name
+            LIMIT ?
+            OFFSET ?
         "#,
-            ab_guid
+            team_guid,
+            team_guid,
+            page_size,
+            offset
         )
         .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_ab_rules error: {:?}", res);
+            log::error!("get_teams error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        let mut ab_rules = Vec::new();
-        for row in res {
-            let uuid = guid_into_uuid(row.guid).unwrap_or("".to_string());
-            let user = row.username;
-            let group = row.groupname;
-            let ab_rule = AbRule {
-                user: Some(user),
-                group: Some(group),
-                rule: row.rule as u32,
-                guid: uuid,
-            };
-            ab_rules.push(ab_rule);
-        }
-        Some(ab_rules)
+        let teams = res
+            .unwrap()
+            .into_iter()
+            .map(|row| Team {
+                guid: guid_into_uuid(row.guid).unwrap_or("".to_string()),
+                name: row.name,
+                email: row.email,
+                note: row.note,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+                users: row.users,
+                groups: row.groups,
+            })
+            .collect();
+        Some((total.unwrap(), teams))
     }
 
-    pub async fn delete_ab_rule(&self, rule: &str) -> Option<()> {
+    /// Create a team
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - the guid of the team, None if the name exists
+    pub async fn create_team(&self, name: &str, email: &str, note: Option<&str>) -> Option<String> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let rule_guid = Uuid::parse_str(rule);
-        if rule_guid.is_err() {
-            log::error!("delete_ab_rule error: {:?}", rule_guid);
-            return None;
-        }
-        let rule_guid = rule_guid.unwrap().as_bytes().to_vec();
+        let team_guid = Uuid::new_v4().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
-            DELETE FROM ab_rule WHERE guid = ?
+            INSERT OR IGNORE INTO team(guid, name, email, note, info, created_at, updated_at)
+                VALUES (?, ?, ?, ?, '{}', current_timestamp, current_timestamp)
         "#,
-            rule_guid
+            team_guid,
+            name,
+            email,
+            note
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("delete_ab_rule error: {:?}", res);
+            log::error!("create_team error: {:?}", res);
             return None;
         }
-        Some(())
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("create_team error: team {} already exists", name);
+            return None;
+        }
+        guid_into_uuid(team_guid)
     }
 
-    pub async fn add_ab_rule(&self, rule: AbRule) -> Option<()> {
+    /// Update a team
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the team does not exist or if the name is used by another team
+    pub async fn update_team(
+        &self,
+        guid: &str,
+        name: &str,
+        email: &str,
+        note: Option<&str>,
+    ) -> Option<()> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let rule_guid = Uuid::new_v4().as_bytes().to_vec();
-        let ab_guid = Uuid::parse_str(&rule.guid);
-        if ab_guid.is_err() {
-            log::error!("add_ab_rule error: {:?}", ab_guid);
+        let team_guid = Uuid::parse_str(guid);
+        if team_guid.is_err() {
+            log::error!("update_team error: {:?}", team_guid);
             return None;
         }
-        let ab_guid = ab_guid.unwrap().as_bytes().to_vec();
-        let user_guid = if rule.user.is_some() {
-            let uuid = Uuid::parse_str(&rule.user.unwrap());
-            if uuid.is_err() {
-                None
-            } else {
-                Some(uuid.unwrap().as_bytes().to_vec())
-            }
-        } else {
-            None
-        };
-
-        let group_guid = if rule.group.is_some() {
-            let uuid = Uuid::parse_str(&rule.group.unwrap());
-            if uuid.is_err() {
-                None
-            } else {
-                Some(uuid.unwrap().as_bytes().to_vec())
-            }
-        } else {
-            None
-        };
-
+        let team_guid = team_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
-            INSERT OR IGNORE INTO ab_rule (guid, ab, user, grp, rule)
-                VALUES (?, ?, ?, ?, ?)
+            UPDATE OR IGNORE team SET name = ?, email = ?, note = ?, updated_at = current_timestamp
+                WHERE guid = ?
         "#,
-            rule_guid,
-            ab_guid,
-            user_guid,
-            group_guid,
-            rule.rule
+            name,
+            email,
+            note,
+            team_guid
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("add_ab_rule error: {:?}", res);
+            log::error!("update_team error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("update_team error: team {} not found or name {} in use", guid, name);
             return None;
         }
         Some(())
     }
 
-    pub async fn get_peers_count(&self, platform: Platform) -> u32 {
-        let mut conn = self.pool.acquire().await.unwrap();
-        let filter = match platform {
-            Platform::Windows => "windows%",
-            Platform::Linux => "linux%",
-            Platform::MacOS => "macos%",
-            Platform::Android => "android%",
-            Platform::All => "%",
-            _ => "unknown%",
-        };
-        let res = sqlx::query!(
+    /// Delete a team and its strategies
+    /// The `Default` team and the teams which still have users or groups cannot be deleted
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the team does not exist or cannot be deleted
+    pub async fn delete_team(&self, guid: &str) -> Option<()> {
+        let team_guid = Uuid::parse_str(guid);
+        if team_guid.is_err() {
+            log::error!("delete_team error: {:?}", team_guid);
+            return None;
+        }
+        let team_guid = team_guid.unwrap();
+        if team_guid.to_string() == DEFAULT_TEAM_GUID {
+            log::error!("delete_team error: the Default team cannot be deleted");
+            return None;
+        }
+        let team_guid = team_guid.as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let in_use = sqlx::query_scalar!(
             r#"
             SELECT
-                COUNT(*) as count
-            FROM
-                peer
-            WHERE
-                json_extract(info,'$.os') like ?
+                EXISTS (SELECT 1 FROM user WHERE team = ?)
+                OR EXISTS (SELECT 1 FROM grp WHERE team = ?) as "in_use!: bool"
         "#,
-            filter
+            team_guid,
+            team_guid
         )
-        .fetch_one(&mut *conn)
-        .await
-        .ok();
-        if res.is_none() {
-            return 0;
+        .fetch_one(&mut *tx)
+        .await;
+        if in_use.is_err() {
+            log::error!("delete_team error: {:?}", in_use);
+            return None;
+        }
+        if in_use.unwrap() {
+            log::error!("delete_team error: team {} still has users or groups", guid);
+            return None;
         }
-        let res = res.unwrap();
-        res.count as u32
-    }
-
-    pub async fn get_cpus_count(&self) -> Vec<CpuCount> {
-        let mut conn = self.pool.acquire().await.unwrap();
-        // for avoiding compiltion error you can use the following query it is included in the build.rs
-        // INSERT OR IGNORE INTO peer (guid, id, uuid, pk, created_at, "user", status, note, region, strategy, info, last_online) VALUES
-        //     (x'95CC7775BA37481DAD7214A4F6CE5A94', 'TESTUSER', randomblob(16), randomblob(16), '1901-01-01 12:00:00', randomblob(16), 0, '', NULL, randomblob(16), '{}', '1901-01-01 12:00:00');
-        // Note: that the build.rs will not remove the peer from the database
-        // for removing the peer from the database you can use the following query
-        // DELETE FROM peer WHERE guid = x'95CC7775BA37481DAD7214A4F6CE5A94';
         let res = sqlx::query!(
             r#"
-            SELECT 
-                COALESCE(trim(json_extract(info,'$.cpu')),"unknown") as cpu, 
-                COUNT(*) AS machine_count
-            FROM 
-                peer 
-            GROUP BY 
-                cpu;
-        "#
+            DELETE FROM strategy WHERE team = ?;
+            DELETE FROM team WHERE guid = ?
+        "#,
+            team_guid,
+            team_guid
         )
-        .fetch_all(&mut *conn)
-        .await
-        .ok();
-        if res.is_none() {
-            return Vec::new();
-        }
-        let res = res.unwrap();
-        let mut cpu_counts = Vec::new();
-        for row in res {
-            let cpu = row.cpu.unwrap_or_else(|| "unknown".to_string());
-            let total = row.machine_count.unwrap_or(0) as u32;
-            cpu_counts.push(CpuCount { cpu, total });
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_team error: {:?}", res);
+            return None;
         }
-        cpu_counts
+        if res.unwrap().rows_affected() == 0 {
+            return None;
+        }
+        tx.commit().await.ok()?;
+        Some(())
     }
 
-    /// Get a group given its guid
-    ///
-    /// # Arguments
-    ///
-    /// * `guid` - group guid in string format
+    /// Get a page of the roles and the number of roles
     ///
     /// # Returns
     ///
-    /// Option<Group>
-    pub async fn get_group(&self, guid: &str) -> Option<Group> {
+    /// Option<(u32, Vec<Role>)>
+    pub async fn get_roles(&self, current: u32, page_size: u32) -> Option<(u32, Vec<Role>)> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let group_guid = Uuid::parse_str(guid);
-        if group_guid.is_err() {
-            log::error!("get_group error: {:?}", group_guid);
+        let current = if current < 1 { 1 } else { current };
+        let offset = (current - 1) as i64 * page_size as i64;
+        let page_size = page_size as i64;
+        let total = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!: u32" FROM role"#)
+            .fetch_one(&mut *conn)
+            .await;
+        if total.is_err() {
+            log::error!("get_roles error: {:?}", total);
             return None;
         }
-        let group_guid = group_guid.unwrap().as_bytes().to_vec();
         let res = sqlx::query!(
             r#"
             SELECT
                 guid,
-                team,
                 name,
                 note,
+                permissions as "permissions!: String",
                 created_at as "created_at!: String",
-                info as "info!: String"
+                updated_at as "updated_at!: String",
+                (SELECT COUNT(*) FROM user_role WHERE user_role.role = role.guid) as "users!: u32"
             FROM
-                grp
-            WHERE
-                guid = ?
+                role
+            ORDER BY
+                name
+            LIMIT ?
+            OFFSET ?
         "#,
-            group_guid
+            page_size,
+            offset
         )
-        .fetch_one(&mut *conn)
+        .fetch_all(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("get_group error: {:?}", res);
+            log::error!("get_roles error: {:?}", res);
             return None;
         }
-        let res = res.unwrap();
-        let guid = guid_into_uuid(res.guid).unwrap_or("".to_string());
-        let team = guid_into_uuid(res.team).unwrap_or("".to_string());
-        Some(Group {
-            guid: guid,
-            name: res.name,
-            team: team,
-            note: res.note,
-            created_at: res.created_at.into(),
-            access_to: Vec::<String>::new(),
-            accessed_from: Vec::<String>::new(),
-            info: res.info,
-        })
+        let roles = res
+            .unwrap()
+            .into_iter()
+            .map(|row| Role {
+                guid: guid_into_uuid(row.guid).unwrap_or("".to_string()),
+                name: row.name,
+                note: row.note,
+                permissions: parse_permissions(&row.permissions),
+                users: row.users,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+            })
+            .collect();
+        Some((total.unwrap(), roles))
     }
 
-    /// Create a new group
-    ///
-    /// # Arguments
-    ///
-    /// * `name` - group name
-    /// * `team` - team name ( must exist in the database )
-    /// * `note` - group note
+    /// Create a role
     ///
     /// # Returns
     ///
-    /// Option<()>
-    pub async fn create_group(&self, name: &str, team: &str, note: &str) -> Option<()> {
+    /// Option<String> - the guid of the role, None if the name exists
+    pub async fn create_role(
+        &self,
+        name: &str,
+        note: Option<&str>,
+        permissions: &[Permission],
+    ) -> Option<String> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let group_guid = Uuid::new_v4().as_bytes().to_vec();
-
+        let role_guid = Uuid::new_v4().as_bytes().to_vec();
+        let permissions = serde_json::to_string(permissions).ok()?;
         let res = sqlx::query!(
             r#"
-            INSERT OR IGNORE INTO grp(guid, team, name, note, created_at, info)
-                VALUES (?, (SELECT guid FROM team  WHERE name = ?) , ?, ?, current_timestamp, '{}')
+            INSERT OR IGNORE INTO role(guid, name, note, permissions, created_at, updated_at)
+                VALUES (?, ?, ?, ?, current_timestamp, current_timestamp)
         "#,
-            group_guid,
-            team,
+            role_guid,
             name,
-            note
+            note,
+            permissions
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("create_group error: {:?}", res);
+            log::error!("create_role error: {:?}", res);
             return None;
         }
-        Some(())
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("create_role error: role {} already exists", name);
+            return None;
+        }
+        guid_into_uuid(role_guid)
     }
 
-    /// Update a group
-    ///
-    /// # Arguments
-    ///
-    /// * `guid` - uuid of the group in string format
-    /// * `name` - group name
-    /// * `team` - team name ( must exist in the database )
-    /// * `note` - group note
+    /// Update a role
     ///
     /// # Returns
     ///
-    /// Option<()>
-    pub async fn update_group(&self, guid: &str, name: &str, team: &str, note: &str) -> Option<()> {
+    /// Option<()> - None if the role does not exist or if the name is used by another role
+    pub async fn update_role(
+        &self,
+        guid: &str,
+        name: &str,
+        note: Option<&str>,
+        permissions: &[Permission],
+    ) -> Option<()> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let group_guid = Uuid::parse_str(guid);
-        if group_guid.is_err() {
-            log::error!("update_group error: {:?}", group_guid);
+        let role_guid = Uuid::parse_str(guid);
+        if role_guid.is_err() {
+            log::error!("update_role error: {:?}", role_guid);
             return None;
         }
-        let group_guid = group_guid.unwrap().as_bytes().to_vec();
-
+        let role_guid = role_guid.unwrap().as_bytes().to_vec();
+        let permissions = serde_json::to_string(permissions).ok()?;
         let res = sqlx::query!(
             r#"
-            UPDATE grp SET team = (SELECT guid FROM team  WHERE name = ?), name = ?, note = ?, created_at = current_timestamp
+            UPDATE OR IGNORE role SET name = ?, note = ?, permissions = ?, updated_at = current_timestamp
                 WHERE guid = ?
         "#,
-            team,
             name,
             note,
-            group_guid
+            permissions,
+            role_guid
         )
         .execute(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("update_group error: {:?}", res);
+            log::error!("update_role error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            log::error!("update_role error: role {} not found or name {} in use", guid, name);
             return None;
         }
         Some(())
     }
 
-    /// Delete a group
+    /// Delete a role, it is removed from its users
+    ///
+    /// # Returns
+    ///
+    /// Option<()> - None if the role does not exist
+    pub async fn delete_role(&self, guid: &str) -> Option<()> {
+        let role_guid = Uuid::parse_str(guid);
+        if role_guid.is_err() {
+            log::error!("delete_role error: {:?}", role_guid);
+            return None;
+        }
+        let role_guid = role_guid.unwrap().as_bytes().to_vec();
+        let mut tx = self.pool.begin().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            DELETE FROM user_role WHERE role = ?;
+            DELETE FROM role WHERE guid = ?
+        "#,
+            role_guid,
+            role_guid
+        )
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            log::error!("delete_role error: {:?}", res);
+            return None;
+        }
+        if res.unwrap().rows_affected() == 0 {
+            return None;
+        }
+        tx.commit().await.ok()?;
+        Some(())
+    }
+
+    /// Get the roles of a user
+    ///
+    /// # Returns
+    ///
+    /// Option<Vec<Role>>
+    pub async fn get_user_roles(&self, user_id: &UserId) -> Option<Vec<Role>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(
+            r#"
+            SELECT
+                role.guid,
+                role.name,
+                role.note,
+                role.permissions as "permissions!: String",
+                role.created_at as "created_at!: String",
+                role.updated_at as "updated_at!: String",
+                (SELECT COUNT(*) FROM user_role r WHERE r.role = role.guid) as "users!: u32"
+            FROM
+                user_role
+                JOIN role ON role.guid = user_role.role
+            WHERE
+                user_role."user" = ?
+            ORDER BY
+                role.name
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_roles error: {:?}", res);
+            return None;
+        }
+        let roles = res
+            .unwrap()
+            .into_iter()
+            .map(|row| Role {
+                guid: guid_into_uuid(row.guid).unwrap_or("".to_string()),
+                name: row.name,
+                note: row.note,
+                permissions: parse_permissions(&row.permissions),
+                users: row.users,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+            })
+            .collect();
+        Some(roles)
+    }
+
+    /// Replace the roles of a user in a single transaction
     ///
     /// # Arguments
     ///
-    /// * `guid` - uuid of the group in string format
+    /// * `user_id` - user guid
+    /// * `roles` - guids of the roles in string format
     ///
     /// # Returns
     ///
-    /// Option<()>
-    pub async fn delete_group(&self, guid: &str) -> Option<()> {
+    /// Option<()> - None if a role does not exist
+    pub async fn set_user_roles(&self, user_id: &UserId, roles: &[String]) -> Option<()> {
+        let mut tx = self.pool.begin().await.unwrap();
+        let res = sqlx::query!(r#"DELETE FROM user_role WHERE "user" = ?"#, user_id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() {
+            log::error!("set_user_roles error: {:?}", res);
+            return None;
+        }
+        for role in roles {
+            let role_guid = Uuid::parse_str(role);
+            if role_guid.is_err() {
+                log::error!("set_user_roles error: {:?}", role_guid);
+                return None;
+            }
+            let role_guid = role_guid.unwrap().as_bytes().to_vec();
+            let res = sqlx::query!(
+                r#"
+                INSERT OR IGNORE INTO user_role("user", role, created_at)
+                    SELECT ?, guid, current_timestamp FROM role WHERE guid = ?
+            "#,
+                user_id,
+                role_guid
+            )
+            .execute(&mut *tx)
+            .await;
+            if res.is_err() {
+                log::error!("set_user_roles error: {:?}", res);
+                return None;
+            }
+            if res.unwrap().rows_affected() == 0 {
+                log::error!("set_user_roles error: role {} not found", role);
+                return None;
+            }
+        }
+        tx.commit().await.ok()?;
+        Some(())
+    }
+
+    /// Get the permissions of a user
+    /// The admins have every permission, the other users have the permissions of their roles
+    ///
+    /// # Returns
+    ///
+    /// Option<(bool, Vec<Permission>)> - None if the user does not exist, whether the user is an admin and its permissions otherwise
+    pub async fn get_user_permissions(&self, user_id: &UserId) -> Option<(bool, Vec<Permission>)> {
         let mut conn = self.pool.acquire().await.unwrap();
-        let group_guid = Uuid::parse_str(guid);
-        if group_guid.is_err() {
-            log::error!("delete_group error: {:?}", group_guid);
+        let admin = sqlx::query_scalar!(r#"SELECT role = 1 as "admin!: bool" FROM user WHERE guid = ?"#, user_id)
+            .fetch_optional(&mut *conn)
+            .await;
+        if admin.is_err() {
+            log::error!("get_user_permissions error: {:?}", admin);
             return None;
         }
-        let group_guid = group_guid.unwrap().as_bytes().to_vec();
+        if admin.unwrap()? {
+            return Some((true, Permission::ALL.to_vec()));
+        }
+        let res = sqlx::query_scalar!(
+            r#"
+            SELECT
+                role.permissions as "permissions!: String"
+            FROM
+                user_role
+                JOIN role ON role.guid = user_role.role
+            WHERE
+                user_role."user" = ?
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("get_user_permissions error: {:?}", res);
+            return None;
+        }
+        let mut permissions: Vec<Permission> = Vec::new();
+        for permission in res.unwrap().iter().flat_map(|p| parse_permissions(p)) {
+            if !permissions.contains(&permission) {
+                permissions.push(permission);
+            }
+        }
+        Some((false, permissions))
+    }
 
+    /// Get the group of a user given its name
+    ///
+    /// # Returns
+    ///
+    /// Option<Option<String>> - None if the user does not exist, the group guid otherwise
+    pub async fn get_user_group_by_name(&self, name: &str) -> Option<Option<String>> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let res = sqlx::query!(r#"SELECT grp FROM user WHERE name = ?"#, name)
+            .fetch_optional(&mut *conn)
+            .await;
+        if res.is_err() {
+            log::error!("get_user_group_by_name error: {:?}", res);
+            return None;
+        }
+        Some(guid_into_uuid(res.unwrap()?.grp))
+    }
+
+    /// Get the group of a device, it is the group of the user owning the device
+    ///
+    /// # Returns
+    ///
+    /// Option<Option<String>> - None if the device does not exist, the group guid or None if the device has no owner otherwise
+    pub async fn get_peer_group(&self, id: &str) -> Option<Option<String>> {
+        let mut conn = self.pool.acquire().await.unwrap();
         let res = sqlx::query!(
             r#"
-            DELETE FROM grp WHERE guid = ?
+            SELECT
+                user.grp as "grp?: Vec<u8>"
+            FROM
+                peer
+                LEFT JOIN user ON user.guid = peer.user
+            WHERE
+                peer.id = ?
         "#,
-            group_guid
+            id
         )
-        .execute(&mut *conn)
+        .fetch_optional(&mut *conn)
         .await;
         if res.is_err() {
-            log::error!("delete_group error: {:?}", res);
+            log::error!("get_peer_group error: {:?}", res);
             return None;
         }
-        Some(())
+        Some(res.unwrap()?.grp.and_then(guid_into_uuid))
+    }
+
+    /// Check if the devices of the `outgoing` group are allowed to connect to the devices of the `incoming` group
+    pub async fn is_cross_group_allowed(&self, outgoing: &str, incoming: &str) -> Option<bool> {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let outgoing_guid = Uuid::parse_str(outgoing);
+        let incoming_guid = Uuid::parse_str(incoming);
+        if outgoing_guid.is_err() || incoming_guid.is_err() {
+            log::error!("is_cross_group_allowed error: {:?} {:?}", outgoing_guid, incoming_guid);
+            return None;
+        }
+        let outgoing_guid = outgoing_guid.unwrap().as_bytes().to_vec();
+        let incoming_guid = incoming_guid.unwrap().as_bytes().to_vec();
+        let res = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM cross_grp WHERE incoming = ? AND outgoing = ?) as "allowed!: bool"
+        "#,
+            incoming_guid,
+            outgoing_guid
+        )
+        .fetch_one(&mut *conn)
+        .await;
+        if res.is_err() {
+            log::error!("is_cross_group_allowed error: {:?}", res);
+            return None;
+        }
+        res.ok()
     }
 
     /// Create a shared address book for a group with one default rule for its owner
@@ -1836,3 +6652,198 @@ impl Database {
         Some(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ab_crypto::AbKeySource;
+
+    async fn test_database(dir: &tempfile::TempDir) -> Database {
+        let keyring = AbKeyring::from_master_keys(AbKeySource::None, Vec::new());
+        Database::open_with_keyring(dir.path().join("db.sqlite3"), Some(keyring)).await
+    }
+
+    async fn insert_peer(db: &Database, id: &str, hostname: &str, age_days: i64) -> Vec<u8> {
+        let guid = Uuid::new_v4().as_bytes().to_vec();
+        let info = format!("{{\"hostname\":\"{}\"}}", hostname);
+        let last_online = format!("-{} days", age_days);
+        sqlx::query(
+            r#"INSERT INTO peer (guid, id, uuid, pk, info, last_online) VALUES (?, ?, ?, ?, ?, datetime('now', ?))"#,
+        )
+        .bind(guid.clone())
+        .bind(id)
+        .bind(guid.clone())
+        .bind(Vec::<u8>::new())
+        .bind(info)
+        .bind(last_online)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        guid
+    }
+
+    async fn heartbeat(db: &Database, id: &str, guid: &[u8]) {
+        let heartbeat = utils::HeartbeatRequest {
+            id: id.to_string(),
+            modified_at: 0,
+            uuid: BASE64_STANDARD.encode(guid),
+            ver: 0,
+        };
+        db.update_heartbeat(heartbeat, None).await.unwrap();
+    }
+
+    async fn presence_events(db: &Database, guid: &[u8]) -> Vec<i64> {
+        sqlx::query_scalar(r#"SELECT online FROM peer_presence WHERE peer = ? ORDER BY created_at"#)
+            .bind(guid.to_vec())
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_peer_cleanup_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_database(&dir).await;
+        db.set_setting("peer_cleanup_merge_duplicates", "1")
+            .await
+            .unwrap();
+        db.set_setting("peer_cleanup_stale_days", "30")
+            .await
+            .unwrap();
+        // online and recently seen devices sharing a common hostname are distinct devices
+        insert_peer(&db, "100", "localhost", 0).await;
+        insert_peer(&db, "desk101", "localhost", 1).await;
+        insert_peer(&db, "102", "LOCALHOST", 1).await;
+        // a stale peer with the hostname of a live device is a duplicate
+        insert_peer(&db, "103", "localhost", 60).await;
+        // a peer with the same id is a duplicate even if it was seen recently
+        insert_peer(&db, "DESK101", "other", 2).await;
+        // a stale peer with its own hostname is only stale
+        insert_peer(&db, "104", "server", 90).await;
+
+        let report = db.get_peer_cleanup_report().await.unwrap();
+        assert_eq!(report.duplicates.len(), 2);
+        let duplicates: Vec<(&str, Vec<&str>)> = report
+            .duplicates
+            .iter()
+            .map(|group| {
+                (
+                    group.keep.id.as_str(),
+                    group
+                        .duplicates
+                        .iter()
+                        .map(|peer| peer.id.as_str())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert!(duplicates.contains(&("desk101", vec!["DESK101"])));
+        assert!(duplicates
+            .iter()
+            .any(|(_, members)| members == &vec!["103"]));
+        let stale: Vec<&str> = report.stale.iter().map(|peer| peer.id.as_str()).collect();
+        assert_eq!(stale, vec!["104"]);
+
+        // without stale days a peer is only a duplicate by hostname once it is flagged as stale
+        db.set_setting("peer_cleanup_stale_days", "0")
+            .await
+            .unwrap();
+        let report = db.get_peer_cleanup_report().await.unwrap();
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].duplicates[0].id, "DESK101");
+        sqlx::query(r#"UPDATE peer SET status = ? WHERE id = '103'"#)
+            .bind(PEER_STATUS_STALE)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let report = db.get_peer_cleanup_report().await.unwrap();
+        assert_eq!(report.duplicates.len(), 2);
+    }
+
+    #[test]
+    fn test_presence_histogram() {
+        let day = 19000 * SECS_PER_DAY;
+        let hour = 3600;
+        let intervals = vec![
+            (day + 20 * hour, day + 28 * hour),
+            (day + 35 * hour, day + 40 * hour),
+            (day, day + hour),
+        ];
+        let (total, histogram) = presence_histogram(&intervals, day + 12 * hour, day + 36 * hour);
+        assert_eq!(total, (9 * hour) as u64);
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram[0].day, "2022-01-08");
+        assert_eq!(histogram[0].online_secs, (4 * hour) as u64);
+        assert_eq!(histogram[1].day, "2022-01-09");
+        assert_eq!(histogram[1].online_secs, (5 * hour) as u64);
+
+        // an empty period has no bucket
+        let (total, histogram) = presence_histogram(&intervals, day, day);
+        assert_eq!(total, 0);
+        assert!(histogram.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_presence() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = test_database(&dir).await;
+        let first = insert_peer(&db, "100", "first", 0).await;
+        let second = insert_peer(&db, "101", "second", 0).await;
+        let idle = insert_peer(&db, "102", "idle", 1).await;
+
+        // the first heartbeat opens an online interval, the next ones keep it open
+        heartbeat(&db, "100", &first).await;
+        assert_eq!(presence_events(&db, &first).await, vec![1]);
+        heartbeat(&db, "100", &first).await;
+        assert_eq!(presence_events(&db, &first).await, vec![1]);
+
+        // a heartbeat after a silence longer than the threshold closes the interval
+        // at the previous heartbeat and opens a new one
+        sqlx::query(
+            r#"UPDATE peer_presence SET created_at = datetime('now', '-2 hours') WHERE peer = ?"#,
+        )
+        .bind(first.clone())
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        sqlx::query(r#"UPDATE peer SET last_online = datetime('now', '-1 hours') WHERE guid = ?"#)
+            .bind(first.clone())
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        heartbeat(&db, "100", &first).await;
+        assert_eq!(presence_events(&db, &first).await, vec![1, 0, 1]);
+
+        heartbeat(&db, "101", &second).await;
+        sqlx::query(r#"UPDATE peer_presence SET created_at = datetime('now', '-30 minutes') WHERE peer = ?"#)
+            .bind(second.clone())
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(presence_events(&db, &idle).await.is_empty());
+
+        let to = chrono::Utc::now().timestamp();
+        let from = to - 4 * 3600;
+        let uptimes = db.get_peers_uptime(from, to, None).await.unwrap();
+        assert_eq!(uptimes.len(), 3);
+        let uptime = |id: &str| uptimes.iter().find(|uptime| uptime.id == id).unwrap();
+        let online_secs = |id: &str| {
+            uptime(id)
+                .histogram
+                .iter()
+                .map(|bucket| bucket.online_secs)
+                .sum::<u64>()
+        };
+        // one hour online before the silence
+        assert!(uptime("100").online);
+        assert!((3595..=3605).contains(&online_secs("100")));
+        assert!((uptime("100").uptime_percent - 25.0).abs() < 0.5);
+        // online for the last half hour
+        assert!(uptime("101").online);
+        assert!((1795..=1805).contains(&online_secs("101")));
+        // never sent a heartbeat in the period
+        assert!(!uptime("102").online);
+        assert_eq!(online_secs("102"), 0);
+        assert_eq!(uptime("102").uptime_percent, 0.0);
+    }
+}
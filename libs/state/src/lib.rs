@@ -13,15 +13,19 @@
 //
 // You should have received a copy of the Affero General Public License
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+mod ab_crypto;
 mod database;
 mod state;
 mod bearer;
+mod mailer;
 mod password;
 
 pub use utils::{UserId, SessionId};
 
 pub use state::ApiState;
+pub use database::TeamMember;
 pub use password::UserPasswordInfo;
-pub use bearer::{AuthenticatedUser, AuthenticatedAdmin};
+pub use ab_crypto::{AbKeySource, AbKeyring};
+pub use bearer::{permission, AuthenticatedUser, Authorized, RequiredPermission};
 
 pub mod types;
@@ -0,0 +1,223 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::collections::HashMap;
+
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// Security of the connection to the SMTP server, read from the `smtp_security` setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain text connection, only for a local relay or a test sink
+    None,
+    /// STARTTLS upgrade, usually on port 587
+    StartTls,
+    /// Implicit TLS, usually on port 465
+    Tls,
+}
+
+/// SMTP configuration read from the `smtp_*` settings
+#[derive(Debug, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: String,
+    pub password: String,
+    pub from: String,
+}
+
+impl SmtpSettings {
+    /// Read the SMTP configuration from the settings
+    ///
+    /// # Returns
+    ///
+    /// Option<SmtpSettings> - None if the `smtp_host` setting is empty or a setting is invalid
+    pub fn from_settings(settings: &HashMap<String, String>) -> Option<Self> {
+        let setting = |key: &str| settings.get(key).map(|value| value.trim().to_string()).unwrap_or_default();
+        let host = setting("smtp_host");
+        if host.is_empty() {
+            return None;
+        }
+        let security = match setting("smtp_security").to_lowercase().as_str() {
+            "none" => SmtpSecurity::None,
+            "tls" => SmtpSecurity::Tls,
+            "starttls" | "" => SmtpSecurity::StartTls,
+            security => {
+                log::error!("smtp_security setting error: unknown security {}", security);
+                return None;
+            }
+        };
+        let port = match setting("smtp_port").as_str() {
+            "" => match security {
+                SmtpSecurity::None => 25,
+                SmtpSecurity::StartTls => 587,
+                SmtpSecurity::Tls => 465,
+            },
+            port => match port.parse::<u16>() {
+                Ok(port) => port,
+                Err(e) => {
+                    log::error!("smtp_port setting error: {:?}", e);
+                    return None;
+                }
+            },
+        };
+        Some(Self {
+            host,
+            port,
+            security,
+            username: setting("smtp_username"),
+            password: settings.get("smtp_password").cloned().unwrap_or_default(),
+            from: setting("smtp_from"),
+        })
+    }
+}
+
+/// Replace the `{key}` placeholders of a template
+pub fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (key, value)| {
+            text.replace(format!("{{{}}}", key).as_str(), value)
+        })
+}
+
+/// Send a plain text mail
+///
+/// # Returns
+///
+/// Result<(), String> - a human readable error if the mail cannot be built or sent
+pub async fn send_mail(settings: &SmtpSettings, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let from = settings
+        .from
+        .parse::<Mailbox>()
+        .map_err(|e| format!("invalid sender {}: {}", settings.from, e))?;
+    let to = to
+        .parse::<Mailbox>()
+        .map_err(|e| format!("invalid recipient {}: {}", to, e))?;
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?;
+    let builder = match settings.security {
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(settings.host.as_str()),
+        SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(settings.host.as_str())
+            .map_err(|e| e.to_string())?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(settings.host.as_str())
+            .map_err(|e| e.to_string())?,
+    };
+    let mut builder = builder.port(settings.port);
+    if !settings.username.is_empty() {
+        builder = builder.credentials(Credentials::new(settings.username.clone(), settings.password.clone()));
+    }
+    builder
+        .build()
+        .send(message)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Start a local SMTP sink accepting one mail, it returns its settings and the received data
+    pub(crate) async fn smtp_sink() -> (SmtpSettings, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = String::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let command = line.to_uppercase();
+                let reply: &[u8] = if command.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        let settings = SmtpSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: String::new(),
+            password: String::new(),
+            from: "SCTGDesk <noreply@example.org>".to_string(),
+        };
+        (settings, sink)
+    }
+
+    #[test]
+    fn test_render_template() {
+        let text = render_template("Hello {name}, {link} {name} {unknown}", &[("name", "alice"), ("link", "http://x")]);
+        assert_eq!(text, "Hello alice, http://x alice {unknown}");
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = HashMap::new();
+        assert!(SmtpSettings::from_settings(&settings).is_none());
+        settings.insert("smtp_host".to_string(), "smtp.example.org".to_string());
+        settings.insert("smtp_security".to_string(), "tls".to_string());
+        let smtp = SmtpSettings::from_settings(&settings).unwrap();
+        assert_eq!(smtp.port, 465);
+        assert_eq!(smtp.security, SmtpSecurity::Tls);
+        settings.insert("smtp_port".to_string(), "not a port".to_string());
+        assert!(SmtpSettings::from_settings(&settings).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_mail() {
+        let (settings, sink) = smtp_sink().await;
+        send_mail(&settings, "alice@example.org", "Welcome", "Hello alice")
+            .await
+            .unwrap();
+        let data = sink.await.unwrap();
+        assert!(data.contains("Subject: Welcome"));
+        assert!(data.contains("To: alice@example.org"));
+        assert!(data.contains("Hello alice"));
+        assert!(send_mail(&settings, "not an address", "Welcome", "Hello").await.is_err());
+    }
+}
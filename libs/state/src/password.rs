@@ -13,6 +13,9 @@
 //
 // You should have received a copy of the Affero General Public License
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
 use crate::database::DatabaseUserPasswordInfo;
 use bcrypt::{hash, verify, DEFAULT_COST};
 
@@ -38,9 +41,133 @@ impl<'s> UserPasswordInfo<'s> {
         is_valid
     }
 
-    pub fn hash_password( given_password: &str) -> String {
-        hash(given_password, DEFAULT_COST).unwrap()
+    pub fn hash_password( given_password: &str, cost: u32) -> String {
+        hash(given_password, cost).unwrap()
+    }
+
+    /// Hash the password again if its stored hash does not have the given cost
+    /// It must only be called once the password has been checked against the stored hash
+    ///
+    /// # Returns
+    ///
+    /// Option<String> - the new hash, None if the stored hash is up to date
+    pub fn rehash( &self, hashed_password: &str, cost: u32) -> Option<String> {
+        if Self::hash_cost(hashed_password) == Some(cost) {
+            return None;
+        }
+        Some(Self::hash_password(self.password, cost))
     }
 
+    /// Read the cost of a bcrypt hash like `$2b$12$...`
+    pub fn hash_cost( hashed_password: &str) -> Option<u32> {
+        hashed_password.split('$').nth(2)?.parse::<u32>().ok()
+    }
+
+}
+
+/// Password policy read from the `password_*` settings
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    /// Minimum number of characters
+    pub min_length: usize,
+    /// Minimum number of character classes among lowercase, uppercase, digits and symbols
+    pub min_classes: usize,
+    /// File with one banned password per line, compared case insensitively
+    pub banned_file: Option<String>,
+    /// Number of previous passwords which cannot be reused
+    pub history_count: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_classes: 1,
+            banned_file: None,
+            history_count: 3,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Check the length, the character classes and the banned list
+    /// The reuse of the previous passwords is checked against the database
+    ///
+    /// # Returns
+    ///
+    /// Result<(), String> - a human readable reason if the password is rejected
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length.max(1) {
+            return Err(format!("password must have at least {} characters", self.min_length.max(1)));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|class| **class).count() < self.min_classes {
+            return Err(format!(
+                "password must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                self.min_classes
+            ));
+        }
+        if let Some(banned_file) = &self.banned_file {
+            let file = match File::open(banned_file) {
+                Ok(file) => file,
+                Err(e) => {
+                    log::error!("password_banned_file error: {} {:?}", banned_file, e);
+                    return Ok(());
+                }
+            };
+            let banned = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .any(|line| line.trim().to_lowercase() == password.to_lowercase());
+            if banned {
+                return Err("password is too common".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_policy() {
+        let mut policy = PasswordPolicy {
+            min_classes: 3,
+            ..Default::default()
+        };
+        assert!(policy.check("").is_err());
+        assert!(policy.check("Ab1!").is_err());
+        assert!(policy.check("abcdefgh").is_err());
+        assert!(policy.check("abcdefgh1").is_err());
+        assert!(policy.check("Abcdefgh1").is_ok());
+        assert!(policy.check("abcdéfgh1!").is_ok());
+
+        let banned_file = std::env::temp_dir().join(format!("banned-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&banned_file, "123456\npassword1\nSummer2024\n").unwrap();
+        policy.banned_file = Some(banned_file.to_string_lossy().to_string());
+        assert_eq!(policy.check("sUMMER2024").unwrap_err(), "password is too common");
+        assert!(policy.check("Summer2025").is_ok());
+        std::fs::remove_file(&banned_file).unwrap();
+    }
+
+    #[test]
+    fn test_hash_cost() {
+        let hashed_password = UserPasswordInfo::hash_password("secret", 5);
+        assert_eq!(UserPasswordInfo::hash_cost(&hashed_password), Some(5));
+        assert!(verify("secret", &hashed_password).unwrap());
+        assert_eq!(UserPasswordInfo::hash_cost("not a hash"), None);
+        let password_info = UserPasswordInfo::from_password("secret");
+        assert_eq!(password_info.rehash(&hashed_password, 5), None);
+        let rehashed_password = password_info.rehash(&hashed_password, 6).unwrap();
+        assert_eq!(UserPasswordInfo::hash_cost(&rehashed_password), Some(6));
+        assert!(verify("secret", &rehashed_password).unwrap());
+    }
 }
 
@@ -13,7 +13,9 @@
 //
 // You should have received a copy of the Affero General Public License
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
-use crate::database::DatabaseUserInfo;
+use crate::ab_crypto::{AbKeySource, AbKeyring};
+use crate::database::{DatabaseUserInfo, TeamMember};
+use crate::mailer;
 use crate::types;
 use crate::{
     bearer::AuthenticatedUserInfo, database::Database, password::UserPasswordInfo, SessionId,
@@ -23,26 +25,45 @@ use std::{
     collections::HashMap,
     default::Default,
     path::Path,
-    sync::atomic::{AtomicU64, Ordering},
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 
+use chrono::NaiveDate;
 use oauth2::ProviderConfig;
 
+use sha2::{Digest, Sha256};
 use tokio::sync::RwLock;
 use utils::{
-    AbPeer, AbRule, AbTag, AddUserRequest, AddressBook, CpuCount, Group, OidcState, Peer, Platform,
-    Token, UpdateUserRequest, UserListResponse,
+    ab_transfer, AbChange, AbChangesResponse, AbExport, AbImportMode, AbImportReport, AbLegacyMigrationReport, AbPeer,
+    AbOrphanedTag, AbPeerRevision, AbPeersBatchItem, AbRecycledPeer, AbRule, AbRuleLevel, AbTag, AbTagRepairMode, AddUserRequest, AddressBook,
+    AuditConn, CpuCount, CrossGroupAccessResponse, Group, OidcState, Peer, PeerActiveDay, PeerCleanupReport, PeerStat, PeerStatField,
+    PeerSysinfoSnapshot, PeerUptime, Permission, Platform, Role, Setting, Team, Token, UpdateUserRequest,
+    UserImportReport, UserListResponse, UserTransfer, InviteResponse, InviteUserRequest, UserInvite, UserTokenKind,
+    PutUserDataRequest, UpdateProfileRequest, UserAddressBook, UserDataEntry, UserDataError, UserDataItem, UserDeletionSummary, UserProfile,
 };
 
+/// Interval between two checks of the database while waiting for address book changes,
+/// the changes made by this server wake up the subscribers immediately
+const AB_CHANGES_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest period of the peers uptime, the report has one bucket per day and per peer
+const PEERS_UPTIME_MAX_DAYS: i64 = 366;
+
 pub struct ApiState {
     last_maintenance_time: AtomicU64,
+    last_peer_cleanup_time: AtomicU64,
     access_tokens: RwLock<HashMap<Token, AccessTokenInfo>>,
     sessions: RwLock<SessionsState>,
     users: RwLock<HashMap<UserId, UserInfo>>,
     address_books: RwLock<HashMap<UserId, AddressBookInfo>>,
     oidc_sessions: RwLock<HashMap<String, OidcState>>,
-    db: Database,
+    /// Times of the recent password reset requests per email and per client ip
+    password_reset_requests: RwLock<HashMap<String, Vec<u64>>>,
+    db: Arc<Database>,
     oauth2_providers: RwLock<Vec<ProviderConfig>>,
 }
 
@@ -67,8 +88,9 @@ pub struct UserInfo {
 
 #[derive(Debug, Default)]
 struct SessionInfo {
-    #[allow(dead_code)]
     user_id: UserId,
+    /// id of the session recorded in the database
+    record_id: String,
 }
 
 #[derive(Debug, Clone)]
@@ -79,6 +101,22 @@ pub struct AddressBookInfo {
 }
 
 const MAINTENANCE_INTERVAL_IN_SECS: u64 = 60;
+const SESSION_TTL_IN_SECS: i64 = 30 * 24 * 3600;
+const PEER_CLEANUP_INTERVAL_IN_SECS: u64 = 24 * 3600;
+/// Number of password reset requests accepted per email and per client ip during the window
+const PASSWORD_RESET_WINDOW_IN_SECS: u64 = 3600;
+const PASSWORD_RESET_MAX_PER_EMAIL: usize = 3;
+const PASSWORD_RESET_MAX_PER_IP: usize = 10;
+
+/// Settings whose value is not returned by the API
+const SECRET_SETTINGS: [&str; 1] = ["smtp_password"];
+/// Value returned by the API in place of a secret setting which is set
+const SECRET_SETTING_MASK: &str = "********";
+
+/// Hash of a token sent by mail, only the hash is stored in the database
+fn hash_user_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
 
 fn secs_from_epoch() -> u64 {
     SystemTime::now()
@@ -87,17 +125,50 @@ fn secs_from_epoch() -> u64 {
         .as_secs()
 }
 
+/// Compare two dotted version strings (1.2.10 > 1.2.9)
+/// Only the leading digits of each component are used so 1.2.4-beta equals 1.2.4
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim()
+            .trim_start_matches(['v', 'V'])
+            .split('.')
+            .map(|part| {
+                let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+                digits.parse::<u64>().unwrap_or(0)
+            })
+            .collect()
+    };
+    let (a, b) = (parse(a), parse(b));
+    for i in 0..a.len().max(b.len()) {
+        let ordering = a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0));
+        if ordering != std::cmp::Ordering::Equal {
+            return ordering;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
 impl ApiState {
     pub async fn new_with_db<P: AsRef<Path>>(db_filename: P) -> Self {
-        let db = Database::open(db_filename).await;
+        Self::with_database(Database::open(db_filename).await)
+    }
+
+    /// Create the state with an address book keyring instead of the keyring of the environment
+    pub async fn new_with_db_and_keyring<P: AsRef<Path>>(db_filename: P, keyring: AbKeyring) -> Self {
+        Self::with_database(Database::open_with_keyring(db_filename, Some(keyring)).await)
+    }
+
+    fn with_database(db: Database) -> Self {
         Self {
             last_maintenance_time: AtomicU64::new(0),
+            last_peer_cleanup_time: AtomicU64::new(0),
             access_tokens: Default::default(),
             sessions: Default::default(),
             users: Default::default(),
             address_books: Default::default(),
-            db,
+            db: Arc::new(db),
             oidc_sessions: Default::default(),
+            password_reset_requests: Default::default(),
             oauth2_providers: Default::default(),
         }
     }
@@ -124,6 +195,94 @@ impl ApiState {
 
     pub async fn maintenance(&self) {
         self.maintenance_flush_address_books().await;
+        self.db.delete_expired_sessions().await;
+        let retention_days = self.db.get_ab_peer_recycle_retention_days().await;
+        self.db.purge_recycled_ab_peers(retention_days).await;
+
+        let now = secs_from_epoch();
+        let last_cleanup = self.last_peer_cleanup_time.load(Ordering::Relaxed);
+        if now >= (last_cleanup + PEER_CLEANUP_INTERVAL_IN_SECS) {
+            self.last_peer_cleanup_time.store(now, Ordering::Relaxed);
+            self.peer_cleanup(false).await;
+        }
+    }
+
+    /// Flag or delete the stale and the duplicate peers according to the peer_cleanup_* settings
+    ///
+    /// # Arguments
+    ///
+    /// - `dry_run` - if true nothing is modified
+    ///
+    /// # Returns
+    ///
+    /// - `Option<PeerCleanupReport>` - What was done, or what would be done for a dry run
+    pub async fn peer_cleanup(&self, dry_run: bool) -> Option<PeerCleanupReport> {
+        let mut report = self.db.get_peer_cleanup_report().await?;
+        if dry_run {
+            return Some(report);
+        }
+        if !report.stale.is_empty() || !report.duplicates.is_empty() {
+            log::info!(
+                "peer cleanup: {} stale peers, {} duplicate groups ({})",
+                report.stale.len(),
+                report.duplicates.len(),
+                report.action
+            );
+            self.db.apply_peer_cleanup(&report).await?;
+        }
+        report.dry_run = false;
+        Some(report)
+    }
+
+    /// Re-encrypt the address book peers credentials with a new key
+    ///
+    /// When the master keys come from the key file a new master key is generated, all the rows are
+    /// re-encrypted and the previous keys are removed from the file.
+    /// When they come from `AB_MASTER_KEY` the new key must already be the first one of the list,
+    /// all the rows are re-encrypted with it and the previous keys can then be removed.
+    /// The server must not be running.
+    ///
+    /// # Returns
+    ///
+    /// - `Option<u64>` - The number of re-encrypted rows, None if there is no master key or on error
+    pub async fn rotate_ab_key(&self) -> Option<u64> {
+        let keyring = self.db.get_ab_keyring();
+        match keyring.source() {
+            AbKeySource::File(_) => {
+                let new_keyring = keyring.rotate()?;
+                let count = self.db.reencrypt_ab_peers(&new_keyring).await?;
+                new_keyring.retire_previous_keys()?;
+                Some(count)
+            }
+            AbKeySource::Env => self.db.reencrypt_ab_peers(keyring).await,
+            AbKeySource::None => None,
+        }
+    }
+
+    /// Get all the settings, the secret settings which are set are masked
+    pub async fn get_settings(&self) -> Option<Vec<Setting>> {
+        let settings = self
+            .db
+            .get_settings()
+            .await?
+            .into_iter()
+            .map(|mut setting| {
+                if SECRET_SETTINGS.contains(&setting.key.as_str()) && !setting.value.is_empty() {
+                    setting.value = SECRET_SETTING_MASK.to_string();
+                }
+                setting
+            })
+            .collect();
+        Some(settings)
+    }
+
+    /// Create or update a setting
+    /// Writing the mask of a secret setting keeps its value, so that the settings read from the API can be written back
+    pub async fn set_setting(&self, key: &str, value: &str) -> Option<()> {
+        if SECRET_SETTINGS.contains(&key) && value == SECRET_SETTING_MASK {
+            return Some(());
+        }
+        self.db.set_setting(key, value).await
     }
 
     pub async fn check_maintenance(&self) {
@@ -144,6 +303,7 @@ impl ApiState {
         username: &String,
         password_info: UserPasswordInfo<'s>,
         admin_only: bool,
+        client_ip: Option<String>,
     ) -> Option<(utils::UserInfo, Token)> {
         let (conn, user_id, email, db_user_info) =
             match self.db.find_user_by_name(username.as_str()).await {
@@ -171,14 +331,21 @@ impl ApiState {
             _ => return None,
         };
 
+        let user_hashed_password = db_password_info.password.clone();
         if !password_info.check(db_password_info) {
             return None;
         }
 
         drop(conn);
 
+        // the configured cost may have changed since the password was hashed
+        let cost = self.db.get_password_hash_cost().await;
+        if let Some(password_hashed) = password_info.rehash(user_hashed_password.as_str(), cost) {
+            self.db.rehash_user_password(&user_id, password_hashed.as_str()).await;
+        }
+
         let access_token = self
-            .get_access_token(user_id, username, db_user_info.admin)
+            .get_access_token(user_id, username, db_user_info.admin, client_ip)
             .await;
 
         Some((
@@ -192,9 +359,27 @@ impl ApiState {
         ))
     }
 
-    async fn get_access_token(&self, user_id: Vec<u8>, username: &String, is_admin: bool) -> Token {
+    async fn get_access_token(
+        &self,
+        user_id: Vec<u8>,
+        username: &String,
+        is_admin: bool,
+        client_ip: Option<String>,
+    ) -> Token {
         let access_token = Token::new_random();
 
+        // keep a trace of the session and where it was opened from
+        let record_id = uuid::Uuid::new_v4().to_string();
+        let record_info = serde_json::json!({ "ip": client_ip, "name": username }).to_string();
+        self.db
+            .add_session(&record_id, user_id.clone(), SESSION_TTL_IN_SECS, &record_info)
+            .await;
+
+        // older clients may have filled the legacy address book
+        if self.db.is_legacy_address_book_pending(&user_id).await {
+            self.migrate_legacy_address_book(&user_id).await;
+        }
+
         let mut state_access_tokens = self.access_tokens.write().await;
         let mut state_sessions = self.sessions.write().await;
         let mut state_users = self.users.write().await;
@@ -220,6 +405,7 @@ impl ApiState {
 
         let session_info = SessionInfo {
             user_id: user_id.clone(),
+            record_id,
         };
 
         let access_token_info = AccessTokenInfo {
@@ -238,7 +424,17 @@ impl ApiState {
         state_access_tokens.get(access_token).map(|t| t.clone())
     }
 
+    /// Get the user's address book in the legacy format
+    /// In compatibility mode it is built from the user's personal address book
     pub async fn get_user_address_book(&self, user_id: UserId) -> Option<AddressBook> {
+        if self.is_ab_legacy_compat().await {
+            let personal = self.get_legacy_compat_personal_guid(&user_id).await?;
+            let data = self.export_ab(personal.as_str()).await?;
+            return Some(AddressBook {
+                ab: ab_transfer::export_legacy(&data),
+                ..Default::default()
+            });
+        }
         let state_address_books = self.address_books.read().await;
 
         let opt_ab = state_address_books
@@ -270,6 +466,24 @@ impl ApiState {
         address_book: AddressBook,
     ) -> Option<()> {
         log::debug!("set_user_ab()");
+        if self.is_ab_legacy_compat().await {
+            let personal = self.get_legacy_compat_personal_guid(&user_id).await?;
+            let data = ab_transfer::import_legacy(address_book.ab.as_str());
+            if data.is_err() {
+                log::error!("set_user_address_book error: {:?}", data.err());
+                return None;
+            }
+            // the legacy clients always send their whole address book
+            self.import_ab(
+                personal.as_str(),
+                data.unwrap(),
+                AbImportMode::Replace,
+                false,
+                &user_id,
+            )
+            .await?;
+            return Some(());
+        }
         let mut state_address_books = self.address_books.write().await;
 
         if let Some(abi) = state_address_books.get_mut(&user_id) {
@@ -293,6 +507,90 @@ impl ApiState {
         Some(())
     }
 
+    /// Check if the legacy `/api/ab` endpoints read and write the personal address books
+    async fn is_ab_legacy_compat(&self) -> bool {
+        self.db
+            .get_setting("ab_legacy_compat")
+            .await
+            .map(|value| value.trim() == "1")
+            .unwrap_or(false)
+    }
+
+    /// Get the revision of the address book returned by the legacy `/api/ab` endpoints
+    ///
+    /// # Returns
+    ///
+    /// - `Option<i64>` - The revision of the personal address book in compatibility mode, None otherwise
+    pub async fn get_user_address_book_revision(&self, user_id: &UserId) -> Option<i64> {
+        if !self.is_ab_legacy_compat().await {
+            return None;
+        }
+        let personal = self.get_legacy_compat_personal_guid(user_id).await?;
+        self.db.get_ab_revision(personal.as_str()).await
+    }
+
+    /// Get the personal address book of a user for the compatibility mode,
+    /// its legacy address book is migrated first if needed
+    async fn get_legacy_compat_personal_guid(&self, user_id: &UserId) -> Option<String> {
+        if self.db.is_legacy_address_book_pending(user_id).await {
+            self.migrate_legacy_address_book(user_id).await;
+        }
+        self.db.get_ab_personal_guid(user_id.clone()).await
+    }
+
+    /// Migrate the legacy address book of a user into its personal address book
+    /// The peers and tags are merged with the existing ones and the migration is recorded
+    /// so that it is done only once
+    ///
+    /// # Returns
+    ///
+    /// - `Option<AbImportReport>` - The changes applied to the personal address book
+    pub async fn migrate_legacy_address_book(&self, user_id: &UserId) -> Option<AbImportReport> {
+        self.maintenance_flush_address_books().await;
+        let legacy = self.db.get_legacy_address_book(user_id.clone()).await?;
+        let personal = self.db.get_ab_personal_guid(user_id.clone()).await?;
+        let data = if legacy.ab.trim().is_empty() {
+            AbExport::default()
+        } else {
+            let data = ab_transfer::import_legacy(legacy.ab.as_str());
+            if data.is_err() {
+                log::error!("migrate_legacy_address_book error: {:?}", data.err());
+                return None;
+            }
+            data.unwrap()
+        };
+        let report = self
+            .import_ab(personal.as_str(), data, AbImportMode::Merge, false, user_id)
+            .await?;
+        self.db.set_legacy_address_book_migrated(user_id).await?;
+        Some(report)
+    }
+
+    /// Migrate all the legacy address books which have not been migrated yet
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AbLegacyMigrationReport>>` - One report per user, with an error if its migration failed
+    pub async fn migrate_legacy_address_books(&self) -> Option<Vec<AbLegacyMigrationReport>> {
+        let users = self.db.get_pending_legacy_address_books().await?;
+        let mut reports = Vec::new();
+        for user_id in users {
+            let report = self
+                .migrate_legacy_address_book(&user_id)
+                .await
+                .unwrap_or_else(|| {
+                    let mut report = AbImportReport::new(AbImportMode::Merge, false);
+                    report.error = Some("migration failed".to_string());
+                    report
+                });
+            reports.push(AbLegacyMigrationReport {
+                user: utils::guid_into_uuid(user_id).unwrap_or_default(),
+                report,
+            });
+        }
+        Some(reports)
+    }
+
     /// Log out the given user from the state.
     ///
     /// This function is used to log out a user when the user's session is
@@ -325,12 +623,40 @@ impl ApiState {
             }
         }
 
-        state_sessions.sessions.remove(&user.session_id);
+        if let Some(session_info) = state_sessions.sessions.remove(&user.session_id) {
+            self.db.delete_session(&session_info.record_id).await;
+        }
         state_access_tokens.remove(&user.access_token);
 
         Some(())
     }
 
+    /// Log out every session of a user, e.g. after a password reset
+    pub async fn user_logout_all(&self, user_id: &UserId) {
+        let mut state_access_tokens = self.access_tokens.write().await;
+        let mut state_sessions = self.sessions.write().await;
+        let mut state_users = self.users.write().await;
+
+        state_access_tokens.retain(|_, token_info| &token_info.user_id != user_id);
+        let session_ids: Vec<SessionId> = state_sessions
+            .sessions
+            .iter()
+            .filter(|(_, session_info)| &session_info.user_id == user_id)
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        for session_id in session_ids {
+            if let Some(session_info) = state_sessions.sessions.remove(&session_id) {
+                self.db.delete_session(&session_info.record_id).await;
+            }
+        }
+        if state_users.remove(user_id).is_some() {
+            let mut state_address_books = self.address_books.write().await;
+            if let Some(abi) = state_address_books.get_mut(user_id) {
+                abi.remove_after_flush = true;
+            }
+        }
+    }
+
     pub async fn get_current_user_name(&self, user: &AuthenticatedUserInfo) -> Option<String> {
         let state_users = self.users.read().await;
         state_users.get(&user.user_id).map(|ui| ui.username.clone())
@@ -341,6 +667,118 @@ impl ApiState {
         state_users.get(&user.user_id).map(|ui| ui.admin)
     }
 
+    /// Get the permissions of a user
+    /// The admins have every permission, the other users have the permissions of their roles
+    ///
+    /// # Returns
+    ///
+    /// - `Option<(bool, Vec<Permission>)>` - None if the user does not exist, whether the user is an admin and its permissions otherwise
+    pub async fn get_user_permissions(&self, user_id: &UserId) -> Option<(bool, Vec<Permission>)> {
+        self.db.get_user_permissions(user_id).await
+    }
+
+    /// Check if a user has a permission
+    pub async fn has_permission(&self, user_id: &UserId, permission: Permission) -> bool {
+        match self.db.get_user_permissions(user_id).await {
+            Some((_, permissions)) => permissions.iter().any(|p| p.grants(permission)),
+            None => false,
+        }
+    }
+
+    /// Check if a user can manage another user, e.g. set its password, disable it or delete it
+    /// The admins manage every user, the other users only manage the users who are not admins
+    /// and who have no permission they do not have themselves
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - false if one of the users does not exist
+    pub async fn can_manage_user(&self, manager: &UserId, target: &UserId) -> bool {
+        let (manager_admin, manager_permissions) = match self.db.get_user_permissions(manager).await {
+            Some(permissions) => permissions,
+            None => return false,
+        };
+        let (target_admin, target_permissions) = match self.db.get_user_permissions(target).await {
+            Some(permissions) => permissions,
+            None => return false,
+        };
+        if manager_admin {
+            return true;
+        }
+        !target_admin
+            && target_permissions
+                .iter()
+                .all(|permission| manager_permissions.iter().any(|p| p.grants(*permission)))
+    }
+
+    /// Get a page of the roles and the number of roles
+    pub async fn get_roles(&self, current: u32, page_size: u32) -> Option<(u32, Vec<Role>)> {
+        self.db.get_roles(current, page_size).await
+    }
+
+    /// Create a role
+    ///
+    /// # Returns
+    ///
+    /// - `Option<String>` - The guid of the role, None if the name exists
+    pub async fn create_role(
+        &self,
+        name: &str,
+        note: Option<&str>,
+        permissions: &[Permission],
+    ) -> Option<String> {
+        self.db.create_role(name, note, permissions).await
+    }
+
+    /// Update a role
+    pub async fn update_role(
+        &self,
+        guid: &str,
+        name: &str,
+        note: Option<&str>,
+        permissions: &[Permission],
+    ) -> Option<()> {
+        self.db.update_role(guid, name, note, permissions).await
+    }
+
+    /// Delete a role, it is removed from its users
+    pub async fn delete_role(&self, guid: &str) -> Option<()> {
+        self.db.delete_role(guid).await
+    }
+
+    /// Get the roles of a user
+    pub async fn get_user_roles(&self, user_id: &UserId) -> Option<Vec<Role>> {
+        self.db.get_user_roles(user_id).await
+    }
+
+    /// Replace the roles of a user
+    ///
+    /// # Arguments
+    ///
+    /// - `user_id` - The user guid
+    ///
+    /// - `roles` - The guids of the roles
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if a role does not exist
+    pub async fn set_user_roles(&self, user_id: &UserId, roles: &[String]) -> Option<()> {
+        self.db.set_user_roles(user_id, roles).await
+    }
+
+    /// Get a page of the connection audit events, the most recent first
+    ///
+    /// # Arguments
+    ///
+    /// - `team` - The team scope, only the events of the peers of its users are returned
+    pub async fn get_audit_conns(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<AuditConn>)> {
+        self.db.get_audit_conns(current, page_size, team).await
+    }
+
     pub async fn with_user_info<R>(
         &self,
         user_id: &UserId,
@@ -364,6 +802,9 @@ impl ApiState {
         old_password: String,
         new_password: String,
     ) -> Option<()> {
+        let (_, user) = self.db.find_user_by_name(username.as_str()).await;
+        let (user_id, _, _) = user?;
+        self.check_password(Some(&user_id), new_password.as_str()).await.ok()?;
         self.db
             .update_user_password(username, old_password, new_password)
             .await
@@ -374,6 +815,9 @@ impl ApiState {
         username: String,
         new_password: String,
     ) -> Option<()> {
+        let (_, user) = self.db.find_user_by_name(username.as_str()).await;
+        let (user_id, _, _) = user?;
+        self.check_password(Some(&user_id), new_password.as_str()).await.ok()?;
         self.db.reset_user_password(username, new_password).await
     }
 
@@ -383,11 +827,163 @@ impl ApiState {
         password: String,
         admin: bool,
     ) -> Option<UserId> {
+        self.check_password(None, password.as_str()).await.ok()?;
         self.db.create_user(username, password, admin).await
     }
 
-    pub async fn user_delete(&self, user_id: &str) -> Option<()> {
-        self.db.delete_user(user_id).await
+    /// Get the profile of a user
+    pub async fn get_user_profile(&self, user_id: &UserId) -> Option<UserProfile> {
+        self.db.get_user_profile(user_id).await
+    }
+
+    /// Update the email, the note and the notification preferences of a user
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if the user does not exist or if the email is used by another user
+    pub async fn update_user_profile(&self, user_id: &UserId, request: UpdateProfileRequest) -> Option<()> {
+        self.db
+            .update_user_profile(
+                user_id,
+                request.email.as_deref(),
+                request.note.as_deref(),
+                request.notifications.as_ref(),
+            )
+            .await
+    }
+
+    /// Change the password of a user after checking its current password
+    /// The new password must already follow the password policy, see `check_password`
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if the current password is wrong
+    pub async fn change_user_password(&self, user_id: &UserId, current_password: &str, password: &str) -> Option<()> {
+        self.db.change_user_password(user_id, current_password, password).await
+    }
+
+    /// Get the entries of the user data storage of a user, without their data
+    pub async fn get_user_data_items(&self, user_id: &UserId) -> Option<Vec<UserDataItem>> {
+        self.db.get_user_data_items(user_id).await
+    }
+
+    /// Get an entry of the user data storage of a user
+    pub async fn get_user_data(&self, user_id: &UserId, data_type: &str) -> Option<UserDataEntry> {
+        self.db.get_user_data(user_id, data_type).await
+    }
+
+    /// Create or replace an entry of the user data storage of a user
+    /// The size of the entry and the number of entries are limited by the `user_data_max_size` and `user_data_max_entries` settings
+    ///
+    /// # Arguments
+    ///
+    /// - `request` - The data and the version of the entry read before, see `PutUserDataRequest`
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Result<String, UserDataError>>` - The new version of the entry or the reason of the rejection, None on error
+    pub async fn put_user_data(
+        &self,
+        user_id: &UserId,
+        data_type: &str,
+        request: PutUserDataRequest,
+    ) -> Option<Result<String, UserDataError>> {
+        if !utils::is_valid_user_data_type(data_type) {
+            return Some(Err(UserDataError::Invalid(format!("invalid type {}", data_type))));
+        }
+        let (max_size, max_entries) = self.db.get_user_data_limits().await;
+        if request.data.len() > max_size {
+            return Some(Err(UserDataError::Invalid(format!(
+                "the data cannot be larger than {} bytes",
+                max_size
+            ))));
+        }
+        self.db
+            .put_user_data(
+                user_id,
+                data_type,
+                request.data.as_str(),
+                request.updated_at.as_deref(),
+                max_entries,
+            )
+            .await
+    }
+
+    /// Delete an entry of the user data storage of a user
+    ///
+    /// # Arguments
+    ///
+    /// - `updated_at` - The version of the entry read before, None for deleting any version
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Result<(), UserDataError>>` - A conflict with the current version, or without version if the entry does not exist, None on error
+    pub async fn delete_user_data(
+        &self,
+        user_id: &UserId,
+        data_type: &str,
+        updated_at: Option<&str>,
+    ) -> Option<Result<(), UserDataError>> {
+        self.db.delete_user_data(user_id, data_type, updated_at).await
+    }
+
+    /// Get the personal address book and the shared address books a user can access
+    pub async fn get_user_address_books(&self, user_id: &UserId) -> Option<Vec<UserAddressBook>> {
+        self.db.get_user_address_books(user_id).await
+    }
+
+    /// Check a new password against the password policy
+    ///
+    /// # Arguments
+    ///
+    /// - `user_id`: the user changing its password, its current and previous passwords cannot be reused
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), String>` - a human readable reason if the password is rejected
+    pub async fn check_password(&self, user_id: Option<&UserId>, password: &str) -> Result<(), String> {
+        let policy = self.db.get_password_policy().await;
+        policy.check(password)?;
+        if let Some(user_id) = user_id {
+            if self.db.is_password_reused(user_id, password, policy.history_count).await == Some(true) {
+                return Err(format!(
+                    "password must differ from the current and the {} previous passwords",
+                    policy.history_count
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a user, its shared address books and its devices are given to `transfer_to` or deleted
+    ///
+    /// # Arguments
+    ///
+    /// - `user_id` - the guid of the user in string format
+    /// - `transfer_to` - the guid in string format of the user receiving the shared address books and the devices
+    /// - `archive` - keep the user as an archived account for the audit logs
+    ///
+    /// # Returns
+    ///
+    /// - `Option<UserDeletionSummary>` - what was transferred and deleted, None if a user does not exist or is archived
+    pub async fn user_delete(
+        &self,
+        user_id: &str,
+        transfer_to: Option<&str>,
+        archive: bool,
+    ) -> Option<UserDeletionSummary> {
+        let user_id = utils::uuid_into_guid(user_id)?;
+        let transfer_to = match transfer_to {
+            Some(transfer_to) => Some(utils::uuid_into_guid(transfer_to)?),
+            None => None,
+        };
+        let summary = self
+            .db
+            .delete_user(&user_id, transfer_to.as_ref(), archive)
+            .await?;
+        self.user_logout_all(&user_id).await;
+        self.address_books.write().await.remove(&user_id);
+        Some(summary)
     }
 
     pub async fn ui_get_user_info(&self, username: String) -> Option<types::UserInfo> {
@@ -395,13 +991,30 @@ impl ApiState {
         res
     }
 
-    pub async fn update_systeminfo(&self, systeminfo: utils::SystemInfo) -> Option<()> {
+    pub async fn update_systeminfo(
+        &self,
+        systeminfo: utils::SystemInfo,
+        client_ip: Option<String>,
+    ) -> Option<()> {
         // must be written in the database immediately because peer is mainly used by hbbs
-        self.db.update_systeminfo(systeminfo).await
+        self.db.update_systeminfo(systeminfo, client_ip).await
+    }
+
+    pub async fn update_heartbeat(
+        &self,
+        heartbeat: utils::HeartbeatRequest,
+        client_ip: Option<String>,
+    ) -> Option<()> {
+        self.db.update_heartbeat(heartbeat, client_ip).await
     }
 
-    pub async fn update_heartbeat(&self, heartbeat: utils::HeartbeatRequest) -> Option<()> {
-        self.db.update_heartbeat(heartbeat).await
+    /// Record a connection audit event sent by a client
+    pub async fn add_audit_conn(
+        &self,
+        audit: &utils::AuditRequest,
+        client_ip: Option<String>,
+    ) -> Option<()> {
+        self.db.add_audit_conn(audit, client_ip).await
     }
 
     pub async fn get_oauth2_config(&self, config_file: &str) -> Option<Vec<ProviderConfig>> {
@@ -500,6 +1113,7 @@ impl ApiState {
     /// # Arguments
     ///
     /// * `uuid_code` - The uuid code of the client
+    /// * `client_ip` - The ip address of the client, recorded with the session
     ///
     /// # Returns
     ///
@@ -510,6 +1124,7 @@ impl ApiState {
     pub async fn oidc_check_session(
         &self,
         uuid_code: String,
+        client_ip: Option<String>,
     ) -> Option<(Token, String, DatabaseUserInfo)> {
         let mut oidc_sessions = self.oidc_sessions.write().await;
         let oidc_session = oidc_sessions.get_mut(&uuid_code);
@@ -542,7 +1157,7 @@ impl ApiState {
                 return None;
             }
             let token = self
-                .get_access_token(uuid_vec, &username, db_user_info.admin)
+                .get_access_token(uuid_vec, &username, db_user_info.admin, client_ip)
                 .await;
             // User has completed the authorization flow
             oidc_sessions.remove(&uuid_code);
@@ -557,8 +1172,8 @@ impl ApiState {
     }
 
     /// Add a peer to an address book
-    pub async fn add_ab_peer(&self, ab: &str, ab_peer: AbPeer) -> Option<()> {
-        self.db.add_peer_to_ab(ab, ab_peer).await
+    pub async fn add_ab_peer(&self, ab: &str, ab_peer: AbPeer, user_id: &UserId) -> Option<()> {
+        self.db.add_peer_to_ab(ab, ab_peer, user_id).await
     }
 
     /// Get all peers from an address book
@@ -566,26 +1181,193 @@ impl ApiState {
         self.db.get_peers_from_ab(ab).await
     }
 
-    /// Delete a peer in an address book
-    pub async fn delete_ab_peer(&self, ab: &str, peers_to_delete: Vec<String>) -> Option<()> {
+    /// Get a page of the peers of an address book matching the filters and the number of matching peers
+    ///
+    /// # Arguments
+    ///
+    /// - `tag` - only the peers with this tag
+    /// - `search` - substring of the peer id or alias, case insensitive
+    /// - `platform` - only the peers of this platform, case insensitive
+    /// - `current` - the page number, starting at 1
+    /// - `page_size` - the number of peers per page
+    pub async fn get_ab_peers_page(
+        &self,
+        ab: &str,
+        tag: Option<&str>,
+        search: Option<&str>,
+        platform: Option<&str>,
+        current: u32,
+        page_size: u32,
+    ) -> Option<(u32, Vec<AbPeer>)> {
+        self.db
+            .get_peers_page_from_ab(ab, tag, search, platform, current, page_size)
+            .await
+    }
+
+    /// Delete peers in an address book, they are moved to the recycle bin
+    pub async fn delete_ab_peer(
+        &self,
+        ab: &str,
+        peers_to_delete: Vec<String>,
+        user_id: &UserId,
+    ) -> Option<()> {
         for peer in peers_to_delete {
-            self.db.delete_peer_from_ab(ab, peer.as_str()).await;
+            self.db.delete_peer_from_ab(ab, peer.as_str(), user_id).await;
         }
         Some(())
     }
 
-    /// Get a peer from an address book
-    pub async fn get_ab_peer(&self, ab: &str, peer: &str) -> Option<AbPeer> {
-        self.db.get_ab_peer(ab, peer).await
+    /// Get the revision of an address book, it changes each time its peers or tags change
+    pub async fn get_ab_revision(&self, ab: &str) -> Option<i64> {
+        self.db.get_ab_revision(ab).await
     }
 
-    /// Add a tag to an address book
-    pub async fn add_ab_tag(&self, ab: &str, tag: AbTag) -> Option<()> {
-        self.db.add_tag_to_ab(ab, tag).await
+    /// Wait for changes of the address books a user can read
+    ///
+    /// # Arguments
+    ///
+    /// - `since` - the last revision known by the user, None to get the current revision of the address books immediately
+    /// - `timeout` - how long to wait for a change
+    ///
+    /// # Returns
+    ///
+    /// - `Option<AbChangesResponse>` - The address books changed after `since`, empty if the timeout expired
+    pub async fn wait_ab_changes(
+        &self,
+        user_id: &UserId,
+        since: Option<i64>,
+        timeout: Duration,
+    ) -> Option<AbChangesResponse> {
+        let mut readable = Vec::new();
+        if let Some(personal) = self.db.get_ab_personal_guid(user_id.clone()).await {
+            readable.push(personal);
+        }
+        for ab in self.db.get_shared_address_books(user_id.clone()).await? {
+            readable.push(ab.ab);
+        }
+        // subscribe before reading the revisions so that no change is missed
+        let mut changes_rx = self.db.subscribe_ab_changes();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let changes: Vec<AbChange> = self
+                .db
+                .get_ab_changes(since.unwrap_or(0))
+                .await?
+                .into_iter()
+                .filter(|change| readable.contains(&change.guid))
+                .collect();
+            // the revisions of the other address books are not disclosed
+            let revision = changes
+                .iter()
+                .map(|change| change.revision)
+                .max()
+                .unwrap_or(0)
+                .max(since.unwrap_or(0));
+            let now = tokio::time::Instant::now();
+            if !changes.is_empty() || since.is_none() || now >= deadline {
+                return Some(AbChangesResponse {
+                    revision,
+                    total: changes.len() as u32,
+                    data: changes,
+                });
+            }
+            let wait = (deadline - now).min(AB_CHANGES_POLL_INTERVAL);
+            let _ = tokio::time::timeout(wait, changes_rx.changed()).await;
+        }
     }
 
-    /// Get all tags from an address book
-    pub async fn get_ab_tags(&self, ab: &str) -> Option<Vec<AbTag>> {
+    /// Copy or move peers to another address book in a single transaction
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AbPeersBatchItem>>` - The result for each peer, None if nothing was changed because of an error
+    pub async fn copy_ab_peers(
+        &self,
+        ab: &str,
+        target: &str,
+        peers: &[String],
+        move_peers: bool,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        self.db
+            .copy_peers_to_ab(ab, target, peers, move_peers, user_id)
+            .await
+    }
+
+    /// Add or remove a tag on peers of an address book in a single transaction
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AbPeersBatchItem>>` - The result for each peer, None if nothing was changed because of an error
+    pub async fn tag_ab_peers(
+        &self,
+        ab: &str,
+        tag: &str,
+        peers: &[String],
+        add: bool,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        self.db.tag_ab_peers(ab, tag, peers, add, user_id).await
+    }
+
+    /// Move the peers of an address book having a tag to the recycle bin in a single transaction
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AbPeersBatchItem>>` - The deleted peers, None if nothing was changed because of an error
+    pub async fn delete_ab_peers_by_tag(
+        &self,
+        ab: &str,
+        tag: &str,
+        user_id: &UserId,
+    ) -> Option<Vec<AbPeersBatchItem>> {
+        self.db.delete_ab_peers_by_tag(ab, tag, user_id).await
+    }
+
+    /// Get the peers of an address book which are in the recycle bin and the retention period in days
+    pub async fn get_ab_recycled_peers(&self, ab: &str) -> Option<(Vec<AbRecycledPeer>, i64)> {
+        let peers = self.db.get_recycled_peers_from_ab(ab).await?;
+        let retention_days = self.db.get_ab_peer_recycle_retention_days().await;
+        Some((peers, retention_days))
+    }
+
+    /// Restore peers of an address book from the recycle bin
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<String>>` - The ids of the restored peers
+    pub async fn restore_ab_peers(
+        &self,
+        ab: &str,
+        peers_to_restore: Vec<String>,
+        user_id: &UserId,
+    ) -> Option<Vec<String>> {
+        let mut restored = Vec::new();
+        for peer in peers_to_restore {
+            if self.db.restore_peer_from_ab(ab, peer.as_str(), user_id).await? {
+                restored.push(peer);
+            }
+        }
+        Some(restored)
+    }
+
+    /// Get the revisions of a peer of an address book, the latest first
+    pub async fn get_ab_peer_history(&self, ab: &str, id: &str) -> Option<Vec<AbPeerRevision>> {
+        self.db.get_ab_peer_history(ab, id).await
+    }
+
+    /// Get a peer from an address book
+    pub async fn get_ab_peer(&self, ab: &str, peer: &str) -> Option<AbPeer> {
+        self.db.get_ab_peer(ab, peer).await
+    }
+
+    /// Add a tag to an address book
+    pub async fn add_ab_tag(&self, ab: &str, tag: AbTag) -> Option<()> {
+        self.db.add_tag_to_ab(ab, tag).await
+    }
+
+    /// Get all tags from an address book
+    pub async fn get_ab_tags(&self, ab: &str) -> Option<Vec<AbTag>> {
         self.db.get_ab_tags(ab).await
     }
 
@@ -599,22 +1381,182 @@ impl ApiState {
         Some(ab_tag)
     }
 
-    /// Rename a tag in an address book
-    pub async fn rename_ab_tag(&self, ab: &str, old_name: &str, tag: AbTag) -> Option<()> {
-        self.db.rename_ab_tag(ab, old_name, tag).await
+    /// Rename a tag in an address book, the peers using it are updated
+    pub async fn rename_ab_tag(
+        &self,
+        ab: &str,
+        old_name: &str,
+        tag: AbTag,
+        user_id: &UserId,
+    ) -> Option<()> {
+        self.db.rename_ab_tag(ab, old_name, tag, user_id).await
     }
 
-    /// Delete some tags from an address book
-    pub async fn delete_ab_tags(&self, ab: &str, tags_to_delete: Vec<String>) -> Option<()> {
+    /// Delete some tags from an address book, they are removed from the peers using them
+    pub async fn delete_ab_tags(
+        &self,
+        ab: &str,
+        tags_to_delete: Vec<String>,
+        user_id: &UserId,
+    ) -> Option<()> {
         for tag in tags_to_delete {
-            self.db.delete_tag_from_ab(ab, tag.as_str()).await;
+            self.db.delete_tag_from_ab(ab, tag.as_str(), user_id).await;
         }
         Some(())
     }
 
+    /// Get the tags used by the peers of an address book which do not exist in the address book
+    pub async fn get_ab_orphaned_tags(&self, ab: &str) -> Option<Vec<AbOrphanedTag>> {
+        self.db.get_ab_orphaned_tags(ab).await
+    }
+
+    /// Create the orphaned tags of an address book or remove them from the peers
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AbOrphanedTag>>` - The repaired tags, None if nothing was changed because of an error
+    pub async fn repair_ab_orphaned_tags(
+        &self,
+        ab: &str,
+        mode: AbTagRepairMode,
+        user_id: &UserId,
+    ) -> Option<Vec<AbOrphanedTag>> {
+        self.db.repair_ab_orphaned_tags(ab, mode, user_id).await
+    }
+
+    /// Export the tags and peers of an address book
+    pub async fn export_ab(&self, ab: &str) -> Option<AbExport> {
+        let tags = self.db.get_ab_tags(ab).await?;
+        let peers = self.db.get_peers_from_ab(ab).await?;
+        Some(AbExport { tags, peers })
+    }
+
+    /// Import tags and peers into an address book
+    ///
+    /// In merge mode the new peers and tags are added and the existing ones are updated,
+    /// the fields missing from an imported peer are kept.
+    /// In replace mode the imported peers replace the existing ones and the peers and tags which are not
+    /// imported are removed, the tags used by the imported peers are always kept.
+    /// Tags used by the peers but not listed are created with the default color.
+    ///
+    /// # Arguments
+    ///
+    /// - `ab` - The address book guid
+    /// - `data` - The imported tags and peers
+    /// - `mode` - Merge or replace
+    /// - `dry_run` - Only compute the changes, nothing is written
+    /// - `user_id` - The user who imports the address book
+    ///
+    /// # Returns
+    ///
+    /// - `Option<AbImportReport>` - The changes applied, or to be applied when dry_run is true,
+    ///   None on error and nothing is imported
+    pub async fn import_ab(
+        &self,
+        ab: &str,
+        data: AbExport,
+        mode: AbImportMode,
+        dry_run: bool,
+        user_id: &UserId,
+    ) -> Option<AbImportReport> {
+        let existing_tags = self.db.get_ab_tags(ab).await?;
+        let existing_peers = self.db.get_peers_from_ab(ab).await?;
+        let mut report = AbImportReport::new(mode, dry_run);
+
+        // the last occurrence of a duplicated peer or tag wins
+        let mut peers: Vec<AbPeer> = Vec::new();
+        for peer in data.peers {
+            peers.retain(|p| p.id != peer.id);
+            peers.push(peer);
+        }
+        let mut tags: Vec<AbTag> = Vec::new();
+        for tag in data.tags {
+            tags.retain(|t| t.name != tag.name);
+            tags.push(tag);
+        }
+        for peer in peers.iter() {
+            for name in peer.tags.iter().flatten() {
+                if !tags.iter().any(|tag| &tag.name == name) {
+                    let color = existing_tags
+                        .iter()
+                        .find(|tag| &tag.name == name)
+                        .map(|tag| tag.color)
+                        .unwrap_or(AbTag::default().color);
+                    tags.push(AbTag {
+                        name: name.clone(),
+                        color,
+                    });
+                }
+            }
+        }
+
+        let mut tags_to_write = Vec::new();
+        for tag in tags.iter() {
+            match existing_tags.iter().find(|t| t.name == tag.name) {
+                None => report.tags_added.push(tag.name.clone()),
+                Some(old) if old.color != tag.color => report.tags_updated.push(tag.name.clone()),
+                Some(_) => continue,
+            }
+            tags_to_write.push(tag.clone());
+        }
+        if mode == AbImportMode::Replace {
+            report.tags_removed = existing_tags
+                .iter()
+                .filter(|old| !tags.iter().any(|tag| tag.name == old.name))
+                .map(|old| old.name.clone())
+                .collect();
+        }
+
+        let imported_ids: Vec<String> = peers.iter().map(|peer| peer.id.clone()).collect();
+        let mut peers_to_write = Vec::new();
+        for peer in peers {
+            let old = existing_peers.iter().find(|p| p.id == peer.id);
+            let peer = match old {
+                Some(old) if mode == AbImportMode::Merge => peer.merge(old.clone()),
+                _ => peer,
+            };
+            match old {
+                None => report.peers_added.push(peer.id.clone()),
+                Some(old)
+                    if serde_json::to_value(old).ok() != serde_json::to_value(&peer).ok() =>
+                {
+                    report.peers_updated.push(peer.id.clone())
+                }
+                Some(_) => {
+                    report.peers_unchanged += 1;
+                    continue;
+                }
+            }
+            peers_to_write.push(peer);
+        }
+        if mode == AbImportMode::Replace {
+            report.peers_removed = existing_peers
+                .iter()
+                .filter(|old| !imported_ids.contains(&old.id))
+                .map(|old| old.id.clone())
+                .collect();
+        }
+        if dry_run {
+            return Some(report);
+        }
+
+        self.db
+            .import_ab_changes(
+                ab,
+                &tags_to_write,
+                &peers_to_write,
+                &report.tags_removed,
+                &report.peers_removed,
+                user_id,
+            )
+            .await?;
+        Some(report)
+    }
+
     /// Add a user
     /// This function is used to add a user to the database
-    pub async fn add_user(&self, user_parameters: AddUserRequest) -> Option<()> {
+    /// The user belongs to the team of its group, which must be in `team` if it is given
+    pub async fn add_user(&self, user_parameters: AddUserRequest, team: Option<&str>) -> Option<()> {
         self.db
             .add_user(
                 user_parameters.name,
@@ -622,10 +1564,280 @@ impl ApiState {
                 user_parameters.email,
                 user_parameters.is_admin,
                 user_parameters.group_name,
+                team,
             )
             .await
     }
 
+    /// Import users, nothing is imported if a user is invalid
+    /// The users without password get a generated password which is only returned in the report
+    ///
+    /// # Arguments
+    ///
+    /// - `users` - The users or the parse error of each row
+    ///
+    /// - `create_groups` - Create the missing groups in the team scope
+    ///
+    /// - `allow_admins` - Allow importing admins
+    ///
+    /// - `team` - The team scope, None for every team
+    ///
+    /// - `dry_run` - Validate the users without importing them
+    ///
+    /// # Returns
+    ///
+    /// - `Option<UserImportReport>` - The error of each row and the created groups
+    pub async fn import_users(
+        &self,
+        users: &[Result<UserTransfer, String>],
+        create_groups: bool,
+        allow_admins: bool,
+        team: Option<&str>,
+        dry_run: bool,
+    ) -> Option<UserImportReport> {
+        self.db
+            .import_users(users, create_groups, allow_admins, team, dry_run)
+            .await
+    }
+
+    /// Export the users of a team, without their password
+    pub async fn export_users(&self, team: Option<&str>) -> Option<Vec<UserTransfer>> {
+        self.db.export_users(team).await
+    }
+
+    /// Invite a new user by mail, the user is disabled until the invitation is accepted
+    ///
+    /// # Arguments
+    ///
+    /// - `request` - The name, the email, the group and the admin flag of the user
+    ///
+    /// - `team` - The team scope, the group must belong to this team
+    ///
+    /// # Returns
+    ///
+    /// - `Option<InviteResponse>` - None if the user cannot be created, the response tells if the mail was sent otherwise
+    pub async fn invite_user(&self, request: InviteUserRequest, team: Option<&str>) -> Option<InviteResponse> {
+        let token = Token::new_random().to_base64();
+        let (user_id, expires_at) = self
+            .db
+            .create_invited_user(&request, team, hash_user_token(token.as_str()).as_str())
+            .await?;
+        Some(
+            self.send_token_mail(&user_id, UserTokenKind::Invite, request.name.as_str(), request.email.as_str(), &token, expires_at)
+                .await,
+        )
+    }
+
+    /// Send a new invitation to a user, the previous invitation is revoked
+    ///
+    /// # Returns
+    ///
+    /// - `Option<InviteResponse>` - None if the user has no invitation
+    pub async fn resend_invite(&self, user_id: &UserId) -> Option<InviteResponse> {
+        let (name, email) = self.db.get_invited_user(user_id).await?;
+        self.send_user_token(user_id, UserTokenKind::Invite, name.as_str(), email.unwrap_or_default().as_str())
+            .await
+    }
+
+    /// Revoke the invitation of a user, the user stays disabled
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if the user has no invitation
+    pub async fn revoke_invite(&self, user_id: &UserId) -> Option<()> {
+        match self.db.delete_user_tokens(user_id, UserTokenKind::Invite).await? {
+            0 => None,
+            _ => Some(()),
+        }
+    }
+
+    /// Get a page of the invitations and the number of invitations
+    pub async fn get_user_invites(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<UserInvite>)> {
+        self.db.get_user_invites(current, page_size, team).await
+    }
+
+    /// Send a password reset mail to an active user
+    /// Nothing is sent for an unknown email or a disabled user, the caller must not tell it to the requester
+    /// The user is looked up and the mail is sent in the background so that the response time does not
+    /// tell whether the email is known
+    /// The requests are limited per email and per client ip to avoid flooding a mailbox
+    ///
+    /// # Arguments
+    ///
+    /// - `client_ip` - The ip address of the requester
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - false if the request is dropped because of the limits
+    pub async fn request_password_reset(&self, email: &str, client_ip: Option<&str>) -> bool {
+        let mut limits = vec![(format!("email:{}", email.to_lowercase()), PASSWORD_RESET_MAX_PER_EMAIL)];
+        if let Some(client_ip) = client_ip {
+            limits.push((format!("ip:{}", client_ip), PASSWORD_RESET_MAX_PER_IP));
+        }
+        let now = secs_from_epoch();
+        let mut requests = self.password_reset_requests.write().await;
+        requests.retain(|_, times| {
+            times.retain(|time| now.saturating_sub(*time) < PASSWORD_RESET_WINDOW_IN_SECS);
+            !times.is_empty()
+        });
+        if limits
+            .iter()
+            .any(|(key, max)| requests.get(key).map(|times| times.len()).unwrap_or(0) >= *max)
+        {
+            log::warn!("request_password_reset: too many requests for {} from {:?}", email, client_ip);
+            return false;
+        }
+        for (key, _) in limits {
+            requests.entry(key).or_default().push(now);
+        }
+        drop(requests);
+        let db = self.db.clone();
+        let email = email.to_string();
+        tokio::spawn(async move {
+            Self::send_password_reset(&db, email.as_str()).await;
+        });
+        true
+    }
+
+    /// Create a password reset token for an active user and send it by mail
+    async fn send_password_reset(db: &Database, email: &str) -> Option<()> {
+        let (user_id, name, active) = db.find_user_by_email(email).await??;
+        if !active {
+            return None;
+        }
+        let token = Token::new_random().to_base64();
+        let hours = db.get_user_token_validity(UserTokenKind::PasswordReset).await;
+        let expires_at = db
+            .create_user_token(&user_id, UserTokenKind::PasswordReset, hash_user_token(token.as_str()).as_str(), hours)
+            .await?;
+        let (smtp, subject, body) =
+            Self::render_token_mail(db, UserTokenKind::PasswordReset, name.as_str(), &token, &expires_at).await?;
+        if smtp.is_none() {
+            log::error!("request_password_reset error: smtp is not configured");
+            return None;
+        }
+        if let Err(error) = mailer::send_mail(&smtp.unwrap(), email, subject.as_str(), body.as_str()).await {
+            log::error!("request_password_reset error: {}", error);
+            return None;
+        }
+        Some(())
+    }
+
+    /// Set the password of a user with the token of an invitation or of a password reset
+    /// The token is single use and all the sessions of the user are closed
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if the token is unknown, expired or of another kind
+    pub async fn set_password_with_token(&self, kind: UserTokenKind, token: &str, password: &str) -> Option<()> {
+        let password_hashed = self.db.hash_password(password).await;
+        let user_id = self
+            .db
+            .use_user_token(kind, hash_user_token(token).as_str(), password_hashed.as_str())
+            .await?;
+        self.user_logout_all(&user_id).await;
+        Some(())
+    }
+
+    /// Get the user of a valid token of an invitation or of a password reset, without using the token
+    ///
+    /// # Returns
+    ///
+    /// - `Option<UserId>` - None if the token is unknown, expired or of another kind
+    pub async fn get_user_token_user(&self, kind: UserTokenKind, token: &str) -> Option<UserId> {
+        self.db.get_user_token_user(kind, hash_user_token(token).as_str()).await
+    }
+
+    /// Create a token for a user and send it by mail
+    async fn send_user_token(
+        &self,
+        user_id: &UserId,
+        kind: UserTokenKind,
+        name: &str,
+        email: &str,
+    ) -> Option<InviteResponse> {
+        let token = Token::new_random().to_base64();
+        let hours = self.db.get_user_token_validity(kind).await;
+        let expires_at = self
+            .db
+            .create_user_token(user_id, kind, hash_user_token(token.as_str()).as_str(), hours)
+            .await?;
+        Some(self.send_token_mail(user_id, kind, name, email, &token, expires_at).await)
+    }
+
+    /// Send the mail of a token and tell if it was sent
+    async fn send_token_mail(
+        &self,
+        user_id: &UserId,
+        kind: UserTokenKind,
+        name: &str,
+        email: &str,
+        token: &str,
+        expires_at: String,
+    ) -> InviteResponse {
+        let res = match Self::render_token_mail(&self.db, kind, name, token, &expires_at).await {
+            Some((Some(smtp), subject, body)) => mailer::send_mail(&smtp, email, subject.as_str(), body.as_str()).await,
+            Some((None, _, _)) => Err("smtp is not configured".to_string()),
+            None => Err("settings cannot be read".to_string()),
+        };
+        if let Err(error) = &res {
+            log::error!("send_user_token error: {}", error);
+        }
+        InviteResponse {
+            msg: "success".to_string(),
+            user: utils::guid_into_uuid(user_id.clone()).unwrap_or_default(),
+            expires_at,
+            sent: res.is_ok(),
+            error: res.err(),
+        }
+    }
+
+    /// Render the mail of a token from the `mail_invite_*` or `mail_reset_*` settings with the `{name}`, `{link}`, `{token}`,
+    /// `{expires_at}` and `{server}` placeholders, the link points to the web console at the `public_url` setting
+    ///
+    /// # Returns
+    ///
+    /// - `Option<(Option<mailer::SmtpSettings>, String, String)>` - The smtp settings, None if smtp is not configured, the subject and the body
+    async fn render_token_mail(
+        db: &Database,
+        kind: UserTokenKind,
+        name: &str,
+        token: &str,
+        expires_at: &str,
+    ) -> Option<(Option<mailer::SmtpSettings>, String, String)> {
+        let settings: HashMap<String, String> = db
+            .get_settings()
+            .await?
+            .into_iter()
+            .map(|setting| (setting.key, setting.value))
+            .collect();
+        let public_url = settings
+            .get("public_url")
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        let (template, path) = match kind {
+            UserTokenKind::Invite => ("mail_invite", "invite"),
+            UserTokenKind::PasswordReset => ("mail_reset", "password/reset"),
+        };
+        let link = format!("{}/ui/#/{}?token={}", public_url, path, token);
+        let values = [
+            ("name", name),
+            ("link", link.as_str()),
+            ("token", token),
+            ("expires_at", expires_at),
+            ("server", public_url.as_str()),
+        ];
+        let setting = |key: String| settings.get(&key).cloned().unwrap_or_default();
+        let subject = mailer::render_template(&setting(format!("{}_subject", template)), &values);
+        let body = mailer::render_template(&setting(format!("{}_body", template)), &values);
+        Some((mailer::SmtpSettings::from_settings(&settings), subject, body))
+    }
+
     /// Change user status
     pub async fn user_change_status(&self, user: &str, disable: bool) -> Option<()> {
         self.db.user_change_status(user, disable as u32).await
@@ -638,8 +1850,9 @@ impl ApiState {
         email: Option<&str>,
         current: u32,
         page_size: u32,
+        team: Option<&str>,
     ) -> Option<Vec<UserListResponse>> {
-        self.db.get_all_users(name, email, current, page_size).await
+        self.db.get_all_users(name, email, current, page_size, team).await
     }
 
     /// Update a user
@@ -651,14 +1864,117 @@ impl ApiState {
         self.db.user_update(user_id, user_parameters).await
     }
 
-    /// Get all peers
-    pub async fn get_all_peers(&self) -> Option<Vec<Peer>> {
-        self.db.get_all_peers().await
+    /// Get all peers, or only the peers of the users of `team`
+    /// Get the peers of a team
+    ///
+    /// # Arguments
+    ///
+    /// - `team` - The team scope, None for every team
+    ///
+    /// - `viewer` - The user restricting the peers to its own and to the ones reachable with the cross group rules, None for every peer
+    pub async fn get_all_peers(&self, team: Option<&str>, viewer: Option<&UserId>) -> Option<Vec<Peer>> {
+        self.db.get_all_peers(team, viewer, None).await
+    }
+
+    /// Get the peers owned by a user
+    pub async fn get_user_peers(&self, user_id: &UserId) -> Option<Vec<Peer>> {
+        self.db.get_all_peers(None, None, Some(user_id)).await
+    }
+
+    /// Get the peers visibility of a user
+    /// The users with the `peers.read` permission see every peer of their team,
+    /// the other users only see their own peers and the peers reachable with the cross group rules of their group
+    ///
+    /// # Returns
+    ///
+    /// - `Option<(Option<String>, Option<UserId>)>` - The team scope and the viewer restricting the peers, None if the user does not exist
+    pub async fn get_peers_visibility(
+        &self,
+        user_id: &UserId,
+    ) -> Option<(Option<String>, Option<UserId>)> {
+        let team = self.db.get_user_team_scope(user_id).await?;
+        if self.has_permission(user_id, Permission::PeersRead).await {
+            Some((team, None))
+        } else {
+            Some((team, Some(user_id.clone())))
+        }
     }
 
-    /// Get groups
-    pub async fn get_groups(&self, offset: u32, page_size: u32) -> Option<Vec<Group>> {
-        self.db.get_groups(offset, page_size).await
+    /// Get the uptime of all peers for a period
+    /// `from` and `to` are dates formatted as YYYY-MM-DD, `to` is included in the period
+    /// By default the period is the last 7 days, it cannot be longer than 366 days
+    ///
+    /// # Returns
+    ///
+    /// - `Option<(String, String, Vec<PeerUptime>)>` - The first and last day of the period and the uptime of each peer,
+    ///   None if the period is invalid or too long
+    pub async fn get_peers_uptime(
+        &self,
+        from: Option<&str>,
+        to: Option<&str>,
+        team: Option<&str>,
+    ) -> Option<(String, String, Vec<PeerUptime>)> {
+        let today = chrono::Utc::now().date_naive();
+        let to_date = match to {
+            Some(to) => NaiveDate::parse_from_str(to, "%Y-%m-%d").ok()?,
+            None => today,
+        };
+        let from_date = match from {
+            Some(from) => NaiveDate::parse_from_str(from, "%Y-%m-%d").ok()?,
+            None => to_date - chrono::Days::new(6),
+        };
+        if from_date > to_date || (to_date - from_date).num_days() >= PEERS_UPTIME_MAX_DAYS {
+            return None;
+        }
+        let from_secs = from_date.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+        let mut to_secs = (to_date + chrono::Days::new(1))
+            .and_hms_opt(0, 0, 0)?
+            .and_utc()
+            .timestamp();
+        // do not count the future as downtime
+        to_secs = to_secs.min(chrono::Utc::now().timestamp()).max(from_secs + 1);
+        let uptimes = self.db.get_peers_uptime(from_secs, to_secs, team).await?;
+        Some((from_date.to_string(), to_date.to_string(), uptimes))
+    }
+
+    /// Get the system info history of a peer given its id
+    pub async fn get_peer_sysinfo_history(
+        &self,
+        id: &str,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerSysinfoSnapshot>> {
+        self.db.get_peer_sysinfo_history(id, team).await
+    }
+
+    /// Get the peers running a client older than `version`
+    /// Peers which never reported their version are not included
+    pub async fn get_peers_with_version_below(
+        &self,
+        version: &str,
+        team: Option<&str>,
+    ) -> Option<Vec<Peer>> {
+        let peers = self.db.get_all_peers(team, None, None).await?;
+        Some(
+            peers
+                .into_iter()
+                .filter(|peer| match &peer.info.version {
+                    Some(peer_version) => {
+                        compare_versions(peer_version, version) == std::cmp::Ordering::Less
+                    }
+                    None => false,
+                })
+                .collect(),
+        )
+    }
+
+    /// Get groups, or only the groups of `team`
+    pub async fn get_groups(
+        &self,
+        offset: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<Vec<Group>> {
+        self.db.get_groups(offset, page_size, team).await
     }
 
     /// Get shared address books
@@ -666,8 +1982,19 @@ impl ApiState {
         self.db.get_shared_address_books(user_id).await
     }
 
-    pub async fn get_ab_rules(&self, offset: u32, page_size: u32, ab: &str) -> Option<Vec<AbRule>> {
-        self.db.get_ab_rules(offset, page_size, ab).await
+    /// Get a page of the rules of a shared address book and the number of rules
+    ///
+    /// # Arguments
+    ///
+    /// - `current` - the page number, starting at 1
+    /// - `page_size` - the number of rules per page
+    pub async fn get_ab_rules(
+        &self,
+        current: u32,
+        page_size: u32,
+        ab: &str,
+    ) -> Option<(u32, Vec<AbRule>)> {
+        self.db.get_ab_rules(current, page_size, ab).await
     }
 
     pub async fn delete_ab_rule(&self, rule: &str) -> Option<()> {
@@ -678,20 +2005,262 @@ impl ApiState {
         self.db.add_ab_rule(rule).await
     }
 
-    pub async fn get_peers_count(&self, platform: Platform) -> u32 {
-        self.db.get_peers_count(platform).await
+    /// Get the address book of a rule
+    pub async fn get_ab_rule_ab(&self, rule: &str) -> Option<String> {
+        self.db.get_ab_rule_ab(rule).await
+    }
+
+    /// Get the effective rule level of a user on an address book
+    /// The owner has full control, otherwise it is the highest rule given to the user,
+    /// to its group or to everyone
+    ///
+    /// # Returns
+    ///
+    /// - `Option<u32>` - The rule level, 0 if the user has no access, None if the address book does not exist
+    pub async fn get_ab_effective_rule(&self, user_id: &UserId, ab: &str) -> Option<u32> {
+        self.db.get_ab_effective_rule(user_id, ab).await
+    }
+
+    /// Check if a user has at least the given access level on an address book
+    ///
+    /// # Arguments
+    ///
+    /// - `user_id` - The user guid
+    /// - `ab` - The address book guid
+    /// - `level` - The minimum access level
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - true if the access is granted
+    pub async fn check_ab_access(&self, user_id: &UserId, ab: &str, level: AbRuleLevel) -> bool {
+        self.db
+            .get_ab_effective_rule(user_id, ab)
+            .await
+            .map(|rule| rule >= level as u32)
+            .unwrap_or(false)
+    }
+
+    /// Check if a user controls a shared address book, i.e. manages its rules, renames or deletes it
+    /// The global admins control every shared address book, the other users need full control on it
+    /// The personal address books cannot be controlled, not even by their owner
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - true if the shared address book exists and the user controls it
+    pub async fn check_ab_control(&self, user_id: &UserId, ab: &str) -> bool {
+        if !self.db.is_shared_address_book(ab).await {
+            return false;
+        }
+        let is_admin = matches!(self.db.get_user_permissions(user_id).await, Some((true, _)));
+        if is_admin && self.db.get_user_team_scope(user_id).await == Some(None) {
+            return true;
+        }
+        self.check_ab_access(user_id, ab, AbRuleLevel::FullControl).await
+    }
+
+    pub async fn get_peers_count(
+        &self,
+        platform: Platform,
+        team: Option<&str>,
+        viewer: Option<&UserId>,
+    ) -> u32 {
+        self.db.get_peers_count(platform, team, viewer).await
+    }
+
+    pub async fn get_cpus_count(&self, team: Option<&str>, viewer: Option<&UserId>) -> Vec<CpuCount> {
+        self.db.get_cpus_count(team, viewer).await
+    }
+
+    pub async fn get_peers_stats(
+        &self,
+        field: PeerStatField,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerStat>> {
+        self.db.get_peers_stats(field, team).await
+    }
+
+    /// Get the number of active peers per day for a period
+    /// A peer is active on a day if it was online at least once during this day
+    /// `from` and `to` follow the same rules as in `get_peers_uptime`
+    pub async fn get_active_peers_per_day(
+        &self,
+        from: Option<&str>,
+        to: Option<&str>,
+        team: Option<&str>,
+    ) -> Option<Vec<PeerActiveDay>> {
+        let (from, to, uptimes) = self.get_peers_uptime(from, to, team).await?;
+        let mut days: Vec<PeerActiveDay> = Vec::new();
+        let mut day = NaiveDate::parse_from_str(&from, "%Y-%m-%d").ok()?;
+        let to = NaiveDate::parse_from_str(&to, "%Y-%m-%d").ok()?;
+        while day <= to {
+            days.push(PeerActiveDay {
+                day: day.to_string(),
+                total: 0,
+            });
+            day = day + chrono::Days::new(1);
+        }
+        for uptime in uptimes {
+            for bucket in uptime.histogram.iter().filter(|b| b.online_secs > 0) {
+                if let Some(active_day) = days.iter_mut().find(|d| d.day == bucket.day) {
+                    active_day.total += 1;
+                }
+            }
+        }
+        Some(days)
+    }
+
+    /// Create a group with its cross group access
+    ///
+    /// # Arguments
+    ///
+    /// - `team` - The guid of the team of the group
+    ///
+    /// - `allowed_incomings` - The guids of the groups allowed to connect to the devices of the group
+    ///
+    /// - `allowed_outgoings` - The guids of the groups whose devices the group is allowed to connect to
+    ///
+    /// # Returns
+    ///
+    /// - `Option<String>` - The guid of the group, None if the group exists or a group guid is invalid
+    pub async fn create_group(
+        &self,
+        name: &str,
+        team: &str,
+        note: &str,
+        allowed_incomings: &[String],
+        allowed_outgoings: &[String],
+    ) -> Option<String> {
+        self.db
+            .create_group(name, team, note, allowed_incomings, allowed_outgoings)
+            .await
+    }
+
+    /// Update a group and replace its cross group access
+    /// The team of the group is not modified
+    ///
+    /// # Returns
+    ///
+    /// - `Option<()>` - None if the group does not exist or a group guid is invalid
+    pub async fn update_group(
+        &self,
+        guid: &str,
+        name: &str,
+        note: &str,
+        allowed_incomings: &[String],
+        allowed_outgoings: &[String],
+    ) -> Option<()> {
+        self.db
+            .update_group(guid, name, note, allowed_incomings, allowed_outgoings)
+            .await
     }
 
-    pub async fn get_cpus_count(&self) -> Vec<CpuCount> {
-        self.db.get_cpus_count().await
+    /// Get the team a user is restricted to
+    /// The members of the `Default` team are not restricted, their admins manage every team
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Option<String>>` - None if the user does not exist, None for the `Default` team or the team guid otherwise
+    pub async fn get_user_team_scope(&self, user_id: &UserId) -> Option<Option<String>> {
+        self.db.get_user_team_scope(user_id).await
     }
 
-    pub async fn create_group(&self, name: &str, team: &str, note: &str) -> Option<()> {
-        self.db.create_group(name, team, note).await
+    /// Check if a user, a group, a peer or an address book is visible in a team scope
+    /// Everything is visible without scope, peers without owner are only visible without scope
+    ///
+    /// # Arguments
+    ///
+    /// - `team` - The team scope, None for every team
+    ///
+    /// - `member` - The checked item
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - true if the item exists and is visible
+    pub async fn is_in_team_scope(&self, team: Option<&str>, member: TeamMember<'_>) -> bool {
+        match self.db.get_team_of(member).await {
+            Some(member_team) => team.is_none() || member_team.as_deref() == team,
+            None => false,
+        }
     }
 
-    pub async fn update_group(&self, guid: &str, name: &str, team: &str, note: &str) -> Option<()> {
-        self.db.update_group(guid, name, team, note).await
+    /// Get a page of the teams and the number of teams
+    ///
+    /// # Arguments
+    ///
+    /// - `team` - The team scope, only this team is returned if it is given
+    pub async fn get_teams(
+        &self,
+        current: u32,
+        page_size: u32,
+        team: Option<&str>,
+    ) -> Option<(u32, Vec<Team>)> {
+        self.db.get_teams(current, page_size, team).await
+    }
+
+    /// Create a team
+    ///
+    /// # Returns
+    ///
+    /// - `Option<String>` - The guid of the team, None if the name exists
+    pub async fn create_team(&self, name: &str, email: &str, note: Option<&str>) -> Option<String> {
+        self.db.create_team(name, email, note).await
+    }
+
+    /// Update a team
+    pub async fn update_team(
+        &self,
+        guid: &str,
+        name: &str,
+        email: &str,
+        note: Option<&str>,
+    ) -> Option<()> {
+        self.db.update_team(guid, name, email, note).await
+    }
+
+    /// Delete a team
+    /// The `Default` team and the teams which still have users or groups cannot be deleted
+    pub async fn delete_team(&self, guid: &str) -> Option<()> {
+        self.db.delete_team(guid).await
+    }
+
+    /// Check if a connection is allowed by the cross group access
+    /// The group of a device is the group of the user owning it, a connection between
+    /// two groups is allowed when they are the same or when the incoming group lists the
+    /// outgoing group in its allowed incomings
+    /// Devices without owner are not restricted
+    ///
+    /// # Arguments
+    ///
+    /// - `from_user` - The name of the user initiating the connection, takes precedence over `from_device`
+    ///
+    /// - `from_device` - The id of the device initiating the connection
+    ///
+    /// - `to_device` - The id of the device receiving the connection
+    ///
+    /// # Returns
+    ///
+    /// - `Option<CrossGroupAccessResponse>` - None if the user or one of the devices does not exist
+    pub async fn check_cross_group_access(
+        &self,
+        from_user: Option<&str>,
+        from_device: Option<&str>,
+        to_device: &str,
+    ) -> Option<CrossGroupAccessResponse> {
+        let from_group = match (from_user, from_device) {
+            (Some(user), _) => self.db.get_user_group_by_name(user).await?,
+            (None, Some(device)) => self.db.get_peer_group(device).await?,
+            (None, None) => return None,
+        };
+        let to_group = self.db.get_peer_group(to_device).await?;
+        let allowed = match (&from_group, &to_group) {
+            (Some(from), Some(to)) if from != to => self.db.is_cross_group_allowed(from, to).await?,
+            _ => true,
+        };
+        Some(CrossGroupAccessResponse {
+            allowed,
+            from_group,
+            to_group,
+        })
     }
 
     pub async fn get_group(&self, guid: &str) -> Option<Group> {
@@ -732,3 +2301,902 @@ impl ApiState {
         self.db.update_shared_address_book(guid, name).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::{guid_into_uuid, UserNotifications, DEFAULT_TEAM_GUID};
+
+    /// Create a state in a temporary directory, with an address book key which is not written in the working directory
+    async fn test_state(dir: &tempfile::TempDir) -> ApiState {
+        let keyring = AbKeyring::from_master_keys(AbKeySource::Env, vec![crate::ab_crypto::generate_master_key()]);
+        ApiState::new_with_db_and_keyring(dir.path().join("db.sqlite3"), keyring).await
+    }
+
+    async fn create_user(state: &ApiState, name: &str, group_name: &str) -> UserId {
+        state
+            .add_user(AddUserRequest {
+                name: name.to_string(),
+                password: "password".to_string(),
+                confirm_password: "password".to_string(),
+                email: format!("{}@example.org", name),
+                is_admin: false,
+                group_name: group_name.to_string(),
+            }, None)
+            .await
+            .expect("add_user failed");
+        let (_, user) = state.db.find_user_by_name(name).await;
+        user.expect("user not found").0
+    }
+
+    async fn add_rule(state: &ApiState, ab: &str, user: Option<&UserId>, group: Option<&str>, rule: u32) {
+        state
+            .add_ab_rule(AbRule {
+                guid: ab.to_string(),
+                user: user.map(|user| guid_into_uuid(user.clone()).unwrap()),
+                group: group.map(|group| group.to_string()),
+                rule,
+            })
+            .await
+            .expect("add_ab_rule failed");
+    }
+
+    async fn assert_levels(state: &ApiState, user: &UserId, ab: &str, expected: u32) {
+        for level in [AbRuleLevel::Read, AbRuleLevel::ReadWrite, AbRuleLevel::FullControl] {
+            assert_eq!(
+                state.check_ab_access(user, ab, level).await,
+                level as u32 <= expected,
+                "level {:?} with effective rule {}",
+                level,
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_team_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let team = state.create_team("Customer", "it@customer.org", None).await.unwrap();
+        assert!(state.create_team("Customer", "it@customer.org", None).await.is_none());
+        let group = state.create_group("Customer staff", &team, "", &[], &[]).await.unwrap();
+
+        let staff = create_user(&state, "staff", "Customer staff").await;
+        let operator = create_user(&state, "operator", "Default").await;
+        assert_eq!(state.get_user_team_scope(&staff).await, Some(Some(team.clone())));
+        assert_eq!(state.get_user_team_scope(&operator).await, Some(None));
+
+        // a team only sees its own users and groups
+        let users = state.get_all_users(None, None, 1, 100, Some(&team)).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "staff");
+        assert_eq!(state.get_all_users(None, None, 1, 100, None).await.unwrap().len(), 3);
+        let groups = state.get_groups(0, 100, Some(&team)).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].guid, group);
+        let staff_guid = guid_into_uuid(staff).unwrap();
+        let operator_guid = guid_into_uuid(operator).unwrap();
+        assert!(state.is_in_team_scope(Some(&team), TeamMember::User(&staff_guid)).await);
+        assert!(!state.is_in_team_scope(Some(&team), TeamMember::User(&operator_guid)).await);
+        assert!(state.is_in_team_scope(None, TeamMember::User(&staff_guid)).await);
+
+        // a team admin cannot put users in the groups of another team
+        let request = AddUserRequest {
+            name: "intruder".to_string(),
+            password: "password".to_string(),
+            confirm_password: "password".to_string(),
+            email: "intruder@example.org".to_string(),
+            is_admin: false,
+            group_name: "Default".to_string(),
+        };
+        assert!(state.add_user(request, Some(&team)).await.is_none());
+
+        // teams in use and the Default team cannot be deleted
+        assert!(state.delete_team(&team).await.is_none());
+        assert!(state.delete_team(DEFAULT_TEAM_GUID).await.is_none());
+        state.user_delete(&staff_guid, None, false).await.unwrap();
+        state.delete_group(&group).await.unwrap();
+        state.delete_team(&team).await.unwrap();
+        assert_eq!(state.get_teams(1, 100, None).await.unwrap().0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_import_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let user = |name: &str, group: &str, is_admin: bool| UserTransfer {
+            name: name.to_string(),
+            email: Some(format!("{}@example.org", name)),
+            group: group.to_string(),
+            is_admin,
+            note: None,
+            password: None,
+            data: Vec::new(),
+        };
+        let users = vec![
+            Ok(user("alice", "Accounting", false)),
+            Ok(user("bob", "Accounting", true)),
+            Err("missing name".to_string()),
+            Ok(user("alice", "Default", false)),
+        ];
+
+        // the errors are reported for each row and nothing is imported
+        let report = state.import_users(&users, true, false, None, false).await.unwrap();
+        assert!(!report.imported);
+        assert_eq!(report.users_created, 1);
+        assert_eq!(report.users_failed, 3);
+        assert!(report.rows[0].error.is_none() && report.rows[0].generated_password.is_none());
+        assert!(report.rows[1].error.is_some());
+        assert!(report.rows[3].error.is_some());
+        assert_eq!(state.get_all_users(None, None, 1, 100, None).await.unwrap().len(), 1);
+
+        // a dry run validates without importing
+        let users = vec![Ok(user("alice", "Accounting", false)), Ok(user("bob", "Accounting", true))];
+        let report = state.import_users(&users, false, true, None, true).await.unwrap();
+        assert_eq!(report.users_failed, 2);
+        let report = state.import_users(&users, true, true, None, true).await.unwrap();
+        assert_eq!(report.users_failed, 0);
+        assert!(!report.imported);
+        assert_eq!(report.groups_created, vec!["Accounting".to_string()]);
+        assert!(state.get_groups(0, 100, None).await.unwrap().iter().all(|group| group.name != "Accounting"));
+
+        let report = state.import_users(&users, true, true, None, false).await.unwrap();
+        assert!(report.imported);
+        let password = report.rows[0].generated_password.clone().unwrap();
+        assert!(state
+            .user_login(&"alice".to_string(), UserPasswordInfo::from_password(&password), false, None)
+            .await
+            .is_some());
+
+        let exported = state.export_users(None).await.unwrap();
+        assert_eq!(exported.len(), 3);
+        assert_eq!(exported[1].group, "Accounting");
+        assert!(exported[1].password.is_none());
+        assert!(exported[2].is_admin);
+    }
+
+    #[tokio::test]
+    async fn test_invitations() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let login = |name: &'static str, password: &'static str| {
+            let state = &state;
+            async move {
+                state
+                    .user_login(&name.to_string(), UserPasswordInfo::from_password(password), false, None)
+                    .await
+                    .is_some()
+            }
+        };
+        // the mail only contains the token for reading it easily
+        state.set_setting("mail_invite_body", "{token}").await.unwrap();
+        state.set_setting("mail_reset_body", "{token}").await.unwrap();
+        let request = InviteUserRequest {
+            name: "alice".to_string(),
+            email: "alice@example.org".to_string(),
+            group_name: "Default".to_string(),
+            is_admin: false,
+        };
+
+        // nothing is left behind when the invitation cannot be created
+        let mut unknown_group = request.clone();
+        unknown_group.group_name = "Unknown".to_string();
+        assert!(state.invite_user(unknown_group, None).await.is_none());
+        assert!(state.db.find_user_by_name("alice").await.1.is_none());
+
+        // without smtp the invitation is created but not sent
+        let response = state.invite_user(request.clone(), None).await.unwrap();
+        assert!(!response.sent);
+        assert!(state.invite_user(request, None).await.is_none());
+        let (_, alice) = state.db.find_user_by_name("alice").await;
+        let (alice, _, info) = alice.unwrap();
+        assert!(!info.active);
+        assert_eq!(state.get_user_invites(1, 10, None).await.unwrap().0, 1);
+
+        let (smtp, sink) = mailer::tests::smtp_sink().await;
+        state.set_setting("smtp_host", smtp.host.as_str()).await.unwrap();
+        state.set_setting("smtp_port", smtp.port.to_string().as_str()).await.unwrap();
+        state.set_setting("smtp_security", "none").await.unwrap();
+        // the smtp password is masked and writing the mask back keeps it
+        state.set_setting("smtp_password", "secret").await.unwrap();
+        let settings = state.get_settings().await.unwrap();
+        let password = settings.iter().find(|setting| setting.key == "smtp_password").unwrap();
+        assert_eq!(password.value, SECRET_SETTING_MASK);
+        state.set_setting("smtp_password", SECRET_SETTING_MASK).await.unwrap();
+        assert_eq!(state.db.get_setting("smtp_password").await.as_deref(), Some("secret"));
+        assert!(state.resend_invite(&alice).await.unwrap().sent);
+        let mail = sink.await.unwrap();
+        let token = mail.lines().last().unwrap().trim().to_string();
+
+        // the token is single use and only accepts an invitation
+        assert!(state
+            .set_password_with_token(UserTokenKind::PasswordReset, &token, "first password")
+            .await
+            .is_none());
+        state
+            .set_password_with_token(UserTokenKind::Invite, &token, "first password")
+            .await
+            .unwrap();
+        assert!(state
+            .set_password_with_token(UserTokenKind::Invite, &token, "other password")
+            .await
+            .is_none());
+        assert!(login("alice", "first password").await);
+        assert!(state.resend_invite(&alice).await.is_none());
+        assert_eq!(state.get_user_invites(1, 10, None).await.unwrap().0, 0);
+
+        // nothing is sent for an unknown email, the mail of a known email is sent in the background
+        let (smtp, sink) = mailer::tests::smtp_sink().await;
+        state.set_setting("smtp_port", smtp.port.to_string().as_str()).await.unwrap();
+        assert!(state.request_password_reset("nobody@example.org", Some("192.0.2.1")).await);
+        assert!(state.request_password_reset("alice@example.org", Some("192.0.2.1")).await);
+        let mail = sink.await.unwrap();
+        assert!(mail.contains("alice@example.org"));
+        let token = mail.lines().last().unwrap().trim().to_string();
+        // a disabled user cannot reset its password
+        let alice_guid = guid_into_uuid(alice.clone()).unwrap();
+        state.db.user_change_status(&alice_guid, 0).await.unwrap();
+        assert!(state
+            .set_password_with_token(UserTokenKind::PasswordReset, &token, "second password")
+            .await
+            .is_none());
+        state.db.user_change_status(&alice_guid, 1).await.unwrap();
+        state
+            .set_password_with_token(UserTokenKind::PasswordReset, &token, "second password")
+            .await
+            .unwrap();
+        assert!(login("alice", "second password").await);
+        assert!(!login("alice", "first password").await);
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let ip = Some("192.0.2.1");
+        for _ in 0..PASSWORD_RESET_MAX_PER_EMAIL {
+            assert!(state.request_password_reset("alice@example.org", ip).await);
+        }
+        assert!(!state.request_password_reset("alice@example.org", Some("192.0.2.2")).await);
+        assert!(!state.request_password_reset("Alice@Example.org", None).await);
+        for i in PASSWORD_RESET_MAX_PER_EMAIL..PASSWORD_RESET_MAX_PER_IP {
+            assert!(state.request_password_reset(&format!("user{}@example.org", i), ip).await);
+        }
+        assert!(!state.request_password_reset("bob@example.org", ip).await);
+        assert!(state.request_password_reset("bob@example.org", Some("192.0.2.2")).await);
+        assert!(state.request_password_reset("carol@example.org", None).await);
+    }
+
+    #[tokio::test]
+    async fn test_import_ab() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let alice = create_user(&state, "alice", "Default").await;
+        let personal = state.get_ab_personal_guid(alice.clone()).await.unwrap();
+        let peer = |id: &str, alias: &str, hostname: Option<&str>, tags: &[&str]| AbPeer {
+            id: id.to_string(),
+            alias: Some(alias.to_string()),
+            hostname: hostname.map(|hostname| hostname.to_string()),
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        };
+        let data = AbExport {
+            tags: vec![],
+            peers: vec![
+                peer("100", "first", Some("host1"), &["work"]),
+                peer("200", "second", Some("host2"), &["home"]),
+            ],
+        };
+        let report = state
+            .import_ab(&personal, data, AbImportMode::Merge, false, &alice)
+            .await
+            .unwrap();
+        assert_eq!(report.peers_added, vec!["100", "200"]);
+        assert_eq!(report.tags_added, vec!["work", "home"]);
+
+        // merge keeps the fields missing from the imported peers
+        let data = AbExport {
+            tags: vec![],
+            peers: vec![peer("100", "renamed", None, &["work"])],
+        };
+        let report = state
+            .import_ab(&personal, data, AbImportMode::Merge, false, &alice)
+            .await
+            .unwrap();
+        assert_eq!(report.peers_updated, vec!["100"]);
+        assert!(report.peers_removed.is_empty());
+        let export = state.export_ab(&personal).await.unwrap();
+        let first = export.peers.iter().find(|p| p.id == "100").unwrap();
+        assert_eq!(first.alias.as_deref(), Some("renamed"));
+        assert_eq!(first.hostname.as_deref(), Some("host1"));
+        assert_eq!(export.peers.len(), 2);
+
+        // a dry run does not change anything
+        let data = AbExport {
+            tags: vec![],
+            peers: vec![peer("100", "replaced", None, &["work"])],
+        };
+        let report = state
+            .import_ab(&personal, data.clone(), AbImportMode::Replace, true, &alice)
+            .await
+            .unwrap();
+        assert_eq!(report.peers_removed, vec!["200"]);
+        assert_eq!(report.tags_removed, vec!["home"]);
+        assert_eq!(state.export_ab(&personal).await.unwrap().peers.len(), 2);
+
+        // replace drops the fields missing from the imported peers and the peers and tags not imported
+        state
+            .import_ab(&personal, data, AbImportMode::Replace, false, &alice)
+            .await
+            .unwrap();
+        let export = state.export_ab(&personal).await.unwrap();
+        assert_eq!(export.peers.len(), 1);
+        assert_eq!(export.peers[0].alias.as_deref(), Some("replaced"));
+        assert_eq!(export.peers[0].hostname, None);
+        let tags: Vec<&str> = export.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(tags, vec!["work"]);
+
+        // the lists of peers report the last change, which is never stored with the peer
+        let (_, page) = state
+            .get_ab_peers_page(&personal, None, None, None, 1, 10)
+            .await
+            .unwrap();
+        assert_eq!(page[0].updated_by.as_deref(), Some("alice"));
+        assert!(page[0].updated_at.is_some());
+        let bob = create_user(&state, "bob", "Default").await;
+        let edited = AbPeer {
+            alias: Some("edited".to_string()),
+            updated_by: Some("mallory".to_string()),
+            ..page[0].clone()
+        };
+        state.db.add_peer_to_ab(&personal, edited, &bob).await.unwrap();
+        let (_, page) = state
+            .get_ab_peers_page(&personal, None, None, None, 1, 10)
+            .await
+            .unwrap();
+        assert_eq!(page[0].alias.as_deref(), Some("edited"));
+        assert_eq!(page[0].updated_by.as_deref(), Some("bob"));
+        let export = state.export_ab(&personal).await.unwrap();
+        assert_eq!(export.peers[0].updated_by, None);
+        assert_eq!(export.peers[0].updated_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_peers_uptime_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let (from, to, _) = state.get_peers_uptime(None, Some("2024-05-07"), None).await.unwrap();
+        assert_eq!((from.as_str(), to.as_str()), ("2024-05-01", "2024-05-07"));
+        assert!(state.get_peers_uptime(Some("2024-05-08"), Some("2024-05-07"), None).await.is_none());
+        assert!(state.get_peers_uptime(Some("2024-05-01"), Some("bad"), None).await.is_none());
+        // the period is limited to 366 days
+        assert!(state.get_peers_uptime(Some("2024-01-01"), Some("2024-12-31"), None).await.is_some());
+        assert!(state.get_peers_uptime(Some("2023-12-31"), Some("2024-12-31"), None).await.is_none());
+        assert!(state.get_peers_uptime(Some("0001-01-01"), None, None).await.is_none());
+        let days = state
+            .get_active_peers_per_day(Some("2024-01-01"), Some("2024-12-31"), None)
+            .await
+            .unwrap();
+        assert_eq!(days.len(), 366);
+        assert!(state.get_active_peers_per_day(Some("0001-01-01"), None, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wait_ab_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let alice = create_user(&state, "alice", "Default").await;
+        let bob = create_user(&state, "bob", "Default").await;
+        let alice_ab = state.get_ab_personal_guid(alice.clone()).await.unwrap();
+        let bob_ab = state.get_ab_personal_guid(bob.clone()).await.unwrap();
+        let ab_peer = |id: &str| AbPeer {
+            id: id.to_string(),
+            ..Default::default()
+        };
+
+        state.db.add_peer_to_ab(&alice_ab, ab_peer("100"), &alice).await.unwrap();
+        let changes = state.wait_ab_changes(&alice, None, Duration::ZERO).await.unwrap();
+        assert_eq!(changes.total, 1);
+        let revision = changes.revision;
+
+        // the changes of the address books the user cannot read are not reported, nor their revision
+        state.db.add_peer_to_ab(&bob_ab, ab_peer("200"), &bob).await.unwrap();
+        let changes = state.wait_ab_changes(&alice, None, Duration::ZERO).await.unwrap();
+        assert_eq!(changes.revision, revision);
+        let changes = state
+            .wait_ab_changes(&alice, Some(revision), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(changes.total, 0);
+        assert_eq!(changes.revision, revision);
+        let changes = state.wait_ab_changes(&bob, None, Duration::ZERO).await.unwrap();
+        assert!(changes.revision > revision);
+
+        state.db.add_peer_to_ab(&alice_ab, ab_peer("101"), &alice).await.unwrap();
+        let changes = state
+            .wait_ab_changes(&alice, Some(revision), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(changes.total, 1);
+        assert_eq!(changes.data[0].guid, alice_ab);
+        assert!(changes.revision > revision);
+
+        // the deletion of an empty shared address book wakes up the users waiting for its changes
+        let shared = state
+            .add_shared_address_book("shared", guid_into_uuid(bob.clone()).unwrap().as_str())
+            .await
+            .unwrap();
+        add_rule(&state, &shared, Some(&alice), None, AbRuleLevel::Read as u32).await;
+        let revision = state.wait_ab_changes(&alice, None, Duration::ZERO).await.unwrap().revision;
+        let started = tokio::time::Instant::now();
+        let (changes, _) = tokio::join!(
+            state.wait_ab_changes(&alice, Some(revision), Duration::from_secs(30)),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                state.delete_shared_address_book(&shared).await.unwrap();
+            }
+        );
+        let changes = changes.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(changes.total, 1);
+        assert_eq!(changes.data[0].guid, shared);
+        assert!(state.get_ab_peers(&shared).await.unwrap().is_empty());
+        assert!(state.delete_shared_address_book(&shared).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_password_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        state.set_setting("password_min_classes", "2").await.unwrap();
+        state.set_setting("password_history_count", "2").await.unwrap();
+        state.set_setting("password_hash_cost", "4").await.unwrap();
+        assert!(state.check_password(None, "").await.is_err());
+        assert!(state.check_password(None, "Pass1").await.is_err());
+        assert!(state.check_password(None, "longenough").await.is_err());
+        assert!(state.check_password(None, "longenough1").await.is_ok());
+
+        let alice = create_user(&state, "alice", "Default").await;
+        for password in ["Password1", "Password2", "Password3"] {
+            state
+                .db
+                .reset_user_password("alice".to_string(), password.to_string())
+                .await
+                .unwrap();
+        }
+        // the current and the 2 previous passwords cannot be reused
+        assert!(state.check_password(Some(&alice), "Password3").await.is_err());
+        assert!(state.check_password(Some(&alice), "Password1").await.is_err());
+        assert!(state.check_password(Some(&alice), "Password5").await.is_ok());
+        state
+            .db
+            .reset_user_password("alice".to_string(), "Password4".to_string())
+            .await
+            .unwrap();
+        assert!(state.check_password(Some(&alice), "Password1").await.is_ok());
+        // the password history also applies to the changes made from the web console
+        assert!(state
+            .ui_reset_user_password("alice".to_string(), "Password3".to_string())
+            .await
+            .is_none());
+        assert!(state
+            .ui_update_user_password("alice".to_string(), "Password4".to_string(), "Password4".to_string())
+            .await
+            .is_none());
+        assert!(state
+            .ui_reset_user_password("nobody".to_string(), "Password6".to_string())
+            .await
+            .is_none());
+
+        // the password is hashed again with the new cost on login, without changing the history
+        state.set_setting("password_hash_cost", "5").await.unwrap();
+        assert!(state
+            .user_login(&"alice".to_string(), UserPasswordInfo::from_password("Password4"), false, None)
+            .await
+            .is_some());
+        let (conn, _) = state.db.find_user_by_name("alice").await;
+        let (_, hashed_password) = state.db.get_user_hashed_password(conn, alice.clone()).await;
+        assert_eq!(UserPasswordInfo::hash_cost(&hashed_password.unwrap().password), Some(5));
+        assert!(state.check_password(Some(&alice), "Password4").await.is_err());
+        assert!(state.check_password(Some(&alice), "Password2").await.is_err());
+        assert!(state
+            .user_login(&"alice".to_string(), UserPasswordInfo::from_password("Password4"), false, None)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_user_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let alice = create_user(&state, "alice", "Default").await;
+        create_user(&state, "bob", "Default").await;
+        let profile = state.get_user_profile(&alice).await.unwrap();
+        assert_eq!(profile.email.as_deref(), Some("alice@example.org"));
+        assert!(!profile.notifications.email_alarm_notification);
+
+        let request = |email: Option<&str>, notifications: Option<UserNotifications>| UpdateProfileRequest {
+            email: email.map(|email| email.to_string()),
+            note: None,
+            notifications,
+        };
+        // the email of another user cannot be taken
+        assert!(state
+            .update_user_profile(&alice, request(Some("bob@example.org"), None))
+            .await
+            .is_none());
+        state
+            .update_user_profile(
+                &alice,
+                request(None, Some(UserNotifications { email_alarm_notification: true })),
+            )
+            .await
+            .unwrap();
+        let profile = state.get_user_profile(&alice).await.unwrap();
+        assert_eq!(profile.email.as_deref(), Some("alice@example.org"));
+        assert!(profile.notifications.email_alarm_notification);
+        state.update_user_profile(&alice, request(Some(""), None)).await.unwrap();
+        let profile = state.get_user_profile(&alice).await.unwrap();
+        assert_eq!(profile.email, None);
+        assert!(profile.notifications.email_alarm_notification);
+
+        // the current password is checked
+        assert!(state
+            .change_user_password(&alice, "wrong password", "new password")
+            .await
+            .is_none());
+        state
+            .change_user_password(&alice, "password", "new password")
+            .await
+            .unwrap();
+        assert!(state
+            .user_login(&"alice".to_string(), UserPasswordInfo::from_password("new password"), false, None)
+            .await
+            .is_some());
+
+        // the personal address book comes first, then the shared address book of the Default group
+        let address_books = state.get_user_address_books(&alice).await.unwrap();
+        assert_eq!(address_books.len(), 2);
+        assert!(address_books[0].personal);
+        assert_eq!(address_books[0].owner, guid_into_uuid(alice.clone()).unwrap());
+        assert_eq!(address_books[0].rule, 3);
+        assert!(!address_books[1].personal);
+    }
+
+    #[tokio::test]
+    async fn test_user_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        state.set_setting("user_data_max_size", "16").await.unwrap();
+        state.set_setting("user_data_max_entries", "2").await.unwrap();
+        let alice = create_user(&state, "alice", "Default").await;
+        let put = |data_type: &'static str, data: &'static str, updated_at: Option<String>| {
+            let state = &state;
+            let alice = &alice;
+            async move {
+                state
+                    .put_user_data(
+                        alice,
+                        data_type,
+                        PutUserDataRequest {
+                            data: data.to_string(),
+                            updated_at,
+                        },
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert!(matches!(put("../layout", "{}", None).await, Err(UserDataError::Invalid(_))));
+        assert!(matches!(put("layout", "01234567890123456", None).await, Err(UserDataError::Invalid(_))));
+        let version = put("layout", "{}", None).await.unwrap();
+        // an existing entry is only replaced at the version read before
+        assert_eq!(put("layout", "[]", None).await, Err(UserDataError::Conflict(Some(version.clone()))));
+        let new_version = put("layout", "[]", Some(version.clone())).await.unwrap();
+        assert!(new_version > version);
+        assert_eq!(
+            put("layout", "[1]", Some(version.clone())).await,
+            Err(UserDataError::Conflict(Some(new_version.clone())))
+        );
+        assert_eq!(state.get_user_data(&alice, "layout").await.unwrap().data, "[]");
+
+        put("favorites", "[]", None).await.unwrap();
+        assert!(matches!(put("recent", "[]", None).await, Err(UserDataError::Invalid(_))));
+        assert_eq!(state.get_user_data_items(&alice).await.unwrap().len(), 2);
+
+        assert_eq!(
+            state.delete_user_data(&alice, "layout", Some(version.as_str())).await.unwrap(),
+            Err(UserDataError::Conflict(Some(new_version.clone())))
+        );
+        state.delete_user_data(&alice, "layout", Some(new_version.as_str())).await.unwrap().unwrap();
+        assert_eq!(
+            state.delete_user_data(&alice, "layout", None).await.unwrap(),
+            Err(UserDataError::Conflict(None))
+        );
+
+        // the data is exported and deleted with the user
+        let users = state.export_users(None).await.unwrap();
+        let exported = users.iter().find(|user| user.name == "alice").unwrap();
+        assert_eq!(exported.data.len(), 1);
+        assert_eq!(exported.data[0].data_type, "favorites");
+        state.user_delete(&guid_into_uuid(alice.clone()).unwrap(), None, false).await.unwrap();
+        assert!(state.get_user_data_items(&alice).await.unwrap().is_empty());
+
+        // concurrent creations cannot exceed the number of entries
+        let bob = create_user(&state, "bob", "Default").await;
+        let create = |data_type: &'static str| {
+            let state = &state;
+            let bob = &bob;
+            async move {
+                let request = PutUserDataRequest {
+                    data: "[]".to_string(),
+                    updated_at: None,
+                };
+                state.put_user_data(bob, data_type, request).await.unwrap()
+            }
+        };
+        let results = tokio::join!(create("a"), create("b"), create("c"), create("d"));
+        let created = [results.0, results.1, results.2, results.3]
+            .iter()
+            .filter(|result| result.is_ok())
+            .count();
+        assert_eq!(created, 2);
+        assert_eq!(state.get_user_data_items(&bob).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_user_deletion() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let owner = create_user(&state, "owner", "Default").await;
+        let heir = create_user(&state, "heir", "Default").await;
+        let reader = create_user(&state, "reader", "Default").await;
+        let owner_guid = guid_into_uuid(owner.clone()).unwrap();
+        let heir_guid = guid_into_uuid(heir.clone()).unwrap();
+        let reader_guid = guid_into_uuid(reader.clone()).unwrap();
+        let shared = state.add_shared_address_book("shared", &owner_guid).await.unwrap();
+        add_rule(&state, &shared, Some(&reader), None, AbRuleLevel::Read as u32).await;
+        let other = state.add_shared_address_book("other", &reader_guid).await.unwrap();
+        add_rule(&state, &other, Some(&owner), None, AbRuleLevel::ReadWrite as u32).await;
+
+        // a user cannot be its own heir
+        assert!(state.user_delete(&owner_guid, Some(&owner_guid), false).await.is_none());
+
+        // the shared address book goes to the heir and the user is archived
+        let summary = state.user_delete(&owner_guid, Some(&heir_guid), true).await.unwrap();
+        assert!(summary.archived);
+        assert_eq!(summary.name, "owner");
+        assert_eq!(summary.transferred_to.as_deref(), Some(heir_guid.as_str()));
+        assert_eq!(summary.address_books_transferred, 1);
+        assert_eq!(summary.address_books_deleted, 1);
+        // the full control rule given to the owner of the shared address book and the rule on the other one
+        assert_eq!(summary.rules_deleted, 2);
+        assert_levels(&state, &heir, &shared, 3).await;
+        assert_levels(&state, &reader, &shared, 1).await;
+        assert_levels(&state, &owner, &other, 0).await;
+
+        // the archived user keeps its name but cannot log in, be enabled, listed or deleted again
+        let (_, archived) = state.db.find_user_by_name("owner").await;
+        assert!(archived.is_some());
+        state.db.user_change_status(&owner_guid, 1).await.unwrap();
+        assert!(state
+            .user_login(&"owner".to_string(), UserPasswordInfo::from_password("password"), false, None)
+            .await
+            .is_none());
+        let users = state.get_all_users(None, None, 1, 100, None).await.unwrap();
+        assert!(users.iter().all(|user| user.name != "owner"));
+        assert!(state.user_delete(&owner_guid, None, false).await.is_none());
+        assert!(state.user_delete(&reader_guid, Some(&owner_guid), false).await.is_none());
+
+        // without heir the owned address books are deleted with the user
+        let summary = state.user_delete(&reader_guid, None, false).await.unwrap();
+        assert!(!summary.archived);
+        assert_eq!(summary.transferred_to, None);
+        assert_eq!(summary.address_books_transferred, 0);
+        assert_eq!(summary.address_books_deleted, 2);
+        assert_eq!(summary.rules_deleted, 2);
+        let (_, deleted) = state.db.find_user_by_name("reader").await;
+        assert!(deleted.is_none());
+        assert_levels(&state, &heir, &other, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_ab_keyring() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db.sqlite3");
+        let master_key = crate::ab_crypto::generate_master_key();
+        let keyring = |master_key: &str| AbKeyring::from_master_keys(AbKeySource::Env, vec![master_key.to_string()]);
+        let state = ApiState::new_with_db_and_keyring(&db, keyring(&master_key)).await;
+        let alice = create_user(&state, "alice", "Default").await;
+        let personal = state.get_ab_personal_guid(alice.clone()).await.unwrap();
+        state.add_ab_peer(&personal, AbPeer::default_test(), &alice).await.unwrap();
+        drop(state);
+
+        let state = ApiState::new_with_db_and_keyring(&db, keyring(&master_key)).await;
+        let peer = state.get_ab_peer(&personal, "123456789").await.unwrap();
+        assert_eq!(peer.password.as_deref(), Some("none"));
+        drop(state);
+
+        // a value which looks encrypted is stored like any other credential
+        let state = ApiState::new_with_db_and_keyring(&db, keyring(&master_key)).await;
+        let bogus = AbPeer {
+            id: "987654321".to_string(),
+            password: Some("enc:v1:bogus:AAAA".to_string()),
+            ..AbPeer::default_test()
+        };
+        state.add_ab_peer(&personal, bogus, &alice).await.unwrap();
+        let peer = state.get_ab_peer(&personal, "987654321").await.unwrap();
+        assert_eq!(peer.password.as_deref(), Some("enc:v1:bogus:AAAA"));
+        // the peers and their revisions
+        assert_eq!(state.rotate_ab_key().await, Some(4));
+        drop(state);
+
+        // with another key the database is opened but the peers cannot be read nor written
+        let other_key = crate::ab_crypto::generate_master_key();
+        let state = ApiState::new_with_db_and_keyring(&db, keyring(&other_key)).await;
+        assert!(state.get_ab_peer(&personal, "123456789").await.is_none());
+        assert_eq!(state.get_ab_peers(&personal).await.map(|peers| peers.len()), Some(0));
+        let page = state.get_ab_peers_page(&personal, None, None, None, 1, 10).await;
+        assert_eq!(page.map(|(_, peers)| peers.len()), Some(0));
+        assert!(state.add_ab_peer(&personal, AbPeer::default_test(), &alice).await.is_none());
+        // the rotation leaves them unchanged
+        assert_eq!(state.rotate_ab_key().await, Some(0));
+        drop(state);
+        let state = ApiState::new_with_db_and_keyring(&db, keyring(&master_key)).await;
+        let peer = state.get_ab_peer(&personal, "123456789").await.unwrap();
+        assert_eq!(peer.password.as_deref(), Some("none"));
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let helpdesk = create_user(&state, "helpdesk", "Default").await;
+        assert_eq!(state.get_user_permissions(&helpdesk).await, Some((false, Vec::new())));
+        assert!(!state.has_permission(&helpdesk, Permission::PeersRead).await);
+
+        let role = state
+            .create_role("Helpdesk", None, &[Permission::PeersManage, Permission::AddressBooksManage])
+            .await
+            .unwrap();
+        assert!(state.create_role("Helpdesk", None, &[]).await.is_none());
+        state.set_user_roles(&helpdesk, std::slice::from_ref(&role)).await.unwrap();
+        // managing the peers includes reading them
+        assert!(state.has_permission(&helpdesk, Permission::PeersRead).await);
+        assert!(state.has_permission(&helpdesk, Permission::AddressBooksManage).await);
+        assert!(!state.has_permission(&helpdesk, Permission::UsersManage).await);
+
+        // unknown roles are rejected without changing the roles of the user
+        assert!(state
+            .set_user_roles(&helpdesk, &[role.clone(), uuid::Uuid::new_v4().to_string()])
+            .await
+            .is_none());
+        assert_eq!(state.get_user_roles(&helpdesk).await.unwrap().len(), 1);
+
+        state
+            .update_role(&role, "Helpdesk", None, &[Permission::UsersManage])
+            .await
+            .unwrap();
+        assert!(state.has_permission(&helpdesk, Permission::UsersManage).await);
+        assert!(!state.has_permission(&helpdesk, Permission::PeersRead).await);
+
+        // a users.manage holder cannot set the password of an admin nor manage a user with more permissions
+        let (_, admin) = state.db.find_user_by_name("admin").await;
+        let admin = admin.unwrap().0;
+        let staff = create_user(&state, "staff", "Default").await;
+        assert!(state.can_manage_user(&helpdesk, &staff).await);
+        assert!(!state.can_manage_user(&helpdesk, &admin).await);
+        let roles_role = state.create_role("Roles", None, &[Permission::RolesManage]).await.unwrap();
+        state.set_user_roles(&staff, &[roles_role]).await.unwrap();
+        assert!(!state.can_manage_user(&helpdesk, &staff).await);
+        assert!(state.can_manage_user(&admin, &staff).await);
+        assert!(state.can_manage_user(&admin, &helpdesk).await);
+
+        state.delete_role(&role).await.unwrap();
+        assert!(state.get_user_roles(&helpdesk).await.unwrap().is_empty());
+        assert!(!state.has_permission(&helpdesk, Permission::UsersManage).await);
+
+        // the admins have every permission
+        assert!(state.has_permission(&admin, Permission::RolesManage).await);
+    }
+
+    #[tokio::test]
+    async fn test_check_ab_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(&dir).await;
+        state
+            .create_group("Support", DEFAULT_TEAM_GUID, "", &[], &[])
+            .await
+            .unwrap();
+        let support_guid = state
+            .get_groups(0, 100, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|group| group.name == "Support")
+            .unwrap()
+            .guid;
+
+        let owner = create_user(&state, "owner", "Default").await;
+        let reader = create_user(&state, "reader", "Default").await;
+        let writer = create_user(&state, "writer", "Support").await;
+        let other = create_user(&state, "other", "Default").await;
+
+        // personal address books are only available to their owner
+        let personal = state.get_ab_personal_guid(owner.clone()).await.unwrap();
+        assert_levels(&state, &owner, &personal, 3).await;
+        assert_levels(&state, &other, &personal, 0).await;
+
+        let shared = state
+            .add_shared_address_book("shared", guid_into_uuid(owner.clone()).unwrap().as_str())
+            .await
+            .unwrap();
+        assert_levels(&state, &owner, &shared, 3).await;
+        assert_levels(&state, &reader, &shared, 0).await;
+
+        // user rule
+        add_rule(&state, &shared, Some(&reader), None, AbRuleLevel::Read as u32).await;
+        assert_levels(&state, &reader, &shared, 1).await;
+        assert_levels(&state, &other, &shared, 0).await;
+
+        // group rule
+        add_rule(&state, &shared, None, Some(&support_guid), AbRuleLevel::ReadWrite as u32).await;
+        assert_levels(&state, &writer, &shared, 2).await;
+        assert_levels(&state, &other, &shared, 0).await;
+
+        // everyone rule, the highest rule wins
+        add_rule(&state, &shared, None, None, AbRuleLevel::Read as u32).await;
+        assert_levels(&state, &other, &shared, 1).await;
+        assert_levels(&state, &writer, &shared, 2).await;
+        add_rule(&state, &shared, Some(&reader), None, AbRuleLevel::FullControl as u32).await;
+        assert_levels(&state, &reader, &shared, 3).await;
+
+        // the shared address books list reports the effective rule
+        let books = state.get_shared_address_books(writer.clone()).await.unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].rule, Some(2));
+
+        // only the owner, the users with full control and the global admins control the address book
+        let (_, admin) = state.db.find_user_by_name("admin").await;
+        let admin = admin.unwrap().0;
+        assert_levels(&state, &admin, &shared, 1).await;
+        assert!(state.check_ab_control(&admin, &shared).await);
+        assert!(state.check_ab_control(&owner, &shared).await);
+        assert!(state.check_ab_control(&reader, &shared).await);
+        assert!(!state.check_ab_control(&writer, &shared).await);
+
+        // unknown address book
+        let unknown = guid_into_uuid(uuid::Uuid::new_v4().as_bytes().to_vec()).unwrap();
+        assert_levels(&state, &owner, &unknown, 0).await;
+        assert!(!state.check_ab_control(&admin, &unknown).await);
+
+        // the rules do not apply to the personal address books and nobody controls them
+        add_rule(&state, &personal, None, None, AbRuleLevel::FullControl as u32).await;
+        assert_levels(&state, &other, &personal, 0).await;
+        let books = state.get_user_address_books(&other).await.unwrap();
+        assert!(!books.iter().any(|book| book.guid == personal));
+        assert!(!state.check_ab_control(&admin, &personal).await);
+        assert!(!state.check_ab_control(&owner, &personal).await);
+        assert!(state.delete_shared_address_book(&personal).await.is_none());
+        assert_levels(&state, &owner, &personal, 3).await;
+
+        // a malformed user or group is not an everyone rule
+        let private = state
+            .add_shared_address_book("private", guid_into_uuid(owner.clone()).unwrap().as_str())
+            .await
+            .unwrap();
+        let (rules, _) = state.get_ab_rules(1, 100, &private).await.unwrap();
+        for (user, group) in [(Some("not-a-uuid"), None), (None, Some("not-a-uuid"))] {
+            let rule = AbRule {
+                guid: private.clone(),
+                user: user.map(|user| user.to_string()),
+                group: group.map(|group| group.to_string()),
+                rule: AbRuleLevel::FullControl as u32,
+            };
+            assert!(state.add_ab_rule(rule).await.is_none());
+        }
+        assert_levels(&state, &other, &private, 0).await;
+        assert_eq!(state.get_ab_rules(1, 100, &private).await.unwrap().0, rules);
+    }
+}
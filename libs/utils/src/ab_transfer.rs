@@ -0,0 +1,270 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{AbExport, AbPeer, AbTag, AbTransferFormat};
+
+/// Columns of the CSV layout, tags are separated by a semicolon
+pub const CSV_COLUMNS: [&str; 6] = ["id", "alias", "tags", "username", "hostname", "platform"];
+
+/// Serialize an address book in the given format
+pub fn export_address_book(data: &AbExport, format: AbTransferFormat) -> String {
+    match format {
+        AbTransferFormat::RustDesk => export_legacy(data),
+        AbTransferFormat::Csv => export_csv(data),
+        AbTransferFormat::Json => serde_json::to_string(data).unwrap_or_default(),
+    }
+}
+
+/// Parse an address book in the given format
+///
+/// # Returns
+///
+/// Result<AbExport, String> - the address book or a human readable error
+pub fn import_address_book(data: &str, format: AbTransferFormat) -> Result<AbExport, String> {
+    match format {
+        AbTransferFormat::RustDesk => import_legacy(data),
+        AbTransferFormat::Csv => import_csv(data),
+        AbTransferFormat::Json => serde_json::from_str(data).map_err(|e| e.to_string()),
+    }
+}
+
+/// Serialize an address book as the legacy RustDesk client blob
+/// {"tags":["tag1"],"peers":[...],"tag_colors":"{\"tag1\":4288585374}"}
+pub fn export_legacy(data: &AbExport) -> String {
+    let tag_colors: HashMap<&str, u32> = data
+        .tags
+        .iter()
+        .map(|tag| (tag.name.as_str(), tag.color))
+        .collect();
+    let blob = serde_json::json!({
+        "tags": data.tags.iter().map(|tag| tag.name.as_str()).collect::<Vec<&str>>(),
+        "peers": data.peers,
+        "tag_colors": serde_json::to_string(&tag_colors).unwrap_or_default(),
+    });
+    blob.to_string()
+}
+
+/// Parse the legacy RustDesk client blob, the tags without color get the default one
+pub fn import_legacy(data: &str) -> Result<AbExport, String> {
+    let blob: Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    let tag_colors: HashMap<String, u32> = match blob.get("tag_colors") {
+        Some(Value::String(colors)) if !colors.is_empty() => {
+            serde_json::from_str(colors).map_err(|e| format!("tag_colors: {}", e))?
+        }
+        Some(Value::Object(_)) => serde_json::from_value(blob["tag_colors"].clone())
+            .map_err(|e| format!("tag_colors: {}", e))?,
+        _ => HashMap::new(),
+    };
+    let tags: Vec<String> = match blob.get("tags") {
+        Some(tags) => serde_json::from_value(tags.clone()).map_err(|e| format!("tags: {}", e))?,
+        None => Vec::new(),
+    };
+    let peers: Vec<AbPeer> = match blob.get("peers") {
+        Some(peers) => {
+            serde_json::from_value(peers.clone()).map_err(|e| format!("peers: {}", e))?
+        }
+        None => Vec::new(),
+    };
+    let tags = tags
+        .into_iter()
+        .map(|name| AbTag {
+            color: tag_colors
+                .get(&name)
+                .copied()
+                .unwrap_or(AbTag::default().color),
+            name,
+        })
+        .collect();
+    Ok(AbExport { tags, peers })
+}
+
+/// Serialize the peers of an address book as CSV
+pub fn export_csv(data: &AbExport) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push_str("\r\n");
+    for peer in data.peers.iter() {
+        let tags = peer.tags.clone().unwrap_or_default().join(";");
+        let fields = [
+            peer.id.as_str(),
+            peer.alias.as_deref().unwrap_or(""),
+            tags.as_str(),
+            peer.username.as_deref().unwrap_or(""),
+            peer.hostname.as_deref().unwrap_or(""),
+            peer.platform.as_deref().unwrap_or(""),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_escape(field)).collect();
+        csv.push_str(fields.join(",").as_str());
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Parse peers from CSV, the first line is the header and only the id column is mandatory
+/// Tags are not listed in the result, they are created from the peers when imported
+pub fn import_csv(data: &str) -> Result<AbExport, String> {
+    let mut rows = parse_csv(data)?.into_iter();
+    let header = rows.next().ok_or("empty csv")?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column.trim().eq_ignore_ascii_case(name))
+    };
+    let id_column = column("id").ok_or("missing id column")?;
+    let alias_column = column("alias");
+    let tags_column = column("tags");
+    let username_column = column("username");
+    let hostname_column = column("hostname");
+    let platform_column = column("platform");
+
+    let mut peers = Vec::new();
+    for (line, row) in rows.enumerate() {
+        if row.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+        };
+        let id = field(Some(id_column)).ok_or(format!("missing id on line {}", line + 2))?;
+        let tags = field(tags_column).map(|tags| {
+            tags.split(';')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect::<Vec<String>>()
+        });
+        peers.push(AbPeer {
+            id,
+            hash: None,
+            password: None,
+            username: field(username_column),
+            hostname: field(hostname_column),
+            platform: field(platform_column),
+            alias: field(alias_column),
+            tags,
+            force_always_relay: None,
+            rdp_port: None,
+            rdp_username: None,
+            login_name: None,
+            same_server: None,
+            updated_at: None,
+            updated_by: None,
+        });
+    }
+    Ok(AbExport {
+        tags: Vec::new(),
+        peers,
+    })
+}
+
+pub(crate) fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Minimal RFC 4180 parser, supports quoted fields with escaped quotes and line breaks
+pub(crate) fn parse_csv(data: &str) -> Result<Vec<Vec<String>>, String> {
+    let data = data.strip_prefix('\u{feff}').unwrap_or(data);
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_round_trip() {
+        let mut peer = AbPeer::default_test();
+        peer.alias = Some("Office, \"main\"".to_string());
+        peer.tags = Some(vec!["tag1".to_string(), "tag2".to_string()]);
+        let data = AbExport {
+            tags: Vec::new(),
+            peers: vec![peer],
+        };
+        let imported = import_csv(&export_csv(&data)).unwrap();
+        assert_eq!(imported.peers.len(), 1);
+        let peer = &imported.peers[0];
+        assert_eq!(peer.id, "123456789");
+        assert_eq!(peer.alias.as_deref(), Some("Office, \"main\""));
+        assert_eq!(
+            peer.tags,
+            Some(vec!["tag1".to_string(), "tag2".to_string()])
+        );
+        assert_eq!(peer.hostname.as_deref(), Some("unknown"));
+        assert_eq!(peer.password, None);
+    }
+
+    #[test]
+    fn test_import_csv_missing_id() {
+        assert!(import_csv("alias,hostname\nfoo,bar\n").is_err());
+        assert!(import_csv("id,alias\n,foo\n").is_err());
+    }
+
+    #[test]
+    fn test_import_legacy() {
+        let blob = r#"{"tags":["tag1","tag2"],"peers":[{"id":"123","tags":["tag1"]}],"tag_colors":"{\"tag1\":1}"}"#;
+        let imported = import_legacy(blob).unwrap();
+        assert_eq!(imported.peers.len(), 1);
+        assert_eq!(imported.tags.len(), 2);
+        assert_eq!(imported.tags[0].color, 1);
+        assert_eq!(imported.tags[1].color, AbTag::default().color);
+        let exported = import_legacy(&export_legacy(&imported)).unwrap();
+        assert_eq!(exported.tags[0].name, "tag1");
+        assert_eq!(exported.tags[0].color, 1);
+    }
+}
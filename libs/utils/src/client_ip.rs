@@ -0,0 +1,305 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+use ipnet::IpNet;
+
+/// Headers set by reverse proxies, they are only trusted when the request comes from a trusted proxy
+pub const FORWARDING_HEADERS: [&str; 5] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpNet>> = OnceLock::new();
+
+/// Get the trusted proxies list
+/// It is read once from the `TRUSTED_PROXIES` environment variable, a comma separated list
+/// of ip addresses or networks (eg. "127.0.0.1,::1,10.0.0.0/8")
+/// By default no proxy is trusted
+pub fn get_trusted_proxies() -> &'static Vec<IpNet> {
+    TRUSTED_PROXIES.get_or_init(|| {
+        let config = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        config
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let net = s
+                    .parse::<IpNet>()
+                    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+                if net.is_err() {
+                    log::error!("invalid trusted proxy: {}", s);
+                }
+                net.ok()
+            })
+            .collect()
+    })
+}
+
+/// Check if an ip address belongs to the trusted proxies list
+pub fn is_trusted_proxy(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    get_trusted_proxies().iter().any(|net| net.contains(&ip))
+}
+
+/// Remove the forwarding headers if the request does not come from a trusted proxy
+/// so that functions like get_host() can use them safely
+pub fn filter_forwarding_headers(remote: Option<IpAddr>, headers: &mut HashMap<String, String>) {
+    if remote.map(is_trusted_proxy).unwrap_or(false) {
+        return;
+    }
+    for header in FORWARDING_HEADERS {
+        headers.remove(header);
+    }
+}
+
+/// Parse a node found in a forwarding header
+/// It can be an ip, an ip with a port, a bracketed ipv6 with a port, all of them may be quoted
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    if let Some(stripped) = node.strip_prefix('[') {
+        return stripped.split(']').next()?.parse::<IpAddr>().ok();
+    }
+    None
+}
+
+/// Get the chain of forwarded addresses from the Forwarded header (RFC 7239)
+/// or from the X-Forwarded-For header, the client first
+fn forwarded_chain(headers: &HashMap<String, String>) -> Vec<Option<IpAddr>> {
+    if let Some(forwarded) = headers.get("forwarded") {
+        return forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") {
+                        Some(parse_node(value))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+    }
+    if let Some(forwarded_for) = headers.get("x-forwarded-for") {
+        return forwarded_for.split(',').map(parse_node).collect();
+    }
+    Vec::new()
+}
+
+/// Get the ip address of the client
+///
+/// If the request comes from a trusted proxy, the forwarding headers are walked from the closest
+/// hop to the farthest one and the first address which is not a trusted proxy is the client.
+/// Forwarded is preferred over X-Forwarded-For, X-Real-IP is used when none of them is present.
+/// Otherwise the remote address of the connection is returned.
+///
+/// # Arguments
+///
+/// * `remote` - the remote address of the connection
+/// * `headers` - the request headers with lowercase names
+///
+/// # Returns
+///
+/// Option<String> - the client ip address
+pub fn get_client_ip(remote: Option<IpAddr>, headers: &HashMap<String, String>) -> Option<String> {
+    let remote = remote?.to_canonical();
+    if !is_trusted_proxy(remote) {
+        return Some(remote.to_string());
+    }
+    let chain = forwarded_chain(headers);
+    if !chain.is_empty() {
+        let mut client = remote;
+        for node in chain.iter().rev() {
+            match node {
+                Some(ip) => {
+                    client = ip.to_canonical();
+                    if !is_trusted_proxy(client) {
+                        break;
+                    }
+                }
+                // obfuscated or unknown node, we cannot go further
+                None => break,
+            }
+        }
+        return Some(client.to_string());
+    }
+    if let Some(ip) = headers.get("x-real-ip").and_then(|ip| parse_node(ip)) {
+        return Some(ip.to_canonical().to_string());
+    }
+    Some(remote.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trust_proxies() {
+        TRUSTED_PROXIES
+            .get_or_init(|| vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]);
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        trust_proxies();
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.9"),
+            ("forwarded", "for=198.51.100.9"),
+            ("x-real-ip", "198.51.100.9"),
+        ]);
+        assert_eq!(
+            get_client_ip(ip("203.0.113.7"), &spoofed).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(get_client_ip(None, &spoofed), None);
+
+        let mut request = headers(&[
+            ("host", "api.example.com"),
+            ("x-forwarded-for", "198.51.100.9"),
+            ("x-forwarded-host", "evil.example.com"),
+            ("x-forwarded-proto", "http"),
+            ("forwarded", "for=198.51.100.9"),
+            ("x-real-ip", "198.51.100.9"),
+        ]);
+        filter_forwarding_headers(ip("203.0.113.7"), &mut request);
+        assert_eq!(request, headers(&[("host", "api.example.com")]));
+
+        let mut request = spoofed.clone();
+        filter_forwarding_headers(None, &mut request);
+        assert!(request.is_empty());
+
+        // the headers set by a trusted proxy are kept
+        let mut request = spoofed.clone();
+        filter_forwarding_headers(ip("::ffff:10.0.0.1"), &mut request);
+        assert_eq!(request, spoofed);
+    }
+
+    #[test]
+    fn test_trusted_proxies_chain() {
+        trust_proxies();
+        // the first untrusted hop from the right is the client, the leftmost entry can be spoofed
+        let request = headers(&[("x-forwarded-for", "198.51.100.9, 203.0.113.5,10.0.0.2")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("203.0.113.5")
+        );
+        assert_eq!(
+            get_client_ip(ip("::ffff:10.0.0.1"), &request).as_deref(),
+            Some("203.0.113.5")
+        );
+        // only trusted proxies, the farthest one is the client
+        let request = headers(&[("x-forwarded-for", "10.0.0.3, ::1, 10.0.0.2")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("10.0.0.3")
+        );
+        // the walk stops at an unknown node
+        let request = headers(&[("x-forwarded-for", "198.51.100.9, unknown, 10.0.0.2")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        trust_proxies();
+        let request = headers(&[(
+            "forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#,
+        )]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("2001:db8::1")
+        );
+        let request = headers(&[("forwarded", r#"For="192.0.2.60:8080";by=10.0.0.1"#)]);
+        assert_eq!(
+            get_client_ip(ip("::1"), &request).as_deref(),
+            Some("192.0.2.60")
+        );
+        let request = headers(&[("forwarded", r#"proto=https;for="[::ffff:192.0.2.61]""#)]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("192.0.2.61")
+        );
+        // Forwarded is preferred over X-Forwarded-For
+        let request = headers(&[
+            ("forwarded", "for=192.0.2.60"),
+            ("x-forwarded-for", "198.51.100.9"),
+        ]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("192.0.2.60")
+        );
+        // an obfuscated client stops the walk at the proxy
+        let request = headers(&[("forwarded", "for=_hidden")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_real_ip_fallback() {
+        trust_proxies();
+        let request = headers(&[("x-real-ip", "198.51.100.4")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("198.51.100.4")
+        );
+        // X-Real-IP is ignored when a forwarding chain is present
+        let request = headers(&[
+            ("x-real-ip", "198.51.100.4"),
+            ("x-forwarded-for", "203.0.113.5"),
+        ]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("203.0.113.5")
+        );
+        // an invalid X-Real-IP falls back on the remote address
+        let request = headers(&[("x-real-ip", "unknown")]);
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &request).as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(
+            get_client_ip(ip("10.0.0.1"), &HashMap::new()).as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+}
@@ -15,6 +15,9 @@
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
 use std::collections::HashMap;
 
+/// Get the scheme and host used by the client for reaching the server
+/// The X-Forwarded-* headers must have been removed from `headers` when the request
+/// does not come from a trusted proxy (see client_ip::filter_forwarding_headers)
 pub fn get_host(headers: HashMap<String, String>) -> String {
     // Default to http
     let mut proto = "http".to_string(); 
@@ -16,9 +16,12 @@
 mod tokens;
 mod bearer;
 pub mod address_book;
+pub mod ab_transfer;
+pub mod user_transfer;
 
 pub mod types;
 pub mod get_host;
+pub mod client_ip;
 
 pub use tokens::Token;
 pub use bearer::{BearerAuthToken, CookieAuthToken, MixedAuthToken, IntoToken};
@@ -83,4 +86,11 @@ pub fn uuid_into_guid(uuid: &str) -> Option<Vec<u8>> {
     let uuid = uuid.unwrap();
     let guid = uuid.as_bytes().to_vec();
     Some(guid)
-}
\ No newline at end of file
+}
+/// Check the type of a user data entry, it has 1 to 30 ASCII letters, digits, `_`, `-` or `.`
+pub fn is_valid_user_data_type(data_type: &str) -> bool {
+    (1..=30).contains(&data_type.len())
+        && data_type
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
@@ -18,6 +18,7 @@ use std::fmt;
 use std::sync::Arc;
 
 use oauth2::oauth_provider::OAuthProvider;
+use rocket::FromForm;
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::JsonSchema;
 use serde::de::Visitor;
@@ -28,6 +29,10 @@ use crate::Token;
 pub type SessionId = u64;
 pub type UserId = Vec<u8>;
 
+/// Guid of the `Default` team created with the database
+/// Its members are not restricted to a team, its admins manage every team
+pub const DEFAULT_TEAM_GUID: &str = "018f2556-22f7-7778-a006-702ca5c23714";
+
 struct BoolVisitor;
 
 impl<'de> Visitor<'de> for BoolVisitor {
@@ -122,6 +127,9 @@ pub struct SystemInfo {
     pub uuid: Option<String>,
     pub version: Option<String>,
     pub ip: Option<String>,
+    /// Last ip address seen by the api server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_ip: Option<String>,
 }
 
 #[derive(Deserialize, Debug, JsonSchema)]
@@ -183,6 +191,12 @@ pub struct AuditRequest {
     pub ip: String,
     #[serde(default)]
     pub uuid: String,
+    #[serde(default)]
+    pub conn_id: i64,
+    #[serde(default)]
+    pub session_id: u64,
+    #[serde(default, rename = "type")]
+    pub conn_type: i64,
 }
 
 // {
@@ -258,7 +272,7 @@ impl Default for AbSharedProfilesResponse {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct AbPeer {
     pub id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -296,6 +310,12 @@ pub struct AbPeer {
         serialize_with = "from_bool_to_str"
     )]
     pub same_server: Option<bool>,
+    /// time of the last change, set by the server in the lists of peers and never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    /// name of the user who made the last change, set by the server in the lists of peers and never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_by: Option<String>,
 }
 impl Default for AbPeer {
     fn default() -> Self {
@@ -313,6 +333,8 @@ impl Default for AbPeer {
             rdp_username: Some("".to_string()),
             login_name: Some("".to_string()),
             same_server: None,
+            updated_at: None,
+            updated_by: None,
         }
     }
 }
@@ -333,11 +355,34 @@ impl AbPeer {
             rdp_username: Some("".to_string()),
             login_name: Some("user".to_string()),
             same_server: None,
+            updated_at: None,
+            updated_by: None,
+        }
+    }
+
+    /// Merge with a previous version of the peer, the missing fields are taken from the old peer
+    pub fn merge(self, old: AbPeer) -> Self {
+        AbPeer {
+            id: self.id,
+            hash: self.hash.or(old.hash),
+            password: self.password.or(old.password),
+            username: self.username.or(old.username),
+            hostname: self.hostname.or(old.hostname),
+            platform: self.platform.or(old.platform),
+            alias: self.alias.or(old.alias),
+            tags: self.tags.or(old.tags),
+            force_always_relay: self.force_always_relay.or(old.force_always_relay),
+            rdp_port: self.rdp_port.or(old.rdp_port),
+            rdp_username: self.rdp_username.or(old.rdp_username),
+            login_name: self.login_name.or(old.login_name),
+            same_server: self.same_server.or(old.same_server),
+            updated_at: None,
+            updated_by: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct AbTag {
     pub name: String,
     pub color: u32,
@@ -357,6 +402,110 @@ pub struct AbTagRenameRequest {
     pub new: String,
 }
 
+/// A peer of an address book which is in the recycle bin
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbRecycledPeer {
+    pub deleted_at: String,
+    #[serde(flatten)]
+    pub peer: AbPeer,
+}
+
+/// Last change of an address book
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbChange {
+    pub guid: String,
+    /// Revisions are increasing across all the address books
+    pub revision: i64,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct AbChangesResponse {
+    /// The revision to send as `since` in the next request
+    pub revision: i64,
+    pub total: u32,
+    pub data: Vec<AbChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct AbRecycleResponse {
+    pub total: u32,
+    pub retention_days: i64,
+    pub data: Vec<AbRecycledPeer>,
+}
+
+/// A revision of a peer of an address book
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbPeerRevision {
+    pub revision: i64,
+    /// create, update, delete or restore
+    pub action: String,
+    pub updated_at: String,
+    /// name of the user who made the change
+    pub updated_by: Option<String>,
+    pub peer: AbPeer,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct AbPeerHistoryResponse {
+    pub total: u32,
+    pub data: Vec<AbPeerRevision>,
+}
+
+/// Copy or move peers to another address book
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbPeersTransferRequest {
+    /// guid of the target address book
+    pub target: String,
+    /// ids of the peers
+    pub peers: Vec<String>,
+}
+
+/// Add or remove a tag on peers
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbPeersTagRequest {
+    pub tag: String,
+    /// ids of the peers
+    pub peers: Vec<String>,
+}
+
+/// Result of a batch operation for a peer
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbPeersBatchItem {
+    pub id: String,
+    /// ok, unchanged or not_found
+    pub result: String,
+}
+
+impl AbPeersBatchItem {
+    pub fn new(id: &str, result: &str) -> Self {
+        AbPeersBatchItem {
+            id: id.to_string(),
+            result: result.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, JsonSchema)]
+pub struct AbPeersBatchResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub total: u32,
+    pub data: Vec<AbPeersBatchItem>,
+}
+
+/// The pagination and the filters of a list of address book peers
+#[derive(FromForm, Debug, JsonSchema)]
+pub struct AbPeersQuery {
+    pub current: u32,
+    #[field(name = "pageSize")]
+    #[serde(rename = "pageSize")]
+    pub page_size: u32,
+    pub tag: Option<String>,
+    pub search: Option<String>,
+    pub platform: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct AbPeersResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -432,6 +581,8 @@ pub struct UpdateUserRequest {
     pub password: Option<String>,
     #[serde(default, rename = "confirm-password", skip_serializing_if = "Option::is_none")]
     pub confirm_password: Option<String>,
+    #[serde(default, rename = "current-password", skip_serializing_if = "Option::is_none")]
+    pub current_password: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -450,6 +601,7 @@ impl Default for UpdateUserRequest {
             name: None,
             password: None,
             confirm_password: None,
+            current_password: None,
             email: None,
             is_admin: None,
             group_name: None,
@@ -608,6 +760,259 @@ pub struct AddUserRequest {
     pub group_name: String,
 }
 
+/// Formats supported by the users import and export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserTransferFormat {
+    /// name, email, group, is_admin, note, password
+    Csv,
+    /// Array of users
+    Json,
+}
+
+/// A user of the import and export, the password is never exported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct UserTransfer {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub group: String,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// Initial password, a password is generated when it is missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Entries of the user data storage, only in the JSON format
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data: Vec<UserDataTransfer>,
+}
+
+/// An entry of the user data storage in the import and export
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct UserDataTransfer {
+    #[serde(rename = "type")]
+    pub data_type: String,
+    pub data: String,
+}
+
+/// Result of the import of one user, `row` starts at 1 with the first user
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserImportRow {
+    pub row: u32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Generated password, only returned once when the user is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated_password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserImportReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub dry_run: bool,
+    /// false if nothing was written because of an error or of the dry run
+    pub imported: bool,
+    /// Groups and users are only created when `imported` is true, they are a preview otherwise
+    pub groups_created: Vec<String>,
+    pub users_created: u32,
+    pub users_failed: u32,
+    pub rows: Vec<UserImportRow>,
+}
+
+impl UserImportReport {
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            error: None,
+            dry_run,
+            imported: false,
+            groups_created: Vec::new(),
+            users_created: 0,
+            users_failed: 0,
+            rows: Vec::new(),
+        }
+    }
+}
+
+/// Kinds of the single use tokens sent by mail to the users
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserTokenKind {
+    /// Invitation of a new user, accepting it sets the password and enables the user
+    Invite,
+    /// Self-service password reset
+    PasswordReset,
+}
+
+impl UserTokenKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserTokenKind::Invite => "invite",
+            UserTokenKind::PasswordReset => "password_reset",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct InviteUserRequest {
+    pub name: String,
+    pub email: String,
+    pub group_name: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct InviteResponse {
+    pub msg: String,
+    /// guid of the invited user
+    pub user: String,
+    pub expires_at: String,
+    /// false if the invitation was not sent, it can be resent later
+    pub sent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A pending invitation
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserInvite {
+    pub user: String,
+    pub name: String,
+    pub email: Option<String>,
+    pub created_at: String,
+    pub expires_at: String,
+    pub expired: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserInvitesResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<UserInvite>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+/// Set a password with the token of an invitation or of a password reset
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct SetPasswordRequest {
+    pub token: String,
+    pub password: String,
+    #[serde(rename = "confirm-password")]
+    pub confirm_password: String,
+}
+
+/// An entry of the user data storage, `updated_at` is the version of the entry
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserDataEntry {
+    #[serde(rename = "type")]
+    pub data_type: String,
+    pub data: String,
+    pub updated_at: String,
+}
+
+/// An entry of the user data storage without its data
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserDataItem {
+    #[serde(rename = "type")]
+    pub data_type: String,
+    /// size of the data in bytes
+    pub size: u32,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserDataListResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<UserDataItem>,
+}
+
+/// Write an entry of the user data storage
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PutUserDataRequest {
+    pub data: String,
+    /// `updated_at` of the entry read before, the write fails if the entry changed since then.
+    /// Without it the write fails if the entry exists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// Reasons why the user data storage rejects a write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserDataError {
+    /// The type, the size or the number of entries is invalid
+    Invalid(String),
+    /// The entry changed since it was read, it holds the current `updated_at` or None if the entry does not exist
+    Conflict(Option<String>),
+}
+
+/// Notification preferences of a user, stored in the info of the user
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct UserNotifications {
+    #[serde(default)]
+    pub email_alarm_notification: bool,
+}
+
+/// Profile of the current user
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserProfile {
+    pub guid: String,
+    pub name: String,
+    pub email: Option<String>,
+    pub note: Option<String>,
+    pub is_admin: bool,
+    pub group_name: String,
+    pub team_name: String,
+    pub created_at: String,
+    pub notifications: UserNotifications,
+}
+
+/// Update of the profile of the current user, the missing fields are unchanged
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UpdateProfileRequest {
+    /// an empty email removes the email
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notifications: Option<UserNotifications>,
+}
+
+/// Change the password of the current user
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ChangePasswordRequest {
+    #[serde(rename = "current-password")]
+    pub current_password: String,
+    pub password: String,
+    #[serde(rename = "confirm-password")]
+    pub confirm_password: String,
+}
+
+/// An address book the current user can access
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserAddressBook {
+    pub guid: String,
+    pub name: String,
+    pub personal: bool,
+    /// guid of the owner
+    pub owner: String,
+    pub rule: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserAddressBooksResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<UserAddressBook>,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct EnableUserRequest {
     pub rows: Vec<String>,
@@ -686,6 +1091,9 @@ pub struct PeerInfo {
     pub uuid: Option<String>,
     pub version: Option<String>,
     pub ip: Option<String>,
+    /// Last ip address seen by the api server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_ip: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
@@ -695,6 +1103,7 @@ pub struct Peer{
     pub status: i32,
     pub strategy_name: String,
     pub last_online: String,
+    pub online: bool,
     pub info: PeerInfo,
 }
 
@@ -718,6 +1127,150 @@ pub struct Group {
     pub info: GroupInfo,
 }
 
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Team {
+    pub guid: String,
+    pub name: String,
+    pub email: String,
+    pub note: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    pub users: u32,
+    pub groups: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct TeamsResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<Team>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AddTeamRequest {
+    pub name: String,
+    pub email: String,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UpdateTeamRequest {
+    pub guid: String,
+    pub name: String,
+    pub email: String,
+    pub note: Option<String>,
+}
+
+/// A permission given to the users by their roles
+/// The admins have every permission
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub enum Permission {
+    #[serde(rename = "users.manage")]
+    UsersManage,
+    #[serde(rename = "groups.manage")]
+    GroupsManage,
+    #[serde(rename = "teams.manage")]
+    TeamsManage,
+    #[serde(rename = "peers.read")]
+    PeersRead,
+    #[serde(rename = "peers.manage")]
+    PeersManage,
+    #[serde(rename = "address_books.manage")]
+    AddressBooksManage,
+    #[serde(rename = "audit.read")]
+    AuditRead,
+    #[serde(rename = "settings.manage")]
+    SettingsManage,
+    #[serde(rename = "roles.manage")]
+    RolesManage,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 9] = [
+        Permission::UsersManage,
+        Permission::GroupsManage,
+        Permission::TeamsManage,
+        Permission::PeersRead,
+        Permission::PeersManage,
+        Permission::AddressBooksManage,
+        Permission::AuditRead,
+        Permission::SettingsManage,
+        Permission::RolesManage,
+    ];
+
+    /// Check if this permission grants `required`, managing the peers includes reading them
+    pub fn grants(&self, required: Permission) -> bool {
+        *self == required || (*self == Permission::PeersManage && required == Permission::PeersRead)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Role {
+    pub guid: String,
+    pub name: String,
+    pub note: Option<String>,
+    pub permissions: Vec<Permission>,
+    pub users: u32,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RolesResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<Role>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AddRoleRequest {
+    pub name: String,
+    pub note: Option<String>,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UpdateRoleRequest {
+    pub guid: String,
+    pub name: String,
+    pub note: Option<String>,
+    pub permissions: Vec<Permission>,
+}
+
+/// Replace the roles of a user
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserRolesRequest {
+    /// guid of the user
+    pub user: String,
+    /// guids of the roles
+    pub roles: Vec<String>,
+}
+
+/// The roles of a user and the resulting permissions
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserRolesResponse {
+    pub msg: String,
+    pub admin: bool,
+    pub permissions: Vec<Permission>,
+    pub data: Vec<Role>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AuditConn {
+    pub guid: String,
+    pub conn_type: Option<i64>,
+    pub created_at: String,
+    /// the event sent by the client in JSON format
+    pub info: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AuditConnResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<AuditConn>,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct AbSharedAddRequest {
     pub name: String,
@@ -732,6 +1285,106 @@ pub struct AbRule {
     pub rule: u32,
 }
 
+/// Formats supported by the address book import and export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbTransferFormat {
+    /// Legacy address book blob of the RustDesk client
+    RustDesk,
+    /// id, alias, tags, username, hostname, platform
+    Csv,
+    /// Full address book including the tags colors
+    Json,
+}
+
+/// What to do with the existing peers and tags when importing an address book
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AbImportMode {
+    /// Add the new peers and tags and update the existing ones
+    Merge,
+    /// The address book content is replaced by the imported one
+    Replace,
+}
+
+/// How the orphaned tags of an address book are repaired
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AbTagRepairMode {
+    /// Create the missing tags with the default color
+    Create,
+    /// Remove the missing tags from the peers
+    Remove,
+}
+
+/// A tag used by peers which does not exist in the address book
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct AbOrphanedTag {
+    pub name: String,
+    /// ids of the peers using the tag
+    pub peers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct AbTagCheckReport {
+    /// The repair mode, None when the address book is only checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair: Option<AbTagRepairMode>,
+    pub total: u32,
+    pub orphaned_tags: Vec<AbOrphanedTag>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct AbExport {
+    pub tags: Vec<AbTag>,
+    pub peers: Vec<AbPeer>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AbImportReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub dry_run: bool,
+    pub mode: AbImportMode,
+    pub peers_added: Vec<String>,
+    pub peers_updated: Vec<String>,
+    pub peers_removed: Vec<String>,
+    pub peers_unchanged: u32,
+    pub tags_added: Vec<String>,
+    pub tags_updated: Vec<String>,
+    pub tags_removed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AbLegacyMigrationReport {
+    pub user: String,
+    pub report: AbImportReport,
+}
+
+impl AbImportReport {
+    pub fn new(mode: AbImportMode, dry_run: bool) -> Self {
+        AbImportReport {
+            error: None,
+            dry_run,
+            mode,
+            peers_added: Vec::new(),
+            peers_updated: Vec::new(),
+            peers_removed: Vec::new(),
+            peers_unchanged: 0,
+            tags_added: Vec::new(),
+            tags_updated: Vec::new(),
+            tags_removed: Vec::new(),
+        }
+    }
+}
+
+/// Access levels granted by the address book rules, a level includes the lower ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AbRuleLevel {
+    Read = 1,
+    ReadWrite = 2,
+    FullControl = 3,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct AbRulesResponse {
     pub msg: String,
@@ -756,6 +1409,22 @@ pub enum Platform {
     Windows,MacOS,Linux,Android,All
 }
 
+pub enum PeerStatField {
+    Os, Version, Cpu, Hostname, Username, Memory, Owner, Group, Strategy, Online
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerStat {
+    pub key: String,
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerActiveDay {
+    pub day: String,
+    pub total: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct CpuCount {
     pub cpu: String,
@@ -768,6 +1437,9 @@ pub struct AddGoupRequest {
     pub note: String,
     pub allowed_outgoings: Vec<String>,
     pub allowed_incomings: Vec<String>,
+    /// guid of the team of the group, only used by the admins of the `Default` team
+    #[serde(default)]
+    pub team: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
@@ -779,6 +1451,15 @@ pub struct UpdateGoupRequest {
     pub allowed_incomings: Vec<String>,
 }
 
+/// Result of a cross group access check
+/// `from_group` and `to_group` are the guids of the groups, None when the device has no owner
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Default)]
+pub struct CrossGroupAccessResponse {
+    pub allowed: bool,
+    pub from_group: Option<String>,
+    pub to_group: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Default)]
 pub struct AbSharedNameRequest {
     pub name: Option<String>,
@@ -786,7 +1467,117 @@ pub struct AbSharedNameRequest {
     pub guid: String,
 }
 
+/// Users to delete
+/// `transfer_to` is the guid of the user receiving the shared address books and the devices of the deleted users,
+/// they are deleted when it is None
+/// `archive` keeps the deleted users as archived accounts so that the audit logs still show their names
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DeleteUserRequest {
     pub rows: Vec<String>,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub transfer_to: Option<String>,
+    #[serde(default)]
+    pub archive: bool,
+}
+
+/// Summary of the deletion of a user
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, Default)]
+pub struct UserDeletionSummary {
+    /// guid of the deleted user
+    pub user: String,
+    pub name: String,
+    pub archived: bool,
+    /// guid of the user who received the shared address books and the devices
+    pub transferred_to: Option<String>,
+    pub address_books_transferred: u64,
+    /// the personal address book and, without transfer, the owned shared address books
+    pub address_books_deleted: u64,
+    pub peers_transferred: u64,
+    pub peers_deleted: u64,
+    /// rules granting the user access to address books
+    pub rules_deleted: u64,
+    pub sessions_closed: u64,
+    /// third party identities (OIDC)
+    pub identities_deleted: u64,
+}
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerPresenceBucket {
+    pub day: String,
+    pub online_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerUptime {
+    pub guid: String,
+    pub id: String,
+    pub online: bool,
+    pub last_online: String,
+    pub uptime_percent: f64,
+    pub histogram: Vec<PeerPresenceBucket>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeersUptimeResponse {
+    pub msg: String,
+    pub total: u32,
+    pub from: String,
+    pub to: String,
+    pub data: Vec<PeerUptime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq)]
+pub struct PeerSysinfoChange {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerSysinfoSnapshot {
+    pub version: u32,
+    pub created_at: String,
+    pub info: PeerInfo,
+    pub changes: Vec<PeerSysinfoChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerSysinfoHistoryResponse {
+    pub msg: String,
+    pub total: u32,
+    pub data: Vec<PeerSysinfoSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerCleanupItem {
+    pub guid: String,
+    pub id: String,
+    pub hostname: Option<String>,
+    pub last_online: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerDuplicateGroup {
+    /// the most recently seen peer, it is kept
+    pub keep: PeerCleanupItem,
+    pub duplicates: Vec<PeerCleanupItem>,
+    /// number of address book entries pointing to the duplicates
+    pub ab_references: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PeerCleanupReport {
+    pub dry_run: bool,
+    /// peers unseen for more than this number of days are stale, 0 disables the detection
+    pub stale_days: u32,
+    /// "flag" or "delete"
+    pub action: String,
+    pub merge_duplicates: bool,
+    pub stale: Vec<PeerCleanupItem>,
+    pub duplicates: Vec<PeerDuplicateGroup>,
+}
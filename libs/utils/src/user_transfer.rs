@@ -0,0 +1,236 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use serde_json::Value;
+
+use crate::ab_transfer::{csv_escape, parse_csv};
+use crate::{is_valid_user_data_type, UserTransfer, UserTransferFormat};
+
+/// Columns of the CSV layout, the password column is empty when exported and the user data is only in the JSON format
+pub const CSV_COLUMNS: [&str; 6] = ["name", "email", "group", "is_admin", "note", "password"];
+
+/// Serialize users in the given format
+pub fn export_users(users: &[UserTransfer], format: UserTransferFormat) -> String {
+    match format {
+        UserTransferFormat::Csv => export_csv(users),
+        UserTransferFormat::Json => serde_json::to_string(users).unwrap_or_default(),
+    }
+}
+
+/// Parse users in the given format
+/// A malformed document is an error, a malformed user is an error of its row
+///
+/// # Returns
+///
+/// Result<Vec<Result<UserTransfer, String>>, String> - the users or the error of each row, or a human readable error
+pub fn import_users(
+    data: &str,
+    format: UserTransferFormat,
+) -> Result<Vec<Result<UserTransfer, String>>, String> {
+    match format {
+        UserTransferFormat::Csv => import_csv(data),
+        UserTransferFormat::Json => import_json(data),
+    }
+}
+
+/// Serialize users as CSV
+pub fn export_csv(users: &[UserTransfer]) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push_str("\r\n");
+    for user in users.iter() {
+        let fields = [
+            user.name.as_str(),
+            user.email.as_deref().unwrap_or(""),
+            user.group.as_str(),
+            if user.is_admin { "true" } else { "false" },
+            user.note.as_deref().unwrap_or(""),
+            "",
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_escape(field)).collect();
+        csv.push_str(fields.join(",").as_str());
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Parse users from CSV, the first line is the header and the name and group columns are mandatory
+pub fn import_csv(data: &str) -> Result<Vec<Result<UserTransfer, String>>, String> {
+    let mut rows = parse_csv(data)?.into_iter();
+    let header = rows.next().ok_or("empty csv")?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column.trim().eq_ignore_ascii_case(name))
+    };
+    let name_column = column("name").ok_or("missing name column")?;
+    let group_column = column("group").ok_or("missing group column")?;
+    let email_column = column("email");
+    let is_admin_column = column("is_admin");
+    let note_column = column("note");
+    let password_column = column("password");
+
+    let mut users = Vec::new();
+    for row in rows {
+        if row.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+        };
+        let is_admin = match field(is_admin_column) {
+            Some(is_admin) => parse_bool(&is_admin),
+            None => Some(false),
+        };
+        let user = match (field(Some(name_column)), field(Some(group_column)), is_admin) {
+            (None, _, _) => Err("missing name".to_string()),
+            (_, None, _) => Err("missing group".to_string()),
+            (_, _, None) => Err("invalid is_admin, expected true or false".to_string()),
+            (Some(name), Some(group), Some(is_admin)) => Ok(UserTransfer {
+                name,
+                email: field(email_column),
+                group,
+                is_admin,
+                note: field(note_column),
+                password: field(password_column),
+                data: Vec::new(),
+            }),
+        };
+        users.push(user);
+    }
+    Ok(users)
+}
+
+/// Parse users from a JSON array
+pub fn import_json(data: &str) -> Result<Vec<Result<UserTransfer, String>>, String> {
+    let rows: Vec<Value> = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    let users = rows
+        .into_iter()
+        .map(|row| {
+            let user: UserTransfer = serde_json::from_value(row).map_err(|e| e.to_string())?;
+            if user.name.trim().is_empty() {
+                return Err("missing name".to_string());
+            }
+            if user.group.trim().is_empty() {
+                return Err("missing group".to_string());
+            }
+            if let Some(entry) = user.data.iter().find(|entry| !is_valid_user_data_type(&entry.data_type)) {
+                return Err(format!("invalid user data type {}", entry.data_type));
+            }
+            Ok(UserTransfer {
+                name: user.name.trim().to_string(),
+                email: user.email.map(|email| email.trim().to_string()).filter(|email| !email.is_empty()),
+                group: user.group.trim().to_string(),
+                is_admin: user.is_admin,
+                note: user.note.filter(|note| !note.is_empty()),
+                password: user.password.filter(|password| !password.is_empty()),
+                data: user.data,
+            })
+        })
+        .collect();
+    Ok(users)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserDataTransfer;
+
+    fn user(name: &str) -> UserTransfer {
+        UserTransfer {
+            name: name.to_string(),
+            email: Some(format!("{}@example.org", name)),
+            group: "Default".to_string(),
+            is_admin: false,
+            note: Some("Accounting, \"3rd floor\"".to_string()),
+            password: None,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let mut admin = user("admin2");
+        admin.is_admin = true;
+        admin.note = None;
+        let users = vec![user("alice"), admin];
+        let imported: Vec<UserTransfer> = import_csv(&export_csv(&users))
+            .unwrap()
+            .into_iter()
+            .map(|user| user.unwrap())
+            .collect();
+        assert_eq!(imported, users);
+        let imported: Vec<UserTransfer> =
+            import_users(&export_users(&users, UserTransferFormat::Json), UserTransferFormat::Json)
+                .unwrap()
+                .into_iter()
+                .map(|user| user.unwrap())
+                .collect();
+        assert_eq!(imported, users);
+    }
+
+    #[test]
+    fn test_user_data_round_trip() {
+        let mut alice = user("alice");
+        alice.data.push(UserDataTransfer {
+            data_type: "favorites".to_string(),
+            data: "[\"123456789\"]".to_string(),
+        });
+        let imported: Vec<UserTransfer> = import_json(&export_users(&[alice.clone()], UserTransferFormat::Json))
+            .unwrap()
+            .into_iter()
+            .map(|user| user.unwrap())
+            .collect();
+        assert_eq!(imported, vec![alice.clone()]);
+        // the CSV format has no user data
+        let imported = import_csv(&export_csv(&[alice])).unwrap();
+        assert!(imported[0].as_ref().unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn test_import_csv_row_errors() {
+        assert!(import_csv("email,group\nfoo@example.org,Default\n").is_err());
+        let users = import_csv("name,group,is_admin,password\nalice,Default,yes,secret\n,Default,no,\nbob,,0,\ncarol,Default,maybe,\n").unwrap();
+        assert_eq!(users.len(), 4);
+        let alice = users[0].as_ref().unwrap();
+        assert!(alice.is_admin);
+        assert_eq!(alice.password.as_deref(), Some("secret"));
+        assert_eq!(alice.email, None);
+        assert_eq!(users[1].as_ref().unwrap_err(), "missing name");
+        assert_eq!(users[2].as_ref().unwrap_err(), "missing group");
+        assert!(users[3].is_err());
+    }
+
+    #[test]
+    fn test_import_json_row_errors() {
+        assert!(import_json("{\"name\":\"alice\"}").is_err());
+        let users = import_json(r#"[{"name":"alice","group":"Default","password":""},{"name":"bob"},{"name":" ","group":"Default"},{"name":"carol","group":"Default","data":[{"type":"../x","data":""}]}]"#).unwrap();
+        assert_eq!(users.len(), 4);
+        assert_eq!(users[0].as_ref().unwrap().password, None);
+        assert!(users[1].is_err());
+        assert_eq!(users[2].as_ref().unwrap_err(), "missing name");
+        assert_eq!(users[3].as_ref().unwrap_err(), "invalid user data type ../x");
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2024 Ronan LE MEILLAT for SCTG Development
+//
+// This file is part of the SCTGDesk project.
+//
+// SCTGDesk is free software: you can redistribute it and/or modify
+// it under the terms of the Affero General Public License version 3 as
+// published by the Free Software Foundation.
+//
+// SCTGDesk is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// Affero General Public License for more details.
+//
+// You should have received a copy of the Affero General Public License
+// along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{http::Status, response::Responder, Response};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::Responses;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use rocket_okapi::response::OpenApiResponderInner;
+use rocket_okapi::OpenApiError;
+
+/// # IfNoneMatch
+///
+/// The value of the `If-None-Match` header of the request, if any.
+#[derive(Debug)]
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    /// Check if one of the entity tags of the header matches `etag`
+    /// The comparison is weak, the `W/` prefixes are ignored
+    pub fn matches(&self, etag: &str) -> bool {
+        let header = match &self.0 {
+            Some(header) => header,
+            None => return false,
+        };
+        let etag = etag.trim_start_matches("W/");
+        header
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = request
+            .headers()
+            .get_one("If-None-Match")
+            .map(|header| header.to_string());
+        Outcome::Success(IfNoneMatch(header))
+    }
+}
+
+impl OpenApiFromRequest<'_> for IfNoneMatch {
+    fn from_request_input(
+        _: &mut OpenApiGenerator,
+        _: String,
+        _: bool,
+    ) -> Result<RequestHeaderInput, OpenApiError> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// # ETagged
+///
+/// A response with an `ETag` header, the body is omitted with a 304 status when the client
+/// already has the current version.
+pub enum ETagged<R> {
+    NotModified(String),
+    Modified(String, R),
+}
+
+impl<R> ETagged<R> {
+    /// Build the response, `body` is only called when the client does not have the current version
+    pub async fn new<F>(etag: String, if_none_match: &IfNoneMatch, body: F) -> Option<Self>
+    where
+        F: std::future::Future<Output = Option<R>>,
+    {
+        if if_none_match.matches(&etag) {
+            return Some(ETagged::NotModified(etag));
+        }
+        Some(ETagged::Modified(etag, body.await?))
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for ETagged<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        match self {
+            ETagged::NotModified(etag) => Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .ok(),
+            ETagged::Modified(etag, body) => Response::build_from(body.respond_to(request)?)
+                .raw_header("ETag", etag)
+                .ok(),
+        }
+    }
+}
+
+impl<R: OpenApiResponderInner> OpenApiResponderInner for ETagged<R> {
+    fn responses(generator: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        use rocket_okapi::okapi::openapi3::{RefOr, Response as OpenApiReponse};
+
+        let mut responses = R::responses(generator)?;
+        responses.responses.insert(
+            "304".to_string(),
+            RefOr::Object(OpenApiReponse {
+                description: "\
+                # [304 Not Modified](https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/304)\n\
+                This response is given when the `If-None-Match` header matches the current `ETag`. \
+                "
+                .to_string(),
+                ..Default::default()
+            }),
+        );
+        Ok(responses)
+    }
+}
+
+/// Build the entity tag of an address book response from the revision of the address book
+/// and the parameters of the request
+pub fn ab_etag<T: Hash>(revision: i64, params: T) -> String {
+    let mut hasher = DefaultHasher::new();
+    params.hash(&mut hasher);
+    format!("\"{}-{:x}\"", revision, hasher.finish())
+}
+
@@ -23,8 +23,10 @@ use rocket_okapi::okapi::openapi3::RequestBody;
 use rocket_okapi::request::OpenApiFromData;
 use rocket_okapi::{JsonSchema,Result as OkapiResult};
 use serde::Deserialize;
+use utils::client_ip::filter_forwarding_headers;
 
 /// A wrapper around a JSON value that includes headers.
+/// The forwarding headers are dropped unless the request comes from a trusted proxy.
 #[derive(Debug)]
 pub struct ExtendedJson<T> {
     pub data: T,
@@ -62,6 +64,8 @@ impl<'r, T: Deserialize<'r>> ExtendedJson<T> {
         headers_src.iter().for_each(|k|{
             headers.insert(k.name.to_string().to_lowercase(),k.value.to_string().to_lowercase());
         });
+        let remote = req.remote().map(|addr| addr.ip());
+        filter_forwarding_headers(remote, &mut headers);
         let limit = req.limits().get("json").unwrap_or(Limits::JSON);
         let string = match data.open(limit).into_string().await {
             Ok(s) if s.is_complete() => s.into_inner(),
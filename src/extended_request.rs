@@ -19,15 +19,19 @@ use rocket_okapi::request::OpenApiFromRequest;
 use rocket_okapi::gen::OpenApiGenerator;
 use rocket_okapi::request::RequestHeaderInput;
 use rocket_okapi::OpenApiError;
+use utils::client_ip::{filter_forwarding_headers, get_client_ip};
 
 /// # ExtendedRequest
 /// 
 /// This struct is used to extend the Request struct from Rocket adding a headers field.
 /// this is needed for analysis of the headers in the request. Particularly useful for
 /// retrieving the host with utils::get_host::get_host()
+/// The forwarding headers are dropped unless the request comes from a trusted proxy,
+/// `client_ip` is the real ip of the client (see utils::client_ip::get_client_ip())
 #[derive(Debug)]
 pub struct ExtendedRequest{
     pub headers: HashMap<String, String>,
+    pub client_ip: Option<String>,
 }
 
 /// # FromRequest for ExtendedRequest
@@ -43,7 +47,10 @@ impl<'r> FromRequest<'r> for ExtendedRequest {
         headers_src.iter().for_each(|k|{
             headers.insert(k.name.to_string().to_lowercase(),k.value.to_string().to_lowercase());
         });
-        Outcome::Success(Self { headers })
+        let remote = request.remote().map(|addr| addr.ip());
+        filter_forwarding_headers(remote, &mut headers);
+        let client_ip = get_client_ip(remote, &headers);
+        Outcome::Success(Self { headers, client_ip })
     }
 }
 
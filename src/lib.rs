@@ -14,6 +14,7 @@
 // You should have received a copy of the Affero General Public License
 // along with SCTGDesk. If not, see <https://www.gnu.org/licenses/agpl-3.0.html>.
 mod api;
+mod etag;
 mod extended_json;
 mod extended_request;
 
@@ -22,15 +23,17 @@ use std::env;
 use std::io::Cursor;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use api::ActionResponse;
+use etag::{ab_etag, ETagged, IfNoneMatch};
 use extended_json::ExtendedJson;
 use extended_request::ExtendedRequest;
 use oauth2::oauth_provider::OAuthProvider;
 use oauth2::oauth_provider::OAuthProviderFactory;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::form::validate::Len;
-use rocket::http::{ContentType, Header};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::{Redirect, Responder};
 use rocket::{async_trait, delete, options, put, routes, uri};
 use rocket::{Request, Response};
@@ -39,22 +42,53 @@ use s3software::extract_version;
 use s3software::get_software_download_page;
 use s3software::{get_s3_config_file, get_signed_release_url_with_config};
 
-use state::{self};
+use state::{self, permission, TeamMember};
 
 #[cfg(feature = "ui")]
 use ui;
+use utils::ab_transfer;
 use utils::guid_into_uuid;
+use utils::AbImportMode;
+use utils::AbImportReport;
+use utils::AbLegacyMigrationReport;
+use utils::AbChangesResponse;
+use utils::AbPeerHistoryResponse;
+use utils::AbPeersBatchResponse;
+use utils::AbPeersQuery;
+use utils::AbPeersTagRequest;
+use utils::AbPeersTransferRequest;
+use utils::CrossGroupAccessResponse;
+use utils::DEFAULT_TEAM_GUID;
+use utils::{AddTeamRequest, TeamsResponse, UpdateTeamRequest};
+use utils::{AddRoleRequest, Permission, RolesResponse, UpdateRoleRequest, UserRolesRequest, UserRolesResponse};
+use utils::AuditConnResponse;
+use utils::{user_transfer, UserImportReport, UserTransferFormat};
+use utils::{InviteResponse, InviteUserRequest, PasswordResetRequest, SetPasswordRequest, UserInvitesResponse, UserTokenKind};
+use utils::{ChangePasswordRequest, UpdateProfileRequest, UserAddressBooksResponse, UserProfile};
+use utils::{PutUserDataRequest, UserDataEntry, UserDataError, UserDataItem, UserDataListResponse};
 use utils::AbProfile;
+use utils::AbRecycleResponse;
 use utils::AbRule;
 use utils::AbRuleAddRequest;
+use utils::AbRuleLevel;
 use utils::AbRuleDeleteRequest;
 use utils::AbRulesResponse;
 use utils::AbSharedAddRequest;
 use utils::AbSharedNameRequest;
+use utils::AbTagCheckReport;
+use utils::AbTagRepairMode;
+use utils::AbTransferFormat;
 use utils::AddGoupRequest;
 use utils::CpuCount;
 use utils::PeersCountResponse;
+use utils::PeerActiveDay;
+use utils::PeerCleanupReport;
+use utils::PeerStat;
+use utils::PeerStatField;
+use utils::PeerSysinfoHistoryResponse;
+use utils::PeersUptimeResponse;
 use utils::Platform;
+use utils::Setting;
 use utils::UpdateGoupRequest;
 use utils::{
     self, get_host::get_host, AbPeer, AbPeersResponse, AbPersonal, AbSettingsResponse,
@@ -78,7 +112,7 @@ use utils::{
 };
 
 type AuthenticatedUser = state::AuthenticatedUser<BearerAuthToken>;
-type AuthenticatedAdmin = state::AuthenticatedAdmin<BearerAuthToken>;
+type Authorized<P> = state::Authorized<BearerAuthToken, P>;
 
 use rocket_okapi::{openapi, openapi_get_routes, rapidoc::*, settings::UrlObject};
 use uuid::Uuid;
@@ -114,6 +148,54 @@ async fn options(_path: PathBuf) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Re-encrypt the address book peers credentials with a new master key
+/// See ApiState::rotate_ab_key
+pub async fn rotate_ab_key() -> Option<u64> {
+    let state = ApiState::new_with_db("db_v2.sqlite3").await;
+    state.rotate_ab_key().await
+}
+
+/// Parse the format of a users file from its extension, CSV for `.csv` and JSON otherwise
+fn user_transfer_format_of(path: &str) -> UserTransferFormat {
+    if path.to_lowercase().ends_with(".csv") {
+        UserTransferFormat::Csv
+    } else {
+        UserTransferFormat::Json
+    }
+}
+
+/// Import the users of a CSV or JSON file
+/// See ApiState::import_users
+pub async fn import_users_file(
+    path: &str,
+    create_groups: bool,
+    dry_run: bool,
+) -> Result<UserImportReport, String> {
+    let data = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let users = user_transfer::import_users(data.as_str(), user_transfer_format_of(path))?;
+    let state = ApiState::new_with_db("db_v2.sqlite3").await;
+    state
+        .import_users(&users, create_groups, true, None, dry_run)
+        .await
+        .ok_or("import failed, check the logs".to_string())
+}
+
+/// Export the users to a CSV or JSON file
+///
+/// # Returns
+///
+/// Result<usize, String> - the number of exported users
+pub async fn export_users_file(path: &str) -> Result<usize, String> {
+    let state = ApiState::new_with_db("db_v2.sqlite3").await;
+    let users = state
+        .export_users(None)
+        .await
+        .ok_or("export failed, check the logs".to_string())?;
+    let data = user_transfer::export_users(&users, user_transfer_format_of(path));
+    std::fs::write(path, data).map_err(|e| e.to_string())?;
+    Ok(users.len())
+}
+
 pub async fn build_rocket(figment: Figment) -> Rocket<Build> {
     let state = ApiState::new_with_db("db_v2.sqlite3").await;
 
@@ -128,6 +210,7 @@ pub async fn build_rocket(figment: Figment) -> Rocket<Build> {
                 ab_get,
                 ab_post,
                 ab,
+                ab_legacy_migrate,
                 current_user,
                 audit,
                 logout,
@@ -138,15 +221,54 @@ pub async fn build_rocket(figment: Figment) -> Rocket<Build> {
                 group_add,
                 group_delete,
                 group_update,
+                group_access,
+                teams,
+                team_add,
+                team_update,
+                team_delete,
+                roles,
+                role_add,
+                role_update,
+                role_delete,
+                user_roles,
+                user_roles_update,
+                user_export,
+                user_import,
+                user_invite,
+                user_invites,
+                user_invite_resend,
+                user_invite_revoke,
+                invite_accept,
+                password_reset_request,
+                password_reset,
+                audit_conns,
                 users,
                 users_client,
                 user_add,
                 user_delete,
                 user_enable,
                 user_update,
+                me,
+                me_update,
+                me_password,
+                me_peers,
+                me_address_books,
+                me_data_list,
+                me_data_get,
+                me_data_put,
+                me_data_delete,
                 peers,
                 peers_count,
                 peers_cpus,
+                peers_uptime,
+                peers_sysinfo_history,
+                peers_report_version,
+                peers_stats,
+                peers_active,
+                peers_cleanup_report,
+                peers_cleanup,
+                settings_get,
+                settings_update,
                 strategies,
                 oidc_auth,
                 oidc_state,
@@ -156,6 +278,19 @@ pub async fn build_rocket(figment: Figment) -> Rocket<Build> {
                 ab_peer_add,
                 ab_peer_update,
                 ab_peer_delete,
+                ab_peer_recycle,
+                ab_peer_restore,
+                ab_changes,
+                ab_tag_check,
+                ab_tag_repair,
+                ab_peers_copy,
+                ab_peers_move,
+                ab_peers_tag,
+                ab_peers_untag,
+                ab_peers_delete_by_tag,
+                ab_peer_history,
+                ab_export,
+                ab_import,
                 ab_peers,
                 ab_personal,
                 ab_tags,
@@ -242,13 +377,14 @@ pub async fn build_rocket(figment: Figment) -> Rocket<Build> {
 #[post("/api/login", format = "application/json", data = "<request>")]
 async fn login(
     state: &State<ApiState>,
+    client: ExtendedRequest,
     request: Json<LoginRequest>,
 ) -> Result<Json<LoginReply>, status::Unauthorized<()>> {
     let status_forbidden = || status::Unauthorized::<()>(());
 
     let user_password_info = UserPasswordInfo::from_password(request.password.as_str());
     let (user, access_token) = state
-        .user_login(&request.username, user_password_info, false)
+        .user_login(&request.username, user_password_info, false, client.client_ip)
         .await
         .ok_or_else(status_forbidden)?;
 
@@ -279,6 +415,7 @@ async fn login(
 /// ## Returns
 ///
 /// If successful, this function returns a `Json<AbGetResponse>` object, which includes the legacy address book information.  <br>
+/// The response has an `ETag` header, a `304 Not Modified` status is returned without body if it matches the `If-None-Match` header.  <br>
 /// If the user is not authorized, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
@@ -290,8 +427,9 @@ async fn login(
 async fn ab_get(
     state: &State<ApiState>,
     user: AuthenticatedUser,
-) -> Result<Json<AbGetResponse>, status::Unauthorized<()>> {
-    ab_get_handler(state, user).await
+    if_none_match: IfNoneMatch,
+) -> Result<ETagged<Json<AbGetResponse>>, status::Unauthorized<()>> {
+    ab_get_handler(state, user, if_none_match).await
 }
 
 /// # Get the User's Address Book
@@ -306,6 +444,7 @@ async fn ab_get(
 /// ## Returns
 ///
 /// If successful, this function returns a `Json<AbGetResponse>` object, which includes the address book information.  <br>
+/// The response has an `ETag` header, a `304 Not Modified` status is returned without body if it matches the `If-None-Match` header.  <br>
 /// If the user is not authorized, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
@@ -320,8 +459,9 @@ async fn ab_get(
 async fn ab_post(
     state: &State<ApiState>,
     user: AuthenticatedUser,
-) -> Result<Json<AbGetResponse>, status::Unauthorized<()>> {
-    ab_get_handler(state, user).await
+    if_none_match: IfNoneMatch,
+) -> Result<ETagged<Json<AbGetResponse>>, status::Unauthorized<()>> {
+    ab_get_handler(state, user, if_none_match).await
 }
 
 /// Common handler for the user's address book
@@ -330,16 +470,29 @@ async fn ab_post(
 ///
 /// * `state` - The API state
 /// * `user` - The authenticated user supplied via a Bearer token
+/// * `if_none_match` - The `If-None-Match` header of the request
 ///
 /// # Returns
 ///
-/// The user's address book in JSON format
+/// The user's address book in JSON format, its entity tag is based on the revision of the
+/// personal address book in compatibility mode and on the content of the address book otherwise
 async fn ab_get_handler(
     state: &State<ApiState>,
     user: AuthenticatedUser,
-) -> Result<Json<AbGetResponse>, status::Unauthorized<()>> {
+    if_none_match: IfNoneMatch,
+) -> Result<ETagged<Json<AbGetResponse>>, status::Unauthorized<()>> {
     log::debug!("ab get");
 
+    let revision = state
+        .get_user_address_book_revision(&user.info.user_id)
+        .await;
+    if let Some(revision) = revision {
+        let etag = ab_etag(revision, "legacy");
+        if if_none_match.matches(&etag) {
+            return Ok(ETagged::NotModified(etag));
+        }
+    }
+
     // Get the user's address book from the state
     let abi = state
         .get_user_address_book(user.info.user_id)
@@ -364,8 +517,16 @@ async fn ab_get_handler(
     // Debug log the reply
     log::debug!("ab get reply: {:?}", Json(&reply));
 
+    let etag = match revision {
+        Some(revision) => ab_etag(revision, "legacy"),
+        None => ab_etag(0, &reply.data),
+    };
+    if if_none_match.matches(&etag) {
+        return Ok(ETagged::NotModified(etag));
+    }
+
     // Return the reply as JSON
-    Ok(Json(reply))
+    Ok(ETagged::Modified(etag, Json(reply)))
 }
 
 /// Set the user's address book
@@ -397,6 +558,45 @@ async fn ab(
     Ok(())
 }
 
+/// # Migrate the Legacy Address Books
+///
+/// This function is an API endpoint that allows an authenticated admin to migrate all the legacy address books
+/// into the personal address books of their owners.
+/// It is tagged with "address book legacy" for OpenAPI documentation.
+///
+/// A legacy address book is also migrated when its owner logs in, each one is migrated only once.  <br>
+/// When the `ab_legacy_compat` setting is `1` (default `0`), the legacy `/api/ab` endpoints read and write the personal address book
+/// so that the old and the new clients share the same address book.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<AbLegacyMigrationReport>>` object with the changes applied for each user.  <br>
+/// If the legacy address books cannot be read, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not an admin.
+///
+/// # Example
+///
+/// POST /api/ab/legacy/migrate
+#[openapi(tag = "address book legacy")]
+#[post("/api/ab/legacy/migrate", format = "application/json")]
+async fn ab_legacy_migrate(
+    state: &State<ApiState>,
+    user: Authorized<permission::SettingsManage>,
+) -> Result<Json<Vec<AbLegacyMigrationReport>>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::NotFound::<()>(()));
+    }
+    let reports = state
+        .migrate_legacy_address_books()
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(reports))
+}
+
 /// # Get the Current User
 ///
 /// This function is an API endpoint that allows an authenticated user to retrieve their current user information.
@@ -440,12 +640,26 @@ async fn current_user(
     Ok(Json(reply))
 }
 
-/// Audit
-#[openapi(tag = "todo")]
+/// # Audit
+///
+/// This function is an API endpoint that is hit by the client for reporting its connections.
+/// Each event is recorded in the connection audit with the ip address the event was sent from.
+/// It is tagged with "audit" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The request data, which includes the connection event.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode.
+#[openapi(tag = "audit")]
 #[post("/api/audit", format = "application/json", data = "<request>")]
-async fn audit(state: &State<ApiState>, request: Json<AuditRequest>) {
+async fn audit(state: &State<ApiState>, client: ExtendedRequest, request: Json<AuditRequest>) {
     log::debug!("audit: {:?}", request);
     state.check_maintenance().await;
+    let res = state.add_audit_conn(&request.0, client.client_ip).await;
+    log::debug!("res: {:?}", res);
 }
 
 /// # Log the User Out
@@ -492,7 +706,7 @@ async fn logout(
 /// # Heartbeat
 ///
 /// This function is an API endpoint that is frequently hit by the client at the /api/heartbeat endpoint.
-/// It updates the `last_online` field of the peer.
+/// It updates the `last_online` field of the peer and records the ip address of the client in its `last_ip` info.
 /// It is tagged with "peer" for OpenAPI documentation.
 ///
 /// ## Parameters
@@ -508,10 +722,14 @@ async fn logout(
 /// This function will return an error if the system is in maintenance mode.
 #[openapi(tag = "peer")]
 #[post("/api/heartbeat", format = "application/json", data = "<request>")]
-async fn heartbeat(state: &State<ApiState>, request: Json<HeartbeatRequest>) -> String {
+async fn heartbeat(
+    state: &State<ApiState>,
+    client: ExtendedRequest,
+    request: Json<HeartbeatRequest>,
+) -> String {
     log::debug!("heartbeat: {:?}", request);
     let heartbeat = request.0;
-    let res = state.update_heartbeat(heartbeat).await;
+    let res = state.update_heartbeat(heartbeat, client.client_ip).await;
     log::debug!("res: {:?}", res);
     "OK".to_string()
 }
@@ -536,9 +754,13 @@ async fn heartbeat(state: &State<ApiState>, request: Json<HeartbeatRequest>) ->
 ///
 #[openapi(tag = "peer")]
 #[post("/api/sysinfo", format = "application/json", data = "<request>")]
-async fn sysinfo(state: &State<ApiState>, request: Json<utils::SystemInfo>) -> String {
+async fn sysinfo(
+    state: &State<ApiState>,
+    client: ExtendedRequest,
+    request: Json<utils::SystemInfo>,
+) -> String {
     let sysinfo = request.0;
-    let res = state.update_systeminfo(sysinfo).await;
+    let res = state.update_systeminfo(sysinfo, client.client_ip).await;
 
     if res.is_none() {
         return "ID_NOT_FOUND".to_string();
@@ -581,7 +803,7 @@ async fn sysinfo(state: &State<ApiState>, request: Json<utils::SystemInfo>) -> S
 )]
 async fn users(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::UsersManage>,
     current: u32,
     #[allow(non_snake_case)] pageSize: u32,
     email: Option<&str>,
@@ -595,7 +817,9 @@ async fn users(
     } else {
         email
     };
-    let res = state.get_all_users(name, email, current, pageSize).await;
+    let res = state
+        .get_all_users(name, email, current, pageSize, user.team.as_deref())
+        .await;
     if res.is_none() {
         return Err(status::NotFound::<()>(()));
     }
@@ -635,7 +859,7 @@ async fn users(
 #[get("/api/groups?<current>&<pageSize>", format = "application/json")]
 async fn groups(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::GroupsManage>,
     #[allow(unused_variables)] current: u32,
     #[allow(non_snake_case, unused_variables)] pageSize: u32,
 ) -> Result<Json<GroupsResponse>, status::NotFound<()>> {
@@ -647,7 +871,9 @@ async fn groups(
     } else {
         pageSize
     };
-    let groups = state.get_groups(offset, page_size).await;
+    let groups = state
+        .get_groups(offset, page_size, user.team.as_deref())
+        .await;
     if groups.is_none() {
         return Err(status::NotFound::<()>(()));
     }
@@ -678,11 +904,17 @@ async fn groups(
 #[get("/api/group/<guid>", format = "application/json")]
 async fn group_get(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::GroupsManage>,
     guid: String,
 ) -> Result<Json<utils::Group>, status::NotFound<()>> {
     log::debug!("group_get");
     state.check_maintenance().await;
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::Group(guid.as_str()))
+        .await
+    {
+        return Err(status::NotFound::<()>(()));
+    }
     let group = state.get_group(guid.as_str()).await;
     if group.is_none() {
         return Err(status::NotFound::<()>(()));
@@ -694,7 +926,7 @@ async fn group_get(
 ///
 /// This function is an API endpoint that allows an authenticated admin to add a new group.
 /// It is tagged with "group" for OpenAPI documentation..<br>
-/// Todo allow to use different team
+/// The group is created in the team of the admin, the admins of the `Default` team can choose the team with `team`.
 ///
 /// ## Parameters
 ///
@@ -707,7 +939,8 @@ async fn group_get(
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the admin is not authorized.
+/// This function will return an error if the system is in maintenance mode, if the admin is not authorized,
+/// if the group already exists or if one of the `allowed_incomings` / `allowed_outgoings` is not the guid of an existing group.
 ///
 /// # Example
 ///
@@ -717,16 +950,34 @@ async fn group_get(
 #[post("/api/group", format = "application/json", data = "<request>")]
 async fn group_add(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::GroupsManage>,
     request: Json<AddGoupRequest>,
 ) -> Result<Json<UsersResponse>, status::Unauthorized<()>> {
     log::debug!("create_group");
     state.check_maintenance().await;
 
     let request = request.into_inner();
-    let _res = state
-        .create_group(request.name.as_str(), "Default", request.note.as_str()) // Todo allow to use different team
-        .await;
+    // the admins of a team can only create groups in their team
+    let team = match (&user.team, &request.team) {
+        (Some(team), _) => team.as_str(),
+        (None, Some(team)) => team.as_str(),
+        (None, None) => DEFAULT_TEAM_GUID,
+    };
+    if !groups_in_team_scope(state, user.team.as_deref(), &request.allowed_incomings).await
+        || !groups_in_team_scope(state, user.team.as_deref(), &request.allowed_outgoings).await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    state
+        .create_group(
+            request.name.as_str(),
+            team,
+            request.note.as_str(),
+            &request.allowed_incomings,
+            &request.allowed_outgoings,
+        )
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
     let response = UsersResponse {
         msg: "success".to_string(),
         total: 1,
@@ -739,7 +990,7 @@ async fn group_add(
 /// # Update a group
 ///
 /// This function is an API endpoint that allows an authenticated admin to update a group.<br>
-/// Todo allow to use different team
+/// The team of the group is not modified, the admins of a team only update the groups of their team.<br>
 ///
 /// ## Parameters
 ///
@@ -748,25 +999,41 @@ async fn group_add(
 /// ## Returns
 ///
 /// If successful, this function returns a `Json<UsersResponse>` object, which includes a success message, the total number of groups, and the list of groups.  <br>
+/// The cross group access of the group is replaced by `allowed_incomings` and `allowed_outgoings`.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the group does not exist or if one of the
+/// `allowed_incomings` / `allowed_outgoings` is not the guid of an existing group.
 #[openapi(tag = "group")]
 #[put("/api/group", format = "application/json", data = "<request>")]
 async fn group_update(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::GroupsManage>,
     request: Json<UpdateGoupRequest>,
 ) -> Result<Json<UsersResponse>, status::Unauthorized<()>> {
     log::debug!("update_group");
     state.check_maintenance().await;
 
     let request = request.into_inner();
-    let _res = state
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::Group(request.guid.as_str()))
+        .await
+        || !groups_in_team_scope(state, user.team.as_deref(), &request.allowed_incomings).await
+        || !groups_in_team_scope(state, user.team.as_deref(), &request.allowed_outgoings).await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    state
         .update_group(
             request.guid.as_str(),
             request.name.as_str(),
-            "Default", // Todo allow to use different team
             request.note.as_str(),
+            &request.allowed_incomings,
+            &request.allowed_outgoings,
         )
-        .await;
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
     let response = UsersResponse {
         msg: "success".to_string(),
         total: 1,
@@ -791,899 +1058,3010 @@ async fn group_update(
 #[delete("/api/group/<guid>", format = "application/json", data = "<request>")]
 async fn group_delete(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::GroupsManage>,
     request: Json<Vec<String>>,
     guid: &str,
 ) -> Result<(), status::Unauthorized<()>> {
     log::debug!("group_delete");
     state.check_maintenance().await;
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::Group(guid))
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
     let _res = state.delete_group(guid).await;
     Ok(())
 }
 
-/// # Get Peers
+/// # Check cross group access
 ///
-/// This function is an API endpoint that retrieves the list of all peers in the network.
-/// It is tagged with "peer" for OpenAPI documentation.
+/// This function is an API endpoint that allows an authenticated admin to check if a connection
+/// between two devices is allowed by the cross group access.
+/// It is tagged with "group" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - none
+/// - `from_user`: The name of the user initiating the connection, takes precedence over `from_device`.
+///
+/// - `from_device`: The id of the device initiating the connection.
+///
+/// - `to_device`: The id of the device receiving the connection.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<PeersResponse>` object, which includes a success message, the total number of peers, and the list of peers.  <br>
-/// If no peers are found, this function returns a `status::NotFound` error.  <br>
+/// If successful, this function returns a `Json<CrossGroupAccessResponse>` object with the groups of both sides
+/// and whether the connection is allowed.  <br>
+/// The group of a device is the group of its owner, devices without owner are not restricted.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if no peers are found.
+/// This function will return a `status::NotFound` error if neither `from_user` nor `from_device` is given,
+/// or if the user or one of the devices does not exist.
 ///
 /// # Example
 ///
-/// GET /api/peers
-#[openapi(tag = "peer")]
-#[get("/api/peers", format = "application/json")]
-async fn peers(
+/// GET /api/group/access?from_user=admin&to_device=123456789
+#[openapi(tag = "group")]
+#[get("/api/group/access?<from_user>&<from_device>&<to_device>", format = "application/json")]
+async fn group_access(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-) -> Result<Json<PeersResponse>, status::NotFound<()>> {
-    log::debug!("peers");
+    user: Authorized<permission::GroupsManage>,
+    from_user: Option<&str>,
+    from_device: Option<&str>,
+    to_device: &str,
+) -> Result<Json<CrossGroupAccessResponse>, status::NotFound<()>> {
+    log::debug!("group_access");
     state.check_maintenance().await;
-    let peers = state.get_all_peers().await;
-
-    if peers.is_none() {
+    let response = state
+        .check_cross_group_access(from_user, from_device, to_device)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    // the admins of a team only check the connections between the devices of their team
+    if !user.is_global()
+        && !groups_in_team_scope(
+            state,
+            user.team.as_deref(),
+            &[response.from_group.clone(), response.to_group.clone()]
+                .into_iter()
+                .map(|group| group.unwrap_or_default())
+                .collect::<Vec<String>>(),
+        )
+        .await
+    {
         return Err(status::NotFound::<()>(()));
     }
-    Ok(Json(PeersResponse {
-        msg: "success".to_string(),
-        total: peers.len() as u32,
-        data: peers.unwrap(),
-    }))
+    Ok(Json(response))
 }
 
-/// # Count Peers per platform
+/// # Get the List of Teams
 ///
-/// This function is an API endpoint that retrieves the count of peers per platform.
-/// It is tagged with "peer" for OpenAPI documentation.
+/// This function is an API endpoint that allows an authenticated admin to retrieve a paginated list of teams.
+/// The admins of the `Default` team see every team, the other admins only see their own team.
+/// It is tagged with "team" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `platform`: The platform to filter the peers by (windows, macos, linux, android or all). <br>
+/// - `current`: The current page number.  
+///
+/// - `pageSize`: The number of teams per page.  
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<PeersCountResponse>` object, which includes the total number of peers for the specified platform.  <br>
+/// If successful, this function returns a `Json<TeamsResponse>` object, which includes a success message, the total number of teams, and the list of teams
+/// with their number of users and groups.  <br>
 ///
-#[openapi(tag = "peer")]
-#[get("/api/peers/count/<platform>", format = "application/json")]
-async fn peers_count(
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the teams cannot be read.
+///
+/// # Example
+///
+/// GET /api/teams?current=1&pageSize=10
+#[openapi(tag = "team")]
+#[get("/api/teams?<current>&<pageSize>", format = "application/json")]
+async fn teams(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    platform: String,
-) -> Result<Json<PeersCountResponse>, status::NotFound<()>> {
-    let count = match platform.to_lowercase().as_str() {
-        "windows" => {
-            let count = state.get_peers_count(Platform::Windows).await;
-            log::debug!("peers_count: {:?}", count);
-            count
-        }
-        "mac" | "macos" => {
-            let count = state.get_peers_count(Platform::MacOS).await;
-            log::debug!("peers_count: {:?}", count);
-            count
-        }
-        "linux" => {
-            let count = state.get_peers_count(Platform::Linux).await;
-            log::debug!("peers_count: {:?}", count);
-            count
-        }
-        "android" => {
-            let count = state.get_peers_count(Platform::Android).await;
-            log::debug!("peers_count: {:?}", count);
-            count
-        }
-        "all" => {
-            let count = state.get_peers_count(Platform::All).await;
-            log::debug!("peers_count: {:?}", count);
-            count
-        }
-        _ => 0,
-    };
-    return Ok(Json(PeersCountResponse { total: count }));
+    user: Authorized<permission::TeamsManage>,
+    current: u32,
+    #[allow(non_snake_case)] pageSize: u32,
+) -> Result<Json<TeamsResponse>, status::NotFound<()>> {
+    log::debug!("teams");
+    state.check_maintenance().await;
+    let (total, teams) = state
+        .get_teams(current, pageSize, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(TeamsResponse {
+        msg: "success".to_string(),
+        total,
+        data: teams,
+    }))
 }
 
-/// # Get the List of cpus used by the peers
+/// # Add a Team
 ///
-/// This function is an API endpoint that retrieves the count of cpus used by the peers.
-/// It is tagged with "peer" for OpenAPI documentation.
+/// This function is an API endpoint that allows an admin of the `Default` team to add a new team.
+/// It is tagged with "team" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
+/// - `request`: The name, the contact email and the note of the team.  <br>
+///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<Vec<CpuCount>>` object, which includes the total number of cpus used by the peers.  <br>
-#[openapi(tag = "peer")]
-#[get("/api/peers/cpus", format = "application/json")]
-async fn peers_cpus(
-    state: &State<ApiState>,
-    _user: AuthenticatedUser,
-) -> Result<Json<Vec<CpuCount>>, status::NotFound<()>> {
-    let cpus = state.get_cpus_count().await;
-    return Ok(Json(cpus));
-}
-
-/// # Login Options
+/// If successful, this function returns a `Json<TeamsResponse>` object with the new team.  <br>
 ///
-/// This is called by the client for knowing the Oauth2 provider(s) available
-/// You must provide a list of Oauth2 providers in the `oauth2.toml` config file
-/// The config file can be overridden by the `OAUTH2_CONFIG_FILE` environment variable
+/// ## Errors
 ///
-/// This function is an API endpoint that is called by the client to get the list of available OAuth2 providers.
-/// The list of providers is defined in the `oauth2.toml` config file, which can be overridden by the `OAUTH2_CONFIG_FILE` environment variable.
-/// It is tagged with "login" for OpenAPI documentation.
+/// This function will return a `status::Unauthorized` error if the admin is not an admin of the `Default` team,
+/// or if the name is already used.
 ///
-/// ## Limitations
+/// # Example
 ///
-/// It needs to be completed for mapping the username and email from the OAuth2 provider to the SCTGDesk user.
+/// POST /api/team
+/// {"name":"Customer","email":"it@customer.org","note":null}
+#[openapi(tag = "team")]
+#[post("/api/team", format = "application/json", data = "<request>")]
+async fn team_add(
+    state: &State<ApiState>,
+    user: Authorized<permission::TeamsManage>,
+    request: Json<AddTeamRequest>,
+) -> Result<Json<TeamsResponse>, status::Unauthorized<()>> {
+    log::debug!("team_add");
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let request = request.into_inner();
+    let guid = state
+        .create_team(request.name.as_str(), request.email.as_str(), request.note.as_deref())
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    let (total, teams) = state
+        .get_teams(1, 1, Some(guid.as_str()))
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(TeamsResponse {
+        msg: "success".to_string(),
+        total,
+        data: teams,
+    }))
+}
+
+/// # Update a Team
+///
+/// This function is an API endpoint that allows an admin of the `Default` team to update a team.
+/// It is tagged with "team" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - none
+/// - `request`: The guid of the team with its new name, contact email and note.  <br>
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<Vec<String>>` object, which includes the list of available OAuth2 providers.  <br>
-/// If the config file is not found or cannot be read, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns an empty response.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the config file is not found or cannot be read.
-///
-/// # Example
-///
-/// GET /api/login-options
-#[openapi(tag = "login")]
-#[get("/api/login-options", format = "application/json")]
-async fn login_options(
+/// This function will return a `status::Unauthorized` error if the admin is not an admin of the `Default` team,
+/// if the team does not exist or if the name is used by another team.
+#[openapi(tag = "team")]
+#[put("/api/team", format = "application/json", data = "<request>")]
+async fn team_update(
     state: &State<ApiState>,
-) -> Result<Json<Vec<String>>, status::Unauthorized<()>> {
-    let mut providers: Vec<String> = Vec::new();
-    let providers_config = state
-        .get_oauth2_config(oauth2::get_providers_config_file().as_str())
-        .await;
-    if providers_config.is_none() {
+    user: Authorized<permission::TeamsManage>,
+    request: Json<UpdateTeamRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("team_update");
+    state.check_maintenance().await;
+    if !user.is_global() {
         return Err(status::Unauthorized::<()>(()));
     }
-    for p in providers_config.unwrap() {
-        providers.push(p.op_auth_string);
-    }
-    Ok(Json(providers))
+    let request = request.into_inner();
+    state
+        .update_team(
+            request.guid.as_str(),
+            request.name.as_str(),
+            request.email.as_str(),
+            request.note.as_deref(),
+        )
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
 }
 
-/// OIDC Auth request
-///
-/// This entrypoint is called by the client for getting the authorization url for the Oauth2 provider he chooses
-///
-/// For testing you can generate a valid uuid field with the following command: `uuidgen | base64`
-/// # OIDC Auth Request
+/// # Delete a Team
 ///
-/// This function is an API endpoint that is called by the client to get the authorization URL for the chosen OAuth2 provider.
-/// It is tagged with "login" for OpenAPI documentation.
+/// This function is an API endpoint that allows an admin of the `Default` team to delete a team.
+/// It is tagged with "team" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `request`: The request data, which includes the chosen OAuth2 provider and a UUID.  <br> For testing you can generate a valid uuid field with the following command: `uuidgen | base64`
+/// - `guid`: The guid of the team.  <br>
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<OidcAuthUrl>` object, which includes the authorization URL and a session code.  <br>
-/// If the UUID is invalid or the OAuth2 provider is not found, this function returns an `OidcAuthUrl` object with an empty URL and an error code.  <br>
+/// If successful, this function returns an empty response.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the UUID is invalid or the OAuth2 provider is not found.
-///
-/// # Example
-///
-/// POST /api/oidc/auth
-/// {
-///     "op": "github",
-///     "uuid": "generated_uuid_base64_encoded"
-/// }
-#[openapi(tag = "login")]
-#[post("/api/oidc/auth", format = "application/json", data = "<request>")]
+/// This function will return a `status::Unauthorized` error if the admin is not an admin of the `Default` team,
+/// if the team does not exist, if it is the `Default` team or if it still has users or groups.
+#[openapi(tag = "team")]
+#[delete("/api/team/<guid>", format = "application/json")]
+async fn team_delete(
+    state: &State<ApiState>,
+    user: Authorized<permission::TeamsManage>,
+    guid: &str,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("team_delete");
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    state
+        .delete_team(guid)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Get the List of Roles
+///
+/// This function is an API endpoint that retrieves a paginated list of the roles with their permissions.
+/// It requires the `roles.manage` permission and is tagged with "role" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `current`: The current page number.  
+///
+/// - `pageSize`: The number of roles per page.  
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<RolesResponse>` object, which includes a success message, the total number of roles, and the list of roles
+/// with their number of users.  <br>
+///
+/// # Example
+///
+/// GET /api/roles?current=1&pageSize=10
+#[openapi(tag = "role")]
+#[get("/api/roles?<current>&<pageSize>", format = "application/json")]
+async fn roles(
+    state: &State<ApiState>,
+    _user: Authorized<permission::RolesManage>,
+    current: u32,
+    #[allow(non_snake_case)] pageSize: u32,
+) -> Result<Json<RolesResponse>, status::NotFound<()>> {
+    log::debug!("roles");
+    state.check_maintenance().await;
+    let (total, roles) = state
+        .get_roles(current, pageSize)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(RolesResponse {
+        msg: "success".to_string(),
+        total,
+        data: roles,
+    }))
+}
+
+/// # Add a Role
+///
+/// This function is an API endpoint that adds a role.
+/// The roles are shared by all the teams, they can only be managed by the members of the `Default` team
+/// with the `roles.manage` permission.
+/// It is tagged with "role" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The name, the note and the permissions of the role.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not a member of the `Default` team
+/// or if the name is already used.
+///
+/// # Example
+///
+/// POST /api/role
+/// {"name":"Helpdesk","note":null,"permissions":["peers.manage","address_books.manage"]}
+#[openapi(tag = "role")]
+#[post("/api/role", format = "application/json", data = "<request>")]
+async fn role_add(
+    state: &State<ApiState>,
+    user: Authorized<permission::RolesManage>,
+    request: Json<AddRoleRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("role_add");
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let request = request.into_inner();
+    state
+        .create_role(request.name.as_str(), request.note.as_deref(), &request.permissions)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Update a Role
+///
+/// This function is an API endpoint that updates the name, the note and the permissions of a role.
+/// It can only be used by the members of the `Default` team with the `roles.manage` permission.
+/// It is tagged with "role" for OpenAPI documentation.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not a member of the `Default` team,
+/// if the role does not exist or if the name is used by another role.
+#[openapi(tag = "role")]
+#[put("/api/role", format = "application/json", data = "<request>")]
+async fn role_update(
+    state: &State<ApiState>,
+    user: Authorized<permission::RolesManage>,
+    request: Json<UpdateRoleRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("role_update");
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let request = request.into_inner();
+    state
+        .update_role(
+            request.guid.as_str(),
+            request.name.as_str(),
+            request.note.as_deref(),
+            &request.permissions,
+        )
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Delete a Role
+///
+/// This function is an API endpoint that deletes a role, the role is removed from its users.
+/// It can only be used by the members of the `Default` team with the `roles.manage` permission.
+/// It is tagged with "role" for OpenAPI documentation.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not a member of the `Default` team
+/// or if the role does not exist.
+#[openapi(tag = "role")]
+#[delete("/api/role/<guid>", format = "application/json")]
+async fn role_delete(
+    state: &State<ApiState>,
+    user: Authorized<permission::RolesManage>,
+    guid: &str,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("role_delete");
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    state
+        .delete_role(guid)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Get the Roles of a User
+///
+/// This function is an API endpoint that retrieves the roles of a user and the resulting permissions.
+/// It requires the `roles.manage` permission, the members of a team only see the users of their team.
+/// It is tagged with "role" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `guid`: The guid of the user.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserRolesResponse>` object with the roles of the user,
+/// whether the user is an admin and its permissions.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `status::NotFound` error if the user does not exist or is not in the team of the caller.
+///
+/// # Example
+///
+/// GET /api/user/roles/018f2556-2301-79eb-91a2-cffe5ced4236
+#[openapi(tag = "role")]
+#[get("/api/user/roles/<guid>", format = "application/json")]
+async fn user_roles(
+    state: &State<ApiState>,
+    user: Authorized<permission::RolesManage>,
+    guid: &str,
+) -> Result<Json<UserRolesResponse>, status::NotFound<()>> {
+    log::debug!("user_roles");
+    state.check_maintenance().await;
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::User(guid))
+        .await
+    {
+        return Err(status::NotFound::<()>(()));
+    }
+    let user_id = uuid_into_guid(guid).ok_or(status::NotFound::<()>(()))?;
+    let roles = state
+        .get_user_roles(&user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let (admin, permissions) = state
+        .get_user_permissions(&user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(UserRolesResponse {
+        msg: "success".to_string(),
+        admin,
+        permissions,
+        data: roles,
+    }))
+}
+
+/// # Set the Roles of a User
+///
+/// This function is an API endpoint that replaces the roles of a user.
+/// It requires the `roles.manage` permission, the members of a team only update the users of their team.
+/// It is tagged with "role" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The guid of the user and the guids of its roles.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not in the team of the caller,
+/// if the user is an admin or has a permission the caller does not have while the caller is not an admin,
+/// or if one of the roles does not exist, the roles of the user are not modified in this case.
+///
+/// # Example
+///
+/// PUT /api/user/roles
+/// {"user":"018f2556-2301-79eb-91a2-cffe5ced4236","roles":["0192a3c4-5d6e-7f80-9a1b-2c3d4e5f6a7b"]}
+#[openapi(tag = "role")]
+#[put("/api/user/roles", format = "application/json", data = "<request>")]
+async fn user_roles_update(
+    state: &State<ApiState>,
+    user: Authorized<permission::RolesManage>,
+    request: Json<UserRolesRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    log::debug!("user_roles_update");
+    state.check_maintenance().await;
+    let request = request.into_inner();
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::User(request.user.as_str()))
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let user_id = uuid_into_guid(request.user.as_str()).ok_or(status::Unauthorized::<()>(()))?;
+    // only the admins update the admins and the users with permissions the current user does not have
+    if !state.can_manage_user(&user.info.user_id, &user_id).await {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    state
+        .set_user_roles(&user_id, &request.roles)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Get the Connection Audit
+///
+/// This function is an API endpoint that retrieves a paginated list of the connection events sent by the clients,
+/// the most recent first.
+/// It requires the `audit.read` permission, the members of a team only see the events of the peers of their team.
+/// It is tagged with "audit" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `current`: The current page number.  
+///
+/// - `pageSize`: The number of events per page.  
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AuditConnResponse>` object, which includes a success message, the total number of events,
+/// and the events.  <br>
+///
+/// # Example
+///
+/// GET /api/audit/conn?current=1&pageSize=10
+#[openapi(tag = "audit")]
+#[get("/api/audit/conn?<current>&<pageSize>", format = "application/json")]
+async fn audit_conns(
+    state: &State<ApiState>,
+    user: Authorized<permission::AuditRead>,
+    current: u32,
+    #[allow(non_snake_case)] pageSize: u32,
+) -> Result<Json<AuditConnResponse>, status::NotFound<()>> {
+    log::debug!("audit_conns");
+    state.check_maintenance().await;
+    let (total, audits) = state
+        .get_audit_conns(current, pageSize, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(AuditConnResponse {
+        msg: "success".to_string(),
+        total,
+        data: audits,
+    }))
+}
+
+/// Check if all the groups are visible in a team scope
+async fn groups_in_team_scope(state: &ApiState, team: Option<&str>, groups: &[String]) -> bool {
+    for group in groups {
+        if !state.is_in_team_scope(team, TeamMember::Group(group)).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// # Get Peers
+///
+/// This function is an API endpoint that retrieves the list of all peers in the network.
+/// The users without the `peers.read` permission only get their own peers and the peers reachable with the cross group rules of their group.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - none
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeersResponse>` object, which includes a success message, the total number of peers, and the list of peers.  <br>
+/// If no peers are found, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if no peers are found.
+///
+/// # Example
+///
+/// GET /api/peers
+#[openapi(tag = "peer")]
+#[get("/api/peers", format = "application/json")]
+async fn peers(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<PeersResponse>, status::NotFound<()>> {
+    log::debug!("peers");
+    state.check_maintenance().await;
+    let (team, viewer) = state
+        .get_peers_visibility(&user.info.user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let peers = state.get_all_peers(team.as_deref(), viewer.as_ref()).await;
+
+    if peers.is_none() {
+        return Err(status::NotFound::<()>(()));
+    }
+    Ok(Json(PeersResponse {
+        msg: "success".to_string(),
+        total: peers.len() as u32,
+        data: peers.unwrap(),
+    }))
+}
+
+/// # Count Peers per platform
+///
+/// This function is an API endpoint that retrieves the count of peers per platform.
+/// The users without the `peers.read` permission only count the peers they can see with `/api/peers`.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `platform`: The platform to filter the peers by (windows, macos, linux, android or all). <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeersCountResponse>` object, which includes the total number of peers for the specified platform.  <br>
+///
+#[openapi(tag = "peer")]
+#[get("/api/peers/count/<platform>", format = "application/json")]
+async fn peers_count(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    platform: String,
+) -> Result<Json<PeersCountResponse>, status::NotFound<()>> {
+    let (team, viewer) = state
+        .get_peers_visibility(&user.info.user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let count = match platform.to_lowercase().as_str() {
+        "windows" => {
+            let count = state.get_peers_count(Platform::Windows, team.as_deref(), viewer.as_ref()).await;
+            log::debug!("peers_count: {:?}", count);
+            count
+        }
+        "mac" | "macos" => {
+            let count = state.get_peers_count(Platform::MacOS, team.as_deref(), viewer.as_ref()).await;
+            log::debug!("peers_count: {:?}", count);
+            count
+        }
+        "linux" => {
+            let count = state.get_peers_count(Platform::Linux, team.as_deref(), viewer.as_ref()).await;
+            log::debug!("peers_count: {:?}", count);
+            count
+        }
+        "android" => {
+            let count = state.get_peers_count(Platform::Android, team.as_deref(), viewer.as_ref()).await;
+            log::debug!("peers_count: {:?}", count);
+            count
+        }
+        "all" => {
+            let count = state.get_peers_count(Platform::All, team.as_deref(), viewer.as_ref()).await;
+            log::debug!("peers_count: {:?}", count);
+            count
+        }
+        _ => 0,
+    };
+    return Ok(Json(PeersCountResponse { total: count }));
+}
+
+/// # Get the List of cpus used by the peers
+///
+/// This function is an API endpoint that retrieves the count of cpus used by the peers.
+/// The users without the `peers.read` permission only count the peers they can see with `/api/peers`.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<CpuCount>>` object, which includes the total number of cpus used by the peers.  <br>
+#[openapi(tag = "peer")]
+#[get("/api/peers/cpus", format = "application/json")]
+async fn peers_cpus(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<CpuCount>>, status::NotFound<()>> {
+    let (team, viewer) = state
+        .get_peers_visibility(&user.info.user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let cpus = state.get_cpus_count(team.as_deref(), viewer.as_ref()).await;
+    return Ok(Json(cpus));
+}
+
+/// # Get the uptime of the peers
+///
+/// This function is an API endpoint that retrieves the uptime of each peer over a period.
+/// A peer is considered online while its heartbeats are not older than the `peer_online_threshold_secs` setting.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `from`: The first day of the period formatted as YYYY-MM-DD (default: 6 days before `to`). <br>
+///
+/// - `to`: The last day of the period formatted as YYYY-MM-DD (default: today). <br>
+///
+/// The period cannot be longer than 366 days.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeersUptimeResponse>` object, which includes for each peer its current online state,
+/// its uptime percentage over the period and the number of seconds it was online each day.  <br>
+/// If the period is invalid or too long, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the period is invalid or too long.
+///
+/// # Example
+///
+/// GET /api/peers/uptime?from=2024-05-01&to=2024-05-07
+#[openapi(tag = "peer")]
+#[get("/api/peers/uptime?<from>&<to>", format = "application/json")]
+async fn peers_uptime(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersRead>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<PeersUptimeResponse>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let (from, to, uptimes) = state
+        .get_peers_uptime(from, to, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(PeersUptimeResponse {
+        msg: "success".to_string(),
+        total: uptimes.len() as u32,
+        from,
+        to,
+        data: uptimes,
+    }))
+}
+
+/// # Get the system info history of a peer
+///
+/// This function is an API endpoint that retrieves the successive system infos reported by a peer.
+/// A new snapshot is stored each time a sysinfo update differs from the previous one, with the list of changed fields.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `id`: The id of the peer. <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeerSysinfoHistoryResponse>` object, which includes the snapshots from the most recent to the oldest.  <br>
+/// If the history cannot be read, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode.
+///
+/// # Example
+///
+/// GET /api/peers/sysinfo/123456789
+#[openapi(tag = "peer")]
+#[get("/api/peers/sysinfo/<id>", format = "application/json")]
+async fn peers_sysinfo_history(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersRead>,
+    id: &str,
+) -> Result<Json<PeerSysinfoHistoryResponse>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let snapshots = state
+        .get_peer_sysinfo_history(id, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(PeerSysinfoHistoryResponse {
+        msg: "success".to_string(),
+        total: snapshots.len() as u32,
+        data: snapshots,
+    }))
+}
+
+/// # List the peers running an outdated client
+///
+/// This function is an API endpoint that retrieves the peers whose reported client version is lower than the given one.
+/// Peers which never reported their version are not listed.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `below`: The version to compare with (eg. 1.2.4). <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeersResponse>` object, which includes the list of outdated peers.  <br>
+/// If the peers cannot be read, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode.
+///
+/// # Example
+///
+/// GET /api/peers/report/version?below=1.2.4
+#[openapi(tag = "peer")]
+#[get("/api/peers/report/version?<below>", format = "application/json")]
+async fn peers_report_version(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersRead>,
+    below: &str,
+) -> Result<Json<PeersResponse>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let peers = state
+        .get_peers_with_version_below(below, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(PeersResponse {
+        msg: "success".to_string(),
+        total: peers.len() as u32,
+        data: peers,
+    }))
+}
+
+/// # Count Peers grouped by a field
+///
+/// This function is an API endpoint that counts the peers grouped by one of their properties.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `field`: The field used for grouping the peers, one of os, version, cpu, hostname, username, memory, owner, group, strategy or online. <br>
+///   The memory is grouped in buckets (< 4GB, 4-8GB, 8-16GB, 16-32GB, >= 32GB). <br>
+///   Peers without a value for the field are counted in the "unknown" key. <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<PeerStat>>` object, which includes the number of peers for each value, the most frequent first.  <br>
+/// If the field is unknown, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the field is unknown.
+///
+/// # Example
+///
+/// GET /api/peers/stats/version
+#[openapi(tag = "peer")]
+#[get("/api/peers/stats/<field>", format = "application/json")]
+async fn peers_stats(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersRead>,
+    field: &str,
+) -> Result<Json<Vec<PeerStat>>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let field = match field.to_lowercase().as_str() {
+        "os" => PeerStatField::Os,
+        "version" => PeerStatField::Version,
+        "cpu" => PeerStatField::Cpu,
+        "hostname" => PeerStatField::Hostname,
+        "username" => PeerStatField::Username,
+        "memory" => PeerStatField::Memory,
+        "owner" => PeerStatField::Owner,
+        "group" => PeerStatField::Group,
+        "strategy" => PeerStatField::Strategy,
+        "online" => PeerStatField::Online,
+        _ => return Err(status::NotFound::<()>(())),
+    };
+    let stats = state
+        .get_peers_stats(field, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(stats))
+}
+
+/// # Count the active Peers per day
+///
+/// This function is an API endpoint that counts, for each day of a period, the peers which were online at least once.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `from`: The first day of the period formatted as YYYY-MM-DD (default: 6 days before `to`). <br>
+///
+/// - `to`: The last day of the period formatted as YYYY-MM-DD (default: today). <br>
+///
+/// The period cannot be longer than 366 days.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<PeerActiveDay>>` object, which includes one entry per day of the period.  <br>
+/// If the period is invalid or too long, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the period is invalid or too long.
+///
+/// # Example
+///
+/// GET /api/peers/active?from=2024-05-01&to=2024-05-31
+#[openapi(tag = "peer")]
+#[get("/api/peers/active?<from>&<to>", format = "application/json")]
+async fn peers_active(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersRead>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<Json<Vec<PeerActiveDay>>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let days = state
+        .get_active_peers_per_day(from, to, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(days))
+}
+
+/// # Preview the Peers cleanup
+///
+/// This function is an API endpoint that allows an authenticated admin to preview what the peers cleanup job would do.
+/// The job runs once a day and is configured with the settings:
+/// - `peer_cleanup_stale_days`: peers unseen for more than this number of days are stale (0 disables the detection)
+/// - `peer_cleanup_action`: `flag` sets the status of the peers to 2, `delete` removes them
+/// - `peer_cleanup_merge_duplicates`: `1` detects the peers sharing an id or a hostname, keeps the most recently seen
+///   one and moves the address book entries of the others to it
+///
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeerCleanupReport>` object, which includes the stale peers and the groups of duplicates.  <br>
+/// If the report cannot be built, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not an admin.
+///
+/// # Example
+///
+/// GET /api/peers/cleanup
+#[openapi(tag = "peer")]
+#[get("/api/peers/cleanup", format = "application/json")]
+async fn peers_cleanup_report(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersManage>,
+) -> Result<Json<PeerCleanupReport>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::NotFound::<()>(()));
+    }
+    let report = state
+        .peer_cleanup(true)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(report))
+}
+
+/// # Run the Peers cleanup
+///
+/// This function is an API endpoint that allows an authenticated admin to run the peers cleanup job immediately.
+/// See `GET /api/peers/cleanup` for the settings of the job.
+/// It is tagged with "peer" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeerCleanupReport>` object, which includes the peers which were flagged or deleted.  <br>
+/// If the cleanup fails, nothing is modified and this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not an admin.
+///
+/// # Example
+///
+/// POST /api/peers/cleanup
+#[openapi(tag = "peer")]
+#[post("/api/peers/cleanup", format = "application/json")]
+async fn peers_cleanup(
+    state: &State<ApiState>,
+    user: Authorized<permission::PeersManage>,
+) -> Result<Json<PeerCleanupReport>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::NotFound::<()>(()));
+    }
+    let report = state
+        .peer_cleanup(false)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(report))
+}
+
+/// # Get the Settings
+///
+/// This function is an API endpoint that allows an authenticated admin to list the server settings.
+/// It is tagged with "settings" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<Setting>>` object, which includes all the settings.  <br>
+/// The secret settings (`smtp_password`) are returned as `********` when they are set, writing this value back keeps them.  <br>
+/// If the settings cannot be read, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not an admin.
+///
+/// # Example
+///
+/// GET /api/settings
+#[openapi(tag = "settings")]
+#[get("/api/settings", format = "application/json")]
+async fn settings_get(
+    state: &State<ApiState>,
+    user: Authorized<permission::SettingsManage>,
+) -> Result<Json<Vec<Setting>>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::NotFound::<()>(()));
+    }
+    let settings = state
+        .get_settings()
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(settings))
+}
+
+/// # Update a Setting
+///
+/// This function is an API endpoint that allows an authenticated admin to create or update a server setting.
+/// It is tagged with "settings" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The setting key and its new value.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty`.  <br>
+/// If the setting cannot be written, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not an admin.
+///
+/// # Example
+///
+/// PUT /api/settings
+/// {"key": "peer_cleanup_stale_days", "value": "90"}
+#[openapi(tag = "settings")]
+#[put("/api/settings", format = "application/json", data = "<request>")]
+async fn settings_update(
+    state: &State<ApiState>,
+    user: Authorized<permission::SettingsManage>,
+    request: Json<Setting>,
+) -> Result<ActionResponse, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::NotFound::<()>(()));
+    }
+    state
+        .set_setting(&request.key, &request.value)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Login Options
+///
+/// This is called by the client for knowing the Oauth2 provider(s) available
+/// You must provide a list of Oauth2 providers in the `oauth2.toml` config file
+/// The config file can be overridden by the `OAUTH2_CONFIG_FILE` environment variable
+///
+/// This function is an API endpoint that is called by the client to get the list of available OAuth2 providers.
+/// The list of providers is defined in the `oauth2.toml` config file, which can be overridden by the `OAUTH2_CONFIG_FILE` environment variable.
+/// It is tagged with "login" for OpenAPI documentation.
+///
+/// ## Limitations
+///
+/// It needs to be completed for mapping the username and email from the OAuth2 provider to the SCTGDesk user.
+///
+/// ## Parameters
+///
+/// - none
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Vec<String>>` object, which includes the list of available OAuth2 providers.  <br>
+/// If the config file is not found or cannot be read, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the config file is not found or cannot be read.
+///
+/// # Example
+///
+/// GET /api/login-options
+#[openapi(tag = "login")]
+#[get("/api/login-options", format = "application/json")]
+async fn login_options(
+    state: &State<ApiState>,
+) -> Result<Json<Vec<String>>, status::Unauthorized<()>> {
+    let mut providers: Vec<String> = Vec::new();
+    let providers_config = state
+        .get_oauth2_config(oauth2::get_providers_config_file().as_str())
+        .await;
+    if providers_config.is_none() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    for p in providers_config.unwrap() {
+        providers.push(p.op_auth_string);
+    }
+    Ok(Json(providers))
+}
+
+/// OIDC Auth request
+///
+/// This entrypoint is called by the client for getting the authorization url for the Oauth2 provider he chooses
+///
+/// For testing you can generate a valid uuid field with the following command: `uuidgen | base64`
+/// # OIDC Auth Request
+///
+/// This function is an API endpoint that is called by the client to get the authorization URL for the chosen OAuth2 provider.
+/// It is tagged with "login" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The request data, which includes the chosen OAuth2 provider and a UUID.  <br> For testing you can generate a valid uuid field with the following command: `uuidgen | base64`
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<OidcAuthUrl>` object, which includes the authorization URL and a session code.  <br>
+/// If the UUID is invalid or the OAuth2 provider is not found, this function returns an `OidcAuthUrl` object with an empty URL and an error code.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the UUID is invalid or the OAuth2 provider is not found.
+///
+/// # Example
+///
+/// POST /api/oidc/auth
+/// {
+///     "op": "github",
+///     "uuid": "generated_uuid_base64_encoded"
+/// }
+#[openapi(tag = "login")]
+#[post("/api/oidc/auth", format = "application/json", data = "<request>")]
 async fn oidc_auth(
     state: &State<ApiState>,
-    request: ExtendedJson<OidcAuthRequest>,
-) -> Json<OidcAuthUrl> {
-    log::debug!("oidc_auth: {:?}", request);
-    let headers = request.headers();
-    log::debug!("headers: {:?}", headers);
-    let request = request.data;
+    request: ExtendedJson<OidcAuthRequest>,
+) -> Json<OidcAuthUrl> {
+    log::debug!("oidc_auth: {:?}", request);
+    let headers = request.headers();
+    log::debug!("headers: {:?}", headers);
+    let request = request.data;
+
+    let uuid_code = Uuid::new_v4().to_string();
+    let uuid_decoded = BASE64_STANDARD.decode(request.uuid.clone());
+    if uuid_decoded.is_err() {
+        return Json(OidcAuthUrl {
+            url: "".to_string(),
+            code: "UUID_ERROR".to_string(),
+        });
+    }
+    let uuid_decoded = uuid_decoded.unwrap();
+    let uuid_client = String::from_utf8(uuid_decoded).unwrap();
+    let callback_url = format!("{}/api/oidc/callback", get_host(headers.clone()));
+    let providers_config = state
+        .get_oauth2_config(oauth2::get_providers_config_file().as_str())
+        .await;
+    if providers_config.is_none() {
+        return Json(OidcAuthUrl {
+            url: "".to_string(),
+            code: "".to_string(),
+        });
+    }
+    let providers_config = providers_config.unwrap();
+    let provider_config = providers_config
+        .iter()
+        .find(|config| config.op == request.op);
+
+    if provider_config.is_none() {
+        return Json(OidcAuthUrl {
+            url: "".to_string(),
+            code: "".to_string(),
+        });
+    }
+    let provider_config = provider_config.unwrap();
+    let provider_trait_object: Arc<dyn OAuthProvider> = {
+        match provider_config.provider {
+            oauth2::Provider::Github => Arc::new(oauth2::github_provider::GithubProvider::new()),
+            oauth2::Provider::Gitlab => todo!(),
+            oauth2::Provider::Google => todo!(),
+            oauth2::Provider::Apple => todo!(),
+            oauth2::Provider::Okta => todo!(),
+            oauth2::Provider::Facebook => todo!(),
+            oauth2::Provider::Azure => todo!(),
+            oauth2::Provider::Auth0 => todo!(),
+            oauth2::Provider::Dex => Arc::new(oauth2::dex_provider::DexProvider::new()),
+            oauth2::Provider::Oauth2 => Arc::new(oauth2::oauth2_provider::Oauth2Provider::new()),
+        }
+    };
+
+    let redirect_url =
+        provider_trait_object.get_redirect_url(callback_url.as_str(), uuid_code.as_str());
+    let _oidc_session = state
+        .insert_oidc_session(
+            uuid_code.clone(),
+            OidcState {
+                id: request.id.clone(),
+                uuid: uuid_client,
+                code: None,
+                auth_token: None,
+                redirect_url: Some(redirect_url.clone()),
+                callback_url: Some(callback_url),
+                provider: Some(provider_trait_object),
+                name: None,
+                email: None,
+            },
+        )
+        .await;
+    log::debug!("uuid_code: {:?}", uuid_code);
+
+    Json(OidcAuthUrl {
+        url: redirect_url.clone(),
+        code: uuid_code,
+    })
+}
+
+/// # OIDC Auth Callback
+///
+/// This function is an API endpoint that serves as the OAuth2 callback.
+/// It exchanges the authorization code for an access token and stores it in the state.
+/// It is tagged with "login" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `code`: The authorization code received from the OIDC provider.  
+///
+/// - `state`: The state parameter received from the OIDC provider. This is the session code.  
+///
+/// ## Returns
+///
+/// If successful, this function returns "OK".  <br>
+/// If the session does not exist or the code exchange fails, this function returns "ERROR".  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the session does not exist or the code exchange fails.
+///
+/// # Example
+///
+/// GET /api/oidc/callback?code=authorization_code&state=session_code
+#[openapi(tag = "login")]
+#[get("/api/oidc/callback?<code>&<state>")]
+async fn oidc_callback(apistate: &State<ApiState>, code: &str, state: &str) -> String {
+    let oidc_code = state; // this is the session code
+    let oidc_authorization_code = code;
+    let updated_oidc_session = apistate
+        .oidc_session_exchange_code(oidc_authorization_code.to_string(), oidc_code.to_string())
+        .await;
+    if updated_oidc_session.is_none() {
+        return "ERROR".to_string();
+    }
+    "OK".to_string()
+}
+
+/// # OIDC State
+///
+/// This function is an API endpoint that checks the state of an OpenID Connect (OIDC) session.
+/// It is tagged with "login" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `code`: The authorization code received from the OIDC provider.  
+///
+/// - `id`: The identifier of the OIDC session.  
+///
+/// - `uuid`: The UUID of the OIDC session.  
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<Option<OidcResponse>>` object.  <br>
+/// If the session does not exist, this function returns `Json(None)`.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the session does not exist.
+///
+/// # Example
+///
+/// GET /api/oidc/auth-query?code=authorization_code&id=session_id&uuid=session_uuid
+#[openapi(tag = "login")]
+#[get("/api/oidc/auth-query?<code>&<id>&<uuid>")]
+async fn oidc_state(
+    state: &State<ApiState>,
+    client: ExtendedRequest,
+    code: &str,
+    id: &str,
+    uuid: &str,
+) -> Json<Option<OidcResponse>> {
+    log::debug!("oidc_state: {:?} {:?} {:?}", code, id, uuid);
+
+    let res = state
+        .oidc_check_session(code.to_string(), client.client_ip)
+        .await;
+
+    if res.is_none() {
+        return Json(None);
+    }
+
+    let (token, username, userinfo) = res.unwrap();
+    let auth_response = OidcResponse {
+        access_token: token.to_base64(),
+        type_field: "access_token".to_string(),
+        tfa_type: "".to_string(),
+        secret: "".to_string(),
+        user: OidcUser {
+            name: username,
+            email: "".to_string(),
+            note: "".to_string(),
+            status: OidcUserStatus::Normal.into(),
+            info: OidcUserInfo {
+                email_verification: false,
+                email_alarm_notification: false,
+                login_device_whitelist: Vec::<String>::new(),
+                other: HashMap::<String, String>::new(),
+            },
+            is_admin: userinfo.admin,
+            third_auth_type: "Oauth2".to_string(),
+        },
+    };
+
+    Json(Some(auth_response))
+}
+
+/// # Get Personal Address Book
+///
+/// This function is an API endpoint that retrieves the personal address book of the authenticated user.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - none
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbPersonal>` object.  <br>
+/// If the user is not authorized to access their personal address book, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not authorized to access their personal address book.
+///
+/// # Example
+///
+/// POST /api/ab/personal
+#[openapi(tag = "address book")]
+#[post("/api/ab/personal")]
+async fn ab_personal(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<AbPersonal>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let guid = state.get_ab_personal_guid(user.info.user_id.clone()).await;
+    if guid.is_none() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let guid = guid.unwrap();
+    log::debug!("user: {:?} ab_personal: {:?}", user.info.user_id, guid);
+    let ab_personal = AbPersonal {
+        guid: guid,
+        error: None,
+    };
+    Ok(Json(ab_personal))
+}
+
+/// # Get the Tags
+///
+/// This function is an API endpoint that retrieves all tags from an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.  
+///
+/// ## Returns
+///
+/// If successful, this function returns a JSON array of `AbTag` objects.  <br>
+/// The response has an `ETag` header, a `304 Not Modified` status is returned without body if it matches the `If-None-Match` header.  <br>
+/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::NotFound` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+///
+/// # Example
+///
+/// POST /api/ab/tags/018fab24-0ae5-731c-be23-88aa4518ea26
+#[openapi(tag = "address book")]
+#[post("/api/ab/tags/<ab>")]
+async fn ab_tags(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    if_none_match: IfNoneMatch,
+) -> Result<ETagged<Json<Vec<AbTag>>>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::NotFound::<()>(()));
+    }
+    let revision = state
+        .get_ab_revision(ab)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let etag = ab_etag(revision, ("tags", ab));
+    ETagged::new(etag, &if_none_match, async {
+        state.get_ab_tags(ab).await.map(Json)
+    })
+    .await
+    .ok_or(status::NotFound::<()>(()))
+}
+
+/// # Add a Tag
+///
+/// This function is an API endpoint that adds a new tag to an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.  
+///
+/// - `request`: A JSON object containing the new tag to be added.  
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
+/// If the tag already exists or the user is not authorized to add it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the tag already exists or the user is not authorized to add it.
+///
+/// # Example
+///
+/// POST /api/ab/tag/add/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"name": "tag1", "color": "#FF0000"}
+#[openapi(tag = "address book")]
+#[post(
+    "/api/ab/tag/add/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_tag_add(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbTag>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let ab_tag = request.0;
+    log::debug!("ab_tag_add: {:?}", ab_tag);
+    state.add_ab_tag(ab, ab_tag).await;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Update a Tag
+///
+/// This function is an API endpoint that updates a tag in an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.  
+///
+/// - `request`: A JSON object containing the updated tag.  
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
+/// If the tag does not exist or the user is not authorized to update it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the tag does not exist or the user is not authorized to update it.
+///
+/// # Example
+///
+/// PUT /api/ab/tag/update/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"name": "tag1", "color": "#FF0000"}
+#[openapi(tag = "address book")]
+#[put(
+    "/api/ab/tag/update/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_tag_update(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbTag>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let ab_tag = request.0;
+    log::debug!("ab_tag_update: {:?}", ab_tag);
+    state.add_ab_tag(ab, ab_tag).await;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Rename a Tag
+///
+/// This function is an API endpoint that renames a tag in an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON object containing the old and new names of the tag.
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
+/// If the tag does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the tag does not exist or the user is not authorized to access it.
+///
+/// # Example
+///
+/// PUT /api/ab/tag/rename/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"old": "tag1", "new": "tag2"}
+#[openapi(tag = "address book")]
+#[put(
+    "/api/ab/tag/rename/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_tag_rename(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbTagRenameRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let ab_tag_old_name = request.0.old;
+    let ab_tag_new_name = request.0.new;
+
+    let ab_tag_old = state.get_ab_tag(ab, ab_tag_old_name.as_str()).await;
+    if ab_tag_old.is_none() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let mut ab_tag_new = ab_tag_old.unwrap();
+    ab_tag_new.name = ab_tag_new_name;
+    state
+        .rename_ab_tag(ab, ab_tag_old_name.as_str(), ab_tag_new, &user.info.user_id)
+        .await;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Delete a Tag
+///
+/// This function is an API endpoint that deletes a tag from an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.  
+///
+/// - `request`: A JSON object containing an array of tag names to be deleted.  
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
+/// If the request is empty or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the request is empty or the user is not authorized to access it.
+///
+/// # Example
+///
+/// DELETE /api/ab/tag/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// ["tag1", "tag2"]
+#[openapi(tag = "address book")]
+#[delete("/api/ab/tag/<ab>", format = "application/json", data = "<request>")]
+async fn ab_tag_delete(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<Vec<String>>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    if request.0.is_empty() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let tags_to_delete = request.0;
+    state.check_maintenance().await;
+    state
+        .delete_ab_tags(ab, tags_to_delete, &user.info.user_id)
+        .await;
+    Ok(ActionResponse::Empty)
+}
+
+/// # Check the tags
+///
+/// This function is an API endpoint that lists the tags used by the peers of an address book which do not exist in the address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// The peers in the recycle bin are ignored.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbTagCheckReport>` object listing the orphaned tags and the peers using them.  <br>
+/// If the user is not allowed to read the address book, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to read the address book.
+///
+/// # Example
+///
+/// GET /api/ab/tag/check/018fab24-0ae5-731c-be23-88aa4518ea26
+#[openapi(tag = "address book")]
+#[get("/api/ab/tag/check/<ab>")]
+async fn ab_tag_check(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+) -> Result<Json<AbTagCheckReport>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let orphaned_tags = state
+        .get_ab_orphaned_tags(ab)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbTagCheckReport {
+        repair: None,
+        total: orphaned_tags.len() as u32,
+        orphaned_tags,
+    }))
+}
 
-    let uuid_code = Uuid::new_v4().to_string();
-    let uuid_decoded = BASE64_STANDARD.decode(request.uuid.clone());
-    if uuid_decoded.is_err() {
-        return Json(OidcAuthUrl {
-            url: "".to_string(),
-            code: "UUID_ERROR".to_string(),
-        });
+/// # Repair the tags
+///
+/// This function is an API endpoint that repairs the tags used by the peers of an address book which do not exist in the address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// The repair is done in a single transaction, the peers in the recycle bin are not changed.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `mode`: `create` to create the missing tags with the default color (default), `remove` to remove them from the peers.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbTagCheckReport>` object listing the repaired tags and the peers using them.  <br>
+/// If the mode is invalid or the user is not allowed to write the address book, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, if the mode is invalid or if the user is not allowed to write the address book.
+///
+/// # Example
+///
+/// POST /api/ab/tag/repair/018fab24-0ae5-731c-be23-88aa4518ea26?mode=remove
+#[openapi(tag = "address book")]
+#[post("/api/ab/tag/repair/<ab>?<mode>")]
+async fn ab_tag_repair(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    mode: Option<&str>,
+) -> Result<Json<AbTagCheckReport>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let mode = match mode.unwrap_or("create").to_lowercase().as_str() {
+        "create" => AbTagRepairMode::Create,
+        "remove" => AbTagRepairMode::Remove,
+        _ => return Err(status::Unauthorized::<()>(())),
+    };
+    let orphaned_tags = state
+        .repair_ab_orphaned_tags(ab, mode, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbTagCheckReport {
+        repair: Some(mode),
+        total: orphaned_tags.len() as u32,
+        orphaned_tags,
+    }))
+}
+
+/// # Get Shared Profiles
+///
+/// This function is an API endpoint that retrieves the shared profiles from an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - none
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbSharedProfilesResponse>` object containing the shared profiles in the address book.  <br>
+/// rule: 1: read, 2: write, 3: full control  <br>
+/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+///
+/// # Example
+///
+/// {"data":[{"guid":"018fab24-0ae5-731c-be23-88aa4518ea26","name":"shared profile","owner":"admin","rule":3}],"total":2}
+#[openapi(tag = "address book")]
+#[post("/api/ab/shared/profiles")]
+async fn ab_shared(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<AbSharedProfilesResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let shared_address_books = state.get_shared_address_books(user.info.user_id).await;
+    let mut ab_shared_profiles = AbSharedProfilesResponse::default();
+    for ab in shared_address_books.expect("shared_address_books is None") {
+        let address_book = AbProfile {
+            guid: ab.ab,
+            name: ab.name.unwrap_or("".to_string()),
+            owner: guid_into_uuid(ab.owner.expect("Invalid owner")).expect("Invalid GUID"),
+            rule: ab.rule.unwrap_or(0),
+            ..Default::default()
+        };
+        ab_shared_profiles.data.push(address_book);
+    }
+    ab_shared_profiles.total = ab_shared_profiles.data.len() as u32;
+    Ok(Json(ab_shared_profiles))
+}
+
+/// # Settings
+///
+/// This function is an API endpoint that retrieves the settings for an address book.<br>
+/// TODO: Implement the settings for an address book.
+#[openapi(tag = "address book")]
+#[post("/api/ab/settings")]
+async fn ab_settings(
+    state: &State<ApiState>,
+    _user: AuthenticatedUser,
+) -> Result<Json<AbSettingsResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let ab_settings = AbSettingsResponse {
+        error: None,
+        max_peer_one_ab: std::u32::MAX,
+    };
+    Ok(Json(ab_settings))
+}
+
+/// # List peers
+///
+/// This function is an API endpoint that lists the peers in an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `current`: The page number, starting at 1.
+///
+/// - `pageSize`: The number of peers per page.
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `tag`: Optional, only the peers with this tag.
+///
+/// - `search`: Optional, only the peers whose id or alias contains this text, case insensitive.
+///
+/// - `platform`: Optional, only the peers of this platform (eg. `Windows`, `Linux`, `Mac OS`, `Android`), case insensitive.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbPeersResponse>` object containing the requested page of peers,
+/// `total` is the number of peers matching the filters.  <br>
+/// Each peer has the time of its last change in `updated_at` and the name of the user who made it in `updated_by`.  <br>
+/// The response has an `ETag` header, a `304 Not Modified` status is returned without body if it matches the `If-None-Match` header.  <br>
+/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+///
+#[openapi(tag = "address book")]
+#[post("/api/ab/peers?<ab>&<query..>")]
+async fn ab_peers(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    query: AbPeersQuery,
+    if_none_match: IfNoneMatch,
+) -> Result<ETagged<Json<AbPeersResponse>>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let revision = state
+        .get_ab_revision(ab)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    let tag = query.tag.as_deref();
+    let search = query.search.as_deref();
+    let platform = query.platform.as_deref();
+    let etag = ab_etag(
+        revision,
+        ("peers", ab, query.current, query.page_size, tag, search, platform),
+    );
+    ETagged::new(etag, &if_none_match, async {
+        let (total, ab_peers) = state
+            .get_ab_peers_page(ab, tag, search, platform, query.current, query.page_size)
+            .await?;
+        Some(Json(AbPeersResponse {
+            error: None,
+            total,
+            data: ab_peers,
+        }))
+    })
+    .await
+    .ok_or(status::Unauthorized::<()>(()))
+}
+
+/// # Wait for address book changes
+///
+/// This function is an API endpoint that waits until an address book the user can read changes.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// Each change of the peers or tags of an address book gives it a new revision, the revisions are increasing across all the address books.
+/// The endpoint returns as soon as an address book readable by the user has a revision greater than `since`, or when the timeout expires.
+/// Without `since` it returns immediately the current revision of the readable address books which have changed at least once.
+///
+/// ## Parameters
+///
+/// - `since`: Optional, the `revision` returned by the previous call.
+///
+/// - `timeout`: Optional, the maximum time to wait in seconds, 30 by default and at most 60.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbChangesResponse>` object listing the changed address books, empty if the timeout expired,
+/// and the revision to send as `since` in the next call.  <br>
+/// If the user is not authorized, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not authorized.
+///
+/// # Example
+///
+/// GET /api/ab/changes?since=42&timeout=30
+#[openapi(tag = "address book")]
+#[get("/api/ab/changes?<since>&<timeout>")]
+async fn ab_changes(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    since: Option<i64>,
+    timeout: Option<u64>,
+) -> Result<Json<AbChangesResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let timeout = timeout.unwrap_or(30).clamp(1, 60);
+    let changes = state
+        .wait_ab_changes(&user.info.user_id, since, Duration::from_secs(timeout))
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(changes))
+}
+
+/// # Add peer
+///
+/// This function is an API endpoint that adds a peer to an address book.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON object containing the new peer information.
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.
+/// If the peer cannot be saved, for instance when its credentials cannot be encrypted, it returns an `ActionResponse::Error`.
+#[openapi(tag = "address book")]
+#[post(
+    "/api/ab/peer/add/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_peer_add(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    request: Json<AbPeer>,
+    ab: &str,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let ab_peer = request.0;
+    state.check_maintenance().await;
+    if state.add_ab_peer(ab, ab_peer, &user.info.user_id).await.is_none() {
+        return Ok(ActionResponse::Error("The peer cannot be saved".to_string()));
+    }
+    Ok(ActionResponse::Empty)
+}
+
+/// # Update peer
+///
+/// This function is an API endpoint that updates a peer in an address book.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON object containing the updated peer information.
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.
+/// If the peer cannot be saved, for instance when its credentials cannot be encrypted, it returns an `ActionResponse::Error`.
+#[openapi(tag = "address book")]
+#[put(
+    "/api/ab/peer/update/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_peer_update(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    request: Json<AbPeer>,
+    ab: &str,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
     }
-    let uuid_decoded = uuid_decoded.unwrap();
-    let uuid_client = String::from_utf8(uuid_decoded).unwrap();
-    let callback_url = format!("{}/api/oidc/callback", get_host(headers.clone()));
-    let providers_config = state
-        .get_oauth2_config(oauth2::get_providers_config_file().as_str())
-        .await;
-    if providers_config.is_none() {
-        return Json(OidcAuthUrl {
-            url: "".to_string(),
-            code: "".to_string(),
-        });
+    let ab_peer = request.0;
+    let old_ab_peer = state.get_ab_peer(ab, ab_peer.id.as_str()).await;
+    if old_ab_peer.is_none() {
+        return Err(status::Unauthorized::<()>(()));
     }
-    let providers_config = providers_config.unwrap();
-    let provider_config = providers_config
-        .iter()
-        .find(|config| config.op == request.op);
+    let ab_peer = ab_peer.merge(old_ab_peer.unwrap());
+    state.check_maintenance().await;
+    if state.add_ab_peer(ab, ab_peer, &user.info.user_id).await.is_none() {
+        return Ok(ActionResponse::Error("The peer cannot be saved".to_string()));
+    }
+    Ok(ActionResponse::Empty)
+}
 
-    if provider_config.is_none() {
-        return Json(OidcAuthUrl {
-            url: "".to_string(),
-            code: "".to_string(),
-        });
+/// # Delete peer
+///
+/// This function is an API endpoint that deletes a peer from an address book.
+/// The deleted peers are moved to the recycle bin, see `/api/ab/peer/recycle/<ab>`.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON object containing an array of peer IDs to be deleted.
+///
+/// ## Returns
+///
+/// If successful, this function returns an `ActionResponse::Empty` object.
+#[openapi(tag = "address book")]
+#[delete("/api/ab/peer/<ab>", format = "application/json", data = "<request>")]
+async fn ab_peer_delete(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<Vec<String>>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
     }
-    let provider_config = provider_config.unwrap();
-    let provider_trait_object: Arc<dyn OAuthProvider> = {
-        match provider_config.provider {
-            oauth2::Provider::Github => Arc::new(oauth2::github_provider::GithubProvider::new()),
-            oauth2::Provider::Gitlab => todo!(),
-            oauth2::Provider::Google => todo!(),
-            oauth2::Provider::Apple => todo!(),
-            oauth2::Provider::Okta => todo!(),
-            oauth2::Provider::Facebook => todo!(),
-            oauth2::Provider::Azure => todo!(),
-            oauth2::Provider::Auth0 => todo!(),
-            oauth2::Provider::Dex => Arc::new(oauth2::dex_provider::DexProvider::new()),
-            oauth2::Provider::Oauth2 => Arc::new(oauth2::oauth2_provider::Oauth2Provider::new()),
-        }
-    };
+    if request.0.is_empty() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let peers_to_delete = request.0;
+    state.check_maintenance().await;
+    state.delete_ab_peer(ab, peers_to_delete, &user.info.user_id).await;
+    Ok(ActionResponse::Empty)
+}
 
-    let redirect_url =
-        provider_trait_object.get_redirect_url(callback_url.as_str(), uuid_code.as_str());
-    let _oidc_session = state
-        .insert_oidc_session(
-            uuid_code.clone(),
-            OidcState {
-                id: request.id.clone(),
-                uuid: uuid_client,
-                code: None,
-                auth_token: None,
-                redirect_url: Some(redirect_url.clone()),
-                callback_url: Some(callback_url),
-                provider: Some(provider_trait_object),
-                name: None,
-                email: None,
-            },
-        )
-        .await;
-    log::debug!("uuid_code: {:?}", uuid_code);
+/// # List the recycle bin
+///
+/// This function is an API endpoint that lists the deleted peers of an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// Deleted peers are kept in the recycle bin for `ab_peer_recycle_retention_days` days (setting, default 30) before being purged.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbRecycleResponse>` object containing the deleted peers, the most recently deleted first.  <br>
+/// If the address book does not exist or the user is not allowed to read it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to read the address book.
+///
+/// # Example
+///
+/// GET /api/ab/peer/recycle/018fab24-0ae5-731c-be23-88aa4518ea26
+#[openapi(tag = "address book")]
+#[get("/api/ab/peer/recycle/<ab>")]
+async fn ab_peer_recycle(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+) -> Result<Json<AbRecycleResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let (peers, retention_days) = state
+        .get_ab_recycled_peers(ab)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbRecycleResponse {
+        total: peers.len() as u32,
+        retention_days,
+        data: peers,
+    }))
+}
 
-    Json(OidcAuthUrl {
-        url: redirect_url.clone(),
-        code: uuid_code,
-    })
+/// # Restore peers from the recycle bin
+///
+/// This function is an API endpoint that restores deleted peers of an address book.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON array of the ids of the peers to restore.
+///
+/// ## Returns
+///
+/// If successful, this function returns a JSON array of the ids of the restored peers.  <br>
+/// If the address book does not exist or the user is not allowed to write it, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address book.
+///
+/// # Example
+///
+/// POST /api/ab/peer/recycle/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// ["123456789"]
+#[openapi(tag = "address book")]
+#[post(
+    "/api/ab/peer/recycle/<ab>",
+    format = "application/json",
+    data = "<request>"
+)]
+async fn ab_peer_restore(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let restored = state
+        .restore_ab_peers(ab, request.0, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(restored))
 }
 
-/// # OIDC Auth Callback
+/// # Copy peers to another address book
 ///
-/// This function is an API endpoint that serves as the OAuth2 callback.
-/// It exchanges the authorization code for an access token and stores it in the state.
-/// It is tagged with "login" for OpenAPI documentation.
+/// This function is an API endpoint that copies peers of an address book to another address book in a single transaction.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// The copied peers are merged into the peers of the target address book and the tags they use are created in the target address book with their color, or the default one.
 ///
 /// ## Parameters
 ///
-/// - `code`: The authorization code received from the OIDC provider.  
+/// - `ab`: The identifier of the source address book.
 ///
-/// - `state`: The state parameter received from the OIDC provider. This is the session code.  
+/// - `request`: A JSON object with the identifier of the target address book and the ids of the peers.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns "OK".  <br>
-/// If the session does not exist or the code exchange fails, this function returns "ERROR".  <br>
+/// If successful, this function returns a `Json<AbPeersBatchResponse>` object with the result of each peer: `ok`, `unchanged` or `not_found`.  <br>
+/// If the user is not allowed to read the source address book or to write the target address book, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the session does not exist or the code exchange fails.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to access the address books.
+/// Nothing is changed if the transaction fails.
 ///
 /// # Example
 ///
-/// GET /api/oidc/callback?code=authorization_code&state=session_code
-#[openapi(tag = "login")]
-#[get("/api/oidc/callback?<code>&<state>")]
-async fn oidc_callback(apistate: &State<ApiState>, code: &str, state: &str) -> String {
-    let oidc_code = state; // this is the session code
-    let oidc_authorization_code = code;
-    let updated_oidc_session = apistate
-        .oidc_session_exchange_code(oidc_authorization_code.to_string(), oidc_code.to_string())
-        .await;
-    if updated_oidc_session.is_none() {
-        return "ERROR".to_string();
+/// POST /api/ab/peers/copy/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"target":"018f2556-2311-7efa-9d25-470a9160c6d7","peers":["123456789","987654321"]}
+#[openapi(tag = "address book")]
+#[post("/api/ab/peers/copy/<ab>", format = "application/json", data = "<request>")]
+async fn ab_peers_copy(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbPeersTransferRequest>,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+        || !state
+            .check_ab_access(&user.info.user_id, &request.target, AbRuleLevel::ReadWrite)
+            .await
+    {
+        return Err(status::Unauthorized::<()>(()));
     }
-    "OK".to_string()
+    transfer_ab_peers(state, &user, ab, request.0, false).await
 }
 
-/// # OIDC State
+/// # Move peers to another address book
 ///
-/// This function is an API endpoint that checks the state of an OpenID Connect (OIDC) session.
-/// It is tagged with "login" for OpenAPI documentation.
+/// This function is an API endpoint that moves peers of an address book to another address book in a single transaction.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// The peers are copied like with `/api/ab/peers/copy` then put in the recycle bin of the source address book.
 ///
 /// ## Parameters
 ///
-/// - `code`: The authorization code received from the OIDC provider.  
+/// - `ab`: The identifier of the source address book.
+///
+/// - `request`: A JSON object with the identifier of the target address book and the ids of the peers.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<AbPeersBatchResponse>` object with the result of each peer: `ok` or `not_found`.  <br>
+/// If the user is not allowed to write both address books, this function returns a `status::Unauthorized` error.  <br>
+///
+/// ## Errors
+///
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address books.
+/// Nothing is changed if the transaction fails.
+///
+/// # Example
+///
+/// POST /api/ab/peers/move/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"target":"018f2556-2311-7efa-9d25-470a9160c6d7","peers":["123456789","987654321"]}
+#[openapi(tag = "address book")]
+#[post("/api/ab/peers/move/<ab>", format = "application/json", data = "<request>")]
+async fn ab_peers_move(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbPeersTransferRequest>,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+        || !state
+            .check_ab_access(&user.info.user_id, &request.target, AbRuleLevel::ReadWrite)
+            .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    transfer_ab_peers(state, &user, ab, request.0, true).await
+}
+
+async fn transfer_ab_peers(
+    state: &State<ApiState>,
+    user: &AuthenticatedUser,
+    ab: &str,
+    request: AbPeersTransferRequest,
+    move_peers: bool,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
+    if request.target == ab {
+        return Ok(Json(AbPeersBatchResponse {
+            error: Some("the source and target address books are the same".to_string()),
+            ..Default::default()
+        }));
+    }
+    let items = state
+        .copy_ab_peers(ab, &request.target, &request.peers, move_peers, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbPeersBatchResponse {
+        error: None,
+        total: items.len() as u32,
+        data: items,
+    }))
+}
+
+/// # Tag peers
+///
+/// This function is an API endpoint that adds a tag to peers of an address book in a single transaction.
+/// It is tagged with "address book" for OpenAPI documentation.
+///
+/// The tag is created with the default color if it does not exist in the address book.
+///
+/// ## Parameters
 ///
-/// - `id`: The identifier of the OIDC session.  
+/// - `ab`: The identifier of the address book.
 ///
-/// - `uuid`: The UUID of the OIDC session.  
+/// - `request`: A JSON object with the tag and the ids of the peers.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<Option<OidcResponse>>` object.  <br>
-/// If the session does not exist, this function returns `Json(None)`.  <br>
+/// If successful, this function returns a `Json<AbPeersBatchResponse>` object with the result of each peer: `ok`, `unchanged` or `not_found`.  <br>
+/// If the user is not allowed to write the address book, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the session does not exist.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address book.
+/// Nothing is changed if the transaction fails.
 ///
 /// # Example
 ///
-/// GET /api/oidc/auth-query?code=authorization_code&id=session_id&uuid=session_uuid
-#[openapi(tag = "login")]
-#[get("/api/oidc/auth-query?<code>&<id>&<uuid>")]
-async fn oidc_state(
+/// POST /api/ab/peers/tag/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"tag":"helpdesk","peers":["123456789","987654321"]}
+#[openapi(tag = "address book")]
+#[post("/api/ab/peers/tag/<ab>", format = "application/json", data = "<request>")]
+async fn ab_peers_tag(
     state: &State<ApiState>,
-    code: &str,
-    id: &str,
-    uuid: &str,
-) -> Json<Option<OidcResponse>> {
-    log::debug!("oidc_state: {:?} {:?} {:?}", code, id, uuid);
-
-    let res = state.oidc_check_session(code.to_string()).await;
-
-    if res.is_none() {
-        return Json(None);
-    }
-
-    let (token, username, userinfo) = res.unwrap();
-    let auth_response = OidcResponse {
-        access_token: token.to_base64(),
-        type_field: "access_token".to_string(),
-        tfa_type: "".to_string(),
-        secret: "".to_string(),
-        user: OidcUser {
-            name: username,
-            email: "".to_string(),
-            note: "".to_string(),
-            status: OidcUserStatus::Normal.into(),
-            info: OidcUserInfo {
-                email_verification: false,
-                email_alarm_notification: false,
-                login_device_whitelist: Vec::<String>::new(),
-                other: HashMap::<String, String>::new(),
-            },
-            is_admin: userinfo.admin,
-            third_auth_type: "Oauth2".to_string(),
-        },
-    };
-
-    Json(Some(auth_response))
+    user: AuthenticatedUser,
+    ab: &str,
+    request: Json<AbPeersTagRequest>,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
+    tag_ab_peers(state, &user, ab, request.0, true).await
 }
 
-/// # Get Personal Address Book
+/// # Untag peers
 ///
-/// This function is an API endpoint that retrieves the personal address book of the authenticated user.
+/// This function is an API endpoint that removes a tag from peers of an address book in a single transaction.
 /// It is tagged with "address book" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - none
+/// - `ab`: The identifier of the address book.
+///
+/// - `request`: A JSON object with the tag and the ids of the peers.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<AbPersonal>` object.  <br>
-/// If the user is not authorized to access their personal address book, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns a `Json<AbPeersBatchResponse>` object with the result of each peer: `ok`, `unchanged` or `not_found`.  <br>
+/// If the user is not allowed to write the address book, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the user is not authorized to access their personal address book.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address book.
+/// Nothing is changed if the transaction fails.
 ///
 /// # Example
 ///
-/// POST /api/ab/personal
+/// POST /api/ab/peers/untag/018fab24-0ae5-731c-be23-88aa4518ea26
+/// Content-Type: application/json
+///
+/// {"tag":"helpdesk","peers":["123456789","987654321"]}
 #[openapi(tag = "address book")]
-#[post("/api/ab/personal")]
-async fn ab_personal(
+#[post("/api/ab/peers/untag/<ab>", format = "application/json", data = "<request>")]
+async fn ab_peers_untag(
     state: &State<ApiState>,
     user: AuthenticatedUser,
-) -> Result<Json<AbPersonal>, status::Unauthorized<()>> {
+    ab: &str,
+    request: Json<AbPeersTagRequest>,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
+    tag_ab_peers(state, &user, ab, request.0, false).await
+}
+
+async fn tag_ab_peers(
+    state: &State<ApiState>,
+    user: &AuthenticatedUser,
+    ab: &str,
+    request: AbPeersTagRequest,
+    add: bool,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let guid = state.get_ab_personal_guid(user.info.user_id.clone()).await;
-    if guid.is_none() {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
         return Err(status::Unauthorized::<()>(()));
     }
-    let guid = guid.unwrap();
-    log::debug!("user: {:?} ab_personal: {:?}", user.info.user_id, guid);
-    let ab_personal = AbPersonal {
-        guid: guid,
+    let items = state
+        .tag_ab_peers(ab, &request.tag, &request.peers, add, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbPeersBatchResponse {
         error: None,
-    };
-    Ok(Json(ab_personal))
+        total: items.len() as u32,
+        data: items,
+    }))
 }
 
-/// # Get the Tags
+/// # Delete peers by tag
 ///
-/// This function is an API endpoint that retrieves all tags from an address book.
+/// This function is an API endpoint that moves all the peers of an address book having a tag to the recycle bin in a single transaction.
 /// It is tagged with "address book" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `ab`: The identifier of the address book.  
+/// - `ab`: The identifier of the address book.
+///
+/// - `tag`: The tag of the peers to delete.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a JSON array of `AbTag` objects.  <br>
-/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::NotFound` error.  <br>
+/// If successful, this function returns a `Json<AbPeersBatchResponse>` object listing the deleted peers.  <br>
+/// If the user is not allowed to write the address book, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address book.
+/// Nothing is changed if the transaction fails.
 ///
 /// # Example
 ///
-/// POST /api/ab/tags/018fab24-0ae5-731c-be23-88aa4518ea26
+/// DELETE /api/ab/peers/by-tag/018fab24-0ae5-731c-be23-88aa4518ea26/helpdesk
 #[openapi(tag = "address book")]
-#[post("/api/ab/tags/<ab>")]
-async fn ab_tags(
+#[delete("/api/ab/peers/by-tag/<ab>/<tag>")]
+async fn ab_peers_delete_by_tag(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     ab: &str,
-) -> Result<Json<Vec<AbTag>>, status::NotFound<()>> {
+    tag: &str,
+) -> Result<Json<AbPeersBatchResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let ab_tags = state.get_ab_tags(ab).await;
-    if ab_tags.is_none() {
-        return Err(status::NotFound::<()>(()));
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
     }
-    let ab_tags = ab_tags.unwrap();
-    Ok(Json(ab_tags))
+    let items = state
+        .delete_ab_peers_by_tag(ab, tag, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbPeersBatchResponse {
+        error: None,
+        total: items.len() as u32,
+        data: items,
+    }))
 }
 
-/// # Add a Tag
+/// # Peer history
 ///
-/// This function is an API endpoint that adds a new tag to an address book.
+/// This function is an API endpoint that lists the revisions of a peer of an address book.
 /// It is tagged with "address book" for OpenAPI documentation.
 ///
+/// A revision is recorded each time the peer is created, updated, deleted or restored, with the user who made the change.
+///
 /// ## Parameters
 ///
-/// - `ab`: The identifier of the address book.  
+/// - `ab`: The identifier of the address book.
 ///
-/// - `request`: A JSON object containing the new tag to be added.  
+/// - `id`: The id of the peer.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
-/// If the tag already exists or the user is not authorized to add it, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns a `Json<AbPeerHistoryResponse>` object containing the revisions, the latest first.  <br>
+/// If the address book does not exist or the user is not allowed to read it, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the tag already exists or the user is not authorized to add it.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to read the address book.
 ///
 /// # Example
 ///
-/// POST /api/ab/tag/add/018fab24-0ae5-731c-be23-88aa4518ea26
-/// Content-Type: application/json
-///
-/// {"name": "tag1", "color": "#FF0000"}
+/// GET /api/ab/peer/history/018fab24-0ae5-731c-be23-88aa4518ea26/123456789
 #[openapi(tag = "address book")]
-#[post(
-    "/api/ab/tag/add/<ab>",
-    format = "application/json",
-    data = "<request>"
-)]
-async fn ab_tag_add(
+#[get("/api/ab/peer/history/<ab>/<id>")]
+async fn ab_peer_history(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     ab: &str,
-    request: Json<AbTag>,
-) -> Result<ActionResponse, status::Unauthorized<()>> {
+    id: &str,
+) -> Result<Json<AbPeerHistoryResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let ab_tag = request.0;
-    log::debug!("ab_tag_add: {:?}", ab_tag);
-    state.add_ab_tag(ab, ab_tag).await;
-    Ok(ActionResponse::Empty)
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let revisions = state
+        .get_ab_peer_history(ab, id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(AbPeerHistoryResponse {
+        total: revisions.len() as u32,
+        data: revisions,
+    }))
 }
 
-/// # Update a Tag
+/// Parse the format parameter of the address book import and export
+fn parse_ab_transfer_format(format: Option<&str>) -> Option<AbTransferFormat> {
+    match format.unwrap_or("json").to_lowercase().as_str() {
+        "rustdesk" | "legacy" => Some(AbTransferFormat::RustDesk),
+        "csv" => Some(AbTransferFormat::Csv),
+        "json" => Some(AbTransferFormat::Json),
+        _ => None,
+    }
+}
+
+/// # Export an address book
 ///
-/// This function is an API endpoint that updates a tag in an address book.
+/// This function is an API endpoint that exports the tags and peers of an address book.
 /// It is tagged with "address book" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `ab`: The identifier of the address book.  
+/// - `ab`: The identifier of the address book.
 ///
-/// - `request`: A JSON object containing the updated tag.  
+/// - `format`: The export format, one of:  <br>
+///   - `json` (default): the full address book including the tags colors, {"tags":[{"name":"tag1","color":4288585374}],"peers":[...]}  <br>
+///   - `rustdesk`: the legacy address book blob of the RustDesk client, as stored by the legacy `/api/ab` endpoint  <br>
+///   - `csv`: id, alias, tags (separated by a semicolon), username, hostname, platform
 ///
 /// ## Returns
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
-/// If the tag does not exist or the user is not authorized to update it, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns the address book in the requested format.  <br>
+/// If the format is unknown, the address book does not exist or the user is not allowed to read it, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the tag does not exist or the user is not authorized to update it.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to read the address book.
 ///
 /// # Example
 ///
-/// PUT /api/ab/tag/update/018fab24-0ae5-731c-be23-88aa4518ea26
-/// Content-Type: application/json
-///
-/// {"name": "tag1", "color": "#FF0000"}
+/// GET /api/ab/export/018fab24-0ae5-731c-be23-88aa4518ea26?format=csv
 #[openapi(tag = "address book")]
-#[put(
-    "/api/ab/tag/update/<ab>",
-    format = "application/json",
-    data = "<request>"
-)]
-async fn ab_tag_update(
+#[get("/api/ab/export/<ab>?<format>")]
+async fn ab_export(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     ab: &str,
-    request: Json<AbTag>,
-) -> Result<ActionResponse, status::Unauthorized<()>> {
+    format: Option<&str>,
+) -> Result<(ContentType, String), status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let ab_tag = request.0;
-    log::debug!("ab_tag_update: {:?}", ab_tag);
-    state.add_ab_tag(ab, ab_tag).await;
-    Ok(ActionResponse::Empty)
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::Read)
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let format = parse_ab_transfer_format(format).ok_or(status::Unauthorized::<()>(()))?;
+    let data = state
+        .export_ab(ab)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    let content_type = match format {
+        AbTransferFormat::Csv => ContentType::CSV,
+        _ => ContentType::JSON,
+    };
+    Ok((content_type, ab_transfer::export_address_book(&data, format)))
 }
 
-/// # Rename a Tag
+/// # Import an address book
 ///
-/// This function is an API endpoint that renames a tag in an address book.
+/// This function is an API endpoint that imports tags and peers into an address book.
 /// It is tagged with "address book" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
 /// - `ab`: The identifier of the address book.
 ///
-/// - `request`: A JSON object containing the old and new names of the tag.
+/// - `format`: The format of the request body, `json` (default), `rustdesk` or `csv`, see the export endpoint.
+///
+/// - `mode`: `merge` (default) adds the new peers and tags and updates the existing ones, the fields missing from an imported peer are kept.  <br>
+///   `replace` also removes the peers and tags which are not imported.
+///
+/// - `dry_run`: If true, nothing is written and the report is a preview of the changes.
+///
+/// - `request`: The address book in the requested format.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
-/// If the tag does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns a `Json<AbImportReport>` object listing the added, updated and removed peers and tags.  <br>
+/// If the data cannot be parsed, the report contains an `error` and nothing is imported.  <br>
+/// If the format or the mode is unknown, or the user is not allowed to write the address book, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the tag does not exist or the user is not authorized to access it.
+/// This function will return an error if the system is in maintenance mode, or if the user is not allowed to write the address book.
 ///
 /// # Example
 ///
-/// PUT /api/ab/tag/rename/018fab24-0ae5-731c-be23-88aa4518ea26
-/// Content-Type: application/json
+/// POST /api/ab/import/018fab24-0ae5-731c-be23-88aa4518ea26?format=csv&mode=merge&dry_run=true
 ///
-/// {"old": "tag1", "new": "tag2"}
+/// id,alias,tags,username,hostname,platform
+/// 123456789,Office,tag1;tag2,someone,office-pc,Windows
 #[openapi(tag = "address book")]
-#[put(
-    "/api/ab/tag/rename/<ab>",
-    format = "application/json",
-    data = "<request>"
-)]
-async fn ab_tag_rename(
+#[post("/api/ab/import/<ab>?<format>&<mode>&<dry_run>", data = "<request>")]
+async fn ab_import(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     ab: &str,
-    request: Json<AbTagRenameRequest>,
-) -> Result<ActionResponse, status::Unauthorized<()>> {
+    format: Option<&str>,
+    mode: Option<&str>,
+    dry_run: Option<bool>,
+    request: String,
+) -> Result<Json<AbImportReport>, status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let ab_tag_old_name = request.0.old;
-    let ab_tag_new_name = request.0.new;
-
-    let ab_tag_old = state.get_ab_tag(ab, ab_tag_old_name.as_str()).await;
-    if ab_tag_old.is_none() {
+    if !state
+        .check_ab_access(&user.info.user_id, ab, AbRuleLevel::ReadWrite)
+        .await
+    {
         return Err(status::Unauthorized::<()>(()));
     }
-    let mut ab_tag_new = ab_tag_old.unwrap();
-    ab_tag_new.name = ab_tag_new_name;
-    state
-        .rename_ab_tag(ab, ab_tag_old_name.as_str(), ab_tag_new)
-        .await;
-    Ok(ActionResponse::Empty)
+    let format = parse_ab_transfer_format(format).ok_or(status::Unauthorized::<()>(()))?;
+    let mode = match mode.unwrap_or("merge").to_lowercase().as_str() {
+        "merge" => AbImportMode::Merge,
+        "replace" => AbImportMode::Replace,
+        _ => return Err(status::Unauthorized::<()>(())),
+    };
+    let dry_run = dry_run.unwrap_or(false);
+    let data = match ab_transfer::import_address_book(request.as_str(), format) {
+        Ok(data) => data,
+        Err(error) => {
+            let mut report = AbImportReport::new(mode, dry_run);
+            report.error = Some(error);
+            return Ok(Json(report));
+        }
+    };
+    let report = state
+        .import_ab(ab, data, mode, dry_run, &user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(report))
 }
 
-/// # Delete a Tag
+/// # List strategies
 ///
-/// This function is an API endpoint that deletes a tag from an address book.
-/// It is tagged with "address book" for OpenAPI documentation.
+/// This function is an API endpoint that retrieves the list of all strategies. <br>
+/// TODO: This function is currently unused.
+///
+#[openapi(tag = "todo")]
+#[get("/api/stategies", format = "application/json")]
+async fn strategies(
+    state: &State<ApiState>,
+    _user: Authorized<permission::PeersManage>,
+) -> Result<Json<UsersResponse>, status::NotFound<()>> {
+    log::debug!("peers");
+    state.check_maintenance().await;
+
+    let response = UsersResponse {
+        msg: "success".to_string(),
+        total: 1,
+        data: "[{}]".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+/// Parse the format parameter of the users import and export
+fn parse_user_transfer_format(format: Option<&str>) -> Option<UserTransferFormat> {
+    match format.unwrap_or("json").to_lowercase().as_str() {
+        "csv" => Some(UserTransferFormat::Csv),
+        "json" => Some(UserTransferFormat::Json),
+        _ => None,
+    }
+}
+
+/// # Export the users
+///
+/// This function is an API endpoint that exports the users in the format of the users import, the passwords are not exported.
+/// It requires the `users.manage` permission, the members of a team only export the users of their team.
+/// It is tagged with "user" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `ab`: The identifier of the address book.  
+/// - `format`: The export format, one of:  <br>
+///   - `json` (default): [{"name":"alice","email":"alice@example.org","group":"Default","is_admin":false,"note":"...","data":[{"type":"favorites","data":"..."}]}]  <br>
+///   - `csv`: name, email, group, is_admin, note, password (always empty), without the user data
 ///
-/// - `request`: A JSON object containing an array of tag names to be deleted.  
+/// ## Returns
+///
+/// If successful, this function returns the users in the requested format.  <br>
+/// If the format is unknown, this function returns a `status::NotFound` error.  <br>
+///
+/// # Example
+///
+/// GET /api/user/export?format=csv
+#[openapi(tag = "user")]
+#[get("/api/user/export?<format>")]
+async fn user_export(
+    state: &State<ApiState>,
+    user: Authorized<permission::UsersManage>,
+    format: Option<&str>,
+) -> Result<(ContentType, String), status::NotFound<()>> {
+    log::debug!("user_export");
+    state.check_maintenance().await;
+    let format = parse_user_transfer_format(format).ok_or(status::NotFound::<()>(()))?;
+    let users = state
+        .export_users(user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    let content_type = match format {
+        UserTransferFormat::Csv => ContentType::CSV,
+        UserTransferFormat::Json => ContentType::JSON,
+    };
+    Ok((content_type, user_transfer::export_users(&users, format)))
+}
+
+/// # Import users
+///
+/// This function is an API endpoint that imports users from CSV or JSON.
+/// Every user is validated first, nothing is imported if a user is invalid.
+/// The users without password get a generated password, which is only returned in the report.
+/// It requires the `users.manage` permission, only the admins can import admins and the members of a team
+/// only import users in the groups of their team.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `format`: The format of the request body, `json` (default) or `csv`, see the export endpoint.
+///
+/// - `create_groups`: If true, the missing groups are created in the team of the user (default: false).
 ///
-/// ## Returns
+/// - `dry_run`: If true, nothing is written and the report is a preview of the import.
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.  <br>
-/// If the request is empty or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+/// - `request`: The users in the requested format.
 ///
-/// ## Errors
+/// ## Returns
 ///
-/// This function will return an error if the system is in maintenance mode, or if the request is empty or the user is not authorized to access it.
+/// If successful, this function returns a `Json<UserImportReport>` object with the result of each row and the created groups.  <br>
+/// If the data cannot be parsed, the report contains an `error` and nothing is imported.  <br>
+/// If the format is unknown, this function returns a `status::Unauthorized` error.  <br>
 ///
 /// # Example
 ///
-/// DELETE /api/ab/tag/018fab24-0ae5-731c-be23-88aa4518ea26
-/// Content-Type: application/json
+/// POST /api/user/import?format=csv&create_groups=true&dry_run=true
 ///
-/// ["tag1", "tag2"]
-#[openapi(tag = "address book")]
-#[delete("/api/ab/tag/<ab>", format = "application/json", data = "<request>")]
-async fn ab_tag_delete(
+/// name,email,group,is_admin,note,password
+/// alice,alice@example.org,Accounting,false,3rd floor,
+#[openapi(tag = "user")]
+#[post("/api/user/import?<format>&<create_groups>&<dry_run>", data = "<request>")]
+async fn user_import(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    ab: &str,
-    request: Json<Vec<String>>,
-) -> Result<ActionResponse, status::Unauthorized<()>> {
-    if request.0.is_empty() {
-        return Err(status::Unauthorized::<()>(()));
-    }
-    let tags_to_delete = request.0;
+    user: Authorized<permission::UsersManage>,
+    format: Option<&str>,
+    create_groups: Option<bool>,
+    dry_run: Option<bool>,
+    request: String,
+) -> Result<Json<UserImportReport>, status::Unauthorized<()>> {
+    log::debug!("user_import");
     state.check_maintenance().await;
-    state.delete_ab_tags(ab, tags_to_delete).await;
-    Ok(ActionResponse::Empty)
+    let format = parse_user_transfer_format(format).ok_or(status::Unauthorized::<()>(()))?;
+    let dry_run = dry_run.unwrap_or(false);
+    let users = match user_transfer::import_users(request.as_str(), format) {
+        Ok(users) => users,
+        Err(error) => {
+            let mut report = UserImportReport::new(dry_run);
+            report.error = Some(error);
+            return Ok(Json(report));
+        }
+    };
+    let (is_admin, _) = state
+        .get_user_permissions(&user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    let report = state
+        .import_users(
+            &users,
+            create_groups.unwrap_or(false),
+            is_admin,
+            user.team.as_deref(),
+            dry_run,
+        )
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(report))
 }
 
-/// # Get Shared Profiles
+/// # Invite a user
 ///
-/// This function is an API endpoint that retrieves the shared profiles from an address book.
-/// It is tagged with "address book" for OpenAPI documentation.
+/// This function is an API endpoint that creates a user and sends an invitation by mail.
+/// The user is disabled until the invitation is accepted with `/api/invite/accept`, the mail is configured with the `smtp_*`
+/// and `mail_invite_*` settings and the invitation expires after `invite_expiry_hours`.
+/// It requires the `users.manage` permission, only the admins can invite admins and the members of a team
+/// only invite users in the groups of their team.
+/// It is tagged with "user" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - none
+/// - `request`: The name, the email, the group and the admin flag of the user.  <br>
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<AbSharedProfilesResponse>` object containing the shared profiles in the address book.  <br>
-/// rule: 1: read, 2: write, 3: full control  <br>
-/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+/// If successful, this function returns a `Json<InviteResponse>` object with the guid of the user and the expiry of the invitation.  <br>
+/// If the mail cannot be sent, `sent` is false and `error` explains why, the invitation can be resent later.  <br>
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+/// This function will return a `status::Unauthorized` error if the name or the email is already used, or if the group is not found.
 ///
 /// # Example
 ///
-/// {"data":[{"guid":"018fab24-0ae5-731c-be23-88aa4518ea26","name":"shared profile","owner":"admin","rule":3}],"total":2}
-#[openapi(tag = "address book")]
-#[post("/api/ab/shared/profiles")]
-async fn ab_shared(
+/// POST /api/user/invite
+/// {"name":"alice","email":"alice@example.org","group_name":"Default","is_admin":false}
+#[openapi(tag = "user")]
+#[post("/api/user/invite", format = "application/json", data = "<request>")]
+async fn user_invite(
     state: &State<ApiState>,
-    user: AuthenticatedUser,
-) -> Result<Json<AbSharedProfilesResponse>, status::Unauthorized<()>> {
+    user: Authorized<permission::UsersManage>,
+    request: Json<InviteUserRequest>,
+) -> Result<Json<InviteResponse>, status::Unauthorized<()>> {
+    log::debug!("user_invite");
     state.check_maintenance().await;
-    let shared_address_books = state.get_shared_address_books(user.info.user_id).await;
-    let mut ab_shared_profiles = AbSharedProfilesResponse::default();
-    for ab in shared_address_books.expect("shared_address_books is None") {
-        let address_book = AbProfile {
-            guid: ab.ab,
-            name: ab.name.unwrap_or("".to_string()),
-            owner: guid_into_uuid(ab.owner.expect("Invalid owner")).expect("Invalid GUID"),
-            rule: 3,
-            ..Default::default()
-        };
-        ab_shared_profiles.data.push(address_book);
+    let request = request.into_inner();
+    if request.is_admin {
+        let (is_admin, _) = state
+            .get_user_permissions(&user.info.user_id)
+            .await
+            .ok_or(status::Unauthorized::<()>(()))?;
+        if !is_admin {
+            return Err(status::Unauthorized::<()>(()));
+        }
     }
-    ab_shared_profiles.total = ab_shared_profiles.data.len() as u32;
-    Ok(Json(ab_shared_profiles))
-}
-
-/// # Settings
-///
-/// This function is an API endpoint that retrieves the settings for an address book.<br>
-/// TODO: Implement the settings for an address book.
-#[openapi(tag = "address book")]
-#[post("/api/ab/settings")]
-async fn ab_settings(
-    state: &State<ApiState>,
-    _user: AuthenticatedUser,
-) -> Result<Json<AbSettingsResponse>, status::Unauthorized<()>> {
-    state.check_maintenance().await;
-    let ab_settings = AbSettingsResponse {
-        error: None,
-        max_peer_one_ab: std::u32::MAX,
-    };
-    Ok(Json(ab_settings))
+    let response = state
+        .invite_user(request, user.team.as_deref())
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(response))
 }
 
-/// # List peers
+/// # Get the List of Invitations
 ///
-/// This function is an API endpoint that lists the peers in an address book.
-/// It is tagged with "address book" for OpenAPI documentation.
+/// This function is an API endpoint that retrieves a paginated list of the invitations which are not accepted yet, expired or not.
+/// It requires the `users.manage` permission, the members of a team only see the invitations of their team.
+/// It is tagged with "user" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `current`: The current page number for pagination. This parameter is currently unused.
+/// - `current`: The current page number.  
 ///
-/// - `pageSize`: The number of items per page for pagination. This parameter is currently unused.
+/// - `pageSize`: The number of invitations per page.  
 ///
-/// - `ab`: The identifier of the address book.
+/// # Example
 ///
-/// ## Returns
+/// GET /api/user/invites?current=1&pageSize=10
+#[openapi(tag = "user")]
+#[get("/api/user/invites?<current>&<pageSize>", format = "application/json")]
+async fn user_invites(
+    state: &State<ApiState>,
+    user: Authorized<permission::UsersManage>,
+    current: u32,
+    #[allow(non_snake_case)] pageSize: u32,
+) -> Result<Json<UserInvitesResponse>, status::NotFound<()>> {
+    log::debug!("user_invites");
+    state.check_maintenance().await;
+    let (total, invites) = state
+        .get_user_invites(current, pageSize, user.team.as_deref())
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(UserInvitesResponse {
+        msg: "success".to_string(),
+        total,
+        data: invites,
+    }))
+}
+
+/// # Resend an Invitation
 ///
-/// If successful, this function returns a `Json<AbPeersResponse>` object containing the peers in the address book.  <br>
-/// If the address book does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+/// This function is an API endpoint that sends a new invitation to a user which has not accepted its invitation yet,
+/// the previous invitation is revoked.
+/// It requires the `users.manage` permission and is tagged with "user" for OpenAPI documentation.
 ///
 /// ## Errors
 ///
-/// This function will return an error if the system is in maintenance mode, or if the address book does not exist or the user is not authorized to access it.
+/// This function will return a `status::Unauthorized` error if the user has no invitation or is not in the team of the caller.
 ///
-#[openapi(tag = "address book")]
-#[post("/api/ab/peers?<current>&<pageSize>&<ab>")]
-async fn ab_peers(
+/// # Example
+///
+/// POST /api/user/invite/018f2556-2301-79eb-91a2-cffe5ced4236/resend
+#[openapi(tag = "user")]
+#[post("/api/user/invite/<guid>/resend", format = "application/json")]
+async fn user_invite_resend(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    #[allow(unused_variables)] current: u32,
-    #[allow(non_snake_case, unused_variables)] pageSize: u32,
-    ab: &str,
-) -> Result<Json<AbPeersResponse>, status::Unauthorized<()>> {
+    user: Authorized<permission::UsersManage>,
+    guid: &str,
+) -> Result<Json<InviteResponse>, status::Unauthorized<()>> {
+    log::debug!("user_invite_resend");
     state.check_maintenance().await;
-    let ab_peers = state.get_ab_peers(ab).await;
-    if ab_peers.is_none() {
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::User(guid))
+        .await
+    {
         return Err(status::Unauthorized::<()>(()));
     }
-    let ab_peers = ab_peers.unwrap();
-    let ab_peer_response = AbPeersResponse {
-        error: None,
-        total: ab_peers.len() as u32,
-        data: ab_peers,
-    };
-    Ok(Json(ab_peer_response))
+    let user_id = uuid_into_guid(guid).ok_or(status::Unauthorized::<()>(()))?;
+    let response = state
+        .resend_invite(&user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(response))
 }
 
-/// # Add peer
+/// # Revoke an Invitation
 ///
-/// This function is an API endpoint that adds a peer to an address book.
-///
-/// ## Parameters
+/// This function is an API endpoint that revokes the invitation of a user, the user stays disabled and can be deleted.
+/// It requires the `users.manage` permission and is tagged with "user" for OpenAPI documentation.
 ///
-/// - `ab`: The identifier of the address book.
+/// ## Errors
 ///
-/// - `request`: A JSON object containing the new peer information.
+/// This function will return a `status::Unauthorized` error if the user has no invitation or is not in the team of the caller.
 ///
-/// ## Returns
+/// # Example
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.
-#[openapi(tag = "address book")]
-#[post(
-    "/api/ab/peer/add/<ab>",
-    format = "application/json",
-    data = "<request>"
-)]
-async fn ab_peer_add(
+/// DELETE /api/user/invite/018f2556-2301-79eb-91a2-cffe5ced4236
+#[openapi(tag = "user")]
+#[delete("/api/user/invite/<guid>", format = "application/json")]
+async fn user_invite_revoke(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    request: Json<AbPeer>,
-    ab: &str,
+    user: Authorized<permission::UsersManage>,
+    guid: &str,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
-    let ab_peer = request.0;
+    log::debug!("user_invite_revoke");
     state.check_maintenance().await;
-    state.add_ab_peer(ab, ab_peer).await;
+    if !state
+        .is_in_team_scope(user.team.as_deref(), TeamMember::User(guid))
+        .await
+    {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let user_id = uuid_into_guid(guid).ok_or(status::Unauthorized::<()>(()))?;
+    state
+        .revoke_invite(&user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
     Ok(ActionResponse::Empty)
 }
 
-/// # Update peer
+/// # Accept an Invitation
 ///
-/// This function is an API endpoint that updates a peer in an address book.
+/// This function is an API endpoint that sets the password of an invited user with the token of its invitation and enables the user.
+/// It does not require authentication, the token is single use.
+/// It is tagged with "login" for OpenAPI documentation.
 ///
 /// ## Parameters
 ///
-/// - `ab`: The identifier of the address book.
-///
-/// - `request`: A JSON object containing the updated peer information.
+/// - `request`: The token received by mail and the new password.  <br>
 ///
 /// ## Returns
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.
-#[openapi(tag = "address book")]
-#[put(
-    "/api/ab/peer/update/<ab>",
-    format = "application/json",
-    data = "<request>"
-)]
-async fn ab_peer_update(
+/// If successful, this function returns an empty response, the user can log in with its password.  <br>
+/// If the passwords mismatch or the password does not follow the password policy, this function returns an error message.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the token is unknown or expired.
+///
+/// # Example
+///
+/// POST /api/invite/accept
+/// {"token":"...","password":"...","confirm-password":"..."}
+#[openapi(tag = "login")]
+#[post("/api/invite/accept", format = "application/json", data = "<request>")]
+async fn invite_accept(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    request: Json<AbPeer>,
-    ab: &str,
+    request: Json<SetPasswordRequest>,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
-    let mut ab_peer = request.0;
-    let old_ab_peer = state.get_ab_peer(ab, ab_peer.id.as_str()).await;
-    if old_ab_peer.is_none() {
-        return Err(status::Unauthorized::<()>(()));
-    }
-    let old_ab_peer = old_ab_peer.unwrap();
-    ab_peer.hash = ab_peer.hash.or(old_ab_peer.hash);
-    ab_peer.password = ab_peer.password.or(old_ab_peer.password);
-    ab_peer.username = ab_peer.username.or(old_ab_peer.username);
-    ab_peer.hostname = ab_peer.hostname.or(old_ab_peer.hostname);
-    ab_peer.platform = ab_peer.platform.or(old_ab_peer.platform);
-    ab_peer.alias = ab_peer.alias.or(old_ab_peer.alias);
-    ab_peer.tags = ab_peer.tags.or(old_ab_peer.tags);
-    ab_peer.force_always_relay = ab_peer
-        .force_always_relay
-        .or(old_ab_peer.force_always_relay);
-    ab_peer.rdp_port = ab_peer.rdp_port.or(old_ab_peer.rdp_port);
-    ab_peer.rdp_username = ab_peer.rdp_username.or(old_ab_peer.rdp_username);
-    ab_peer.login_name = ab_peer.login_name.or(old_ab_peer.login_name);
-    ab_peer.same_server = ab_peer.same_server.or(old_ab_peer.same_server);
-    state.check_maintenance().await;
-    state.add_ab_peer(ab, ab_peer).await;
-    Ok(ActionResponse::Empty)
+    log::debug!("invite_accept");
+    state.check_maintenance().await;
+    set_password_with_token(state, UserTokenKind::Invite, request.into_inner()).await
 }
 
-/// # Delete peer
+/// # Request a Password Reset
 ///
-/// This function is an API endpoint that deletes a peer from an address book.
+/// This function is an API endpoint that sends a password reset link by mail to an active user.
+/// It does not require authentication and always succeeds, so it does not tell whether the email is known,
+/// the user is looked up and the mail is sent in the background so that the response time does not tell it either.
+/// At most 3 requests per email and 10 requests per client ip are handled per hour, the others are ignored.
+/// The link expires after `password_reset_expiry_hours`.
+/// It is tagged with "login" for OpenAPI documentation.
 ///
-/// ## Parameters
+/// # Example
 ///
-/// - `ab`: The identifier of the address book.
+/// POST /api/password/reset/request
+/// {"email":"alice@example.org"}
+#[openapi(tag = "login")]
+#[post("/api/password/reset/request", format = "application/json", data = "<request>")]
+async fn password_reset_request(
+    state: &State<ApiState>,
+    client: ExtendedRequest,
+    request: Json<PasswordResetRequest>,
+) -> ActionResponse {
+    log::debug!("password_reset_request");
+    state.check_maintenance().await;
+    state
+        .request_password_reset(request.email.trim(), client.client_ip.as_deref())
+        .await;
+    ActionResponse::Empty
+}
+
+/// # Reset a Password
 ///
-/// - `request`: A JSON object containing an array of peer IDs to be deleted.
+/// This function is an API endpoint that sets the password of a user with the token of a password reset,
+/// all the sessions of the user are closed.
+/// It does not require authentication, the token is single use.
+/// It is tagged with "login" for OpenAPI documentation.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns an `ActionResponse::Empty` object.
-#[openapi(tag = "address book")]
-#[delete("/api/ab/peer/<ab>", format = "application/json", data = "<request>")]
-async fn ab_peer_delete(
+/// If the passwords mismatch or the password does not follow the password policy, this function returns an error message.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the token is unknown or expired, or if the user is disabled.
+///
+/// # Example
+///
+/// POST /api/password/reset
+/// {"token":"...","password":"...","confirm-password":"..."}
+#[openapi(tag = "login")]
+#[post("/api/password/reset", format = "application/json", data = "<request>")]
+async fn password_reset(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
-    ab: &str,
-    request: Json<Vec<String>>,
+    request: Json<SetPasswordRequest>,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
-    if request.0.is_empty() {
-        return Err(status::Unauthorized::<()>(()));
-    }
-    let peers_to_delete = request.0;
+    log::debug!("password_reset");
     state.check_maintenance().await;
-    state.delete_ab_peer(ab, peers_to_delete).await;
-    Ok(ActionResponse::Empty)
+    set_password_with_token(state, UserTokenKind::PasswordReset, request.into_inner()).await
 }
 
-/// # List strategies
-///
-/// This function is an API endpoint that retrieves the list of all strategies. <br>
-/// TODO: This function is currently unused.
-///
-#[openapi(tag = "todo")]
-#[get("/api/stategies", format = "application/json")]
-async fn strategies(
+/// Set a password with the token of an invitation or of a password reset
+async fn set_password_with_token(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
-) -> Result<Json<UsersResponse>, status::NotFound<()>> {
-    log::debug!("peers");
-    state.check_maintenance().await;
-
-    let response = UsersResponse {
-        msg: "success".to_string(),
-        total: 1,
-        data: "[{}]".to_string(),
-    };
-
-    Ok(Json(response))
+    kind: UserTokenKind,
+    request: SetPasswordRequest,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    if request.password != request.confirm_password {
+        return Ok(ActionResponse::Error("Passwords mismatch".to_string()));
+    }
+    let user_id = state
+        .get_user_token_user(kind, request.token.as_str())
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    if let Err(error) = state.check_password(Some(&user_id), request.password.as_str()).await {
+        return Ok(ActionResponse::Error(error));
+    }
+    state
+        .set_password_with_token(kind, request.token.as_str(), request.password.as_str())
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(ActionResponse::Empty)
 }
 
 /// # Add user
@@ -1696,18 +4074,29 @@ async fn strategies(
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<UsersResponse>` object containing the updated user information.
+/// If successful, this function returns a `Json<UsersResponse>` object containing the updated user information.  <br>
+/// If the passwords mismatch or the password does not follow the password policy, the message starts with `error:`.
 #[openapi(tag = "user")]
 #[post("/api/user", format = "application/json", data = "<request>")]
 async fn user_add(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::UsersManage>,
     request: Json<AddUserRequest>,
 ) -> Result<Json<UsersResponse>, status::Unauthorized<()>> {
     log::debug!("create_user");
     state.check_maintenance().await;
 
     let user_parameters = request.0;
+    // only the admins can create other admins
+    if user_parameters.is_admin {
+        let (is_admin, _) = state
+            .get_user_permissions(&user.info.user_id)
+            .await
+            .ok_or(status::Unauthorized::<()>(()))?;
+        if !is_admin {
+            return Err(status::Unauthorized::<()>(()));
+        }
+    }
     if user_parameters.password != user_parameters.confirm_password {
         return Ok(Json(UsersResponse {
             msg: "error: Passwords mismatch".to_string(),
@@ -1715,7 +4104,16 @@ async fn user_add(
             data: "[{}]".to_string(),
         }));
     }
-    let res = state.add_user(user_parameters).await;
+    if let Err(error) = state.check_password(None, user_parameters.password.as_str()).await {
+        return Ok(Json(UsersResponse {
+            msg: format!("error: {}", error),
+            total: 0,
+            data: "[{}]".to_string(),
+        }));
+    }
+    let res = state
+        .add_user(user_parameters, user.team.as_deref())
+        .await;
     if res.is_none() {
         return Err(status::Unauthorized::<()>(()));
     }
@@ -1730,38 +4128,87 @@ async fn user_add(
 
 /// # Delete user
 /// 
-/// This function is an API endpoint that deletes a user.
+/// This function is an API endpoint that deletes users.
+/// The admins and the users with permissions the current user does not have are skipped unless the current user is an admin.
+/// Each user is deleted in a single transaction: its shared address books and its devices are given to
+/// `transfer_to` or deleted, its personal address book, its address book rules, its sessions, its third party
+/// identities and its user data are deleted.
+/// With `archive` the users are kept as archived accounts, without email nor usable password, so that the
+/// audit logs still show their names. An archived user cannot log in nor be enabled again.
 /// 
 /// ## Parameters
 /// 
-/// - `request`: A JSON object containing the list of users to delete.
+/// - `request`: A JSON object containing the list of users to delete, the optional guid of the user receiving
+///   their shared address books and devices and the archive flag.
 /// 
 /// ## Returns
 /// 
-/// If successful, this function returns a `Json<UsersResponse>` object containing the number of users deleted.
+/// If successful, this function returns a `Json<UsersResponse>` object containing the number of users deleted,
+/// its `data` field is the JSON array of the `UserDeletionSummary` of each deleted user.
+/// 
+/// ## Errors
+/// 
+/// - `error: invalid transfer target` if the target does not exist, is out of the team scope or is deleted too.
+/// 
+/// # Example
+/// 
+/// ```json
+/// {
+///   "rows": ["018f2556-2301-79eb-91a2-cffe5ced4236"],
+///   "transfer_to": "018f2556-2301-79eb-91a2-cffe5ced4237",
+///   "archive": true
+/// }
+/// ```
 #[openapi(tag = "user")]
 #[delete("/api/user", format = "application/json", data = "<request>")]
 async fn user_delete(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::UsersManage>,
     request: Json<DeleteUserRequest>,
 ) -> Result<Json<UsersResponse>, status::Unauthorized<()>> {
-    log::debug!("create_user");
+    log::debug!("user_delete");
     state.check_maintenance().await;
 
     let delete_users = request.0;
 
-    let mut count = 0;
+    if let Some(transfer_to) = delete_users.transfer_to.as_deref() {
+        if delete_users.rows.iter().any(|uuid| uuid == transfer_to)
+            || !state
+                .is_in_team_scope(user.team.as_deref(), TeamMember::User(transfer_to))
+                .await
+        {
+            return Ok(Json(UsersResponse {
+                msg: "error: invalid transfer target".to_string(),
+                total: 0,
+                data: "[]".to_string(),
+            }));
+        }
+    }
+
+    let mut summaries = Vec::new();
     for uuid in delete_users.rows {
-        let res = state.user_delete(uuid.as_str()).await;
-        if res.is_some() {
-            count += 1;
+        if !state
+            .is_in_team_scope(user.team.as_deref(), TeamMember::User(uuid.as_str()))
+            .await
+        {
+            continue;
+        }
+        // only the admins delete the admins and the users with permissions the current user does not have
+        match uuid_into_guid(uuid.as_str()) {
+            Some(guid) if state.can_manage_user(&user.info.user_id, &guid).await => {}
+            _ => continue,
+        }
+        let res = state
+            .user_delete(uuid.as_str(), delete_users.transfer_to.as_deref(), delete_users.archive)
+            .await;
+        if let Some(summary) = res {
+            summaries.push(summary);
         }
     }
     let response = UsersResponse {
         msg: "success".to_string(),
-        total: count,
-        data: "[{}]".to_string(),
+        total: summaries.len() as u32,
+        data: serde_json::to_string(&summaries).unwrap_or_default(),
     };
 
     Ok(Json(response))
@@ -1770,6 +4217,7 @@ async fn user_delete(
 /// # Enable users
 ///
 /// This function is an API endpoint that enables or disables users.
+/// The admins and the users with permissions the current user does not have are skipped unless the current user is an admin.
 ///
 /// ## Parameters
 ///
@@ -1782,7 +4230,7 @@ async fn user_delete(
 #[post("/api/enable-users", format = "application/json", data = "<request>")]
 async fn user_enable(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::UsersManage>,
     request: Json<EnableUserRequest>,
 ) -> Result<Json<UsersResponse>, status::Unauthorized<()>> {
     log::debug!("create_user");
@@ -1792,6 +4240,17 @@ async fn user_enable(
 
     let mut count = 0;
     for uuid in enable_users.rows {
+        if !state
+            .is_in_team_scope(user.team.as_deref(), TeamMember::User(uuid.as_str()))
+            .await
+        {
+            continue;
+        }
+        // only the admins enable or disable the admins and the users with permissions the current user does not have
+        match uuid_into_guid(uuid.as_str()) {
+            Some(guid) if state.can_manage_user(&user.info.user_id, &guid).await => {}
+            _ => continue,
+        }
         let res = state
             .user_change_status(uuid.as_str(), enable_users.disable)
             .await;
@@ -1811,7 +4270,11 @@ async fn user_enable(
 /// # Update user
 ///
 /// This function is an API endpoint that updates a user.<br>
-/// Normal user can only update themselves, admin can update any user.<br>
+/// Normal user can only update themselves, the users with the `users.manage` permission can update any user of their team
+/// who is not an admin and has no permission they do not have, the admins can update any user.<br>
+/// Only the admins can change `is_admin` and only the users with the `users.manage` permission can change `status`.<br>
+/// The users without the `users.manage` permission must give their `current-password` to change their own password,
+/// like with `/api/me/password`.<br>
 ///
 /// ## Parameters
 ///
@@ -1819,7 +4282,9 @@ async fn user_enable(
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<UsersResponse>` object containing the updated user information.
+/// If successful, this function returns a `Json<UsersResponse>` object containing the updated user information.  <br>
+/// A new password must not reuse the current or the previous passwords of the user, if it is rejected or if the current password
+/// is wrong the message starts with `error:`.
 #[openapi(tag = "user")]
 #[put("/api/user", format = "application/json", data = "<request>")]
 async fn user_update(
@@ -1835,24 +4300,464 @@ async fn user_update(
     }
 
     let guid = guid.unwrap();
-    let is_admin = state
-        .is_current_user_admin(&user.info)
+    let (is_admin, permissions) = state
+        .get_user_permissions(&user.info.user_id)
         .await
-        .unwrap_or(false);
+        .ok_or(status::Unauthorized::<()>(()))?;
+    let can_manage_users = permissions
+        .iter()
+        .any(|permission| permission.grants(Permission::UsersManage));
 
-    if !is_admin && user.info.user_id != guid {
+    if !can_manage_users && user.info.user_id != guid {
         return Err(status::Unauthorized::<()>(()));
     }
+    if user.info.user_id != guid {
+        // the admins of a team only update the users of their team
+        let team = state
+            .get_user_team_scope(&user.info.user_id)
+            .await
+            .ok_or(status::Unauthorized::<()>(()))?;
+        let uuid = guid_into_uuid(guid.clone()).ok_or(status::Unauthorized::<()>(()))?;
+        if !state
+            .is_in_team_scope(team.as_deref(), TeamMember::User(uuid.as_str()))
+            .await
+        {
+            return Err(status::Unauthorized::<()>(()));
+        }
+        // only the admins update the admins and the users with permissions the current user does not have
+        if !state.can_manage_user(&user.info.user_id, &guid).await {
+            return Err(status::Unauthorized::<()>(()));
+        }
+    }
     let response = UsersResponse {
         msg: "success".to_string(),
         total: 1,
         data: "[{}]".to_string(),
     };
-    let user_update = request.0;
+    let mut user_update = request.0;
+    // only the admins can give the admin role and only the user managers can enable or disable a user
+    if !is_admin {
+        user_update.is_admin = None;
+    }
+    if !can_manage_users {
+        user_update.status = None;
+    }
+    // a new password must follow the password policy
+    if let Some(password) = user_update.password.as_deref().filter(|password| !password.is_empty()) {
+        if user_update.confirm_password.as_deref() != Some(password) {
+            return Ok(Json(UsersResponse {
+                msg: "error: Passwords mismatch".to_string(),
+                total: 0,
+                data: "[{}]".to_string(),
+            }));
+        }
+        if let Err(error) = state.check_password(Some(&guid), password).await {
+            return Ok(Json(UsersResponse {
+                msg: format!("error: {}", error),
+                total: 0,
+                data: "[{}]".to_string(),
+            }));
+        }
+        // the users who cannot manage the users prove they know their current password
+        if !can_manage_users {
+            let current_password = user_update.current_password.as_deref().unwrap_or_default();
+            if state
+                .change_user_password(&guid, current_password, password)
+                .await
+                .is_none()
+            {
+                return Ok(Json(UsersResponse {
+                    msg: "error: Wrong current password".to_string(),
+                    total: 0,
+                    data: "[{}]".to_string(),
+                }));
+            }
+            user_update.password = None;
+        }
+    }
     state.user_update(guid, user_update).await;
     Ok(Json(response))
 }
 
+/// # Get my Profile
+///
+/// This function is an API endpoint that returns the profile of the current user.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserProfile>` object with the email, the note, the group, the team and the notification preferences of the user.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// GET /api/me
+#[openapi(tag = "user")]
+#[get("/api/me", format = "application/json")]
+async fn me(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<UserProfile>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let profile = state
+        .get_user_profile(&user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(profile))
+}
+
+/// # Update my Profile
+///
+/// This function is an API endpoint that updates the email, the note and the notification preferences of the current user.
+/// The missing fields are unchanged, an empty email removes the email.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The fields to update.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns an empty response.  <br>
+/// If the email is invalid or used by another user, this function returns an error message.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// PUT /api/me
+/// {"email":"alice@example.org","note":"3rd floor","notifications":{"email_alarm_notification":true}}
+#[openapi(tag = "user")]
+#[put("/api/me", format = "application/json", data = "<request>")]
+async fn me_update(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    request: Json<UpdateProfileRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let request = request.into_inner();
+    if let Some(email) = request.email.as_deref().map(|email| email.trim()) {
+        if !email.is_empty() && !email.contains('@') {
+            return Ok(ActionResponse::Error("Invalid email".to_string()));
+        }
+    }
+    if state
+        .update_user_profile(&user.info.user_id, request)
+        .await
+        .is_none()
+    {
+        return Ok(ActionResponse::Error("Email already used".to_string()));
+    }
+    Ok(ActionResponse::Empty)
+}
+
+/// # Change my Password
+///
+/// This function is an API endpoint that changes the password of the current user after checking its current password.
+/// The new password must follow the password policy and must not reuse the current or the previous passwords.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `request`: The current password and the new password.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns an empty response.  <br>
+/// If the current password is wrong, if the passwords mismatch or if the password does not follow the password policy, this function returns an error message.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// PUT /api/me/password
+/// {"current-password":"...","password":"...","confirm-password":"..."}
+#[openapi(tag = "user")]
+#[put("/api/me/password", format = "application/json", data = "<request>")]
+async fn me_password(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    request: Json<ChangePasswordRequest>,
+) -> Result<ActionResponse, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    if request.password != request.confirm_password {
+        return Ok(ActionResponse::Error("Passwords mismatch".to_string()));
+    }
+    if let Err(error) = state
+        .check_password(Some(&user.info.user_id), request.password.as_str())
+        .await
+    {
+        return Ok(ActionResponse::Error(error));
+    }
+    if state
+        .change_user_password(
+            &user.info.user_id,
+            request.current_password.as_str(),
+            request.password.as_str(),
+        )
+        .await
+        .is_none()
+    {
+        return Ok(ActionResponse::Error("Wrong current password".to_string()));
+    }
+    Ok(ActionResponse::Empty)
+}
+
+/// # Get my Devices
+///
+/// This function is an API endpoint that returns the peers owned by the current user.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<PeersResponse>` object with the peers of the user.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// GET /api/me/peers
+#[openapi(tag = "user")]
+#[get("/api/me/peers", format = "application/json")]
+async fn me_peers(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<PeersResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let peers = state
+        .get_user_peers(&user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(PeersResponse {
+        msg: "success".to_string(),
+        total: peers.len() as u32,
+        data: peers,
+    }))
+}
+
+/// # Get my Address Books
+///
+/// This function is an API endpoint that returns the personal address book of the current user and the shared address books it can access.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserAddressBooksResponse>` object with the address books and the effective rule of the user on each of them.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// GET /api/me/address-books
+#[openapi(tag = "user")]
+#[get("/api/me/address-books", format = "application/json")]
+async fn me_address_books(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<UserAddressBooksResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let address_books = state
+        .get_user_address_books(&user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(UserAddressBooksResponse {
+        msg: "success".to_string(),
+        total: address_books.len() as u32,
+        data: address_books,
+    }))
+}
+
+/// # List my Data
+///
+/// This function is an API endpoint that lists the entries of the user data storage of the current user, without their data.
+/// The user data storage keeps the preferences of the clients and of the web console, like the recent sessions, the favorites or the layout.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserDataListResponse>` object with the type, the size and the version of each entry.
+///
+/// ## Errors
+///
+/// This function will return a `status::Unauthorized` error if the user is not authenticated.
+///
+/// # Example
+///
+/// GET /api/me/data
+#[openapi(tag = "user")]
+#[get("/api/me/data", format = "application/json")]
+async fn me_data_list(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+) -> Result<Json<UserDataListResponse>, status::Unauthorized<()>> {
+    state.check_maintenance().await;
+    let items = state
+        .get_user_data_items(&user.info.user_id)
+        .await
+        .ok_or(status::Unauthorized::<()>(()))?;
+    Ok(Json(UserDataListResponse {
+        msg: "success".to_string(),
+        total: items.len() as u32,
+        data: items,
+    }))
+}
+
+/// # Get my Data
+///
+/// This function is an API endpoint that returns an entry of the user data storage of the current user.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `data_type`: The type of the entry.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserDataEntry>` object with the data and its version `updated_at`.
+///
+/// ## Errors
+///
+/// This function will return a `status::NotFound` error if the entry does not exist.
+///
+/// # Example
+///
+/// GET /api/me/data/favorites
+#[openapi(tag = "user")]
+#[get("/api/me/data/<data_type>", format = "application/json")]
+async fn me_data_get(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    data_type: &str,
+) -> Result<Json<UserDataEntry>, status::NotFound<()>> {
+    state.check_maintenance().await;
+    let entry = state
+        .get_user_data(&user.info.user_id, data_type)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
+    Ok(Json(entry))
+}
+
+/// # Put my Data
+///
+/// This function is an API endpoint that creates or replaces an entry of the user data storage of the current user.
+/// The writes are optimistic: an entry is created when `updated_at` is missing, it is replaced when `updated_at` is the version read before.
+/// The type has 1 to 30 letters, digits, `_`, `-` or `.`, the size and the number of entries are limited by the
+/// `user_data_max_size` and `user_data_max_entries` settings.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `data_type`: The type of the entry.  <br>
+/// - `request`: The data and the version read before.  <br>
+///
+/// ## Returns
+///
+/// If successful, this function returns a `Json<UserDataItem>` object with the new version of the entry.
+///
+/// ## Errors
+///
+/// This function will return a `400 Bad Request` error if the type, the size or the number of entries is invalid,
+/// and a `409 Conflict` error if the entry exists or changed since it was read.
+///
+/// # Example
+///
+/// PUT /api/me/data/favorites
+/// {"data":"[\"123456789\"]","updated_at":"2024-06-01 12:00:00.000"}
+#[openapi(tag = "user")]
+#[put("/api/me/data/<data_type>", format = "application/json", data = "<request>")]
+async fn me_data_put(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    data_type: &str,
+    request: Json<PutUserDataRequest>,
+) -> Result<Json<UserDataItem>, status::Custom<ActionResponse>> {
+    state.check_maintenance().await;
+    let request = request.into_inner();
+    let size = request.data.len() as u32;
+    let updated_at = state
+        .put_user_data(&user.info.user_id, data_type, request)
+        .await
+        .ok_or(status::Custom(
+            Status::InternalServerError,
+            ActionResponse::Error("The entry cannot be stored".to_string()),
+        ))?
+        .map_err(user_data_error)?;
+    Ok(Json(UserDataItem {
+        data_type: data_type.to_string(),
+        size,
+        updated_at,
+    }))
+}
+
+/// # Delete my Data
+///
+/// This function is an API endpoint that deletes an entry of the user data storage of the current user.
+/// It is tagged with "user" for OpenAPI documentation.
+///
+/// ## Parameters
+///
+/// - `data_type`: The type of the entry.  <br>
+/// - `updated_at`: The version read before, the entry is deleted whatever its version without it.  <br>
+///
+/// ## Errors
+///
+/// This function will return a `404 Not Found` error if the entry does not exist and a `409 Conflict` error if it changed since it was read.
+///
+/// # Example
+///
+/// DELETE /api/me/data/favorites?updated_at=2024-06-01%2012:00:00.000
+#[openapi(tag = "user")]
+#[delete("/api/me/data/<data_type>?<updated_at>", format = "application/json")]
+async fn me_data_delete(
+    state: &State<ApiState>,
+    user: AuthenticatedUser,
+    data_type: &str,
+    updated_at: Option<&str>,
+) -> Result<ActionResponse, status::Custom<ActionResponse>> {
+    state.check_maintenance().await;
+    let res = state
+        .delete_user_data(&user.info.user_id, data_type, updated_at)
+        .await
+        .ok_or(status::Custom(
+            Status::InternalServerError,
+            ActionResponse::Error("The entry cannot be deleted".to_string()),
+        ))?;
+    match res {
+        Ok(()) => Ok(ActionResponse::Empty),
+        Err(UserDataError::Conflict(None)) => Err(status::Custom(
+            Status::NotFound,
+            ActionResponse::Error("The entry does not exist".to_string()),
+        )),
+        Err(error) => Err(user_data_error(error)),
+    }
+}
+
+/// Map a rejected write of the user data storage to its response
+fn user_data_error(error: UserDataError) -> status::Custom<ActionResponse> {
+    match error {
+        UserDataError::Invalid(error) => status::Custom(Status::BadRequest, ActionResponse::Error(error)),
+        UserDataError::Conflict(Some(updated_at)) => status::Custom(
+            Status::Conflict,
+            ActionResponse::Error(format!("The entry exists or changed, its current version is {}", updated_at)),
+        ),
+        UserDataError::Conflict(None) => status::Custom(
+            Status::Conflict,
+            ActionResponse::Error("The entry does not exist anymore".to_string()),
+        ),
+    }
+}
+
 /// # Add OIDC Provider
 ///
 /// This function is an API endpoint that adds an OIDC provider.
@@ -1862,11 +4767,14 @@ async fn user_update(
 #[put("/api/oidc/settings", format = "application/json", data = "<_request>")]
 async fn oidc_add(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::SettingsManage>,
     _request: Json<EnableUserRequest>,
 ) -> Result<Json<EnableUserRequest>, status::Unauthorized<()>> {
     log::debug!("Add OIDC Provider");
     state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
 
     Err(status::Unauthorized::<()>(()))
 }
@@ -1880,10 +4788,13 @@ async fn oidc_add(
 #[get("/api/oidc/settings", format = "application/json")]
 async fn oidc_get(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::SettingsManage>,
 ) -> Result<Json<OidcSettingsResponse>, status::Unauthorized<()>> {
     log::debug!("create_user");
     state.check_maintenance().await;
+    if !user.is_global() {
+        return Err(status::Unauthorized::<()>(()));
+    }
     Err(status::Unauthorized::<()>(()))
 }
 
@@ -1911,7 +4822,7 @@ async fn oidc_get(
 )]
 async fn users_client(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     current: u32,
     #[allow(non_snake_case, unused_variables)] pageSize: u32,
     #[allow(unused_variables)] accessible: Option<bool>,
@@ -1919,8 +4830,12 @@ async fn users_client(
 ) -> Result<Json<UserList>, status::NotFound<()>> {
     log::debug!("users");
     state.check_maintenance().await;
+    let team = state
+        .get_user_team_scope(&user.info.user_id)
+        .await
+        .ok_or(status::NotFound::<()>(()))?;
 
-    let res = state.get_all_users(None, None, current, pageSize).await;
+    let res = state.get_all_users(None, None, current, pageSize, team.as_deref()).await;
     if res.is_none() {
         return Err(status::NotFound::<()>(()));
     }
@@ -2106,16 +5021,18 @@ async fn software_download() -> Redirect {
 ///
 /// ## Parameters
 ///
-/// - `current`: The current page number for pagination. This parameter is currently unused.
+/// - `current`: The page number, starting at 1.
 ///
-/// - `pageSize`: The number of items per page for pagination. This parameter is currently unused.
+/// - `pageSize`: The number of rules per page.
 ///
 /// - `ab`: The identifier of the shared address book.
 ///
 /// ## Returns
 ///
-/// If successful, this function returns a `Json<AbRulesResponse>` object containing the rules for the address book.  <br>
+/// If successful, this function returns a `Json<AbRulesResponse>` object containing the requested page of rules,
+/// `total` is the number of rules of the address book.  <br>
 /// If the address book does not exist or the user is not authorized to access it, this function returns a `status::Unauthorized` error.  <br>
+/// The global admins and the users with full control on the address book can list its rules.  <br>
 ///
 /// ## Errors
 ///
@@ -2125,21 +5042,23 @@ async fn software_download() -> Redirect {
 #[get("/api/ab/rules?<current>&<pageSize>&<ab>", format = "application/json")]
 async fn ab_rules(
     state: &State<ApiState>,
-    _user: AuthenticatedUser,
+    user: AuthenticatedUser,
     current: u32,
-    #[allow(unused_variables)] pageSize: u32,
+    #[allow(non_snake_case)] pageSize: u32,
     ab: &str,
 ) -> Result<Json<AbRulesResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
-    let current = if current < 1 { 0 } else { current - 1 };
+    if !state.check_ab_control(&user.info.user_id, ab).await {
+        return Err(status::Unauthorized::<()>(()));
+    }
     let rules = state.get_ab_rules(current, pageSize, ab).await;
     if rules.is_none() {
         return Err(status::Unauthorized::<()>(()));
     }
-    let rules = rules.unwrap();
+    let (total, rules) = rules.unwrap();
     let response = AbRulesResponse {
         msg: "success".to_string(),
-        total: rules.len() as u32,
+        total,
         data: rules,
     };
     Ok(Json(response))
@@ -2157,7 +5076,8 @@ async fn ab_rules(
 /// ## Returns
 ///
 /// If successful, this function returns an `ActionResponse::Empty` indicating that the rule was successfully added. <br>
-/// If the system is in maintenance mode, this function returns a `status::Unauthorized` error.
+/// If the user or the group is not a valid guid, this function returns an `ActionResponse::Error`, the rule is not added. <br>
+/// If the user is neither a global admin nor has full control on the address book, this function returns a `status::Unauthorized` error.
 ///
 /// ## Errors
 ///
@@ -2166,17 +5086,36 @@ async fn ab_rules(
 #[post("/api/ab/rule", format = "application/json", data = "<request>")]
 async fn ab_rule_add(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::AddressBooksManage>,
     request: Json<AbRuleAddRequest>,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
     state.check_maintenance().await;
+    if !state.check_ab_control(&user.info.user_id, request.0.guid.as_str()).await {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    // the admins of a team only give access to the users and groups of their team
+    if let Some(rule_user) = request.0.user.as_deref() {
+        if !state
+            .is_in_team_scope(user.team.as_deref(), TeamMember::User(rule_user))
+            .await
+        {
+            return Err(status::Unauthorized::<()>(()));
+        }
+    }
+    if let Some(rule_group) = request.0.group.as_deref() {
+        if !groups_in_team_scope(state, user.team.as_deref(), &[rule_group.to_string()]).await {
+            return Err(status::Unauthorized::<()>(()));
+        }
+    }
     let rule = AbRule {
         guid: request.0.guid,
         user: request.0.user,
         group: request.0.group,
         rule: request.0.rule,
     };
-    state.add_ab_rule(rule).await;
+    if state.add_ab_rule(rule).await.is_none() {
+        return Ok(ActionResponse::Error("Invalid rule".to_string()));
+    }
     Ok(ActionResponse::Empty)
 }
 
@@ -2192,7 +5131,7 @@ async fn ab_rule_add(
 /// ## Returns
 ///
 /// If successful, this function returns an `ActionResponse::Empty` indicating that the rule was successfully deleted. <br>
-/// If the system is in maintenance mode, this function returns a `status::Unauthorized` error.
+/// If the rule does not exist or the user is neither a global admin nor has full control on its address book, this function returns a `status::Unauthorized` error.
 ///
 /// ## Errors
 ///
@@ -2201,11 +5140,19 @@ async fn ab_rule_add(
 #[delete("/api/ab/rule", format = "application/json", data = "<request>")]
 async fn ab_rule_delete(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::AddressBooksManage>,
     request: Json<AbRuleDeleteRequest>,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
     state.check_maintenance().await;
     let rule = request.0.guid;
+    let ab = state.get_ab_rule_ab(rule.as_str()).await;
+    if ab.is_none() {
+        return Err(status::Unauthorized::<()>(()));
+    }
+    let ab = ab.unwrap();
+    if !state.check_ab_control(&user.info.user_id, ab.as_str()).await {
+        return Err(status::Unauthorized::<()>(()));
+    }
     state.delete_ab_rule(rule.as_str()).await;
     Ok(ActionResponse::Empty)
 }
@@ -2226,7 +5173,7 @@ async fn ab_rule_delete(
 #[post("/api/ab/shared/add", format = "application/json", data = "<request>")]
 async fn ab_shared_add(
     state: &State<ApiState>,
-    user: AuthenticatedAdmin,
+    user: Authorized<permission::AddressBooksManage>,
     request: Json<AbSharedAddRequest>,
 ) -> Result<Json<AbSharedProfilesResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
@@ -2263,15 +5210,22 @@ async fn ab_shared_add(
 /// ## Returns
 /// 
 /// If successful, this function returns an `ActionResponse::Empty` object.
+/// The request is unauthorized if one of the address books is a personal address book
+/// or is not controlled by the user.
 #[openapi(tag = "address book")]
 #[delete("/api/ab/shared", format = "application/json", data = "<request>")]
 async fn ab_shared_delete(
     state: &State<ApiState>,
-    _user: AuthenticatedAdmin,
+    user: Authorized<permission::AddressBooksManage>,
     request: Json<Vec<String>>,
 ) -> Result<ActionResponse, status::Unauthorized<()>> {
     state.check_maintenance().await;
     let shared_profiles_to_delete = request.0;
+    for ab in shared_profiles_to_delete.iter() {
+        if !state.check_ab_control(&user.info.user_id, ab.as_str()).await {
+            return Err(status::Unauthorized::<()>(()));
+        }
+    }
     state.delete_shared_address_books(shared_profiles_to_delete).await;
     Ok(ActionResponse::Empty)
 }
@@ -2292,12 +5246,15 @@ async fn ab_shared_delete(
 #[put("/api/ab/shared/update/profile", format = "application/json", data = "<request>")]
 async fn ab_shared_name(
     state: &State<ApiState>,
-    user: AuthenticatedAdmin,
+    user: Authorized<permission::AddressBooksManage>,
     request: Json<AbSharedNameRequest>,
 ) -> Result<Json<AbSharedProfilesResponse>, status::Unauthorized<()>> {
     state.check_maintenance().await;
     let shared_profile = request.0;
     let name = shared_profile.name.expect("Currently name is required");
+    if !state.check_ab_control(&user.info.user_id, shared_profile.guid.as_str()).await {
+        return Err(status::Unauthorized::<()>(()));
+    }
     state.update_shared_address_book(shared_profile.guid.as_str(), name.as_str()).await;
     let shared_profiles = state.get_shared_address_books(user.info.user_id).await;
     let mut ab_shared_profiles = AbSharedProfilesResponse::default();
@@ -2306,7 +5263,7 @@ async fn ab_shared_name(
             guid: ab.ab,
             name: ab.name.unwrap_or("".to_string()),
             owner: guid_into_uuid(ab.owner.expect("Invalid owner")).expect("Invalid GUID"),
-            rule: 3,
+            rule: ab.rule.unwrap_or(0),
             ..Default::default()
         };
         ab_shared_profiles.data.push(address_book);
@@ -17,8 +17,8 @@ use rocket::{
     config::LogLevel,
     data::{Limits, ToByteUnit},
 };
-use sctgdesk_api_server::build_rocket;
-use clap::{Arg, Command};
+use sctgdesk_api_server::{build_rocket, export_users_file, import_users_file, rotate_ab_key};
+use clap::{Arg, ArgAction, Command};
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
@@ -50,8 +50,52 @@ async fn main() -> Result<(), rocket::Error> {
             .help("Sets the secret key for the server")
             .to_owned()
             .default_value("wJq+s/xvwZjmMX3ev0p4gQTs9Ej5wt0brsk3ZGhoBTg="))
+        .arg(Arg::new("rotate_ab_key")
+            .long("rotate-ab-key")
+            .help("Re-encrypts the address book peers credentials with a new master key and exits")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("import_users")
+            .long("import-users")
+            .value_name("FILE")
+            .help("Imports the users of a CSV or JSON file, prints the report and exits"))
+        .arg(Arg::new("export_users")
+            .long("export-users")
+            .value_name("FILE")
+            .help("Exports the users to a CSV or JSON file and exits"))
+        .arg(Arg::new("create_groups")
+            .long("create-groups")
+            .help("Creates the missing groups when importing users")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("dry_run")
+            .long("dry-run")
+            .help("Validates the users without importing them")
+            .action(ArgAction::SetTrue))
         .get_matches();
 
+    if matches.get_flag("rotate_ab_key") {
+        match rotate_ab_key().await {
+            Some(count) => println!("{} address book rows re-encrypted", count),
+            None => eprintln!("address book key rotation failed, check the logs"),
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("import_users") {
+        match import_users_file(path, matches.get_flag("create_groups"), matches.get_flag("dry_run")).await {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default()),
+            Err(error) => eprintln!("users import failed: {}", error),
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("export_users") {
+        match export_users_file(path).await {
+            Ok(count) => println!("{} users exported", count),
+            Err(error) => eprintln!("users export failed: {}", error),
+        }
+        return Ok(());
+    }
+
     // Get values from command line arguments
     let address = matches.get_one::<String>("address").unwrap();
     let port = (matches.get_one::<String>("port").unwrap()).parse::<u16>().unwrap();
@@ -71,7 +115,7 @@ async fn main() -> Result<(), rocket::Error> {
         .merge(("log_level", log_level))
         .merge(("secret_key", secret_key))
         .merge(("ident", format!("SCTGDeskApiServer/{}", env!("CARGO_PKG_VERSION"))))
-        .merge(("limits", Limits::new().limit("json", 2.mebibytes())));
+        .merge(("limits", Limits::new().limit("json", 2.mebibytes()).limit("string", 2.mebibytes())));
 
     // Launch Rocket
     let _rocket = build_rocket(figment).await.ignite().await?.launch().await?;